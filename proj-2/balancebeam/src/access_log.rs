@@ -0,0 +1,367 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use sha2::{Digest, Sha256};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Layout of the access log lines
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// One line when a request is forwarded and another when its response is sent
+    Default,
+    /// Apache's Common Log Format, one line per response
+    Clf,
+    /// Apache's Combined Log Format (CLF plus referer and user agent), one line per response
+    Combined,
+}
+
+/// What the access log (and the metrics) need to know about a request by the time its response is
+/// sent
+pub struct RequestInfo {
+    /// Whether the sampler picked this request for logging
+    pub sampled: bool,
+    /// When we finished reading the request
+    pub received: Instant,
+    /// The upstream the request was forwarded to, once it has been
+    pub upstream: Option<String>,
+    pub client_ip: String,
+    /// The request line, or None if the request couldn't be parsed
+    pub request_line: Option<String>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    /// Hex SHA-256 of the request body as the client sent it, if --hash-bodies is on
+    pub request_body_sha256: Option<String>,
+    /// Length of the response body, if it was streamed to the client instead of being held in the
+    /// response
+    pub streamed_body_len: Option<usize>,
+    /// Hex SHA-256 of a response body that was streamed to the client with --hash-bodies on, or
+    /// `-` if it didn't make it through whole
+    pub response_body_sha256: Option<String>,
+    /// Where the client stands against its rate limit, once the request has been counted against
+    /// it. The response tells the client.
+    pub rate_limit: Option<crate::rate_limit::Quota>,
+}
+
+impl RequestInfo {
+    pub fn new(request: &http::Request<Vec<u8>>, client_ip: &str, sampled: bool) -> RequestInfo {
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        RequestInfo {
+            sampled,
+            received: Instant::now(),
+            upstream: None,
+            client_ip: client_ip.to_string(),
+            request_line: Some(crate::request::format_request_line(request)),
+            referer: header("referer"),
+            user_agent: header("user-agent"),
+            request_body_sha256: None,
+            streamed_body_len: None,
+            response_body_sha256: None,
+            rate_limit: None,
+        }
+    }
+
+    /// For responses to requests we couldn't parse. These are always errors, so they are always
+    /// logged.
+    pub fn unparsed(client_ip: &str) -> RequestInfo {
+        RequestInfo {
+            sampled: true,
+            received: Instant::now(),
+            upstream: None,
+            client_ip: client_ip.to_string(),
+            request_line: None,
+            referer: None,
+            user_agent: None,
+            request_body_sha256: None,
+            streamed_body_len: None,
+            response_body_sha256: None,
+            rate_limit: None,
+        }
+    }
+}
+
+/// Formats a Common or Combined Log Format line, e.g.
+/// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET / HTTP/1.1" 200 2326 "-" "curl/7.68.0"`.
+/// Times are always given in UTC.
+pub fn format_line(
+    format: LogFormat,
+    info: &RequestInfo,
+    response: &http::Response<Vec<u8>>,
+    time: SystemTime,
+) -> String {
+    let body_len = info
+        .streamed_body_len
+        .unwrap_or_else(|| response.body().len());
+    let body_bytes = match body_len {
+        0 => "-".to_string(),
+        len => len.to_string(),
+    };
+    let mut line = format!(
+        "{} - - [{}] \"{}\" {} {}",
+        info.client_ip,
+        format_clf_time(time),
+        quote_escape(info.request_line.as_deref().unwrap_or("-")),
+        response.status().as_u16(),
+        body_bytes
+    );
+    if format == LogFormat::Combined {
+        line.push_str(&format!(
+            " \"{}\" \"{}\"",
+            quote_escape(info.referer.as_deref().unwrap_or("-")),
+            quote_escape(info.user_agent.as_deref().unwrap_or("-"))
+        ));
+    }
+    line
+}
+
+/// Formats the trailer --hash-bodies adds to access log lines, e.g.
+/// ` request_sha256=e3b0... response_sha256=2cf2...`. The response is hashed as it is sent to the
+/// client, i.e. after any compression, but without the framing of a chunked body. Requests we
+/// couldn't parse, and streamed bodies that didn't make it through whole, have `-` for their
+/// digest.
+pub fn format_body_digests(info: &RequestInfo, response: &http::Response<Vec<u8>>) -> String {
+    let response_sha256 = match &info.response_body_sha256 {
+        Some(digest) => digest.clone(),
+        None => payload_sha256(response.body(), crate::response::is_chunked(response)),
+    };
+    format!(
+        " request_sha256={} response_sha256={}",
+        info.request_body_sha256.as_deref().unwrap_or("-"),
+        response_sha256
+    )
+}
+
+/// Returns the hex SHA-256 of a body held in full, hashing only the chunk data of a chunked one so
+/// that the digest doesn't depend on how the sender split it up.
+pub fn payload_sha256(body: &[u8], chunked: bool) -> String {
+    let mut hasher = BodyHasher::default();
+    match chunked.then(|| crate::chunked::decode(body)) {
+        Some(Ok(payload)) => hasher.update(&payload),
+        // Chunks are checked when they're read, so this is a body that isn't chunked after all
+        _ => hasher.update(body),
+    }
+    hasher.finish()
+}
+
+/// Hashes a body a piece at a time as it's passed on, for bodies that are never held whole
+#[derive(Default)]
+pub struct BodyHasher(Sha256);
+
+impl BodyHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    /// Returns the hex digest of everything passed to update.
+    pub fn finish(self) -> String {
+        self.0
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+/// Escapes quotes and backslashes so that client-supplied strings can't break out of their quoted
+/// field in the log line.
+fn quote_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats a time as `10/Oct/2000:13:55:36 +0000`.
+fn format_clf_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs());
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Converts a number of days since 1970-01-01 into a (year, month, day) date in the proleptic
+/// Gregorian calendar, using Howard Hinnant's civil_from_days algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Decides which requests get access log lines, so that busy proxies can log a representative
+/// fraction of their traffic instead of all of it.
+pub struct Sampler {
+    /// Fraction of requests to log, between 0 and 1
+    rate: f64,
+    rng: parking_lot::Mutex<StdRng>,
+}
+
+impl Sampler {
+    pub fn new(rate: f64) -> Sampler {
+        Sampler::with_rng(rate, StdRng::from_entropy())
+    }
+
+    fn with_rng(rate: f64, rng: StdRng) -> Sampler {
+        Sampler {
+            rate,
+            rng: parking_lot::Mutex::new(rng),
+        }
+    }
+
+    /// Returns true if the next request should be logged. The common "log everything" case never
+    /// touches the RNG.
+    pub fn should_log(&self) -> bool {
+        if self.rate >= 1.0 {
+            true
+        } else if self.rate <= 0.0 {
+            false
+        } else {
+            self.rng.lock().gen_bool(self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// The usual regex for parsing Combined Log Format lines
+    const COMBINED_LOG_REGEX: &str = r#"^(\S+) (\S+) (\S+) \[([\w:/]+\s[+\-]\d{4})\] "((?:[^"\\]|\\.)*)" (\d{3}) (\d+|-) "((?:[^"\\]|\\.)*)" "((?:[^"\\]|\\.)*)"$"#;
+
+    #[test]
+    fn test_combined_log_format() {
+        let request = http::Request::builder()
+            .uri("/index.html?page=2")
+            .header("Referer", "http://example.com/start")
+            .header("User-Agent", "curl/7.68.0 \"quoted\"")
+            .body(Vec::new())
+            .unwrap();
+        let info = RequestInfo::new(&request, "10.0.0.7", true);
+        let response = http::Response::builder()
+            .status(200)
+            .body(b"hello".to_vec())
+            .unwrap();
+        // 2000-10-10 13:55:36 UTC
+        let time = UNIX_EPOCH + Duration::from_secs(971186136);
+        let line = format_line(LogFormat::Combined, &info, &response, time);
+        assert_eq!(
+            line,
+            r#"10.0.0.7 - - [10/Oct/2000:13:55:36 +0000] "GET /index.html?page=2 HTTP/1.1" 200 5 "http://example.com/start" "curl/7.68.0 \"quoted\"""#
+        );
+
+        let captures = regex::Regex::new(COMBINED_LOG_REGEX)
+            .unwrap()
+            .captures(&line)
+            .expect("Line doesn't match the Combined Log Format");
+        assert_eq!(&captures[1], "10.0.0.7");
+        assert_eq!(&captures[5], "GET /index.html?page=2 HTTP/1.1");
+        assert_eq!(&captures[6], "200");
+        assert_eq!(&captures[7], "5");
+    }
+
+    #[test]
+    fn test_common_log_format() {
+        let info = RequestInfo::unparsed("10.0.0.7");
+        let response = http::Response::builder()
+            .status(400)
+            .body(Vec::new())
+            .unwrap();
+        // 2024-02-29 00:00:00 UTC
+        let time = UNIX_EPOCH + Duration::from_secs(1709164800);
+        assert_eq!(
+            format_line(LogFormat::Clf, &info, &response, time),
+            r#"10.0.0.7 - - [29/Feb/2024:00:00:00 +0000] "-" 400 -"#
+        );
+    }
+
+    #[test]
+    fn test_body_digests() {
+        let request = http::Request::builder()
+            .method("POST")
+            .body(b"hello".to_vec())
+            .unwrap();
+        let mut info = RequestInfo::new(&request, "10.0.0.7", true);
+        info.request_body_sha256 = Some(payload_sha256(request.body(), false));
+        let response = http::Response::builder()
+            .status(204)
+            .body(Vec::new())
+            .unwrap();
+        assert_eq!(
+            format_body_digests(&info, &response),
+            " request_sha256=2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824 \
+             response_sha256=e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert!(
+            format_body_digests(&RequestInfo::unparsed("10.0.0.7"), &response)
+                .starts_with(" request_sha256=- ")
+        );
+
+        // However the upstream splits a body into chunks, it hashes like the data it carries
+        let hello =
+            " response_sha256=2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        for chunks in [
+            &b"5\r\nhello\r\n0\r\n\r\n"[..],
+            b"2;ext=1\r\nhe\r\n3\r\nllo\r\n0\r\nx-checksum: 1\r\n\r\n",
+        ] {
+            let response = http::Response::builder()
+                .header("transfer-encoding", "chunked")
+                .body(chunks.to_vec())
+                .unwrap();
+            assert!(format_body_digests(&info, &response).ends_with(hello));
+        }
+
+        // A streamed body carries the digest it got on its way through, if it made it through
+        let mut info = RequestInfo::unparsed("10.0.0.7");
+        let mut hasher = BodyHasher::default();
+        hasher.update(b"hel");
+        hasher.update(b"lo");
+        info.response_body_sha256 = Some(hasher.finish());
+        assert!(format_body_digests(&info, &response).ends_with(hello));
+    }
+
+    #[test]
+    fn test_sampler_logs_configured_fraction() {
+        let sampler = Sampler::with_rng(0.05, StdRng::seed_from_u64(0));
+        let logged = (0..100_000).filter(|_| sampler.should_log()).count();
+        assert!(
+            (4_500..=5_500).contains(&logged),
+            "{} requests logged",
+            logged
+        );
+    }
+
+    #[test]
+    fn test_sampler_extremes() {
+        let always = Sampler::with_rng(1.0, StdRng::seed_from_u64(0));
+        assert!((0..1000).all(|_| always.should_log()));
+        let never = Sampler::with_rng(0.0, StdRng::seed_from_u64(0));
+        assert!(!(0..1000).any(|_| never.should_log()));
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{metrics, profiling, request, response, ProxyState};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Requests whose path starts with this prefix are answered by balancebeam itself instead of being
+/// forwarded to an upstream (when admin endpoints are enabled)
+const ADMIN_PATH_PREFIX: &str = "/balancebeam/";
+
+/// Answers a request addressed to one of balancebeam's own control endpoints. Returns None if the
+/// request isn't addressed to balancebeam and should be proxied as usual.
+pub async fn handle_admin_request(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+) -> Option<http::Response<Vec<u8>>> {
+    let path = request.uri().path();
+    if !path.starts_with(ADMIN_PATH_PREFIX) {
+        return None;
+    }
+    let method = request.method();
+    Some(match &path[ADMIN_PATH_PREFIX.len()..] {
+        "metrics" if method == http::Method::GET => metrics_response(state).await,
+        "metrics/reset" if method == http::Method::POST => {
+            state.metrics.reset();
+            log::info!("Metrics counters were reset");
+            response::make_text_response(http::StatusCode::OK, "Metrics reset\n".to_string())
+        }
+        "debug/pprof/profile" if state.enable_profiling && method == http::Method::GET => {
+            profile_response(state, request).await
+        }
+        "metrics" | "metrics/reset" => state.error_response(http::StatusCode::METHOD_NOT_ALLOWED),
+        "debug/pprof/profile" if state.enable_profiling => {
+            state.error_response(http::StatusCode::METHOD_NOT_ALLOWED)
+        }
+        _ => state.error_response(http::StatusCode::NOT_FOUND),
+    })
+}
+
+/// Reads the length of the profile to capture from a `seconds=N` query parameter, the way Go's
+/// net/http/pprof takes it. Returns None if the value isn't a whole number of seconds within reason.
+fn profile_seconds(query: Option<&str>) -> Option<u64> {
+    let value = query
+        .unwrap_or("")
+        .split('&')
+        .find_map(|param| param.strip_prefix("seconds="));
+    match value {
+        Some(value) => value
+            .parse()
+            .ok()
+            .filter(|seconds| (1..=profiling::MAX_PROFILE_SECONDS).contains(seconds)),
+        None => Some(profiling::DEFAULT_PROFILE_SECONDS),
+    }
+}
+
+/// Captures a CPU profile of the proxy and answers with it, for `go tool pprof` and the like.
+async fn profile_response(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    let seconds = match profile_seconds(request.uri().query()) {
+        Some(seconds) => seconds,
+        None => {
+            return response::make_text_response(
+                http::StatusCode::BAD_REQUEST,
+                format!(
+                    "seconds must be a whole number from 1 to {}\n",
+                    profiling::MAX_PROFILE_SECONDS
+                ),
+            )
+        }
+    };
+    log::info!("Capturing a {} second CPU profile", seconds);
+    match profiling::capture_cpu_profile(Duration::from_secs(seconds)).await {
+        Ok(profile) => {
+            response::make_response(http::StatusCode::OK, "application/octet-stream", profile)
+        }
+        Err(pprof::Error::Running) => response::make_text_response(
+            http::StatusCode::CONFLICT,
+            "A CPU profile is already being captured\n".to_string(),
+        ),
+        Err(err) => {
+            log::error!("Failed to capture a CPU profile: {}", err);
+            state.error_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Renders the metrics, along with the current state of the upstreams.
+async fn metrics_response(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let active = state.active_upstream_addresses.read().await;
+    let gauges = metrics::UpstreamGauges {
+        up: state
+            .upstream_addresses
+            .iter()
+            .map(|address| (address.clone(), active.contains(address)))
+            .collect(),
+        idle: state.connection_pool.idle_counts(),
+        in_use: state.upstream_in_flight.lock().clone(),
+    };
+    drop(active);
+    response::make_text_response(http::StatusCode::OK, state.metrics.render(&gauges))
+}
+
+/// Serves `GET /metrics` on a listener of its own (--metrics-bind), so that a Prometheus server
+/// can scrape balancebeam without the admin endpoints being reachable from wherever clients are.
+pub async fn serve_metrics(listener: TcpListener, state: ProxyState) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    handle_metrics_connection(stream, &state).await;
+                });
+            }
+            Err(err) => log::warn!("Failed to accept a metrics connection: {}", err),
+        }
+    }
+}
+
+/// Answers requests on a connection to the metrics listener until the scraper hangs up.
+async fn handle_metrics_connection(mut stream: TcpStream, state: &ProxyState) {
+    let mut read_ahead = Vec::new();
+    loop {
+        let request =
+            match request::read_from_stream(&mut stream, &mut read_ahead, state.request_options)
+                .await
+            {
+                Ok(request) => request,
+                Err(request::Error::IncompleteRequest(0)) => return,
+                Err(err) => {
+                    log::debug!("Bad request on the metrics listener: {}", err);
+                    return;
+                }
+            };
+        let response = match (request.method(), request.uri().path()) {
+            (&http::Method::GET, "/metrics") => metrics_response(state).await,
+            (_, "/metrics") => state.error_response(http::StatusCode::METHOD_NOT_ALLOWED),
+            _ => state.error_response(http::StatusCode::NOT_FOUND),
+        };
+        if let Err(err) = response::write_to_stream(&response, &mut stream).await {
+            log::debug!("Failed to send metrics: {}", err);
+            return;
+        }
+    }
+}
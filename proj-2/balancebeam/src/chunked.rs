@@ -0,0 +1,163 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Ways reading a chunked body can fail
+#[derive(Debug)]
+pub enum Error {
+    /// The chunks are malformed, or the peer hung up before the last one
+    Malformed,
+    /// The body (in its chunked form) is bigger than the size limit
+    TooLarge,
+    /// Encountered an I/O error when reading from the stream
+    Io(std::io::Error),
+}
+
+/// Returns true if a message with these headers has a body sent as a series of chunks
+/// (Transfer-Encoding: chunked), the way a body whose length isn't known up front is streamed.
+/// Only chunked bodies can be followed by trailers.
+pub fn is_chunked(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all("transfer-encoding")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// How far scan got through a chunked body
+#[derive(Debug, PartialEq)]
+pub enum ChunkScan {
+    /// The body (last chunk and trailers included) ends after this many bytes
+    Complete(usize),
+    /// The body isn't all here yet. Everything before this offset is whole chunks, so the next scan
+    /// can pick up from there.
+    Partial(usize),
+}
+
+/// Finds the end of the line starting at `start`, returning the offset of its CRLF
+fn find_crlf(buffer: &[u8], start: usize) -> Option<usize> {
+    buffer[start..]
+        .windows(2)
+        .position(|window| window == b"\r\n")
+        .map(|offset| start + offset)
+}
+
+/// Parses a chunk size line (without its CRLF), ignoring any chunk extensions.
+pub fn parse_size_line(line: &[u8]) -> Result<usize, Error> {
+    let line = std::str::from_utf8(line).or(Err(Error::Malformed))?;
+    let size_field = line.split(';').next().unwrap().trim();
+    usize::from_str_radix(size_field, 16).or(Err(Error::Malformed))
+}
+
+/// Walks the chunks of a chunked body from `start` (which must be the beginning of a chunk) to
+/// find where the body ends, without decoding it. Chunk extensions are skipped over, and the
+/// trailer section after the last chunk counts as part of the body.
+pub fn scan(buffer: &[u8], start: usize) -> Result<ChunkScan, Error> {
+    let mut pos = start;
+    loop {
+        let size_line_end = match find_crlf(buffer, pos) {
+            Some(line_end) => line_end,
+            None => return Ok(ChunkScan::Partial(pos)),
+        };
+        let size = parse_size_line(&buffer[pos..size_line_end])?;
+        if size == 0 {
+            // The last chunk is followed by any number of trailer fields, then an empty line
+            let mut line_start = size_line_end + 2;
+            loop {
+                match find_crlf(buffer, line_start) {
+                    Some(line_end) if line_end == line_start => {
+                        return Ok(ChunkScan::Complete(line_end + 2))
+                    }
+                    Some(line_end) => line_start = line_end + 2,
+                    None => return Ok(ChunkScan::Partial(pos)),
+                }
+            }
+        }
+        let data_end = (size_line_end + 2)
+            .checked_add(size)
+            .ok_or(Error::Malformed)?;
+        if buffer.len() < data_end + 2 {
+            return Ok(ChunkScan::Partial(pos));
+        }
+        if &buffer[data_end..data_end + 2] != b"\r\n" {
+            return Err(Error::Malformed);
+        }
+        pos = data_end + 2;
+    }
+}
+
+/// Joins the data of every chunk in a complete chunked body, as read by read_body. Chunk
+/// extensions and trailers are dropped.
+pub fn decode(body: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut decoded = Vec::new();
+    let mut pos = 0;
+    loop {
+        let size_line_end = find_crlf(body, pos).ok_or(Error::Malformed)?;
+        let size = parse_size_line(&body[pos..size_line_end])?;
+        if size == 0 {
+            return Ok(decoded);
+        }
+        let data_start = size_line_end + 2;
+        let data_end = data_start.checked_add(size).ok_or(Error::Malformed)?;
+        let data = body.get(data_start..data_end).ok_or(Error::Malformed)?;
+        decoded.extend_from_slice(data);
+        pos = data_end + 2;
+    }
+}
+
+/// Reads a chunked body, starting with whatever of it is in read_ahead. The body is returned in
+/// its chunked form, trailers and all, so that it can be passed on exactly as the peer sent it.
+/// Anything read past the end of the body is left in read_ahead. A body of more than max_size
+/// bytes, counting the chunk framing, is refused as soon as that much of it has arrived.
+pub async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    read_ahead: &mut Vec<u8>,
+    max_size: usize,
+) -> Result<Vec<u8>, Error> {
+    let mut body = std::mem::take(read_ahead);
+    let mut scanned = 0;
+    loop {
+        match scan(&body, scanned)? {
+            ChunkScan::Complete(len) if len > max_size => return Err(Error::TooLarge),
+            ChunkScan::Complete(len) => {
+                *read_ahead = body.split_off(len);
+                return Ok(body);
+            }
+            ChunkScan::Partial(whole_chunks) => scanned = whole_chunks,
+        }
+        if body.len() > max_size {
+            return Err(Error::TooLarge);
+        }
+        let mut buffer = [0_u8; 512];
+        let bytes_read = stream.read(&mut buffer).await.map_err(Error::Io)?;
+        if bytes_read == 0 {
+            return Err(Error::Malformed);
+        }
+        body.extend_from_slice(&buffer[..bytes_read]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_chunks_with_trailers() {
+        let body = b"5;ext=1\r\nhello\r\n0\r\ngrpc-status: 0\r\n\r\nHTTP/1.1";
+        assert_eq!(scan(body, 0).unwrap(), ChunkScan::Complete(body.len() - 8));
+        // Cut short in the trailers, the scan resumes from the last chunk
+        assert_eq!(scan(&body[..20], 0).unwrap(), ChunkScan::Partial(16));
+        // Cut short in the first chunk's data
+        assert_eq!(scan(&body[..12], 0).unwrap(), ChunkScan::Partial(0));
+        assert!(matches!(scan(b"zz\r\n", 0), Err(Error::Malformed)));
+        assert!(matches!(scan(b"2\r\nabc\r\n", 0), Err(Error::Malformed)));
+    }
+
+    #[test]
+    fn test_decode() {
+        let body = b"5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nx-checksum: 1\r\n\r\n";
+        assert_eq!(decode(body).unwrap(), b"hello, world");
+        assert_eq!(decode(b"0\r\n\r\n").unwrap(), b"");
+        assert!(matches!(decode(b"9\r\nhello\r\n"), Err(Error::Malformed)));
+    }
+}
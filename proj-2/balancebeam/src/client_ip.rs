@@ -0,0 +1,137 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A block of IP addresses in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`. A bare address
+/// is a block containing just that address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Cidr, String> {
+        let (address, prefix_len) = match spec.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (spec, None),
+        };
+        let network: IpAddr = address
+            .parse()
+            .map_err(|_| format!("invalid IP address {:?}", address))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|&len| len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length in {:?}", spec))?,
+            None => max_len,
+        };
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(network).into(),
+                u32::from(ip).into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(network.into(), ip.into(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Returns true if the first prefix_len of the bits bits of the two addresses are the same.
+fn prefix_matches(network: u128, ip: u128, bits: u32, prefix_len: u32) -> bool {
+    let shift = bits - prefix_len;
+    shift >= bits || network >> shift == ip >> shift
+}
+
+/// Works out which IP address a request really came from. A proxy or CDN in front of us puts the
+/// address of its own client in a header, which we believe only if the request reached us directly
+/// from a proxy we trust; anyone else could claim any address they like.
+pub fn real_client_ip(
+    peer: IpAddr,
+    request: &http::Request<Vec<u8>>,
+    header: Option<&str>,
+    trusted_proxies: &[Cidr],
+) -> IpAddr {
+    let header = match header {
+        Some(header) => header,
+        None => return peer,
+    };
+    if !trusted_proxies.iter().any(|cidr| cidr.contains(peer)) {
+        return peer;
+    }
+    request
+        .headers()
+        .get(header)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr_contains() {
+        let private: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(private.contains("10.1.2.3".parse().unwrap()));
+        assert!(!private.contains("11.0.0.1".parse().unwrap()));
+        assert!(!private.contains("::ffff:10.1.2.3".parse().unwrap()));
+
+        let single: Cidr = "192.0.2.1".parse().unwrap();
+        assert!(single.contains("192.0.2.1".parse().unwrap()));
+        assert!(!single.contains("192.0.2.2".parse().unwrap()));
+
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains("203.0.113.9".parse().unwrap()));
+
+        let documentation: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(documentation.contains("2001:db8:1::5".parse().unwrap()));
+        assert!(!documentation.contains("2001:db9::5".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("not-an-ip/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_real_client_ip_only_from_trusted_peers() {
+        let request = http::Request::builder()
+            .header("CF-Connecting-IP", "203.0.113.7")
+            .body(Vec::new())
+            .unwrap();
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let header = Some("cf-connecting-ip");
+        let proxy: IpAddr = "10.0.0.5".parse().unwrap();
+        let stranger: IpAddr = "198.51.100.1".parse().unwrap();
+
+        assert_eq!(
+            real_client_ip(proxy, &request, header, &trusted),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            real_client_ip(stranger, &request, header, &trusted),
+            stranger
+        );
+        assert_eq!(real_client_ip(proxy, &request, None, &trusted), proxy);
+        assert_eq!(
+            real_client_ip(proxy, &request, Some("true-client-ip"), &trusted),
+            proxy
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use flate2::write::{DeflateEncoder, GzEncoder};
+use std::io::Write;
+
+/// Content codings balancebeam can apply to response bodies
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+/// Encodings we support, in the order we prefer them when a client rates several equally
+const SUPPORTED_ENCODINGS: [Encoding; 2] = [Encoding::Gzip, Encoding::Deflate];
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Parses an Accept-Encoding header value (e.g. `br;q=1.0, gzip;q=0.5, *;q=0`) into lowercased
+/// (coding, qvalue) pairs. A coding without a q parameter has quality 1; entries with an
+/// unparseable q are dropped.
+fn parse_accept_encoding(value: &str) -> Vec<(String, f32)> {
+    value
+        .split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';');
+            let coding = params.next()?.trim().to_ascii_lowercase();
+            if coding.is_empty() {
+                return None;
+            }
+            let mut quality = 1.0;
+            for param in params {
+                if let Some((name, value)) = param.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        quality = value.trim().parse::<f32>().ok()?;
+                    }
+                }
+            }
+            Some((coding, quality))
+        })
+        .collect()
+}
+
+/// Picks the best encoding that both the client (per its Accept-Encoding header) and balancebeam
+/// support, respecting the client's quality values. A supported coding the client doesn't name
+/// explicitly gets the quality of the `*` wildcard, if present. Returns None if no supported
+/// encoding is acceptable (quality 0 means "not acceptable").
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let offered = parse_accept_encoding(accept_encoding);
+    let quality_of = |name: &str| {
+        offered
+            .iter()
+            .find(|(coding, _)| coding == name)
+            .or_else(|| offered.iter().find(|(coding, _)| coding == "*"))
+            .map_or(0.0, |(_, quality)| *quality)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in SUPPORTED_ENCODINGS {
+        let quality = quality_of(encoding.name());
+        // Strictly greater, so that ties go to the encoding listed first in SUPPORTED_ENCODINGS
+        if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Compresses the response body with the given encoding, updating Content-Encoding,
+/// Content-Length, and Vary to match. Responses that are already encoded or have no body are left
+/// untouched.
+pub fn compress_response(response: &mut http::Response<Vec<u8>>, encoding: Encoding) {
+    // A chunked body is passed through in its chunked framing, which we'd have to undo first
+    if response.body().is_empty()
+        || response.headers().contains_key("content-encoding")
+        || crate::response::is_chunked(response)
+    {
+        return;
+    }
+    let compressed = match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder
+                .write_all(response.body())
+                .and_then(|_| encoder.finish())
+        }
+        Encoding::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder
+                .write_all(response.body())
+                .and_then(|_| encoder.finish())
+        }
+    };
+    let compressed = match compressed {
+        Ok(compressed) => compressed,
+        Err(err) => {
+            log::warn!("Failed to {} response body: {}", encoding.name(), err);
+            return;
+        }
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(
+        "content-encoding",
+        http::HeaderValue::from_static(encoding.name()),
+    );
+    headers.insert("content-length", http::HeaderValue::from(compressed.len()));
+    headers.append("vary", http::HeaderValue::from_static("Accept-Encoding"));
+    *response.body_mut() = compressed;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_respects_quality_values() {
+        // br is preferred but unsupported, so fall back to the best supported option
+        assert_eq!(
+            negotiate("br;q=1.0, gzip;q=0.5, deflate;q=0.8"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(
+            negotiate("br;q=1.0, gzip;q=0.9, deflate;q=0.8"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate("deflate, gzip;q=0.2"), Some(Encoding::Deflate));
+    }
+
+    #[test]
+    fn test_negotiate_ties_and_wildcards() {
+        assert_eq!(negotiate("deflate, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0, *;q=0.1"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("GZIP;Q=0.3"), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn test_negotiate_nothing_acceptable() {
+        assert_eq!(negotiate("br"), None);
+        assert_eq!(negotiate("gzip;q=0, deflate;q=0"), None);
+        assert_eq!(negotiate("identity, *;q=0"), None);
+        assert_eq!(negotiate(""), None);
+    }
+}
@@ -0,0 +1,77 @@
+use tokio::sync::mpsc;
+
+/// Number of events that can be waiting for the subscriber before new ones are dropped
+pub const CHANNEL_CAPACITY: usize = 1024;
+
+/// Something that happened in the proxy, for anyone observing it from outside
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProxyEvent {
+    /// A client opened a connection to us
+    ConnectionAccepted { client: String },
+    /// An upstream answered a request we forwarded to it
+    RequestForwarded {
+        upstream: String,
+        status: http::StatusCode,
+    },
+    /// An upstream was taken out of rotation, by a failed health check or too many failed requests
+    UpstreamEjected { upstream: String },
+    /// A client's request was turned away with a 429
+    RateLimited { client: String },
+}
+
+/// The sending end of the event stream. Sending never waits, because a slow subscriber mustn't
+/// slow down the proxy: events that don't fit in the channel are dropped instead.
+#[derive(Clone)]
+pub struct EventSender {
+    sender: mpsc::Sender<ProxyEvent>,
+}
+
+impl EventSender {
+    /// Returns a sender along with the receiving end for the subscriber.
+    pub fn channel(capacity: usize) -> (EventSender, mpsc::Receiver<ProxyEvent>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        (EventSender { sender }, receiver)
+    }
+
+    pub fn emit(&self, event: ProxyEvent) {
+        if let Err(err) = self.sender.try_send(event) {
+            log::debug!("Dropped proxy event: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_full_channel_drops_events() {
+        let (sender, mut receiver) = EventSender::channel(2);
+        for n in 0..5 {
+            sender.emit(ProxyEvent::RateLimited {
+                client: format!("10.0.0.{}", n),
+            });
+        }
+        let received: Vec<ProxyEvent> = std::iter::from_fn(|| receiver.try_recv().ok()).collect();
+        assert_eq!(
+            received,
+            [
+                ProxyEvent::RateLimited {
+                    client: "10.0.0.0".to_string()
+                },
+                ProxyEvent::RateLimited {
+                    client: "10.0.0.1".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_emit_without_subscriber() {
+        let (sender, receiver) = EventSender::channel(1);
+        drop(receiver);
+        sender.emit(ProxyEvent::UpstreamEjected {
+            upstream: "127.0.0.1:8080".to_string(),
+        });
+    }
+}
@@ -0,0 +1,107 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::Duration;
+
+/// How often (as percentages of upstream responses) each kind of fault is injected
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FaultRates {
+    /// Responses held back for the configured delay before being sent on
+    pub delay_percent: f64,
+    /// Responses replaced with a 503
+    pub error_percent: f64,
+    /// Responses never sent, with the client connection dropped instead
+    pub drop_percent: f64,
+}
+
+impl FaultRates {
+    pub fn any(&self) -> bool {
+        self.delay_percent > 0.0 || self.error_percent > 0.0 || self.drop_percent > 0.0
+    }
+}
+
+/// The faults picked for one response. A delayed response can also be turned into an error or
+/// dropped, once the delay is over.
+#[derive(Debug, Default, PartialEq)]
+pub struct Faults {
+    pub delay: Option<Duration>,
+    pub error: bool,
+    pub drop_connection: bool,
+}
+
+/// Makes upstreams look less reliable than they are, for testing how clients cope. Each kind of
+/// fault is rolled for independently.
+pub struct FaultInjector {
+    rates: FaultRates,
+    delay: Duration,
+    rng: parking_lot::Mutex<StdRng>,
+}
+
+impl FaultInjector {
+    /// A seed makes the sequence of faults reproducible from one run to the next.
+    pub fn new(rates: FaultRates, delay: Duration, seed: Option<u64>) -> FaultInjector {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        FaultInjector {
+            rates,
+            delay,
+            rng: parking_lot::Mutex::new(rng),
+        }
+    }
+
+    /// Picks the faults to inject into the next response.
+    pub fn roll(&self) -> Faults {
+        let mut rng = self.rng.lock();
+        let mut hit = |percent: f64| percent > 0.0 && rng.gen_bool((percent / 100.0).min(1.0));
+        Faults {
+            delay: hit(self.rates.delay_percent).then_some(self.delay),
+            error: hit(self.rates.error_percent),
+            drop_connection: hit(self.rates.drop_percent),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fault_rates_applied() {
+        let rates = FaultRates {
+            delay_percent: 10.0,
+            error_percent: 25.0,
+            drop_percent: 2.0,
+        };
+        let injector = FaultInjector::new(rates, Duration::from_millis(50), Some(0));
+        let faults: Vec<Faults> = (0..100_000).map(|_| injector.roll()).collect();
+        let count = |pred: fn(&Faults) -> bool| faults.iter().filter(|f| pred(f)).count();
+        let delayed = count(|f| f.delay == Some(Duration::from_millis(50)));
+        let errors = count(|f| f.error);
+        let dropped = count(|f| f.drop_connection);
+        assert!((9_500..=10_500).contains(&delayed), "{} delayed", delayed);
+        assert!((24_000..=26_000).contains(&errors), "{} errors", errors);
+        assert!((1_700..=2_300).contains(&dropped), "{} dropped", dropped);
+    }
+
+    #[test]
+    fn test_no_faults_by_default() {
+        let injector = FaultInjector::new(FaultRates::default(), Duration::from_secs(1), Some(0));
+        assert!(!FaultRates::default().any());
+        assert!((0..1000).all(|_| injector.roll() == Faults::default()));
+    }
+
+    #[test]
+    fn test_seeded_faults_are_reproducible() {
+        let rates = FaultRates {
+            delay_percent: 50.0,
+            error_percent: 50.0,
+            drop_percent: 50.0,
+        };
+        let first = FaultInjector::new(rates, Duration::from_secs(1), Some(42));
+        let second = FaultInjector::new(rates, Duration::from_secs(1), Some(42));
+        for _ in 0..100 {
+            assert_eq!(first.roll(), second.roll());
+        }
+    }
+}
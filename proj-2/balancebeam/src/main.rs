@@ -1,14 +1,33 @@
+mod access_log;
+mod admin;
+mod chunked;
+mod client_ip;
+mod compression;
+mod events;
+mod fault;
+mod metrics;
+mod pool;
+mod privileges;
+mod profiling;
+mod rate_limit;
 mod request;
 mod response;
+mod routing;
+mod selector;
+mod tls;
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use http::StatusCode;
-use rand::{Rng, SeedableRng};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Mutex, RwLock};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::signal;
+use tokio::sync::{watch, Notify, OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio::task::JoinSet;
 use tokio::time;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
@@ -18,18 +37,401 @@ struct CmdOptions {
     /// "IP/port to bind to"
     #[arg(short, long, default_value = "0.0.0.0:1100")]
     bind: String,
-    /// "Upstream host to forward requests to"
-    #[arg(short, long)]
-    upstream: Vec<String>,
+    /// "IP/port to serve Prometheus metrics on, at GET /metrics, apart from proxied traffic"
+    #[arg(long)]
+    metrics_bind: Option<String>,
+    /// "PEM file with the certificate chain to terminate TLS with, making clients connect over https://. On SIGHUP, it and --tls-key are read again for new connections"
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<std::path::PathBuf>,
+    /// "PEM file with the private key for --tls-cert"
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<std::path::PathBuf>,
+    /// "Number of TLS sessions to remember so that reconnecting clients can resume them without a full handshake (0 = no resumption)"
+    #[arg(long, default_value = "256", requires = "tls_cert")]
+    tls_session_cache_size: usize,
+    /// "Once listening (and with --tls-key read), switch to this user, by name or uid, so that only
+    /// binding a privileged port happens as root"
+    #[arg(long)]
+    run_as_user: Option<String>,
+    /// "Group to switch to along with --run-as-user, by name or gid (default: the user's primary group)"
+    #[arg(long, requires = "run_as_user")]
+    run_as_group: Option<String>,
+    /// "Upstream host to forward requests to, as [/PATH_PREFIX=]HOST[=WEIGHT][#FLAG...]. An upstream
+    /// with a path prefix only serves requests under that prefix; weights default to 1. The
+    /// #no-chunked flag marks an upstream that can't read chunked request bodies, so they're sent
+    /// to it with a Content-Length instead; #max_body=BYTES marks the largest request body it
+    /// accepts, so bigger ones get a 413 without being forwarded to it; #tls-insecure accepts any
+    /// certificate from it with --upstream-tls"
+    #[arg(short, long, value_parser = parse_upstream)]
+    upstream: Vec<UpstreamSpec>,
+    /// "Strategy used to pick an upstream for each new client connection"
+    #[arg(long, value_enum, default_value = "random")]
+    lb_algorithm: LbAlgorithm,
+    /// "Send requests carrying this cookie to the same upstream whenever it's in rotation, picked by the cookie's value; other requests go by --lb-algorithm"
+    #[arg(long)]
+    sticky_cookie: Option<String>,
+    /// "Maximum number of concurrent client connections to proxy to each upstream (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    max_connections_per_upstream: usize,
+    /// "Maximum number of client connections to proxy to all upstreams combined (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    max_total_upstream_connections: usize,
+    /// "How long a new connection may wait for an upstream slot when all are at capacity"
+    #[arg(long, default_value = "0")]
+    queue_timeout_ms: u64,
+    /// "Times to retry a request against another upstream when one fails before responding"
+    #[arg(long, default_value = "0")]
+    max_retries: usize,
+    /// "Idle upstream connections to keep open for reuse by later clients, per upstream (0 = none)"
+    #[arg(long, default_value = "0")]
+    max_idle_per_upstream: usize,
+    /// "Open this many connections to each healthy upstream at startup and keep them in the pool for the first clients (at most --max-idle-per-upstream)"
+    #[arg(long, default_value = "0")]
+    prewarm_connections_per_upstream: usize,
+    /// "Close an upstream connection instead of reusing it once it has served a response body larger than this (in bytes)"
+    #[arg(long)]
+    reuse_max_response_bytes: Option<usize>,
+    /// "Local IP address to originate upstream connections from"
+    #[arg(long)]
+    upstream_source_ip: Option<IpAddr>,
+    /// "Forward proxy (http://HOST:PORT) to tunnel upstream connections through with CONNECT"
+    #[arg(long, value_parser = parse_http_proxy)]
+    upstream_http_proxy: Option<String>,
+    /// "Connect to upstreams over TLS, verifying their certificates against the web PKI roots"
+    #[arg(long)]
+    upstream_tls: bool,
+    /// "Accept any certificate from every upstream with --upstream-tls (for self-signed upstreams in testing only; an upstream's #tls-insecure flag does this for that upstream alone)"
+    #[arg(long, requires = "upstream_tls")]
+    upstream_tls_insecure: bool,
+    /// "Give up on connecting to an upstream after this long (in milliseconds) and try another"
+    #[arg(long, default_value = "3000")]
+    upstream_connect_timeout_ms: u64,
+    /// "Give up on an upstream with a 504 if writing a request to it, or reading a response's headers or body from it, takes longer than this (in milliseconds)"
+    #[arg(long)]
+    upstream_read_timeout_ms: Option<u64>,
     /// "Perform active health checks on this interval (in seconds)"
     #[arg(long, default_value = "10")]
     active_health_check_interval: usize,
     /// "Path to send request to for active health checks"
     #[arg(long, default_value = "/")]
     active_health_check_path: String,
+    /// "Probe upstreams that are out of rotation on this shorter interval (in seconds), so that they're put back soon after they recover"
+    #[arg(long)]
+    ejected_recheck_interval: Option<u64>,
+    /// "Take an upstream out of rotation until its next passing health check once it answers this many requests in a row with a 5xx (0 = never)"
+    #[arg(long, default_value = "0")]
+    passive_failure_threshold: usize,
+    /// "Run one round of health checks before accepting any connections, so that only healthy upstreams are used from the start"
+    #[arg(long)]
+    wait_for_healthy_on_startup: bool,
+    /// "With --wait-for-healthy-on-startup, start serving with every upstream if the health checks take longer than this (in seconds)"
+    #[arg(long, requires = "wait_for_healthy_on_startup")]
+    startup_timeout: Option<u64>,
+    /// "On SIGTERM or SIGINT, stop accepting connections and give open ones this long (in seconds) to finish before exiting"
+    #[arg(long, default_value = "10")]
+    shutdown_grace_seconds: u64,
+    /// "Maximum rate at which new client connections are accepted, per second (0 = unlimited); connections beyond it wait in the listen backlog"
+    #[arg(long, default_value = "0")]
+    max_accepts_per_second: f64,
+    /// "Header holding the real client IP, as set by a proxy or CDN in front of us (e.g. CF-Connecting-IP)"
+    #[arg(long)]
+    real_ip_header: Option<String>,
+    /// "Address block (CIDR) of a proxy allowed to set --real-ip-header (repeatable)"
+    #[arg(long)]
+    trusted_proxy: Vec<client_ip::Cidr>,
     /// "Maximum number of requests to accept per IP per minute (0 = unlimited)"
     #[arg(long, default_value = "0")]
     max_requests_per_minute: usize,
+    /// "Requests per second each client IP may sustain, on average (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    rate_limit_rate: f64,
+    /// "Requests a client IP may send in a burst, on top of --rate-limit-rate (default: one second's worth)"
+    #[arg(long)]
+    rate_limit_burst: Option<u32>,
+    /// "Requests per second balancebeam may send each upstream (0 = unlimited); requests beyond it go to another upstream, or get a 503"
+    #[arg(long, default_value = "0")]
+    max_requests_per_upstream_per_second: f64,
+    /// "Layout of access log lines"
+    #[arg(long, value_enum, default_value = "default")]
+    log_format: access_log::LogFormat,
+    /// "Add SHA-256 digests of request and response bodies to access log lines"
+    #[arg(long)]
+    hash_bodies: bool,
+    /// "Fraction of requests (0.0 to 1.0) to write access log lines for; errors are always logged"
+    #[arg(long, default_value = "1.0")]
+    log_sample_rate: f64,
+    /// "Log a warning for every request that takes longer than this many milliseconds to answer,
+    /// sampled or not"
+    #[arg(long)]
+    slow_request_threshold_ms: Option<u64>,
+    /// "What to do with request headers continued onto another line (obsolete line folding)"
+    #[arg(long, value_enum, default_value = "reject")]
+    obs_fold: request::ObsFold,
+    /// "Accept requests whose target names a host (e.g. GET http://host/), as sent to forward proxies"
+    #[arg(long)]
+    allow_absolute_uri: bool,
+    /// "Collapse repeated slashes and resolve . and .. segments in request paths before routing
+    /// and forwarding them"
+    #[arg(long)]
+    normalize_path: bool,
+    /// "With --normalize-path, also decode percent-encoded letters, digits, and -._~ (other
+    /// percent-encoding, such as %2F, is always kept)"
+    #[arg(long)]
+    decode_unreserved_percent: bool,
+    /// "Count the balancebeam instances each request is forwarded through in X-Balancebeam-Hops, answering a request that has been through more than this with a 508 Loop Detected"
+    #[arg(long)]
+    max_hops: Option<usize>,
+    /// "Maximum number of pipelined requests read from a client before answering them"
+    #[arg(long, default_value = "8")]
+    max_pipeline_depth: usize,
+    /// "Close a client connection once its requests and responses add up to this many bytes (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    max_connection_bytes: u64,
+    /// "Forcibly close a client connection still open this many seconds after it was accepted, whatever it's waiting on (a safety net for connections no other timeout catches)"
+    #[arg(long)]
+    connection_hard_limit: Option<u64>,
+    /// "Give up on an upstream response with a 504 if reading all of it (headers and body) takes longer than this (in seconds)"
+    #[arg(long)]
+    response_read_timeout: Option<u64>,
+    /// "Answer requests with a body larger than this many bytes with a 413, without forwarding any of them"
+    #[arg(long, default_value_t = request::MAX_BODY_SIZE)]
+    max_request_body_bytes: usize,
+    /// "Pass request bodies of more than this many bytes on to the upstream as they arrive, rather than reading all of them first (not with --max-retries, which needs the whole body)"
+    #[arg(long, default_value = "65536")]
+    stream_request_body_bytes: usize,
+    /// "Maximum size of an upstream response's status line and headers, in bytes"
+    #[arg(long, default_value_t = response::MAX_HEADERS_SIZE)]
+    max_response_header_bytes: usize,
+    /// "Status to respond with when a request's path matches no upstream's path prefix"
+    #[arg(long, default_value = "404", value_parser = parse_status)]
+    no_route_status: StatusCode,
+    /// "Body to respond with when a request's path matches no upstream's path prefix"
+    #[arg(long)]
+    no_route_body: Option<String>,
+    /// "Only allow these methods for requests under a path prefix, answering others with a 405, as PREFIX=METHOD,METHOD (repeatable; an empty prefix is the default group)"
+    #[arg(long, value_parser = parse_route_methods)]
+    route_methods: Vec<(String, Vec<http::Method>)>,
+    /// "Send the contents of a file as the body of the error responses with a status, as STATUS=PATH (repeatable)"
+    #[arg(long, value_parser = parse_error_body)]
+    error_body: Vec<(StatusCode, std::path::PathBuf)>,
+    /// "Set a header on requests before they're forwarded, as NAME:VALUE, replacing any the client sent; an empty value removes the header (repeatable; the last one for a header wins)"
+    #[arg(long, value_parser = parse_set_header)]
+    set_header: Vec<(http::HeaderName, http::HeaderValue)>,
+    /// "Remove a header (e.g. X-Powered-By) from upstream responses before they reach the client (repeatable)"
+    #[arg(long)]
+    strip_response_header: Vec<http::HeaderName>,
+    /// "Tell upstreams the Host the client asked for and whether it connected over https, in X-Forwarded-Host and X-Forwarded-Proto"
+    #[arg(long)]
+    add_forwarded_headers: bool,
+    /// "Name the upstream that served each response in an X-Upstream-Server header (this gives away the upstreams' addresses)"
+    #[arg(long)]
+    add_upstream_header: bool,
+    /// "For resilience testing: percentage of upstream responses to hold back for --fault-inject-delay-ms"
+    #[arg(long, default_value = "0")]
+    fault_inject_delay_percent: f64,
+    /// "How long --fault-inject-delay-percent holds responses back, in milliseconds"
+    #[arg(long, default_value = "1000")]
+    fault_inject_delay_ms: u64,
+    /// "For resilience testing: percentage of upstream responses to replace with a 503"
+    #[arg(long, default_value = "0")]
+    fault_inject_error_percent: f64,
+    /// "For resilience testing: percentage of upstream responses to drop, closing the client connection instead"
+    #[arg(long, default_value = "0")]
+    fault_inject_drop_percent: f64,
+    /// "Seed for picking which responses get faults injected, to make test runs reproducible"
+    #[arg(long)]
+    fault_inject_seed: Option<u64>,
+    /// "Compress response bodies when the client accepts gzip or deflate"
+    #[arg(long)]
+    compress_responses: bool,
+    /// "Answer requests under /balancebeam/ (metrics, etc.) instead of forwarding them"
+    #[arg(long)]
+    enable_admin_endpoints: bool,
+    /// "Serve CPU profiles at /balancebeam/debug/pprof/profile?seconds=N (needs --enable-admin-endpoints)"
+    #[arg(long, requires = "enable_admin_endpoints")]
+    enable_profiling: bool,
+    /// "Explain what went wrong in the headers and body of error responses we generate"
+    #[arg(long)]
+    verbose_errors: bool,
+    /// "Log connection lifecycle events (connections accepted, requests forwarded, upstreams
+    /// ejected, clients rate limited) as they happen"
+    #[arg(long)]
+    log_events: bool,
+}
+
+/// An upstream as given on the command line, e.g. `10.0.0.1:80=3`, `/api=10.0.0.1:80` or
+/// `10.0.0.1:80#no-chunked#max_body=1048576#tls-insecure`
+#[derive(Clone, Debug)]
+struct UpstreamSpec {
+    /// Path prefix this upstream serves, or the empty string for the default group
+    route: String,
+    address: String,
+    weight: usize,
+    /// Whether chunked request bodies must be turned into Content-Length ones for this upstream
+    no_chunked: bool,
+    /// Largest request body this upstream accepts, in bytes, if it's known
+    max_body: Option<usize>,
+    /// Whether any TLS certificate is accepted from this upstream
+    tls_insecure: bool,
+}
+
+fn parse_upstream(spec: &str) -> Result<UpstreamSpec, String> {
+    let mut flags = spec.split('#');
+    let spec = flags.next().unwrap();
+    let mut no_chunked = false;
+    let mut max_body = None;
+    let mut tls_insecure = false;
+    for flag in flags {
+        match flag.split_once('=') {
+            None if flag == "no-chunked" => no_chunked = true,
+            None if flag == "tls-insecure" => tls_insecure = true,
+            Some(("max_body", bytes)) => match bytes.parse::<usize>() {
+                Ok(bytes) => max_body = Some(bytes),
+                Err(_) => {
+                    return Err(format!(
+                        "invalid max_body {:?} (expected a number of bytes)",
+                        bytes
+                    ))
+                }
+            },
+            _ => return Err(format!("unknown upstream flag {:?}", flag)),
+        }
+    }
+    // Addresses never start with a slash, so a leading one marks a path prefix
+    let (route, spec) = if spec.starts_with('/') {
+        spec.split_once('=')
+            .ok_or_else(|| format!("path prefix {:?} is missing an upstream address", spec))?
+    } else {
+        ("", spec)
+    };
+    match spec.rsplit_once('=') {
+        Some((address, weight)) => match weight.parse::<usize>() {
+            Ok(weight) if weight > 0 => Ok(UpstreamSpec {
+                route: route.to_string(),
+                address: address.to_string(),
+                weight,
+                no_chunked,
+                max_body,
+                tls_insecure,
+            }),
+            _ => Err(format!(
+                "invalid weight {:?} (expected a positive integer)",
+                weight
+            )),
+        },
+        None => Ok(UpstreamSpec {
+            route: route.to_string(),
+            address: spec.to_string(),
+            weight: 1,
+            no_chunked,
+            max_body,
+            tls_insecure,
+        }),
+    }
+}
+
+/// Parses a forward proxy URL such as `http://proxy.internal:3128` into the address to connect to.
+fn parse_http_proxy(url: &str) -> Result<String, String> {
+    let uri = url
+        .parse::<http::Uri>()
+        .map_err(|err| format!("invalid proxy URL {:?}: {}", url, err))?;
+    if uri.scheme_str().is_some_and(|scheme| scheme != "http") {
+        return Err(format!("proxy URL {:?} must use http://", url));
+    }
+    if !matches!(uri.path(), "" | "/") || uri.query().is_some() {
+        return Err(format!("proxy URL {:?} must not have a path", url));
+    }
+    let authority = uri
+        .authority()
+        .ok_or_else(|| format!("proxy URL {:?} is missing a host", url))?;
+    if authority.as_str().contains('@') {
+        return Err(format!("proxy URL {:?} must not contain credentials", url));
+    }
+    Ok(format!(
+        "{}:{}",
+        authority.host(),
+        authority.port_u16().unwrap_or(80)
+    ))
+}
+
+fn parse_status(status: &str) -> Result<StatusCode, String> {
+    status
+        .parse::<StatusCode>()
+        .map_err(|_| format!("invalid HTTP status {:?}", status))
+}
+
+fn parse_set_header(spec: &str) -> Result<(http::HeaderName, http::HeaderValue), String> {
+    let (name, value) = spec
+        .split_once(':')
+        .ok_or_else(|| format!("expected NAME:VALUE, got {:?}", spec))?;
+    let name = name
+        .trim()
+        .parse::<http::HeaderName>()
+        .map_err(|_| format!("invalid header name {:?}", name))?;
+    let value = value
+        .trim()
+        .parse::<http::HeaderValue>()
+        .map_err(|_| format!("invalid value for header {}: {:?}", name, value))?;
+    Ok((name, value))
+}
+
+fn parse_route_methods(spec: &str) -> Result<(String, Vec<http::Method>), String> {
+    let (route, methods) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected PREFIX=METHOD,METHOD, got {:?}", spec))?;
+    let methods = methods
+        .split(',')
+        .map(|method| {
+            method
+                .trim()
+                .parse::<http::Method>()
+                .map_err(|_| format!("invalid method {:?}", method))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((route.to_string(), methods))
+}
+
+fn parse_error_body(spec: &str) -> Result<(StatusCode, std::path::PathBuf), String> {
+    let (status, path) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected STATUS=PATH, got {:?}", spec))?;
+    Ok((parse_status(status)?, std::path::PathBuf::from(path)))
+}
+
+/// Loads the --error-body files, guessing each one's content type from its extension.
+fn load_error_bodies(
+    specs: &[(StatusCode, std::path::PathBuf)],
+) -> Result<HashMap<StatusCode, ErrorBody>, String> {
+    let mut bodies = HashMap::new();
+    for (status, path) in specs {
+        let body = std::fs::read(path)
+            .map_err(|err| format!("could not read {}: {}", path.display(), err))?;
+        let content_type = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("html") | Some("htm") => "text/html; charset=utf-8",
+            Some("json") => "application/json",
+            _ => "text/plain",
+        };
+        bodies.insert(*status, ErrorBody { content_type, body });
+    }
+    Ok(bodies)
+}
+
+/// A body configured with --error-body to replace the default one for an error status
+struct ErrorBody {
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum LbAlgorithm {
+    /// Pick an active upstream at random, in proportion to its weight
+    Random,
+    /// Pick the active upstream with the fewest in-flight connections
+    LeastConnections,
+    /// Pick the active upstream with the fewest in-flight connections relative to its weight
+    WeightedLeastConnections,
+    /// Take turns among the active upstreams
+    RoundRobin,
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
@@ -44,15 +446,147 @@ struct ProxyState {
     /// Where we should send requests when doing active health checks (Milestone 4)
     #[allow(dead_code)]
     active_health_check_path: String,
+    /// How often upstreams out of rotation are probed in between rounds of active health checks,
+    /// if more often than the rounds themselves
+    ejected_recheck_interval: Option<time::Duration>,
+    /// When the next health check that could bring an upstream back is due
+    next_health_check: Arc<parking_lot::Mutex<time::Instant>>,
+    /// Consecutive 5xx responses after which an upstream is taken out of rotation (0 = never)
+    passive_failure_threshold: usize,
+    /// Number of 5xx responses each upstream has sent in a row
+    upstream_failure_streaks: Arc<parking_lot::Mutex<HashMap<String, usize>>>,
     /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
     #[allow(dead_code)]
     max_requests_per_minute: usize,
-    /// Addresses of healthy servers that we are proxying to
+    /// Addresses of healthy servers that we are proxying to. Health checks rebuild this, and an
+    /// upstream that keeps failing requests is taken out of it in between
     active_upstream_addresses: Arc<RwLock<Vec<String>>>,
+    /// Connection attempts to each upstream that failed since its last health check. A failed
+    /// connection only steers its own request elsewhere; whether the upstream stays in rotation is
+    /// up to the health checker.
+    connect_failures: Arc<parking_lot::Mutex<HashMap<String, usize>>>,
     /// Addresses of all servers
     upstream_addresses: Vec<String>,
-    /// Rate monitor, counts access number for each upstream address per minute
-    rate_monitor: Arc<Mutex<HashMap<String, usize>>>,
+    /// Weight of each server, parallel to upstream_addresses
+    upstream_weights: Vec<usize>,
+    /// Path prefix each server is routed under, parallel to upstream_addresses (empty for servers
+    /// in the default group)
+    upstream_routes: Vec<String>,
+    /// Whether each server needs chunked request bodies de-chunked, parallel to upstream_addresses
+    upstream_no_chunked: Vec<bool>,
+    /// Largest request body each server accepts, if known, parallel to upstream_addresses
+    upstream_max_body: Vec<Option<usize>>,
+    /// Whether each server was flagged #tls-insecure, parallel to upstream_addresses
+    upstream_tls_insecure: Vec<bool>,
+    /// What we answer with when a request matches no route
+    no_route_status: StatusCode,
+    no_route_body: Option<String>,
+    /// Methods that --route-methods limits a route to, keyed by its prefix. Other routes allow any.
+    route_methods: Arc<HashMap<String, Vec<http::Method>>>,
+    /// Bodies to send instead of the default for error responses with these statuses
+    error_bodies: Arc<HashMap<StatusCode, ErrorBody>>,
+    /// How we pick an upstream for each new client connection
+    selector: Arc<dyn selector::UpstreamSelector>,
+    /// Number of client connections currently being proxied to each upstream address
+    upstream_in_flight: Arc<parking_lot::Mutex<HashMap<String, usize>>>,
+    /// Maximum number of client connections proxied to one upstream at a time (0 = unlimited)
+    max_connections_per_upstream: usize,
+    /// One permit per client connection that may be proxied at a time across every upstream, if
+    /// there's a limit
+    total_upstream_connections: Option<Arc<Semaphore>>,
+    /// How long, in milliseconds, a connection waits for a free upstream slot before giving up
+    queue_timeout_ms: u64,
+    /// How many other upstreams a request is retried against after its upstream fails without
+    /// sending back any of a response
+    max_retries: usize,
+    /// Notified whenever a client connection releases its upstream slot
+    upstream_slot_freed: Arc<Notify>,
+    /// Idle upstream connections left over from earlier clients
+    connection_pool: Arc<pool::ConnectionPool>,
+    /// Largest response body after which an upstream connection is still reused, if there's a limit
+    reuse_max_response_bytes: Option<usize>,
+    /// Connection each upstream's health checks are sent over, kept open between rounds
+    health_probe_connections: Arc<parking_lot::Mutex<HashMap<String, tls::UpstreamStream>>>,
+    /// Local address that upstream connections are bound to before connecting, if any
+    upstream_source_ip: Option<IpAddr>,
+    /// Address of the forward proxy upstream connections are tunneled through, if any
+    upstream_http_proxy: Option<String>,
+    /// Connector that wraps upstream connections in TLS, if they're to be encrypted
+    upstream_tls: Option<TlsConnector>,
+    /// Connector for upstreams flagged #tls-insecure, which accepts any certificate
+    insecure_upstream_tls: Option<TlsConnector>,
+    /// Longest we wait for a connection to an upstream (through the forward proxy, if any) to be set
+    /// up
+    upstream_connect_timeout: time::Duration,
+    /// Longest each write of a request to an upstream, and each read of a response's head or body,
+    /// may take, if there's a limit
+    upstream_read_timeout: Option<time::Duration>,
+    /// Header that trusted proxies put the real client IP in, if any
+    real_ip_header: Option<String>,
+    /// Peers whose real_ip_header we believe
+    trusted_proxies: Vec<client_ip::Cidr>,
+    /// Recent requests from each client IP, if there's a per-minute limit on them
+    request_windows: Option<Arc<rate_limit::SlidingWindows>>,
+    /// Token bucket for each client IP, if token bucket rate limiting is enabled
+    token_buckets: Option<Arc<rate_limit::TokenBuckets>>,
+    /// Token bucket for each upstream address, if upstream request rates are capped
+    upstream_rate_limits: Option<Arc<rate_limit::TokenBuckets>>,
+    /// How strictly client requests are parsed
+    request_options: request::ReadOptions,
+    /// Whether request paths are normalized before routing
+    normalize_path: bool,
+    /// Whether path normalization decodes percent-encoded unreserved characters
+    decode_unreserved_percent: bool,
+    /// Most balancebeam instances a request may have been forwarded through before it reaches us,
+    /// if we're watching for forwarding loops
+    max_hops: Option<usize>,
+    /// Most pipelined requests read from a client connection at a time
+    max_pipeline_depth: usize,
+    /// Request and response bytes after which a client connection is closed (0 = unlimited)
+    max_connection_bytes: u64,
+    /// Longest a client connection may stay open before its task is aborted, if there's a limit
+    connection_hard_limit: Option<time::Duration>,
+    /// Client connection tasks that may still be running, tracked while there's a hard limit to
+    /// enforce on them
+    connection_tasks: Arc<parking_lot::Mutex<Vec<ConnectionTask>>>,
+    /// Longest we wait for an upstream to send its whole response, if there's a limit
+    response_read_timeout: Option<time::Duration>,
+    /// Largest upstream response head (status line and headers) we are willing to buffer
+    max_response_header_bytes: usize,
+    /// Headers set on requests before they're forwarded, in the order given. An empty value means
+    /// the header is removed.
+    set_headers: Arc<Vec<(http::HeaderName, http::HeaderValue)>>,
+    /// Headers removed from upstream responses, e.g. ones that give away the upstream's internals
+    strip_response_headers: Arc<Vec<http::HeaderName>>,
+    /// Whether requests carry X-Forwarded-Host and X-Forwarded-Proto to the upstream
+    add_forwarded_headers: bool,
+    /// Whether responses say which upstream served them, in X-Upstream-Server
+    add_upstream_header: bool,
+    /// Injects faults into upstream responses, if any fault injection is configured
+    fault_injector: Option<Arc<fault::FaultInjector>>,
+    /// Whether we compress response bodies for clients that accept it
+    compress_responses: bool,
+    /// Whether requests under /balancebeam/ are answered by us rather than forwarded
+    enable_admin_endpoints: bool,
+    /// Whether the admin endpoints include on-demand CPU profiling
+    enable_profiling: bool,
+    /// Whether the error responses we generate explain what went wrong
+    verbose_errors: bool,
+    /// Picks the requests that get access log lines
+    log_sampler: Arc<access_log::Sampler>,
+    /// Requests taking longer than this to answer are logged as warnings
+    slow_request_threshold: Option<time::Duration>,
+    /// Layout of access log lines
+    log_format: access_log::LogFormat,
+    /// Whether access log lines carry digests of the request and response bodies
+    hash_bodies: bool,
+    /// Traffic counters, exposed through the admin endpoints
+    metrics: Arc<metrics::Metrics>,
+    /// Where lifecycle events go, if anyone is subscribed to them
+    events: Option<events::EventSender>,
+    /// Becomes true once we've been asked to shut down, so that connections close as soon as they
+    /// have no request in progress
+    shutting_down: watch::Receiver<bool>,
 }
 
 #[tokio::main]
@@ -60,17 +594,106 @@ async fn main() {
     // Initialize the logging library. You can print log messages using the `log` macros:
     // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
     // just looks a little prettier.
-    if let Err(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "debug");
     }
     pretty_env_logger::init();
 
     // Parse the command line arguments passed to this program
     let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
+    if options.upstream.is_empty() {
         log::error!("At least one upstream server must be specified using the --upstream option.");
         std::process::exit(1);
     }
+    if !options.rate_limit_rate.is_finite() || options.rate_limit_rate < 0.0 {
+        log::error!("--rate-limit-rate must be a non-negative number of requests per second.");
+        std::process::exit(1);
+    }
+    if !options.max_requests_per_upstream_per_second.is_finite()
+        || options.max_requests_per_upstream_per_second < 0.0
+    {
+        log::error!(
+            "--max-requests-per-upstream-per-second must be a non-negative number of requests."
+        );
+        std::process::exit(1);
+    }
+    if !options.max_accepts_per_second.is_finite() || options.max_accepts_per_second < 0.0 {
+        log::error!("--max-accepts-per-second must be a non-negative number.");
+        std::process::exit(1);
+    }
+    let fault_rates = fault::FaultRates {
+        delay_percent: options.fault_inject_delay_percent,
+        error_percent: options.fault_inject_error_percent,
+        drop_percent: options.fault_inject_drop_percent,
+    };
+    for (flag, percent) in [
+        ("--fault-inject-delay-percent", fault_rates.delay_percent),
+        ("--fault-inject-error-percent", fault_rates.error_percent),
+        ("--fault-inject-drop-percent", fault_rates.drop_percent),
+    ] {
+        if !(0.0..=100.0).contains(&percent) {
+            log::error!("{} must be between 0 and 100.", flag);
+            std::process::exit(1);
+        }
+    }
+    if fault_rates.any() {
+        log::warn!("Fault injection is enabled; some responses will be delayed, failed or dropped");
+    }
+    if options.upstream_tls_insecure {
+        log::warn!(
+            "--upstream-tls-insecure is set: TLS certificates are not verified for any upstream, so \
+            anyone on the network path can impersonate them"
+        );
+    }
+    for upstream in options
+        .upstream
+        .iter()
+        .filter(|upstream| upstream.tls_insecure)
+    {
+        if !options.upstream_tls {
+            log::error!(
+                "Upstream {} is flagged #tls-insecure, which only applies with --upstream-tls.",
+                upstream.address
+            );
+            std::process::exit(1);
+        }
+        if !options.upstream_tls_insecure {
+            log::warn!(
+                "Upstream {} is flagged #tls-insecure: its TLS certificate is not verified",
+                upstream.address
+            );
+        }
+    }
+    if !(0.0..=1.0).contains(&options.log_sample_rate) {
+        log::error!("--log-sample-rate must be between 0.0 and 1.0.");
+        std::process::exit(1);
+    }
+    if options.real_ip_header.is_some() && options.trusted_proxy.is_empty() {
+        log::warn!("--real-ip-header is ignored unless some --trusted-proxy is given");
+    }
+    if options.max_pipeline_depth == 0 {
+        log::error!("--max-pipeline-depth must be at least 1.");
+        std::process::exit(1);
+    }
+    if options.rate_limit_burst == Some(0) {
+        log::error!("--rate-limit-burst must be at least 1.");
+        std::process::exit(1);
+    }
+    if options.prewarm_connections_per_upstream > options.max_idle_per_upstream {
+        log::error!(
+            "--prewarm-connections-per-upstream can't be more than --max-idle-per-upstream, since \
+            warm connections wait in the pool."
+        );
+        std::process::exit(1);
+    }
+    if let Some(source_ip) = options.upstream_source_ip {
+        // Make sure the address actually belongs to this host now, rather than failing every
+        // upstream connection later
+        if let Err(err) = std::net::TcpListener::bind((source_ip, 0)) {
+            log::error!("Invalid --upstream-source-ip {}: {}", source_ip, err);
+            std::process::exit(1);
+        }
+    }
 
     // Start listening for connections
     let listener = match TcpListener::bind(&options.bind).await {
@@ -80,245 +703,2497 @@ async fn main() {
             std::process::exit(1);
         }
     };
-    log::info!("Listening for requests on {}", options.bind);
+    // Log the address we actually got, which differs from --bind when it asks for port 0
+    match listener.local_addr() {
+        Ok(address) => log::info!("Listening for requests on {}", address),
+        Err(_) => log::info!("Listening for requests on {}", options.bind),
+    }
+    let metrics_listener = match &options.metrics_bind {
+        Some(metrics_bind) => match TcpListener::bind(metrics_bind).await {
+            Ok(listener) => {
+                match listener.local_addr() {
+                    Ok(address) => log::info!("Serving metrics on {}", address),
+                    Err(_) => log::info!("Serving metrics on {}", metrics_bind),
+                }
+                Some(listener)
+            }
+            Err(err) => {
+                log::error!("Could not bind to {}: {}", metrics_bind, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let tls_acceptor = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert_path), Some(key_path)) => {
+            match tls::load_acceptor(cert_path, key_path, options.tls_session_cache_size) {
+                Ok(acceptor) => {
+                    log::info!("Terminating TLS with {}", cert_path.display());
+                    Some(acceptor)
+                }
+                Err(err) => {
+                    log::error!("Invalid --tls-cert or --tls-key: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => None,
+    };
+    // Kept behind a lock so that a SIGHUP can swap in the certificate from the files as they are
+    // then
+    let tls_acceptor = tls_acceptor.map(|acceptor| Arc::new(RwLock::new(acceptor)));
+    if let (Some(acceptor), Some(cert_path), Some(key_path)) =
+        (&tls_acceptor, &options.tls_cert, &options.tls_key)
+    {
+        let hangups = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(err) => {
+                log::error!("Could not listen for SIGHUP: {}", err);
+                std::process::exit(1);
+            }
+        };
+        tokio::spawn(reload_tls_on_hangup(
+            hangups,
+            acceptor.clone(),
+            cert_path.clone(),
+            key_path.clone(),
+            options.tls_session_cache_size,
+        ));
+    }
+
+    let error_bodies = match load_error_bodies(&options.error_body) {
+        Ok(bodies) => bodies,
+        Err(err) => {
+            log::error!("Invalid --error-body: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // A route given more than once allows the methods of each
+    let mut route_methods: HashMap<String, Vec<http::Method>> = HashMap::new();
+    for (route, methods) in &options.route_methods {
+        if !options
+            .upstream
+            .iter()
+            .any(|upstream| upstream.route == *route)
+        {
+            log::error!("Invalid --route-methods: no upstream serves {:?}", route);
+            std::process::exit(1);
+        }
+        let allowed = route_methods.entry(route.clone()).or_default();
+        for method in methods {
+            if !allowed.contains(method) {
+                allowed.push(method.clone());
+            }
+        }
+    }
+
+    // Everything that may need root (the privileged port, a key only root can read) is done now
+    if let Some(user) = &options.run_as_user {
+        match privileges::drop_to(user, options.run_as_group.as_deref()) {
+            Ok((uid, gid)) => log::info!("Running as uid {}, gid {}", uid, gid),
+            Err(err) => {
+                log::error!("Could not switch to --run-as-user {}: {}", user, err);
+                std::process::exit(1);
+            }
+        }
+    }
 
     // Handle incoming connections
+    let upstream_addresses: Vec<String> = options
+        .upstream
+        .iter()
+        .map(|upstream| upstream.address.clone())
+        .collect();
+    let token_buckets = (options.rate_limit_rate > 0.0).then(|| {
+        let burst = options
+            .rate_limit_burst
+            .map_or(options.rate_limit_rate.ceil(), f64::from);
+        Arc::new(rate_limit::TokenBuckets::new(
+            options.rate_limit_rate,
+            burst,
+        ))
+    });
+    let max_total_upstream_connections = options.max_total_upstream_connections;
+    let total_upstream_connections = (max_total_upstream_connections > 0)
+        .then(|| Arc::new(Semaphore::new(max_total_upstream_connections)));
+    let max_requests_per_minute = options.max_requests_per_minute;
+    let request_windows = (max_requests_per_minute > 0).then(|| {
+        Arc::new(rate_limit::SlidingWindows::new(
+            max_requests_per_minute,
+            time::Duration::from_secs(60),
+        ))
+    });
+    // An upstream may take one second's worth of requests at once
+    let upstream_rate = options.max_requests_per_upstream_per_second;
+    let upstream_rate_limits = (upstream_rate > 0.0).then(|| {
+        Arc::new(rate_limit::TokenBuckets::new(
+            upstream_rate,
+            upstream_rate.ceil(),
+        ))
+    });
+    let fault_delay = time::Duration::from_millis(options.fault_inject_delay_ms);
+    let fault_seed = options.fault_inject_seed;
+    let fault_injector = fault_rates.any().then(|| {
+        Arc::new(fault::FaultInjector::new(
+            fault_rates,
+            fault_delay,
+            fault_seed,
+        ))
+    });
+    let events = options.log_events.then(|| {
+        let (sender, mut receiver) = events::EventSender::channel(events::CHANNEL_CAPACITY);
+        tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                log::info!("Event: {:?}", event);
+            }
+        });
+        sender
+    });
+    let (shutdown_sender, shutting_down) = watch::channel(false);
+    let upstream_tls = options
+        .upstream_tls
+        .then(|| tls::upstream_connector(options.upstream_tls_insecure));
+    let insecure_upstream_tls = (options.upstream_tls
+        && !options.upstream_tls_insecure
+        && options
+            .upstream
+            .iter()
+            .any(|upstream| upstream.tls_insecure))
+    .then(|| tls::upstream_connector(true));
+    let mut selector: Arc<dyn selector::UpstreamSelector> = match options.lb_algorithm {
+        LbAlgorithm::Random => Arc::new(selector::Random),
+        LbAlgorithm::LeastConnections => Arc::new(selector::LeastConnections),
+        LbAlgorithm::WeightedLeastConnections => Arc::new(selector::WeightedLeastConnections),
+        LbAlgorithm::RoundRobin => Arc::new(selector::RoundRobin::new()),
+    };
+    if let Some(cookie) = &options.sticky_cookie {
+        selector = Arc::new(selector::StickyCookie::new(cookie, selector));
+    }
     let state = ProxyState {
-        upstream_addresses: options.upstream.clone(),
-        active_upstream_addresses: Arc::new(RwLock::new(options.upstream)),
+        upstream_weights: options
+            .upstream
+            .iter()
+            .map(|upstream| upstream.weight)
+            .collect(),
+        upstream_routes: options
+            .upstream
+            .iter()
+            .map(|upstream| upstream.route.clone())
+            .collect(),
+        upstream_no_chunked: options
+            .upstream
+            .iter()
+            .map(|upstream| upstream.no_chunked)
+            .collect(),
+        upstream_max_body: options
+            .upstream
+            .iter()
+            .map(|upstream| upstream.max_body)
+            .collect(),
+        upstream_tls_insecure: options
+            .upstream
+            .iter()
+            .map(|upstream| upstream.tls_insecure)
+            .collect(),
+        no_route_status: options.no_route_status,
+        route_methods: Arc::new(route_methods),
+        no_route_body: options.no_route_body,
+        error_bodies: Arc::new(error_bodies),
+        active_upstream_addresses: Arc::new(RwLock::new(upstream_addresses.clone())),
+        connect_failures: Arc::new(parking_lot::Mutex::new(HashMap::new())),
+        upstream_addresses,
+        selector,
+        upstream_in_flight: Arc::new(parking_lot::Mutex::new(HashMap::new())),
+        max_connections_per_upstream: options.max_connections_per_upstream,
+        total_upstream_connections,
+        queue_timeout_ms: options.queue_timeout_ms,
+        max_retries: options.max_retries,
+        upstream_slot_freed: Arc::new(Notify::new()),
+        connection_pool: Arc::new(pool::ConnectionPool::new(options.max_idle_per_upstream)),
+        reuse_max_response_bytes: options.reuse_max_response_bytes,
+        health_probe_connections: Arc::new(parking_lot::Mutex::new(HashMap::new())),
+        upstream_source_ip: options.upstream_source_ip,
+        upstream_http_proxy: options.upstream_http_proxy,
+        upstream_tls,
+        insecure_upstream_tls,
+        upstream_connect_timeout: time::Duration::from_millis(options.upstream_connect_timeout_ms),
+        upstream_read_timeout: options
+            .upstream_read_timeout_ms
+            .map(time::Duration::from_millis),
         active_health_check_interval: options.active_health_check_interval,
+        ejected_recheck_interval: options
+            .ejected_recheck_interval
+            .map(time::Duration::from_secs),
         active_health_check_path: options.active_health_check_path,
+        passive_failure_threshold: options.passive_failure_threshold,
+        upstream_failure_streaks: Arc::new(parking_lot::Mutex::new(HashMap::new())),
+        next_health_check: Arc::new(parking_lot::Mutex::new(
+            time::Instant::now()
+                + time::Duration::from_secs(options.active_health_check_interval as u64),
+        )),
         max_requests_per_minute: options.max_requests_per_minute,
-        rate_monitor: Arc::new(Mutex::new(HashMap::new())),
+        real_ip_header: options.real_ip_header,
+        trusted_proxies: options.trusted_proxy,
+        request_windows,
+        token_buckets,
+        upstream_rate_limits,
+        normalize_path: options.normalize_path,
+        decode_unreserved_percent: options.decode_unreserved_percent,
+        request_options: request::ReadOptions {
+            obs_fold: options.obs_fold,
+            allow_absolute_uri: options.allow_absolute_uri,
+            max_body_bytes: options.max_request_body_bytes,
+            // Sending a body again to another upstream needs all of it in hand
+            stream_body_over: if options.max_retries > 0 {
+                None
+            } else {
+                Some(options.stream_request_body_bytes)
+            },
+        },
+        max_hops: options.max_hops,
+        max_pipeline_depth: options.max_pipeline_depth,
+        max_connection_bytes: options.max_connection_bytes,
+        connection_hard_limit: options.connection_hard_limit.map(time::Duration::from_secs),
+        connection_tasks: Arc::new(parking_lot::Mutex::new(Vec::new())),
+        response_read_timeout: options.response_read_timeout.map(time::Duration::from_secs),
+        max_response_header_bytes: options.max_response_header_bytes,
+        set_headers: Arc::new(options.set_header),
+        strip_response_headers: Arc::new(options.strip_response_header),
+        add_forwarded_headers: options.add_forwarded_headers,
+        add_upstream_header: options.add_upstream_header,
+        fault_injector,
+        compress_responses: options.compress_responses,
+        enable_admin_endpoints: options.enable_admin_endpoints,
+        enable_profiling: options.enable_profiling,
+        verbose_errors: options.verbose_errors,
+        log_sampler: Arc::new(access_log::Sampler::new(options.log_sample_rate)),
+        slow_request_threshold: options
+            .slow_request_threshold_ms
+            .map(time::Duration::from_millis),
+        log_format: options.log_format,
+        hash_bodies: options.hash_bodies,
+        metrics: Arc::new(metrics::Metrics::default()),
+        events,
+        shutting_down,
     };
 
-    start_health_check(&state);
+    if options.wait_for_healthy_on_startup {
+        wait_for_healthy_upstreams(&state, options.startup_timeout).await;
+    }
+    if options.prewarm_connections_per_upstream > 0 {
+        prewarm_connections(&state, options.prewarm_connections_per_upstream).await;
+    }
 
-    start_rate_monitor(&state);
+    start_health_check(&state);
+    if let Some(hard_limit) = state.connection_hard_limit {
+        let state_ref = state.clone();
+        tokio::spawn(async move {
+            reap_connections(&state_ref, hard_limit).await;
+        });
+    }
+    if let Some(metrics_listener) = metrics_listener {
+        tokio::spawn(admin::serve_metrics(metrics_listener, state.clone()));
+    }
 
+    // A burst of one spreads a flood of connections out evenly, instead of letting a second's worth
+    // through at once
+    let accept_rate = options.max_accepts_per_second;
+    let mut accept_throttle = (accept_rate > 0.0)
+        .then(|| rate_limit::Throttle::new(accept_rate, 1.0, std::time::Instant::now()));
+    // Every client connection's task, so that shutting down can wait for them
+    let mut connections = JoinSet::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
     loop {
-        if let Ok((stream, _)) = listener.accept().await {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        // Finished tasks stay in the set until they're collected
+        while connections.try_join_next().is_some() {}
+        if let Ok((stream, peer)) = accepted {
+            // While we wait our turn here, nothing else is accepted, so later connections stay in
+            // the listen backlog
+            if let Some(throttle) = &mut accept_throttle {
+                let wait = throttle.reserve(std::time::Instant::now());
+                if !wait.is_zero() {
+                    time::sleep(wait).await;
+                }
+            }
             let state_ref = state.clone();
-            tokio::spawn(async move {
-                handle_connection(stream, &state_ref).await;
+            // Taken now, so that a reload only affects connections accepted after it
+            let tls_acceptor = match &tls_acceptor {
+                Some(acceptor) => Some(acceptor.read().await.clone()),
+                None => None,
+            };
+            let task = connections.spawn(async move {
+                state_ref.metrics.client_connection_opened();
+                // Counted as closed from a guard, so that a task the reaper aborts is counted too
+                let _open = OpenClientConnection(state_ref.metrics.clone());
+                match tls_acceptor {
+                    // The handshake happens here rather than in the accept loop, so that a slow
+                    // client can't hold up everyone else's connections
+                    Some(acceptor) => {
+                        let peer = stream.peer_addr();
+                        match acceptor.accept(stream).await {
+                            Ok(stream) => handle_connection(stream, &state_ref).await,
+                            Err(err) => log::info!("TLS handshake with {:?} failed: {}", peer, err),
+                        }
+                    }
+                    None => handle_connection(stream, &state_ref).await,
+                }
             });
+            if state.connection_hard_limit.is_some() {
+                state.connection_tasks.lock().push(ConnectionTask {
+                    peer,
+                    accepted: time::Instant::now(),
+                    handle: task,
+                });
+            }
         }
     }
+
+    // New connections are refused from here on
+    drop(listener);
+    while connections.try_join_next().is_some() {}
+    let open = connections.len();
+    log::info!(
+        "Shutting down; giving {} open connections up to {}s to finish",
+        open,
+        options.shutdown_grace_seconds
+    );
+    let _ = shutdown_sender.send(true);
+    let grace = time::Duration::from_secs(options.shutdown_grace_seconds);
+    let _ = time::timeout(grace, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    let unfinished = connections.len();
+    connections.shutdown().await;
+    log::info!(
+        "Drained {} connections; forcibly closed {}",
+        open - unfinished,
+        unfinished
+    );
 }
 
-async fn connect_to_upstream(state: &ProxyState) -> Result<TcpStream, std::io::Error> {
-    // Keep connecting to active upstreams.
-    loop {
-        let mut rng = rand::rngs::StdRng::from_entropy();
-        let upstream_idx = rng.gen_range(0..state.active_upstream_addresses.read().await.len());
-        let upstream_ip = &state
-            .active_upstream_addresses
-            .read()
-            .await
-            .get(upstream_idx)
-            .unwrap()
-            .clone();
+/// A client connection's task, as tracked for the reaper
+struct ConnectionTask {
+    peer: SocketAddr,
+    accepted: time::Instant,
+    handle: tokio::task::AbortHandle,
+}
 
-        match TcpStream::connect(upstream_ip).await {
-            Ok(stream) => return Ok(stream),
-            Err(err) => {
-                log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
-                state
-                    .active_upstream_addresses
-                    .write()
-                    .await
-                    .remove(upstream_idx);
-                // Return error only when there is no active upstream left.
-                if state.active_upstream_addresses.read().await.len() == 0 {
-                    return Err(err);
-                }
-            }
-        }
+/// Counts a client connection among the active ones for as long as its task is alive, however the
+/// task ends
+struct OpenClientConnection(Arc<metrics::Metrics>);
+
+impl Drop for OpenClientConnection {
+    fn drop(&mut self) {
+        self.0.client_connection_closed();
     }
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!(
-        "{} <- {}",
-        client_ip,
-        response::format_response_line(&response)
-    );
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
-        log::warn!("Failed to send response to client: {}", error);
-        return;
+/// Aborts the task of every client connection that's been open longer than hard_limit. This is
+/// a backstop for a task stuck somewhere none of the timeouts inside handle_connection reach, so
+/// checking once a second is plenty. Tasks that have finished by themselves are dropped from the
+/// list along the way.
+async fn reap_connections(state: &ProxyState, hard_limit: time::Duration) {
+    let mut ticks = time::interval(time::Duration::from_secs(1));
+    loop {
+        ticks.tick().await;
+        let now = time::Instant::now();
+        state.connection_tasks.lock().retain(|task| {
+            if task.handle.is_finished() {
+                return false;
+            }
+            if now.duration_since(task.accepted) < hard_limit {
+                return true;
+            }
+            log::warn!(
+                "Aborting connection from {} still open after {:?}, over --connection-hard-limit",
+                task.peer,
+                now.duration_since(task.accepted)
+            );
+            task.handle.abort();
+            false
+        });
     }
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("Connection received from {}", client_ip);
-
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+/// Resolves once we're asked to shut down, with SIGINT (e.g. Ctrl-C) or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            log::error!("Could not listen for SIGTERM: {}", err);
+            std::process::exit(1);
         }
     };
-    let upstream_ip = upstream_conn.peer_addr().unwrap().ip().to_string();
+    tokio::select! {
+        _ = signal::ctrl_c() => log::info!("Received SIGINT"),
+        _ = terminate.recv() => log::info!("Received SIGTERM"),
+    }
+}
 
-    // The client may now send us one or more requests. Keep trying to read requests until the
-    // client hangs up or we get an error.
-    loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
-            Ok(request) => request,
-            // Handle case where client closed connection and is no longer sending requests
-            Err(request::Error::IncompleteRequest(0)) => {
-                log::debug!("Client finished sending requests. Shutting down connection");
-                return;
-            }
-            // Handle I/O error in reading from the client
-            Err(request::Error::ConnectionError(io_err)) => {
-                log::info!("Error reading request from client stream: {}", io_err);
-                return;
+/// Loads the TLS certificate and key again each time we get a SIGHUP (e.g. after a renewal), for
+/// connections accepted from then on. Connections already open keep the session they negotiated. If
+/// the files can't be loaded, the certificate we have keeps being used.
+async fn reload_tls_on_hangup(
+    mut hangups: signal::unix::Signal,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+    cert_path: std::path::PathBuf,
+    key_path: std::path::PathBuf,
+    session_cache_size: usize,
+) {
+    while hangups.recv().await.is_some() {
+        match tls::load_acceptor(&cert_path, &key_path, session_cache_size) {
+            Ok(reloaded) => {
+                *acceptor.write().await = reloaded;
+                log::info!("Reloaded TLS certificate from {}", cert_path.display());
             }
-            Err(error) => {
-                log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
-                    request::Error::IncompleteRequest(_)
-                    | request::Error::MalformedRequest(_)
-                    | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
-                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                });
-                send_response(&mut client_conn, &response).await;
-                continue;
-            }
-        };
-        log::info!(
-            "{} -> {}: {}",
-            client_ip,
-            upstream_ip,
-            request::format_request_line(&request)
-        );
+            Err(err) => log::error!(
+                "Could not reload --tls-cert or --tls-key, keeping the current certificate: {}",
+                err
+            ),
+        }
+    }
+}
 
-        // When reach rate limit, respond to request with HTTP error 429 (Too Many Requests)
-        // rather than forwarding the requests to the upstream servers.
-        if let Err(status) = check_rate_limit(state, &upstream_ip).await {
-            let response = response::make_http_error(status);
-            send_response(&mut client_conn, &response).await;
-            continue;
+impl ProxyState {
+    /// Passes an event on to the subscriber, if there is one. This never waits.
+    fn emit(&self, event: events::ProxyEvent) {
+        if let Some(events) = &self.events {
+            events.emit(event);
         }
+    }
 
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+    fn upstream_weight(&self, address: &str) -> usize {
+        self.upstream_addresses
+            .iter()
+            .position(|upstream| upstream == address)
+            .map_or(1, |idx| self.upstream_weights[idx])
+    }
 
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::error!(
-                "Failed to send request to upstream {}: {}",
-                upstream_ip,
-                error
-            );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+    fn accepts_chunked(&self, address: &str) -> bool {
+        self.upstream_addresses
+            .iter()
+            .position(|upstream| upstream == address)
+            .is_none_or(|idx| !self.upstream_no_chunked[idx])
+    }
+
+    /// Returns the connector for TLS connections to the given upstream, if they're to be encrypted.
+    fn tls_connector(&self, address: &str) -> Option<&TlsConnector> {
+        let insecure = self
+            .upstream_addresses
+            .iter()
+            .position(|upstream| upstream == address)
+            .is_some_and(|idx| self.upstream_tls_insecure[idx]);
+        match &self.insecure_upstream_tls {
+            Some(connector) if insecure => Some(connector),
+            _ => self.upstream_tls.as_ref(),
         }
-        log::debug!("Forwarded request to server");
+    }
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await
-        {
-            Ok(response) => response,
-            Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
-                return;
-            }
-        };
-        // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+    fn max_body(&self, address: &str) -> Option<usize> {
+        self.upstream_addresses
+            .iter()
+            .position(|upstream| upstream == address)
+            .and_then(|idx| self.upstream_max_body[idx])
     }
-}
 
-fn start_health_check(state: &ProxyState) {
-    let state_ref = state.clone();
-    tokio::spawn(async move {
-        health_check(&state_ref).await;
-    });
-}
+    /// Returns the route prefix whose upstreams should serve a request for the given path, or None
+    /// if no route covers it.
+    fn route_for(&self, path: &str) -> Option<&str> {
+        routing::longest_prefix_match(self.upstream_routes.iter().map(String::as_str), path)
+    }
 
-async fn health_check(state: &ProxyState) {
-    loop {
-        time::sleep(time::Duration::from_secs(
-            state.active_health_check_interval as u64,
-        ))
-        .await;
-
-        let mut active_servers = state.active_upstream_addresses.write().await;
-        active_servers.clear();
-
-        for upstream in &state.upstream_addresses {
-            let request = http::Request::builder()
-                .method(http::Method::GET)
-                .uri(&state.active_health_check_path)
-                .header("Host", upstream)
-                .body(Vec::<u8>::new())
-                .unwrap();
-
-            match TcpStream::connect(upstream).await {
-                Ok(mut stream) => {
-                    if let Err(err) = request::write_to_stream(&request, &mut stream).await {
-                        log::error!("failed to write to stream {}, {}", upstream, err);
-                    }
+    fn in_route(&self, address: &str, route: &str) -> bool {
+        self.upstream_addresses
+            .iter()
+            .zip(&self.upstream_routes)
+            .any(|(upstream, upstream_route)| upstream == address && upstream_route == route)
+    }
 
-                    if let Ok(resp) =
-                        response::read_from_stream(&mut stream, &request.method()).await
-                    {
-                        if http::StatusCode::OK == resp.status() {
-                            active_servers.push(upstream.clone());
-                        }
-                    } else {
-                        log::error!("failed to receive OK status from stream {}", upstream)
-                    }
-                }
-                Err(err) => {
-                    log::error!("failed to connect to stream {}, {}", upstream, err);
-                }
-            }
+    /// Builds the response for a request that matches no route.
+    fn no_route_response(&self) -> http::Response<Vec<u8>> {
+        match &self.no_route_body {
+            Some(body) => response::make_text_response(self.no_route_status, body.clone()),
+            None => self.error_response(self.no_route_status),
         }
     }
-}
 
-fn start_rate_monitor(state: &ProxyState) {
-    let state_ref = state.clone();
-    tokio::spawn(async move {
-        reset_rate_monitor(&state_ref).await;
-    });
-}
-
-async fn reset_rate_monitor(state: &ProxyState) {
-    time::sleep(time::Duration::from_secs(60)).await;
+    /// Builds a response for an error we're reporting ourselves, with the body configured for its
+    /// status if there is one.
+    fn error_response(&self, status: StatusCode) -> http::Response<Vec<u8>> {
+        match self.error_bodies.get(&status) {
+            Some(error_body) => {
+                response::make_response(status, error_body.content_type, error_body.body.clone())
+            }
+            None => response::make_http_error(status),
+        }
+    }
 
-    state.rate_monitor.lock().await.clear();
-}
+    /// Builds the response for a request we couldn't parse. With verbose errors, a 400 says what
+    /// was wrong with the request.
+    fn unparsable_request_response(&self, error: &request::Error) -> http::Response<Vec<u8>> {
+        let status = match error {
+            request::Error::IncompleteRequest(_)
+            | request::Error::MalformedRequest(_)
+            | request::Error::ObsFoldedHeader
+            | request::Error::AbsoluteFormTarget
+            | request::Error::DuplicateHost
+            | request::Error::InvalidContentLength
+            | request::Error::ContentLengthMismatch
+            | request::Error::InvalidChunkedBody => http::StatusCode::BAD_REQUEST,
+            request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+            request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+        };
+        if self.verbose_errors && status == http::StatusCode::BAD_REQUEST {
+            response::make_text_response(status, format!("Bad request: {}\n", error))
+        } else {
+            self.error_response(status)
+        }
+    }
 
-async fn check_rate_limit(state: &ProxyState, upstream: &str) -> Result<(), StatusCode> {
-    if state.max_requests_per_minute == 0 {
-        return Ok(());
+    /// Builds the 405 for a request whose method its route doesn't allow, which lists the methods
+    /// that are.
+    fn method_not_allowed_response(&self, allowed: &[http::Method]) -> http::Response<Vec<u8>> {
+        let mut response = self.error_response(http::StatusCode::METHOD_NOT_ALLOWED);
+        let allowed: Vec<&str> = allowed.iter().map(http::Method::as_str).collect();
+        response.headers_mut().insert(
+            "allow",
+            http::HeaderValue::from_str(&allowed.join(", ")).unwrap(),
+        );
+        response
     }
 
-    let mut rate_monitor = state.rate_monitor.lock().await;
-    let rate = rate_monitor.entry(upstream.to_string()).or_default();
-    *rate += 1;
-    if *rate > state.max_requests_per_minute {
-        log::error!("reach maximum limit for stream {}", upstream.to_string());
-        return Err(http::StatusCode::TOO_MANY_REQUESTS);
+    /// Builds a 503 for a request no upstream could take. With verbose errors, it gives the reason,
+    /// since an upstream that's up but busy calls for different action than one that's down.
+    fn unavailable_response(&self, reason: &str) -> http::Response<Vec<u8>> {
+        if !self.verbose_errors {
+            return self.error_response(http::StatusCode::SERVICE_UNAVAILABLE);
+        }
+        response::make_text_response(
+            http::StatusCode::SERVICE_UNAVAILABLE,
+            format!("{}\n", reason),
+        )
     }
 
-    Ok(())
+    /// Builds the 503 for a request whose route has no healthy upstreams. With verbose errors, it
+    /// says so, and says when the next health check might bring an upstream back.
+    fn no_healthy_upstreams_response(&self) -> http::Response<Vec<u8>> {
+        if !self.verbose_errors {
+            return self.error_response(http::StatusCode::SERVICE_UNAVAILABLE);
+        }
+        let next_check = self
+            .next_health_check
+            .lock()
+            .saturating_duration_since(time::Instant::now());
+        let mut response = response::make_text_response(
+            http::StatusCode::SERVICE_UNAVAILABLE,
+            format!(
+                "No healthy upstreams. Next health check in {}s.\n",
+                next_check.as_secs_f64().ceil()
+            ),
+        );
+        response.headers_mut().insert(
+            "x-balancebeam-healthy-upstreams",
+            http::HeaderValue::from_static("0"),
+        );
+        response
+    }
+}
+
+/// Marks a client connection as in flight to an upstream for as long as it is alive. Dropping it
+/// (on any exit path out of handle_connection) decrements the upstream's in-flight count and wakes
+/// up any connections queued waiting for a free upstream slot.
+struct InFlightGuard {
+    counts: Arc<parking_lot::Mutex<HashMap<String, usize>>>,
+    slot_freed: Arc<Notify>,
+    /// Where the connection is counted among the active upstream connections
+    metrics: Arc<metrics::Metrics>,
+    address: String,
+    /// Counts the connection against the limit on upstream connections overall, if there is one
+    _total_permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if let Some(count) = self.counts.lock().get_mut(&self.address) {
+            *count = count.saturating_sub(1);
+        }
+        self.metrics.upstream_connection_closed();
+        self.slot_freed.notify_waiters();
+    }
+}
+
+/// Reasons connect_to_upstream can fail to hand back an upstream connection
+#[derive(Debug)]
+enum UpstreamUnavailable {
+    /// Every upstream that could serve the request failed its last health check
+    NoHealthyUpstreams,
+    /// The selector turned down every healthy upstream it was offered
+    SelectorDeclined,
+    /// We couldn't connect to any active upstream
+    ConnectFailed(std::io::Error),
+    /// Every active upstream stayed at its concurrency limit for the whole queue timeout
+    AllAtCapacity,
+    /// The limit on upstream connections overall stayed reached for the whole queue timeout
+    TotalConnectionLimit,
+    /// Every active upstream with room for another connection is at its request rate cap
+    AllRateLimited,
+}
+
+/// Opens a connection to an upstream address. If a forward proxy is configured, the connection is a
+/// CONNECT tunnel through the proxy, which behaves just like a direct connection once it's set up.
+/// Tunneling even plaintext traffic (rather than sending absolute-form requests to the proxy)
+/// keeps each connection tied to one upstream, so pooling and health checks work unchanged.
+///
+/// With --upstream-tls, the connection (tunneled or not) is then encrypted, with the upstream's
+/// host as the name its certificate has to be for.
+///
+/// An upstream that's routable but not accepting connections could otherwise leave us waiting for
+/// as long as the OS keeps retrying, so setting up the connection fails with TimedOut once the
+/// upstream connect timeout runs out.
+async fn dial_upstream(
+    state: &ProxyState,
+    address: &str,
+) -> Result<tls::UpstreamStream, std::io::Error> {
+    let dial = async {
+        let stream = match &state.upstream_http_proxy {
+            Some(proxy) => {
+                let mut stream = open_tcp(state, proxy).await?;
+                open_tunnel(&mut stream, address).await?;
+                stream
+            }
+            None => open_tcp(state, address).await?,
+        };
+        match state.tls_connector(address) {
+            Some(connector) => {
+                let server_name = tls::upstream_server_name(address)?;
+                let stream = connector.connect(server_name, stream).await?;
+                Ok(tls::UpstreamStream::Tls(Box::new(stream)))
+            }
+            None => Ok(tls::UpstreamStream::Plain(stream)),
+        }
+    };
+    time::timeout(state.upstream_connect_timeout, dial)
+        .await
+        .unwrap_or_else(|_| {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "connection timed out after {}ms",
+                    state.upstream_connect_timeout.as_millis()
+                ),
+            ))
+        })
+}
+
+/// Asks the forward proxy on the other end of the stream to tunnel it to the given address.
+async fn open_tunnel(stream: &mut TcpStream, address: &str) -> Result<(), std::io::Error> {
+    let request = http::Request::builder()
+        .method(http::Method::CONNECT)
+        .uri(address)
+        .header("Host", address)
+        .body(Vec::new())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    request::write_to_stream(&request, stream).await?;
+    let response = response::read_from_stream(
+        stream,
+        &mut Vec::new(),
+        &http::Method::CONNECT,
+        response::MAX_HEADERS_SIZE,
+    )
+    .await
+    .map_err(|err| {
+        std::io::Error::other(format!(
+            "bad response from proxy to CONNECT {}: {:?}",
+            address, err
+        ))
+    })?;
+    if !response.status().is_success() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            format!(
+                "proxy refused to CONNECT to {}: {}",
+                address,
+                response::format_response_line(&response)
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Opens a TCP connection to an address, originating from the configured source IP if there is
+/// one.
+async fn open_tcp(state: &ProxyState, address: &str) -> Result<TcpStream, std::io::Error> {
+    let source_ip = match state.upstream_source_ip {
+        Some(source_ip) => source_ip,
+        None => return TcpStream::connect(address).await,
+    };
+    let mut last_err = None;
+    for target in tokio::net::lookup_host(address).await? {
+        // A socket bound to an IPv4 source can't reach an IPv6 destination, and vice versa
+        if target.is_ipv4() != source_ip.is_ipv4() {
+            continue;
+        }
+        let socket = if target.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.bind(SocketAddr::new(source_ip, 0))?;
+        match socket.connect(target).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!(
+                "{} has no address reachable from source IP {}",
+                address, source_ip
+            ),
+        )
+    }))
+}
+
+/// Connects to one of the active upstreams serving the given route, other than the excluded ones,
+/// as picked by the selector for the request in context, reusing an idle pooled connection to it if
+/// there is one. The returned flag says whether the connection came from the pool.
+async fn connect_to_upstream(
+    state: &ProxyState,
+    route: &str,
+    context: &selector::RequestContext<'_>,
+    excluded: &[String],
+) -> Result<(tls::UpstreamStream, InFlightGuard, bool), UpstreamUnavailable> {
+    let queue_deadline = time::Instant::now() + time::Duration::from_millis(state.queue_timeout_ms);
+    // Upstreams we couldn't connect to are excluded for the rest of this call only
+    let mut excluded = excluded.to_vec();
+    let mut connect_error = None;
+    let mut total_permit = None;
+    // Keep connecting to active upstreams.
+    loop {
+        let active_upstreams: Vec<String> = state
+            .active_upstream_addresses
+            .read()
+            .await
+            .iter()
+            .filter(|address| state.in_route(address, route) && !excluded.contains(address))
+            .cloned()
+            .collect();
+        if active_upstreams.is_empty() {
+            return Err(match connect_error {
+                Some(err) => UpstreamUnavailable::ConnectFailed(err),
+                None => UpstreamUnavailable::NoHealthyUpstreams,
+            });
+        }
+        if let (None, Some(total)) = (&total_permit, &state.total_upstream_connections) {
+            match time::timeout_at(queue_deadline, total.clone().acquire_owned()).await {
+                Ok(Ok(permit)) => total_permit = Some(permit),
+                _ => return Err(UpstreamUnavailable::TotalConnectionLimit),
+            }
+        }
+
+        // Register for slot wakeups before checking capacity, so that a slot freed between the
+        // check and the wait below can't be missed.
+        let slot_freed = state.upstream_slot_freed.notified();
+        tokio::pin!(slot_freed);
+        slot_freed.as_mut().enable();
+
+        // Pick an upstream and count this connection against it under a single lock, so that
+        // concurrent connections see each other when comparing loads and checking capacity.
+        let in_flight = {
+            let mut counts = state.upstream_in_flight.lock();
+            let mut available: Vec<&String> = active_upstreams
+                .iter()
+                .filter(|address| {
+                    state.max_connections_per_upstream == 0
+                        || counts.get(*address).copied().unwrap_or(0)
+                            < state.max_connections_per_upstream
+                })
+                .collect();
+            // An upstream at its request rate cap won't be able to take the request either, but
+            // unlike a free connection slot, there's no point queueing for its next token
+            if let Some(limits) = &state.upstream_rate_limits {
+                let had_capacity = !available.is_empty();
+                let now = std::time::Instant::now();
+                available.retain(|address| limits.has_token(address, now));
+                if had_capacity && available.is_empty() {
+                    return Err(UpstreamUnavailable::AllRateLimited);
+                }
+            }
+            if available.is_empty() {
+                None
+            } else {
+                let candidates: Vec<selector::Upstream> = available
+                    .iter()
+                    .map(|address| selector::Upstream {
+                        address,
+                        weight: state.upstream_weight(address),
+                        in_flight: counts.get(*address).copied().unwrap_or(0),
+                    })
+                    .collect();
+                let upstream_idx = match state.selector.select(&candidates, context) {
+                    Some(idx) if idx < candidates.len() => idx,
+                    _ => {
+                        log::warn!(
+                            "Selector declined {} {} among {} upstreams",
+                            context.method,
+                            context.path,
+                            candidates.len()
+                        );
+                        return Err(UpstreamUnavailable::SelectorDeclined);
+                    }
+                };
+                let address = available[upstream_idx].clone();
+                *counts.entry(address.clone()).or_default() += 1;
+                state.metrics.upstream_connection_opened();
+                Some(InFlightGuard {
+                    counts: state.upstream_in_flight.clone(),
+                    slot_freed: state.upstream_slot_freed.clone(),
+                    metrics: state.metrics.clone(),
+                    address,
+                    _total_permit: total_permit.take(),
+                })
+            }
+        };
+        let in_flight = match in_flight {
+            Some(in_flight) => in_flight,
+            None => {
+                // Every upstream is saturated. Wait for a slot to free up, as long as we haven't
+                // been queued for too long already.
+                if time::timeout_at(queue_deadline, slot_freed).await.is_err() {
+                    return Err(UpstreamUnavailable::AllAtCapacity);
+                }
+                continue;
+            }
+        };
+        let upstream_ip = in_flight.address.clone();
+
+        if let Some(stream) = state.connection_pool.take(&upstream_ip) {
+            state.metrics.record_upstream_reuse(&upstream_ip);
+            return Ok((stream, in_flight, true));
+        }
+        match dial_upstream(state, &upstream_ip).await {
+            Ok(stream) => {
+                state.metrics.record_upstream_dial(&upstream_ip);
+                return Ok((stream, in_flight, false));
+            }
+            Err(err) => {
+                log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
+                *state
+                    .connect_failures
+                    .lock()
+                    .entry(upstream_ip.clone())
+                    .or_default() += 1;
+                excluded.push(upstream_ip);
+                connect_error = Some(err);
+            }
+        }
+    }
+}
+
+/// Sends a response to the client, returning its size in bytes. The response is written to the
+/// access log if its request was sampled for logging, or if it's an error. Once the request has
+/// been counted against the client's rate limit, the response says where the client stands.
+async fn send_response<C: AsyncWrite + Unpin>(
+    state: &ProxyState,
+    client_conn: &mut C,
+    response: &mut http::Response<Vec<u8>>,
+    info: &access_log::RequestInfo,
+) -> u64 {
+    if let Some(quota) = &info.rate_limit {
+        quota.add_headers(response.headers_mut());
+    }
+    state.metrics.record_response(
+        info.upstream.as_deref(),
+        response.status(),
+        info.received.elapsed(),
+    );
+    write_response(state, client_conn, response, info).await
+}
+
+/// Sends a response to the client without counting it in the metrics, returning its size in bytes.
+async fn write_response<C: AsyncWrite + Unpin>(
+    state: &ProxyState,
+    client_conn: &mut C,
+    response: &http::Response<Vec<u8>>,
+    info: &access_log::RequestInfo,
+) -> u64 {
+    log_response(state, response, info);
+    match response::write_to_stream(response, client_conn).await {
+        Ok(()) => {}
+        Err(error) => log_write_error(&error, info),
+    }
+    log_if_slow(state, info);
+    response::encoded_len(response) as u64
+}
+
+/// Sends a response whose body is still on its way from the upstream, passing the body on to the
+/// client as it arrives rather than collecting it first. Returns the number of bytes sent, or
+/// None if the body didn't make it through whole, in which case neither connection is fit for
+/// another exchange. The body's length is recorded in info.
+async fn stream_response<C: AsyncWrite + Unpin>(
+    state: &ProxyState,
+    client_conn: &mut C,
+    response: &mut http::Response<Vec<u8>>,
+    body: response::StreamableBody,
+    upstream_conn: &mut UpstreamConnection,
+    upstream_read_ahead: &mut Vec<u8>,
+    info: &mut access_log::RequestInfo,
+) -> Option<u64> {
+    if let Some(quota) = &info.rate_limit {
+        quota.add_headers(response.headers_mut());
+    }
+    state.metrics.record_response(
+        info.upstream.as_deref(),
+        response.status(),
+        info.received.elapsed(),
+    );
+    // A chunked body's length, and a hashed body's digest, are only known once the body has been
+    // sent, so such a response is logged then
+    let log_up_front = matches!(body, response::StreamableBody::Length(_)) && !state.hash_bodies;
+    if let response::StreamableBody::Length(len) = body {
+        info.streamed_body_len = Some(len);
+    }
+    if log_up_front {
+        log_response(state, response, info);
+    }
+    if let Err(error) = response::write_head_to_stream(response, client_conn).await {
+        log_write_error(&error, info);
+        return None;
+    }
+    let mut hasher = state.hash_bodies.then(access_log::BodyHasher::default);
+    let result = match body {
+        response::StreamableBody::Length(len) => response::stream_body(
+            &mut upstream_conn.stream,
+            upstream_read_ahead,
+            len,
+            client_conn,
+            hasher.as_mut(),
+        )
+        .await
+        .map(|()| len),
+        response::StreamableBody::Chunked => {
+            let result = response::stream_chunked_body(
+                &mut upstream_conn.stream,
+                upstream_read_ahead,
+                client_conn,
+                hasher.as_mut(),
+            )
+            .await;
+            info.streamed_body_len = result.as_ref().ok().copied();
+            result
+        }
+    };
+    if !log_up_front {
+        info.response_body_sha256 = match (hasher, &result) {
+            (Some(hasher), Ok(_)) => Some(hasher.finish()),
+            (Some(_), Err(_)) => Some("-".to_string()),
+            (None, _) => None,
+        };
+        log_response(state, response, info);
+    }
+    log_if_slow(state, info);
+    match result {
+        Ok(body_len) => Some((response::encoded_len(response) + body_len) as u64),
+        Err(response::StreamError::Read(error)) => {
+            log::error!(
+                "Error streaming response body from upstream {}: {:?}",
+                upstream_conn.ip,
+                error
+            );
+            None
+        }
+        Err(response::StreamError::Write(error)) => {
+            log_write_error(&error, info);
+            None
+        }
+    }
+}
+
+/// Writes the access log line for a response, if its request was sampled for logging or it's an
+/// error.
+fn log_response(
+    state: &ProxyState,
+    response: &http::Response<Vec<u8>>,
+    info: &access_log::RequestInfo,
+) {
+    let status = response.status();
+    if info.sampled || status.is_client_error() || status.is_server_error() {
+        let digests = if state.hash_bodies {
+            access_log::format_body_digests(info, response)
+        } else {
+            String::new()
+        };
+        match state.log_format {
+            access_log::LogFormat::Default => log::info!(
+                "{} <- {}{}",
+                info.client_ip,
+                response::format_response_line(response),
+                digests
+            ),
+            format => log::info!(
+                "{}{}",
+                access_log::format_line(format, info, response, std::time::SystemTime::now()),
+                digests
+            ),
+        }
+    }
+}
+
+/// Warns about a request that took longer than the slow request threshold, from when we finished
+/// reading it to when its response was sent.
+fn log_if_slow(state: &ProxyState, info: &access_log::RequestInfo) {
+    let threshold = match state.slow_request_threshold {
+        Some(threshold) => threshold,
+        None => return,
+    };
+    let elapsed = info.received.elapsed();
+    if elapsed > threshold {
+        log::warn!(
+            "Slow request from {}: \"{}\" took {}ms (upstream {})",
+            info.client_ip,
+            info.request_line.as_deref().unwrap_or("-"),
+            elapsed.as_millis(),
+            info.upstream.as_deref().unwrap_or("-")
+        );
+    }
+}
+
+/// Logs a failure to send a response to the client. Clients abort downloads all the time (closing
+/// a browser tab, say). That's their prerogative rather than something wrong with us, so it's kept
+/// out of the warnings.
+fn log_write_error(error: &std::io::Error, info: &access_log::RequestInfo) {
+    if is_disconnect(error) {
+        log::debug!(
+            "Client {} went away before its response was sent: {}",
+            info.client_ip,
+            error
+        );
+    } else {
+        log::warn!("Failed to send response to client: {}", error);
+    }
+}
+
+/// Returns true if an I/O error means the peer closed or reset the connection
+fn is_disconnect(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Reasons forwarding a request to an upstream and reading back its response can fail
+#[derive(Debug)]
+enum ForwardError {
+    /// The request couldn't be written to the upstream connection
+    Write(std::io::Error),
+    /// The upstream's response couldn't be read
+    Read(response::Error),
+    /// The upstream didn't take the request or finish sending its response within the response
+    /// read timeout, the upstream read timeout or the client's deadline
+    Timeout,
+}
+
+impl ForwardError {
+    /// Returns true if the failure looks like the upstream closed the connection before it ever saw
+    /// the request, which is what happens when an upstream times out a connection that sat idle
+    /// between requests. Nothing came back from the upstream, so it can't have answered.
+    fn is_stale_connection(&self) -> bool {
+        match self {
+            ForwardError::Write(_) => true,
+            ForwardError::Read(response::Error::IncompleteResponse(0)) => true,
+            ForwardError::Read(response::Error::ConnectionError(err)) => is_disconnect(err),
+            ForwardError::Read(_) | ForwardError::Timeout => false,
+        }
+    }
+}
+
+/// Writes a request to the upstream connection and reads back the upstream's final response.
+/// Informational responses the upstream sends first (e.g. 103 Early Hints) are relayed to the
+/// client as they arrive, except for 100 Continue: by then we've sent the whole request body, so
+/// the client has nothing to continue with.
+///
+/// A request body left on the client connection (see request::streamed_body_len) is copied to the
+/// upstream from client_read_ahead and then client_conn right after the head, as part of writing
+/// the request. A client waiting on 100 Continue before sending it is told to go ahead by us.
+///
+/// read_ahead is left holding anything the upstream sent after the final response, which after a
+/// 101 Switching Protocols is the start of the new protocol.
+///
+/// With stream_body, a body that can be streamed (see response::streamable_body) is left on the
+/// connection for the caller to pass on as it arrives, and how it's delimited is returned along
+/// with the response.
+///
+/// The response read timeout covers everything we read, so an upstream that answers promptly but
+/// then trickles out its body can't hold the client up for longer than that either. The upstream
+/// read timeout applies to writing the request, reading the response head and reading its body
+/// separately, each as a whole, so a body that trickles in a byte at a time is cut off too. None of
+/// it may go past client_deadline, the deadline the client set for the request.
+#[allow(clippy::too_many_arguments)]
+async fn forward_request<C: AsyncRead + AsyncWrite + Unpin>(
+    state: &ProxyState,
+    upstream_conn: &mut tls::UpstreamStream,
+    client_conn: &mut C,
+    client_read_ahead: &mut Vec<u8>,
+    request: &http::Request<Vec<u8>>,
+    read_ahead: &mut Vec<u8>,
+    stream_body: bool,
+    client_deadline: Option<time::Instant>,
+    request_body_sha256: &mut Option<String>,
+) -> Result<(http::Response<Vec<u8>>, Option<response::StreamableBody>), ForwardError> {
+    let write = async {
+        request::write_to_stream(request, upstream_conn).await?;
+        if let Some(len) = request::streamed_body_len(request) {
+            if request::expects_continue(request) {
+                client_conn
+                    .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                    .await?;
+            }
+            let mut hasher = state.hash_bodies.then(access_log::BodyHasher::default);
+            request::copy_body(
+                client_conn,
+                client_read_ahead,
+                upstream_conn,
+                len,
+                hasher.as_mut(),
+            )
+            .await?;
+            *request_body_sha256 = hasher.map(access_log::BodyHasher::finish);
+        }
+        Ok(())
+    };
+    let write_deadline = earliest(
+        client_deadline,
+        state
+            .upstream_read_timeout
+            .map(|timeout| time::Instant::now() + timeout),
+    );
+    match write_deadline {
+        Some(deadline) => time::timeout_at(deadline, write)
+            .await
+            .map_err(|_| ForwardError::Timeout)?,
+        None => write.await,
+    }
+    .map_err(ForwardError::Write)?;
+    log::debug!("Forwarded request to server");
+    read_ahead.clear();
+    let deadline = earliest(
+        client_deadline,
+        state
+            .response_read_timeout
+            .map(|timeout| time::Instant::now() + timeout),
+    );
+    loop {
+        let read =
+            response::read_headers(upstream_conn, read_ahead, state.max_response_header_bytes);
+        let mut response = with_deadline(deadline, state.upstream_read_timeout, read).await?;
+        if !response::is_informational(response.status()) {
+            if stream_body {
+                let body = response::streamable_body(&response, request.method(), read_ahead);
+                if body.is_some() {
+                    return Ok((response, body));
+                }
+            }
+            let read = response::read_body_from_stream(
+                upstream_conn,
+                read_ahead,
+                request.method(),
+                &mut response,
+            );
+            with_deadline(deadline, state.upstream_read_timeout, read).await?;
+            return Ok((response, None));
+        }
+        if response.status() == http::StatusCode::CONTINUE {
+            continue;
+        }
+        log::debug!(
+            "Relaying informational response {}",
+            response::format_response_line(&response)
+        );
+        if let Err(error) = response::write_to_stream(&response, client_conn).await {
+            log::debug!(
+                "Failed to relay informational response to client: {}",
+                error
+            );
+        }
+    }
+}
+
+/// Waits for part of a response to be read, giving up at the deadline or once the read has taken
+/// longer than timeout, whichever comes first.
+async fn with_deadline<T>(
+    deadline: Option<time::Instant>,
+    timeout: Option<time::Duration>,
+    read: impl std::future::Future<Output = Result<T, response::Error>>,
+) -> Result<T, ForwardError> {
+    let read_deadline = timeout.map(|timeout| time::Instant::now() + timeout);
+    match earliest(deadline, read_deadline) {
+        Some(deadline) => time::timeout_at(deadline, read)
+            .await
+            .map_err(|_| ForwardError::Timeout)?,
+        None => read.await,
+    }
+    .map_err(ForwardError::Read)
+}
+
+/// Returns whichever of two optional deadlines comes first.
+fn earliest(a: Option<time::Instant>, b: Option<time::Instant>) -> Option<time::Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Once the upstream has agreed to switch protocols, the connection is no longer HTTP as far as
+/// we're concerned, whatever the new protocol is: bytes are copied both ways until either side
+/// hangs up. Anything either side sent right behind the upgrade is passed on first.
+async fn tunnel_upgraded_connection<C: AsyncRead + AsyncWrite + Unpin>(
+    client_conn: &mut C,
+    client_read_ahead: &[u8],
+    upstream_conn: &mut tls::UpstreamStream,
+    upstream_read_ahead: &[u8],
+) {
+    if let Err(error) = upstream_conn.write_all(client_read_ahead).await {
+        log::debug!("Upgraded connection closed by the upstream: {}", error);
+        return;
+    }
+    if let Err(error) = client_conn.write_all(upstream_read_ahead).await {
+        log::debug!("Upgraded connection closed by the client: {}", error);
+        return;
+    }
+    match tokio::io::copy_bidirectional(client_conn, upstream_conn).await {
+        Ok((to_upstream, to_client)) => log::debug!(
+            "Upgraded connection closed after {} bytes to the upstream and {} to the client",
+            to_upstream + client_read_ahead.len() as u64,
+            to_client + upstream_read_ahead.len() as u64
+        ),
+        Err(error) => log::debug!("Upgraded connection closed: {}", error),
+    }
+}
+
+/// An open connection to an upstream, on behalf of one client connection
+struct UpstreamConnection {
+    stream: tls::UpstreamStream,
+    /// Holds our slot on the upstream for as long as the connection is open
+    in_flight: InFlightGuard,
+    ip: String,
+    /// Route prefix the upstream was picked for
+    route: String,
+    /// Selection key of the request the upstream was picked for (see
+    /// UpstreamSelector::selection_key)
+    selection_key: Option<String>,
+    /// Whether the connection has already carried a request, for this client or (if it came
+    /// from the pool) an earlier one. Only a reused connection can have gone stale while idle.
+    reused: bool,
+    /// Whether the last response left the connection fit to carry another request
+    reusable: bool,
+}
+
+impl UpstreamConnection {
+    async fn open(
+        state: &ProxyState,
+        route: &str,
+        context: &selector::RequestContext<'_>,
+    ) -> Result<Self, UpstreamUnavailable> {
+        UpstreamConnection::open_excluding(state, route, context, &[]).await
+    }
+
+    /// Like open, but never picks one of the excluded upstream addresses.
+    async fn open_excluding(
+        state: &ProxyState,
+        route: &str,
+        context: &selector::RequestContext<'_>,
+        excluded: &[String],
+    ) -> Result<Self, UpstreamUnavailable> {
+        let (stream, in_flight, pooled) =
+            connect_to_upstream(state, route, context, excluded).await?;
+        // Through a forward proxy, our peer is the proxy rather than the upstream. A pooled
+        // connection the upstream has since reset no longer has a peer address at all.
+        let ip = match stream.tcp().peer_addr() {
+            Ok(addr) if state.upstream_http_proxy.is_none() => addr.ip().to_string(),
+            _ => in_flight.address.clone(),
+        };
+        Ok(UpstreamConnection {
+            ip,
+            stream,
+            in_flight,
+            route: route.to_string(),
+            selection_key: state.selector.selection_key(context),
+            reused: pooled,
+            reusable: true,
+        })
+    }
+
+    /// Returns true if this connection can carry a request for the given route with the given
+    /// selection key, rather than the request needing an upstream selected for it.
+    fn serves(&self, route: &str, selection_key: &Option<String>) -> bool {
+        self.route == route && (selection_key.is_none() || *selection_key == self.selection_key)
+    }
+
+    /// Returns the connection to the pool for a later client if it can carry another request, and
+    /// gives up our slot on the upstream either way.
+    fn release(self, state: &ProxyState) {
+        if self.reusable {
+            state
+                .connection_pool
+                .put(&self.in_flight.address, self.stream);
+        }
+    }
+
+    /// A connection that just carried a large response body is assumed to be cheaper to replace
+    /// than to keep around.
+    fn check_reuse_after_body(&mut self, state: &ProxyState, body_len: usize) {
+        if state
+            .reuse_max_response_bytes
+            .is_some_and(|max_bytes| body_len > max_bytes)
+        {
+            log::debug!(
+                "Not reusing connection to upstream {} after a {}-byte response",
+                self.ip,
+                body_len
+            );
+            self.reusable = false;
+        }
+    }
+}
+
+/// Returns true if the client has already closed its end of the connection. This doesn't wait for
+/// the client, and doesn't consume any pipelined request bytes it may have sent.
+async fn client_hung_up(client_conn: &TcpStream) -> bool {
+    let mut buf = [0_u8; 1];
+    matches!(
+        time::timeout(time::Duration::ZERO, client_conn.peek(&mut buf)).await,
+        Ok(Ok(0)) | Ok(Err(_))
+    )
+}
+
+/// A connection from a client: plain TCP, or TLS over TCP when we terminate TLS
+trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {
+    /// The TCP connection underneath, for what only it can tell us, like who's on the other end
+    fn tcp(&self) -> &TcpStream;
+    /// The scheme the client reached us with
+    fn scheme(&self) -> &'static str;
+    /// The server name the client asked for in its TLS handshake, if it used TLS and sent one
+    fn sni(&self) -> Option<&str>;
+}
+
+impl ClientStream for TcpStream {
+    fn tcp(&self) -> &TcpStream {
+        self
+    }
+
+    fn scheme(&self) -> &'static str {
+        "http"
+    }
+
+    fn sni(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl ClientStream for tokio_rustls::server::TlsStream<TcpStream> {
+    fn tcp(&self) -> &TcpStream {
+        self.get_ref().0
+    }
+
+    fn scheme(&self) -> &'static str {
+        "https"
+    }
+
+    fn sni(&self) -> Option<&str> {
+        self.get_ref().1.server_name()
+    }
+}
+
+/// What we know about a client connection as a whole, as opposed to any one request on it
+struct ConnectionContext {
+    peer_ip: IpAddr,
+    /// See ClientStream::sni
+    sni: Option<String>,
+}
+
+impl ConnectionContext {
+    fn new<S: ClientStream>(client_conn: &S) -> ConnectionContext {
+        ConnectionContext {
+            peer_ip: client_conn.tcp().peer_addr().unwrap().ip(),
+            sni: client_conn.sni().map(str::to_string),
+        }
+    }
+
+    /// Builds what a selector gets to know about a request on this connection
+    fn request_context<'a>(
+        &'a self,
+        request: &'a http::Request<Vec<u8>>,
+        client_ip: &'a str,
+    ) -> selector::RequestContext<'a> {
+        selector::RequestContext::new(request, client_ip, self.sni.as_deref())
+    }
+}
+
+async fn handle_connection<S: ClientStream>(mut client_conn: S, state: &ProxyState) {
+    let connection = ConnectionContext::new(&client_conn);
+    let peer_ip = connection.peer_ip;
+    if client_conn.scheme() == "https" {
+        // A client that didn't send a server name is logged with an empty one
+        log::info!(
+            "Connection received from {} (SNI {:?})",
+            peer_ip,
+            connection.sni.as_deref().unwrap_or("")
+        );
+    } else {
+        log::info!("Connection received from {}", peer_ip);
+    }
+    state.emit(events::ProxyEvent::ConnectionAccepted {
+        client: peer_ip.to_string(),
+    });
+
+    // We only know which upstreams can serve the client once we've seen a request's path, so the
+    // upstream connection is opened for the first request, and reopened whenever a later request
+    // is for a different route or has a different selection key (such as another session cookie).
+    let mut upstream: Option<UpstreamConnection> = None;
+
+    // A client may pipeline requests, sending more before we've answered the first. Whatever we've
+    // read of those is kept in read_ahead, and up to max_pipeline_depth of them are parsed into a
+    // batch, which is answered before we read anything more from the client.
+    let mut read_ahead = Vec::new();
+    let mut pipeline = VecDeque::new();
+
+    // Request and response bytes transferred over the connection so far
+    let mut connection_bytes: u64 = 0;
+
+    // Length of a streamed request body (see request::streamed_body_len) still on the connection,
+    // because its request was answered without being forwarded
+    let mut unread_body = 0;
+
+    // Whether the last request asked for the connection to be closed once it was answered (see
+    // request::wants_close). Anything the client pipelined after it goes unanswered.
+    let mut client_closing = false;
+
+    // The client may now send us one or more requests. Keep trying to read requests until the
+    // client hangs up or we get an error.
+    loop {
+        if client_closing {
+            log::debug!("Closing connection from {} as the client asked", peer_ip);
+            if let Some(conn) = upstream {
+                conn.release(state);
+            }
+            return;
+        }
+        // A connection that has used up its byte allowance is closed once the response that took
+        // it over the limit has been sent, so that one client can't hog our bandwidth indefinitely.
+        if state.max_connection_bytes > 0 && connection_bytes >= state.max_connection_bytes {
+            log::info!(
+                "Connection from {} transferred {} bytes, over --max-connection-bytes; closing it",
+                peer_ip,
+                connection_bytes
+            );
+            if let Some(conn) = upstream {
+                conn.release(state);
+            }
+            return;
+        }
+        if pipeline.is_empty() {
+            if unread_body > 0 {
+                if let Err(error) =
+                    request::discard_body(&mut client_conn, &mut read_ahead, unread_body).await
+                {
+                    log::debug!(
+                        "Error reading past an unforwarded request body: {:?}",
+                        error
+                    );
+                    if let Some(conn) = upstream {
+                        conn.release(state);
+                    }
+                    return;
+                }
+                unread_body = 0;
+            }
+            // Once we're shutting down, a connection is closed as soon as it's answered everything
+            // it has asked for. One that's waiting on the client's next request is closed then too.
+            let mut shutting_down = state.shutting_down.clone();
+            let read =
+                request::read_from_stream(&mut client_conn, &mut read_ahead, state.request_options);
+            let request = tokio::select! {
+                request = read => request,
+                _ = shutting_down.wait_for(|shutting_down| *shutting_down) => {
+                    log::debug!("Closing connection from {} to shut down", peer_ip);
+                    if let Some(conn) = upstream {
+                        conn.release(state);
+                    }
+                    return;
+                }
+            };
+            pipeline.push_back(request);
+            // The request after one with a streamed body can't be read until the body has been
+            // passed on, and nothing after one we couldn't find the end of can be read at all
+            while pipeline.len() < state.max_pipeline_depth
+                && request::has_buffered_request(&read_ahead)
+                && !matches!(pipeline.back(), Some(Ok(request)) if request::streamed_body_len(request).is_some())
+                && !matches!(pipeline.back(), Some(Err(error)) if !error.allows_next_request())
+            {
+                pipeline.push_back(
+                    request::read_from_stream(
+                        &mut client_conn,
+                        &mut read_ahead,
+                        state.request_options,
+                    )
+                    .await,
+                );
+            }
+            if pipeline.len() > 1 {
+                log::debug!("Read a batch of {} pipelined requests", pipeline.len());
+            }
+        }
+
+        // Take the next request from the client
+        let mut request = match pipeline.pop_front().unwrap() {
+            Ok(request) => {
+                unread_body = request::streamed_body_len(&request).unwrap_or(0);
+                client_closing = request::wants_close(&request);
+                connection_bytes += (request::encoded_len(&request) + unread_body) as u64;
+                request
+            }
+            // Handle case where client closed connection and is no longer sending requests
+            Err(request::Error::IncompleteRequest(0)) => {
+                log::debug!("Client finished sending requests. Shutting down connection");
+                if let Some(conn) = upstream {
+                    conn.release(state);
+                }
+                return;
+            }
+            // Handle I/O error in reading from the client
+            Err(request::Error::ConnectionError(io_err)) => {
+                log::info!("Error reading request from client stream: {}", io_err);
+                return;
+            }
+            Err(error) => {
+                log::debug!("Error parsing request: {:?}", error);
+                let mut response = state.unparsable_request_response(&error);
+                let closing = !error.allows_next_request();
+                if closing {
+                    response
+                        .headers_mut()
+                        .insert("connection", http::HeaderValue::from_static("close"));
+                }
+                let info = access_log::RequestInfo::unparsed(&peer_ip.to_string());
+                connection_bytes +=
+                    send_response(state, &mut client_conn, &mut response, &info).await;
+                if closing {
+                    log::debug!(
+                        "Closing connection from {}, as the end of its request is unknown",
+                        peer_ip
+                    );
+                    if let Some(conn) = upstream {
+                        conn.release(state);
+                    }
+                    return;
+                }
+                continue;
+            }
+        };
+
+        // Behind a trusted proxy, the client we're serving is the proxy's client, not the proxy
+        let client_ip = client_ip::real_client_ip(
+            peer_ip,
+            &request,
+            state.real_ip_header.as_deref(),
+            &state.trusted_proxies,
+        )
+        .to_string();
+        let mut info =
+            access_log::RequestInfo::new(&request, &client_ip, state.log_sampler.should_log());
+        // A streamed body is hashed as it's forwarded
+        if state.hash_bodies && request::streamed_body_len(&request).is_none() {
+            info.request_body_sha256 = Some(access_log::payload_sha256(
+                request.body(),
+                request::is_chunked(&request),
+            ));
+        }
+        // The other formats log each request in a single line, once its response is sent
+        let log_request = info.sampled && state.log_format == access_log::LogFormat::Default;
+
+        // Paths that differ only in spelling (`//a`, `/b/../a`) have to route the same, as well as
+        // reach the upstream the same
+        if state.normalize_path {
+            let path =
+                routing::normalize_path(request.uri().path(), state.decode_unreserved_percent);
+            if path != request.uri().path() {
+                log::debug!("Normalized path {:?} to {:?}", request.uri().path(), path);
+                request::set_path(&mut request, &path);
+            }
+        }
+
+        // Requests to the admin endpoints are about balancebeam itself, so they're answered here
+        // and kept out of the traffic metrics.
+        if state.enable_admin_endpoints {
+            if let Some(response) = admin::handle_admin_request(state, &request).await {
+                connection_bytes += write_response(state, &mut client_conn, &response, &info).await;
+                continue;
+            }
+        }
+
+        // A client that has given up on the request by now has no use for an answer, so the
+        // upstreams are spared the work
+        let client_deadline = match request::deadline(&request) {
+            Some(deadline) => match deadline.duration_since(std::time::SystemTime::now()) {
+                Ok(remaining) if !remaining.is_zero() => Some(time::Instant::now() + remaining),
+                _ => {
+                    log::debug!(
+                        "Request from {} is already past its deadline: {}",
+                        client_ip,
+                        request::format_request_line(&request)
+                    );
+                    let mut response = state.error_response(http::StatusCode::GATEWAY_TIMEOUT);
+                    connection_bytes +=
+                        send_response(state, &mut client_conn, &mut response, &info).await;
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        // Upstreams that are balancebeam instances pointing back at each other would pass the
+        // request around forever
+        let hops = request::hops(&request);
+        if state.max_hops.is_some_and(|max_hops| hops > max_hops) {
+            log::warn!(
+                "Request from {} has already been through {} balancebeam instances; dropping it \
+                as a forwarding loop: {}",
+                client_ip,
+                hops,
+                request::format_request_line(&request)
+            );
+            let mut response = state.error_response(http::StatusCode::LOOP_DETECTED);
+            connection_bytes += send_response(state, &mut client_conn, &mut response, &info).await;
+            continue;
+        }
+
+        let route = match state.route_for(request.uri().path()) {
+            Some(route) => route,
+            None => {
+                if log_request {
+                    log::info!(
+                        "{} -> (no route): {}",
+                        client_ip,
+                        request::format_request_line(&request)
+                    );
+                }
+                let mut response = state.no_route_response();
+                connection_bytes +=
+                    send_response(state, &mut client_conn, &mut response, &info).await;
+                continue;
+            }
+        };
+        if let Some(allowed) = state.route_methods.get(route) {
+            if !allowed.contains(request.method()) {
+                log::debug!(
+                    "Route {:?} doesn't allow {} requests: {}",
+                    route,
+                    request.method(),
+                    request::format_request_line(&request)
+                );
+                let mut response = state.method_not_allowed_response(allowed);
+                connection_bytes +=
+                    send_response(state, &mut client_conn, &mut response, &info).await;
+                continue;
+            }
+        }
+        // When reach rate limit, respond to request with HTTP error 429 (Too Many Requests)
+        // rather than forwarding the requests to the upstream servers. This is checked before an
+        // upstream is picked, so a client over its limit can't make balancebeam dial upstreams.
+        match check_rate_limit(state, &client_ip).await {
+            Ok(quota) => info.rate_limit = quota,
+            Err(quota) => {
+                info.rate_limit = Some(quota);
+                state.metrics.record_rate_limited();
+                if log_request {
+                    log::info!(
+                        "{} -> (rate limited): {}",
+                        client_ip,
+                        request::format_request_line(&request)
+                    );
+                }
+                let mut response = state.error_response(http::StatusCode::TOO_MANY_REQUESTS);
+                connection_bytes +=
+                    send_response(state, &mut client_conn, &mut response, &info).await;
+                continue;
+            }
+        }
+
+        let selection_key = state
+            .selector
+            .selection_key(&connection.request_context(&request, &client_ip));
+        if upstream
+            .as_ref()
+            .is_some_and(|conn| !conn.serves(route, &selection_key))
+        {
+            // Give up our slot on the old upstream before possibly queueing for a new one
+            upstream.take().unwrap().release(state);
+        }
+        if upstream.is_none() {
+            match UpstreamConnection::open(
+                state,
+                route,
+                &connection.request_context(&request, &client_ip),
+            )
+            .await
+            {
+                Ok(conn) => upstream = Some(conn),
+                Err(error) => {
+                    let mut response = match error {
+                        UpstreamUnavailable::NoHealthyUpstreams => {
+                            log::error!("No healthy upstreams for route {:?}", route);
+                            state.no_healthy_upstreams_response()
+                        }
+                        UpstreamUnavailable::SelectorDeclined => {
+                            state.unavailable_response("No upstream was selected for the request.")
+                        }
+                        UpstreamUnavailable::ConnectFailed(err) => {
+                            log::error!("Could not connect to any upstream: {}", err);
+                            state.error_response(http::StatusCode::BAD_GATEWAY)
+                        }
+                        UpstreamUnavailable::AllAtCapacity => {
+                            log::warn!(
+                                "Every upstream for route {:?} stayed at its connection limit for \
+                                the whole queue timeout",
+                                route
+                            );
+                            state.unavailable_response("All upstreams are at capacity.")
+                        }
+                        UpstreamUnavailable::TotalConnectionLimit => {
+                            log::warn!(
+                                "Every upstream connection balancebeam may open is in use, and \
+                                none freed up within the queue timeout"
+                            );
+                            state.unavailable_response("Too many upstream connections are in use.")
+                        }
+                        UpstreamUnavailable::AllRateLimited => {
+                            log::warn!(
+                                "Every upstream for route {:?} is at its request rate cap",
+                                route
+                            );
+                            state.unavailable_response(
+                                "All upstreams are at their request rate cap.",
+                            )
+                        }
+                    };
+                    send_response(state, &mut client_conn, &mut response, &info).await;
+                    return;
+                }
+            }
+        }
+        let upstream_conn = upstream.as_mut().unwrap();
+
+        if log_request {
+            log::info!(
+                "{} -> {}: {}",
+                client_ip,
+                upstream_conn.ip,
+                request::format_request_line(&request)
+            );
+        }
+
+        // An upstream that's known to refuse a body this big would only cut the upload off partway
+        // through, so the client is told up front
+        let max_body = state.max_body(&upstream_conn.in_flight.address);
+        if let Some(max_body) = max_body {
+            let body_len = request::content_len(&request);
+            if body_len > max_body {
+                log::info!(
+                    "Request body of {} bytes from {} is over the {}-byte max_body of upstream {}",
+                    body_len,
+                    client_ip,
+                    max_body,
+                    upstream_conn.ip
+                );
+                let mut response = state.error_response(http::StatusCode::PAYLOAD_TOO_LARGE);
+                connection_bytes +=
+                    send_response(state, &mut client_conn, &mut response, &info).await;
+                continue;
+            }
+        }
+
+        // Requests are counted against an upstream's rate cap only once nothing else will turn them
+        // away, so one refused with a 429 or 413 doesn't use up the upstream's allowance. If this
+        // connection's upstream has used its allowance up since the connection was opened, the
+        // request goes to another, as long as the body fits that one's max_body too.
+        if let Some(limits) = &state.upstream_rate_limits {
+            let conn = upstream.take().unwrap();
+            let now = std::time::Instant::now();
+            let conn = if limits.try_take(&conn.in_flight.address, now) {
+                Some(conn)
+            } else {
+                log::debug!(
+                    "Upstream {} is at its request rate cap; trying another",
+                    conn.in_flight.address
+                );
+                conn.release(state);
+                match UpstreamConnection::open(
+                    state,
+                    route,
+                    &connection.request_context(&request, &client_ip),
+                )
+                .await
+                {
+                    Ok(conn)
+                        if state
+                            .max_body(&conn.in_flight.address)
+                            .is_none_or(|max_body| request::content_len(&request) <= max_body)
+                            && limits.try_take(&conn.in_flight.address, now) =>
+                    {
+                        Some(conn)
+                    }
+                    Ok(conn) => {
+                        conn.release(state);
+                        None
+                    }
+                    Err(err) => {
+                        log::debug!("Could not open another upstream connection: {:?}", err);
+                        None
+                    }
+                }
+            };
+            match conn {
+                Some(conn) => upstream = Some(conn),
+                None => {
+                    log::warn!(
+                        "Every upstream for route {:?} is at its request rate cap",
+                        route
+                    );
+                    let mut response =
+                        state.unavailable_response("All upstreams are at their request rate cap.");
+                    connection_bytes +=
+                        send_response(state, &mut client_conn, &mut response, &info).await;
+                    continue;
+                }
+            }
+        }
+        let upstream_conn = upstream.as_mut().unwrap();
+
+        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
+        // (We're the ones connecting directly to the upstream server, so without this header, the
+        // upstream server will only know our IP, not the client's.)
+        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+        // These say what the client asked us for, so any the client sent itself are replaced
+        if state.add_forwarded_headers {
+            match request::host(&request).map(http::HeaderValue::from_str) {
+                Some(Ok(host)) => {
+                    request.headers_mut().insert("x-forwarded-host", host);
+                }
+                _ => {
+                    request.headers_mut().remove("x-forwarded-host");
+                }
+            }
+            request.headers_mut().insert(
+                "x-forwarded-proto",
+                http::HeaderValue::from_static(client_conn.scheme()),
+            );
+        }
+        for (name, value) in state.set_headers.iter() {
+            request::set_header_value(&mut request, name, value);
+        }
+        request::decline_h2c_upgrade(&mut request);
+        if state.max_hops.is_some() {
+            request::set_hops(&mut request, hops + 1);
+        }
+        // The upstream has as long as we'll wait for it, which may be less than the client would
+        if let Some(deadline) = client_deadline {
+            let mut remaining = deadline.saturating_duration_since(time::Instant::now());
+            if let Some(timeout) = state.response_read_timeout {
+                remaining = remaining.min(timeout);
+            }
+            request::set_deadline(&mut request, std::time::SystemTime::now() + remaining);
+        }
+
+        // Forward the request to the server. If the upstream quietly closed our connection while
+        // it sat idle since the previous request, an idempotent request is safe to send again, so
+        // reconnect and retry it once rather than failing the client with a 502. An upstream that
+        // fails before sending back any of a response can't have answered the request either, so
+        // it's retried against up to max_retries other upstreams. A streamed body is never sent
+        // again, since some of it may already be gone from the client connection.
+        let mut retried_stale_connection = false;
+        let mut failed_upstreams = Vec::new();
+        let mut upstream_read_ahead = Vec::new();
+        let compression = request
+            .headers()
+            .get("accept-encoding")
+            .and_then(|value| value.to_str().ok())
+            .and_then(compression::negotiate)
+            .filter(|_| state.compress_responses);
+        // Compressing the body, or swapping the response out for an injected fault, needs the
+        // whole body in hand. So does answering with a 504 if the body isn't done by the
+        // response read timeout, the upstream read timeout or the client's deadline, since by then
+        // a streamed response's 200 would be long gone. Otherwise a large body is passed on as it
+        // arrives.
+        let stream_body = compression.is_none()
+            && state.fault_injector.is_none()
+            && state.response_read_timeout.is_none()
+            && state.upstream_read_timeout.is_none()
+            && client_deadline.is_none();
+        let result = loop {
+            // Checked here rather than once up front, since a retry can land on another upstream
+            if request::is_chunked(&request)
+                && !state.accepts_chunked(&upstream_conn.in_flight.address)
+            {
+                request::dechunk(&mut request)
+                    .expect("chunks are checked when the request is read");
+            }
+            let result = forward_request(
+                state,
+                &mut upstream_conn.stream,
+                &mut client_conn,
+                &mut read_ahead,
+                &request,
+                &mut upstream_read_ahead,
+                stream_body,
+                client_deadline,
+                &mut info.request_body_sha256,
+            )
+            .await;
+            match &result {
+                Err(error)
+                    if upstream_conn.reused
+                        && !retried_stale_connection
+                        && unread_body == 0
+                        && request.method().is_idempotent()
+                        && error.is_stale_connection() =>
+                {
+                    log::info!(
+                        "Upstream {} closed idle connection ({:?}); reconnecting to retry request",
+                        upstream_conn.ip,
+                        error
+                    );
+                    match UpstreamConnection::open(
+                        state,
+                        route,
+                        &connection.request_context(&request, &client_ip),
+                    )
+                    .await
+                    {
+                        Ok(conn) => {
+                            *upstream_conn = conn;
+                            retried_stale_connection = true;
+                        }
+                        Err(err) => {
+                            log::error!("Could not reconnect to an upstream: {:?}", err);
+                            break result;
+                        }
+                    }
+                }
+                Err(error)
+                    if failed_upstreams.len() < state.max_retries
+                        && unread_body == 0
+                        && error.is_stale_connection() =>
+                {
+                    failed_upstreams.push(upstream_conn.in_flight.address.clone());
+                    match UpstreamConnection::open_excluding(
+                        state,
+                        route,
+                        &connection.request_context(&request, &client_ip),
+                        &failed_upstreams,
+                    )
+                    .await
+                    {
+                        Ok(conn) => {
+                            log::warn!(
+                                "Upstream {} failed before responding ({:?}); retrying request \
+                                against {} (retry {} of {})",
+                                upstream_conn.in_flight.address,
+                                error,
+                                conn.in_flight.address,
+                                failed_upstreams.len(),
+                                state.max_retries
+                            );
+                            *upstream_conn = conn;
+                        }
+                        Err(err) => {
+                            log::warn!("No other upstream to retry request against: {:?}", err);
+                            break result;
+                        }
+                    }
+                }
+                _ => break result,
+            }
+        };
+        // Once forwarded, the body is gone from the connection, or the connection is closed below
+        unread_body = 0;
+        let reused_connection = std::mem::replace(&mut upstream_conn.reused, true);
+        // Count the request against the upstream that actually served it. After a stale
+        // connection retry, that's not necessarily the one this client connection started out on.
+        let served_by = upstream_conn.in_flight.address.clone();
+        state
+            .metrics
+            .record_upstream_request(&served_by, reused_connection);
+        info.upstream = Some(served_by);
+
+        // A client that gives up on a request often hangs up at about the same moment the upstream
+        // does (e.g. both sides timing out an idle connection). There's nobody left to send a 502
+        // to, so this is an ordinary teardown rather than an upstream failure.
+        if let Err(error) = &result {
+            if error.is_stale_connection() && client_hung_up(client_conn.tcp()).await {
+                log::debug!(
+                    "Client {} and upstream {} both closed the connection ({:?})",
+                    client_ip,
+                    upstream_conn.ip,
+                    error
+                );
+                return;
+            }
+        }
+        let (mut response, streamed_body) = match result {
+            Ok((response, streamed_body)) => {
+                state.emit(events::ProxyEvent::RequestForwarded {
+                    upstream: upstream_conn.in_flight.address.clone(),
+                    status: response.status(),
+                });
+                record_upstream_status(state, &upstream_conn.in_flight.address, response.status())
+                    .await;
+                upstream_conn.reusable =
+                    response::leaves_connection_reusable(&response, request.method());
+                // A streamed chunked body is only measured once it's been sent
+                match streamed_body {
+                    Some(response::StreamableBody::Length(len)) => {
+                        upstream_conn.check_reuse_after_body(state, len)
+                    }
+                    Some(response::StreamableBody::Chunked) => {}
+                    None => upstream_conn.check_reuse_after_body(state, response.body().len()),
+                }
+                (response, streamed_body)
+            }
+            Err(ForwardError::Write(error)) => {
+                log::error!(
+                    "Failed to send request to upstream {}: {}",
+                    upstream_conn.ip,
+                    error
+                );
+                let mut response = state.error_response(http::StatusCode::BAD_GATEWAY);
+                send_response(state, &mut client_conn, &mut response, &info).await;
+                return;
+            }
+            Err(ForwardError::Read(error)) => {
+                log::error!("Error reading response from server: {:?}", error);
+                let mut response = state.error_response(http::StatusCode::BAD_GATEWAY);
+                send_response(state, &mut client_conn, &mut response, &info).await;
+                return;
+            }
+            // The rest of the response may still be on its way, so the upstream connection can't
+            // be used for anything else; it's closed along with the client's
+            Err(ForwardError::Timeout) => {
+                log::error!(
+                    "Upstream {} didn't take the request or finish its response in time",
+                    upstream_conn.ip
+                );
+                let mut response = state.error_response(http::StatusCode::GATEWAY_TIMEOUT);
+                send_response(state, &mut client_conn, &mut response, &info).await;
+                return;
+            }
+        };
+        // After a 101, the connection carries whatever protocol the client asked to upgrade to
+        // (WebSocket or anything else), which we just pass through
+        if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+            if !request.headers().contains_key("upgrade") {
+                log::error!(
+                    "Upstream {} switched protocols without being asked to",
+                    upstream_conn.ip
+                );
+                let mut response = state.error_response(http::StatusCode::BAD_GATEWAY);
+                send_response(state, &mut client_conn, &mut response, &info).await;
+                return;
+            }
+            send_response(state, &mut client_conn, &mut response, &info).await;
+            log::debug!(
+                "Upstream {} switched protocols to {:?}; tunneling",
+                upstream_conn.ip,
+                response.headers().get("upgrade")
+            );
+            tunnel_upgraded_connection(
+                &mut client_conn,
+                &read_ahead,
+                &mut upstream_conn.stream,
+                &upstream_read_ahead,
+            )
+            .await;
+            return;
+        }
+        for name in state.strip_response_headers.iter() {
+            response.headers_mut().remove(name);
+        }
+        if state.add_upstream_header {
+            response::extend_header_value(
+                &mut response,
+                "x-upstream-server",
+                &upstream_conn.in_flight.address,
+            );
+        }
+        if let Some(body) = streamed_body {
+            let sent = stream_response(
+                state,
+                &mut client_conn,
+                &mut response,
+                body,
+                upstream_conn,
+                &mut upstream_read_ahead,
+                &mut info,
+            )
+            .await;
+            match sent {
+                Some(sent) => connection_bytes += sent,
+                // Neither connection is at a point where another exchange could start
+                None => return,
+            }
+            if body == response::StreamableBody::Chunked {
+                upstream_conn.check_reuse_after_body(state, info.streamed_body_len.unwrap());
+            }
+            if !upstream_conn.reusable {
+                log::debug!("Upstream {} won't reuse this connection", upstream_conn.ip);
+                upstream = None;
+            }
+            log::debug!("Streamed response to client");
+            continue;
+        }
+        if let Some(encoding) = compression {
+            compression::compress_response(&mut response, encoding);
+        }
+        if let Some(injector) = &state.fault_injector {
+            let faults = injector.roll();
+            if let Some(delay) = faults.delay {
+                log::info!(
+                    "Injecting fault: delaying response to {} by {:?}",
+                    client_ip,
+                    delay
+                );
+                time::sleep(delay).await;
+            }
+            if faults.drop_connection {
+                log::info!("Injecting fault: dropping connection from {}", client_ip);
+                return;
+            }
+            if faults.error {
+                log::info!(
+                    "Injecting fault: failing request from {} with a 503",
+                    client_ip
+                );
+                response = state.error_response(http::StatusCode::SERVICE_UNAVAILABLE);
+            }
+        }
+        // If the upstream is done with this connection (it said Connection: close, or marked the
+        // end of the body by hanging up), the next request needs a fresh one. Closing it now also
+        // frees our slot on the upstream while the client decides what to do next.
+        if !upstream_conn.reusable {
+            log::debug!("Upstream {} won't reuse this connection", upstream_conn.ip);
+            upstream = None;
+        }
+
+        // Forward the response to the client
+        connection_bytes += send_response(state, &mut client_conn, &mut response, &info).await;
+        log::debug!("Forwarded response to client");
+    }
+}
+
+/// Keeps track of how many 5xx responses an upstream has sent in a row, and takes it out of rotation
+/// once that reaches the passive failure threshold. The next health check it passes puts it back.
+async fn record_upstream_status(state: &ProxyState, upstream: &str, status: StatusCode) {
+    if state.passive_failure_threshold == 0 {
+        return;
+    }
+    let failing = {
+        let mut streaks = state.upstream_failure_streaks.lock();
+        if !status.is_server_error() {
+            streaks.remove(upstream);
+            return;
+        }
+        let streak = streaks.entry(upstream.to_string()).or_default();
+        *streak += 1;
+        if *streak < state.passive_failure_threshold {
+            return;
+        }
+        streaks.remove(upstream).unwrap()
+    };
+    let mut active = state.active_upstream_addresses.write().await;
+    if active.iter().any(|address| address == upstream) {
+        log::warn!(
+            "Upstream {} failed {} requests in a row; taking it out of rotation until it passes a \
+            health check",
+            upstream,
+            failing
+        );
+        active.retain(|address| address != upstream);
+        state.emit(events::ProxyEvent::UpstreamEjected {
+            upstream: upstream.to_string(),
+        });
+    }
+}
+
+fn start_health_check(state: &ProxyState) {
+    let state_ref = state.clone();
+    tokio::spawn(async move {
+        health_check(&state_ref).await;
+    });
+    if let Some(interval) = state.ejected_recheck_interval {
+        let state_ref = state.clone();
+        tokio::spawn(async move {
+            recheck_ejected_upstreams(&state_ref, interval).await;
+        });
+    }
+}
+
+async fn health_check(state: &ProxyState) {
+    loop {
+        let interval = time::Duration::from_secs(state.active_health_check_interval as u64);
+        let next_check = time::Instant::now() + interval;
+        *state.next_health_check.lock() = next_check;
+        time::sleep_until(next_check).await;
+
+        let healthy = check_upstreams(state).await;
+        let mut active = state.active_upstream_addresses.write().await;
+        for upstream in active.iter().filter(|upstream| !healthy.contains(upstream)) {
+            state.emit(events::ProxyEvent::UpstreamEjected {
+                upstream: upstream.clone(),
+            });
+        }
+        for (upstream, failures) in state.connect_failures.lock().drain() {
+            if healthy.contains(&upstream) {
+                log::info!(
+                    "Upstream {} passed its health check after {} failed connection attempts",
+                    upstream,
+                    failures
+                );
+            }
+        }
+        *active = healthy;
+    }
+}
+
+/// Probes only the upstreams that are out of rotation, on the ejected recheck interval, and puts
+/// back the ones that pass. Taking upstreams out is left to health_check's full rounds (and to
+/// passive failure counting).
+async fn recheck_ejected_upstreams(state: &ProxyState, interval: time::Duration) {
+    loop {
+        let next_check = time::Instant::now() + interval;
+        {
+            let mut next_health_check = state.next_health_check.lock();
+            *next_health_check = (*next_health_check).min(next_check);
+        }
+        time::sleep_until(next_check).await;
+
+        let ejected: Vec<String> = {
+            let active = state.active_upstream_addresses.read().await;
+            state
+                .upstream_addresses
+                .iter()
+                .filter(|upstream| !active.contains(upstream))
+                .cloned()
+                .collect()
+        };
+        let mut recovered = Vec::new();
+        for upstream in ejected {
+            if probe_upstream(state, &upstream).await {
+                log::info!(
+                    "Upstream {} passed a recheck; putting it back into rotation",
+                    upstream
+                );
+                recovered.push(upstream);
+            }
+        }
+        if recovered.is_empty() {
+            continue;
+        }
+        // Rebuilt in the configured order, the same order a full round of checks leaves it in
+        let mut active = state.active_upstream_addresses.write().await;
+        *active = state
+            .upstream_addresses
+            .iter()
+            .filter(|upstream| active.contains(upstream) || recovered.contains(upstream))
+            .cloned()
+            .collect();
+    }
+}
+
+/// Runs one round of health checks before we start serving. If the round doesn't finish within
+/// the timeout, every upstream stays in rotation, as if we hadn't waited at all.
+async fn wait_for_healthy_upstreams(state: &ProxyState, timeout_secs: Option<u64>) {
+    log::info!("Checking upstream health before accepting connections");
+    let healthy = match timeout_secs {
+        Some(secs) => time::timeout(time::Duration::from_secs(secs), check_upstreams(state))
+            .await
+            .ok(),
+        None => Some(check_upstreams(state).await),
+    };
+    match healthy {
+        Some(healthy) => {
+            log::info!(
+                "{} of {} upstreams are healthy",
+                healthy.len(),
+                state.upstream_addresses.len()
+            );
+            *state.active_upstream_addresses.write().await = healthy;
+        }
+        None => log::warn!("Startup health checks timed out; serving with every upstream"),
+    }
+}
+
+/// Opens connections to every upstream in rotation and leaves them in the pool, so that the first
+/// clients don't have to wait for them to be dialed. An upstream that can't be reached just gets
+/// fewer.
+async fn prewarm_connections(state: &ProxyState, per_upstream: usize) {
+    let upstreams = state.active_upstream_addresses.read().await.clone();
+    for upstream in &upstreams {
+        let mut opened = 0;
+        while opened < per_upstream {
+            match dial_upstream(state, upstream).await {
+                Ok(stream) => {
+                    state.metrics.record_upstream_dial(upstream);
+                    state.connection_pool.put(upstream, stream);
+                    opened += 1;
+                }
+                Err(err) => {
+                    log::warn!("Could not prewarm a connection to {}: {}", upstream, err);
+                    break;
+                }
+            }
+        }
+        log::info!("Prewarmed {} connections to {}", opened, upstream);
+    }
+}
+
+/// Sends a health check request to each upstream, returning the ones that answered 200 OK.
+async fn check_upstreams(state: &ProxyState) -> Vec<String> {
+    let mut healthy = Vec::new();
+    for upstream in &state.upstream_addresses {
+        if probe_upstream(state, upstream).await {
+            healthy.push(upstream.clone());
+        }
+    }
+    healthy
+}
+
+/// Sends one health check request to an upstream, returning whether it answered 200 OK. Checks go
+/// over the connection kept from the previous round where there is one, so that every round
+/// doesn't dial every upstream afresh. If the upstream has closed that connection since, a new
+/// one is dialed in its place.
+async fn probe_upstream(state: &ProxyState, upstream: &str) -> bool {
+    let request = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(&state.active_health_check_path)
+        .header("Host", upstream)
+        .body(Vec::<u8>::new())
+        .unwrap();
+
+    let mut kept = state.health_probe_connections.lock().remove(upstream);
+    loop {
+        let reused = kept.is_some();
+        let mut stream = match kept.take() {
+            Some(stream) => stream,
+            None => match dial_upstream(state, upstream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::error!("failed to connect to stream {}, {}", upstream, err);
+                    return false;
+                }
+            },
+        };
+        if let Err(err) = request::write_to_stream(&request, &mut stream).await {
+            log::error!("failed to write to stream {}, {}", upstream, err);
+        }
+        match response::read_from_stream(
+            &mut stream,
+            &mut Vec::new(),
+            request.method(),
+            state.max_response_header_bytes,
+        )
+        .await
+        {
+            Ok(resp) => {
+                if response::leaves_connection_reusable(&resp, request.method()) {
+                    state
+                        .health_probe_connections
+                        .lock()
+                        .insert(upstream.to_string(), stream);
+                }
+                return resp.status() == http::StatusCode::OK;
+            }
+            Err(err) if reused => {
+                log::debug!(
+                    "Health check connection to {} went away ({:?}); redialing",
+                    upstream,
+                    err
+                );
+            }
+            Err(_) => {
+                log::error!("failed to receive OK status from stream {}", upstream);
+                return false;
+            }
+        }
+    }
+}
+
+/// Counts a request from the client against the rate limits, returning the client's quota after
+/// it (None if there are no limits), or Err with the quota of a client that's over a limit and has
+/// to be turned away with a 429. With both limits on, the quota is the tighter of the two, and a
+/// request turned away by either one is counted against neither.
+async fn check_rate_limit(
+    state: &ProxyState,
+    client: &str,
+) -> Result<Option<rate_limit::Quota>, rate_limit::Quota> {
+    let now = std::time::Instant::now();
+    // The token is only taken once the sliding window has let the request through too
+    if let Some(token_buckets) = &state.token_buckets {
+        if !token_buckets.has_token(client, now) {
+            return Err(rate_limited_by_bucket(state, token_buckets, client, now));
+        }
+    }
+    let mut quota: Option<rate_limit::Quota> = None;
+    if let Some(request_windows) = &state.request_windows {
+        let allowed = request_windows.try_record(client, now);
+        let window_quota = request_windows.quota(client, now);
+        if !allowed {
+            log::error!(
+                "Client {} went over {} requests per minute",
+                client,
+                state.max_requests_per_minute
+            );
+            state.emit(events::ProxyEvent::RateLimited {
+                client: client.to_string(),
+            });
+            return Err(window_quota);
+        }
+        quota = Some(window_quota);
+    }
+    if let Some(token_buckets) = &state.token_buckets {
+        // Another request from the client may have taken the last token since has_token
+        if !token_buckets.try_take(client, now) {
+            return Err(rate_limited_by_bucket(state, token_buckets, client, now));
+        }
+        let bucket_quota = token_buckets.quota(client, now);
+        quota = Some(match quota {
+            Some(quota) => quota.tighter(bucket_quota),
+            None => bucket_quota,
+        });
+    }
+
+    Ok(quota)
+}
+
+/// Reports a client that ran out of rate limit tokens, returning its quota for the 429.
+fn rate_limited_by_bucket(
+    state: &ProxyState,
+    token_buckets: &rate_limit::TokenBuckets,
+    client: &str,
+    now: std::time::Instant,
+) -> rate_limit::Quota {
+    log::warn!("Client {} ran out of rate limit tokens", client);
+    state.emit(events::ProxyEvent::RateLimited {
+        client: client.to_string(),
+    });
+    token_buckets.quota(client, now)
 }
@@ -0,0 +1,429 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the request duration histogram buckets, in seconds (the Prometheus client
+/// libraries' defaults)
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Upstream label for requests that balancebeam answered without forwarding them
+const NO_UPSTREAM: &str = "none";
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations that fell in each bucket, not cumulative. Observations larger than every
+    /// bucket are only reflected in `count`.
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|&bound| value <= bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Number of connections of one kind open right now, along with the most there have been open at
+/// once since startup
+#[derive(Default)]
+struct ConnectionGauge {
+    active: AtomicU64,
+    peak: AtomicU64,
+}
+
+impl ConnectionGauge {
+    fn opened(&self) {
+        let active = self.active.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak.fetch_max(active, Ordering::Relaxed);
+    }
+
+    fn closed(&self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters describing the traffic balancebeam has proxied since startup (or since the last reset).
+///
+/// Scalar counters are plain atomics so that the hot path in handle_connection never blocks on
+/// them. Labeled counters are kept in maps behind a lock, which is only held for the duration of a
+/// single increment.
+#[derive(Default)]
+pub struct Metrics {
+    /// Requests answered in all, whether by an upstream or by balancebeam itself
+    requests_served_total: AtomicU64,
+    /// Requests answered, by the upstream that served them (or NO_UPSTREAM) and status code
+    requests: parking_lot::Mutex<BTreeMap<(String, u16), u64>>,
+    /// Time from reading each request to sending its response
+    request_durations: parking_lot::Mutex<Histogram>,
+    /// Requests that were rejected by the rate limiter
+    rate_limited_total: AtomicU64,
+    /// Requests forwarded to each upstream address
+    upstream_requests: parking_lot::Mutex<BTreeMap<String, u64>>,
+    /// Responses sent to clients, by status code
+    responses: parking_lot::Mutex<BTreeMap<u16, u64>>,
+    /// New TCP connections opened to each upstream address for client traffic
+    upstream_dials: parking_lot::Mutex<BTreeMap<String, u64>>,
+    /// Idle pooled connections to each upstream address handed out instead of dialing
+    upstream_reuses: parking_lot::Mutex<BTreeMap<String, u64>>,
+    /// Requests forwarded to each upstream address as the first request on a freshly dialed
+    /// connection
+    new_connection_requests: parking_lot::Mutex<BTreeMap<String, u64>>,
+    /// Requests forwarded to each upstream address over a connection that had already carried one,
+    /// whether for the same client or (through the pool) an earlier one
+    reused_connection_requests: parking_lot::Mutex<BTreeMap<String, u64>>,
+    /// Client connections being served. Like the upstream gauges, these aren't affected by a
+    /// reset, so the peak is the highest since startup.
+    client_connections: ConnectionGauge,
+    /// Upstream connections carrying a client's traffic, across every upstream
+    upstream_connections: ConnectionGauge,
+}
+
+/// The state of the upstreams at the moment the metrics are rendered. Unlike the counters, these
+/// aren't tracked by Metrics itself, and aren't affected by a reset.
+#[derive(Default)]
+pub struct UpstreamGauges {
+    /// Whether each configured upstream is currently considered healthy
+    pub up: BTreeMap<String, bool>,
+    /// Connections sitting in the pool, by upstream address
+    pub idle: HashMap<String, usize>,
+    /// Connections currently carrying a client's traffic, by upstream address
+    pub in_use: HashMap<String, usize>,
+}
+
+/// Escapes a label value for the text exposition format, which only needs backslashes, quotes and
+/// newlines escaped.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn increment(counters: &parking_lot::Mutex<BTreeMap<String, u64>>, upstream: &str) {
+    *counters.lock().entry(upstream.to_string()).or_default() += 1;
+}
+
+impl Metrics {
+    pub fn record_rate_limited(&self) {
+        self.rate_limited_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a request forwarded to an upstream, and whether the connection it went over had
+    /// carried a request before.
+    pub fn record_upstream_request(&self, upstream: &str, reused_connection: bool) {
+        increment(&self.upstream_requests, upstream);
+        if reused_connection {
+            increment(&self.reused_connection_requests, upstream);
+        } else {
+            increment(&self.new_connection_requests, upstream);
+        }
+    }
+
+    pub fn client_connection_opened(&self) {
+        self.client_connections.opened();
+    }
+
+    pub fn client_connection_closed(&self) {
+        self.client_connections.closed();
+    }
+
+    pub fn upstream_connection_opened(&self) {
+        self.upstream_connections.opened();
+    }
+
+    pub fn upstream_connection_closed(&self) {
+        self.upstream_connections.closed();
+    }
+
+    pub fn record_upstream_dial(&self, upstream: &str) {
+        increment(&self.upstream_dials, upstream);
+    }
+
+    pub fn record_upstream_reuse(&self, upstream: &str) {
+        increment(&self.upstream_reuses, upstream);
+    }
+
+    /// Counts a response sent to a client. `upstream` is the upstream that served the request, or
+    /// None if balancebeam answered it without forwarding it, and `elapsed` is how long the request
+    /// took to answer.
+    pub fn record_response(
+        &self,
+        upstream: Option<&str>,
+        status: http::StatusCode,
+        elapsed: Duration,
+    ) {
+        self.requests_served_total.fetch_add(1, Ordering::Relaxed);
+        let upstream = upstream.unwrap_or(NO_UPSTREAM).to_string();
+        *self
+            .requests
+            .lock()
+            .entry((upstream, status.as_u16()))
+            .or_default() += 1;
+        *self.responses.lock().entry(status.as_u16()).or_default() += 1;
+        self.request_durations.lock().observe(elapsed.as_secs_f64());
+    }
+
+    /// Zeroes every counter. Each counter is reset atomically, but the counters are not reset as a
+    /// group, so an increment racing with the reset may be kept by one counter and dropped by
+    /// another. That undercount is acceptable for metrics.
+    pub fn reset(&self) {
+        self.requests_served_total.store(0, Ordering::Relaxed);
+        self.requests.lock().clear();
+        *self.request_durations.lock() = Histogram::default();
+        self.rate_limited_total.store(0, Ordering::Relaxed);
+        self.upstream_requests.lock().clear();
+        self.responses.lock().clear();
+        self.upstream_dials.lock().clear();
+        self.upstream_reuses.lock().clear();
+        self.new_connection_requests.lock().clear();
+        self.reused_connection_requests.lock().clear();
+    }
+
+    /// Renders the counters, along with the given upstream gauges, in the Prometheus text
+    /// exposition format.
+    pub fn render(&self, gauges: &UpstreamGauges) -> String {
+        let mut out = String::new();
+        writeln!(out, "# TYPE balancebeam_requests_served_total counter").unwrap();
+        writeln!(
+            out,
+            "balancebeam_requests_served_total {}",
+            self.requests_served_total.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        writeln!(out, "# TYPE balancebeam_requests_total counter").unwrap();
+        for ((upstream, status), count) in self.requests.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_requests_total{{upstream=\"{}\",status=\"{}\"}} {}",
+                escape_label_value(upstream),
+                status,
+                count
+            )
+            .unwrap();
+        }
+        writeln!(out, "# TYPE balancebeam_rate_limited_total counter").unwrap();
+        writeln!(
+            out,
+            "balancebeam_rate_limited_total {}",
+            self.rate_limited_total.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        writeln!(out, "# TYPE balancebeam_upstream_requests_total counter").unwrap();
+        for (upstream, count) in self.upstream_requests.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_upstream_requests_total{{upstream=\"{}\"}} {}",
+                escape_label_value(upstream),
+                count
+            )
+            .unwrap();
+        }
+        writeln!(out, "# TYPE balancebeam_responses_total counter").unwrap();
+        for (status, count) in self.responses.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_responses_total{{status=\"{}\"}} {}",
+                status, count
+            )
+            .unwrap();
+        }
+
+        let durations = self.request_durations.lock();
+        writeln!(out, "# TYPE balancebeam_request_duration_seconds histogram").unwrap();
+        let mut cumulative = 0;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(durations.buckets.iter()) {
+            cumulative += count;
+            writeln!(
+                out,
+                "balancebeam_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "balancebeam_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            durations.count
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "balancebeam_request_duration_seconds_sum {}",
+            durations.sum
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "balancebeam_request_duration_seconds_count {}",
+            durations.count
+        )
+        .unwrap();
+        drop(durations);
+
+        writeln!(out, "# TYPE balancebeam_active_upstreams gauge").unwrap();
+        writeln!(
+            out,
+            "balancebeam_active_upstreams {}",
+            gauges.up.values().filter(|up| **up).count()
+        )
+        .unwrap();
+        writeln!(out, "# TYPE balancebeam_upstream_up gauge").unwrap();
+        for (upstream, up) in &gauges.up {
+            writeln!(
+                out,
+                "balancebeam_upstream_up{{upstream=\"{}\"}} {}",
+                escape_label_value(upstream),
+                *up as u8
+            )
+            .unwrap();
+        }
+        for (name, values) in [
+            ("balancebeam_upstream_connections_idle", &gauges.idle),
+            ("balancebeam_upstream_connections_in_use", &gauges.in_use),
+        ] {
+            writeln!(out, "# TYPE {} gauge", name).unwrap();
+            let values: BTreeMap<_, _> = values.iter().collect();
+            for (upstream, value) in values {
+                writeln!(
+                    out,
+                    "{}{{upstream=\"{}\"}} {}",
+                    name,
+                    escape_label_value(upstream),
+                    value
+                )
+                .unwrap();
+            }
+        }
+        for (name, gauge) in [
+            ("balancebeam_client_connections", &self.client_connections),
+            (
+                "balancebeam_upstream_connections",
+                &self.upstream_connections,
+            ),
+        ] {
+            for (suffix, value) in [("active", &gauge.active), ("peak", &gauge.peak)] {
+                writeln!(out, "# TYPE {}_{} gauge", name, suffix).unwrap();
+                writeln!(out, "{}_{} {}", name, suffix, value.load(Ordering::Relaxed)).unwrap();
+            }
+        }
+        let dials = self.upstream_dials.lock().clone();
+        let reuses = self.upstream_reuses.lock().clone();
+        let new_connection_requests = self.new_connection_requests.lock().clone();
+        let reused_connection_requests = self.reused_connection_requests.lock().clone();
+        for (name, counts) in [
+            ("balancebeam_upstream_dials_total", &dials),
+            ("balancebeam_upstream_connection_reuses_total", &reuses),
+            (
+                "balancebeam_upstream_new_connection_requests_total",
+                &new_connection_requests,
+            ),
+            (
+                "balancebeam_upstream_reused_connection_requests_total",
+                &reused_connection_requests,
+            ),
+        ] {
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            for (upstream, count) in counts {
+                writeln!(
+                    out,
+                    "{}{{upstream=\"{}\"}} {}",
+                    name,
+                    escape_label_value(upstream),
+                    count
+                )
+                .unwrap();
+            }
+        }
+        // Fraction of connection acquisitions that were served from the pool
+        writeln!(
+            out,
+            "# TYPE balancebeam_upstream_connection_reuse_ratio gauge"
+        )
+        .unwrap();
+        let upstreams: BTreeSet<&String> = dials.keys().chain(reuses.keys()).collect();
+        for upstream in upstreams {
+            let dialed = dials.get(upstream).copied().unwrap_or(0);
+            let reused = reuses.get(upstream).copied().unwrap_or(0);
+            writeln!(
+                out,
+                "balancebeam_upstream_connection_reuse_ratio{{upstream=\"{}\"}} {}",
+                escape_label_value(upstream),
+                reused as f64 / (dialed + reused) as f64
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label_values_escaped() {
+        let metrics = Metrics::default();
+        metrics.record_upstream_request("we\"ird\\host\n:80", false);
+        let out = metrics.render(&UpstreamGauges::default());
+        assert!(
+            out.contains(r#"balancebeam_upstream_requests_total{upstream="we\"ird\\host\n:80"} 1"#),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn test_duration_histogram_is_cumulative() {
+        let metrics = Metrics::default();
+        for millis in [1, 20, 20, 700, 60_000] {
+            metrics.record_response(
+                Some("10.0.0.1:80"),
+                http::StatusCode::OK,
+                Duration::from_millis(millis),
+            );
+        }
+        let out = metrics.render(&UpstreamGauges::default());
+        for line in [
+            r#"balancebeam_request_duration_seconds_bucket{le="0.005"} 1"#,
+            r#"balancebeam_request_duration_seconds_bucket{le="0.025"} 3"#,
+            r#"balancebeam_request_duration_seconds_bucket{le="0.5"} 3"#,
+            r#"balancebeam_request_duration_seconds_bucket{le="1"} 4"#,
+            r#"balancebeam_request_duration_seconds_bucket{le="10"} 4"#,
+            r#"balancebeam_request_duration_seconds_bucket{le="+Inf"} 5"#,
+            "balancebeam_request_duration_seconds_count 5",
+            r#"balancebeam_requests_total{upstream="10.0.0.1:80",status="200"} 5"#,
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {}:\n{}", line, out);
+        }
+    }
+
+    #[test]
+    fn test_connection_gauges_track_peak() {
+        let metrics = Metrics::default();
+        for _ in 0..3 {
+            metrics.client_connection_opened();
+        }
+        metrics.upstream_connection_opened();
+        metrics.client_connection_closed();
+        metrics.client_connection_closed();
+        metrics.client_connection_opened();
+        metrics.reset();
+        let out = metrics.render(&UpstreamGauges::default());
+        for line in [
+            "balancebeam_client_connections_active 2",
+            "balancebeam_client_connections_peak 3",
+            "balancebeam_upstream_connections_active 1",
+            "balancebeam_upstream_connections_peak 1",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {}:\n{}", line, out);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use crate::tls::UpstreamStream;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Waker};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Idle upstream connections left open after the client they were opened for went away, so that
+/// later clients can skip the TCP handshake to the upstream.
+pub struct ConnectionPool {
+    /// Most idle connections kept per upstream address (0 disables pooling)
+    max_idle_per_upstream: usize,
+    idle: parking_lot::Mutex<HashMap<String, Vec<UpstreamStream>>>,
+}
+
+/// Returns true if an idle connection can carry another request: the upstream hasn't closed it,
+/// and hasn't sent anything since its last response (which we would mistake for the response to
+/// the next request). This doesn't wait; a close that hasn't reached us yet goes unnoticed, which
+/// the stale connection retry in handle_connection is there for.
+///
+/// The read goes through TLS, if the connection has it, so that TLS records that aren't data (such
+/// as the session tickets a TLS 1.3 upstream sends after the handshake) are taken care of rather
+/// than mistaken for a response.
+fn is_usable(stream: &mut UpstreamStream) -> bool {
+    let mut buf = [0_u8; 1];
+    let mut buf = ReadBuf::new(&mut buf);
+    let mut cx = Context::from_waker(Waker::noop());
+    Pin::new(stream).poll_read(&mut cx, &mut buf).is_pending()
+}
+
+impl ConnectionPool {
+    pub fn new(max_idle_per_upstream: usize) -> ConnectionPool {
+        ConnectionPool {
+            max_idle_per_upstream,
+            idle: parking_lot::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes an idle connection to the given upstream out of the pool, if there is one. The most
+    /// recently returned connection is handed out first, since it's the least likely to have been
+    /// timed out by the upstream. Connections that are no longer usable are closed and skipped.
+    pub fn take(&self, address: &str) -> Option<UpstreamStream> {
+        let mut idle = self.idle.lock();
+        let connections = idle.get_mut(address)?;
+        while let Some(mut stream) = connections.pop() {
+            if is_usable(&mut stream) {
+                return Some(stream);
+            }
+            log::debug!(
+                "Discarding pooled connection to {} that is no longer usable",
+                address
+            );
+        }
+        None
+    }
+
+    /// Returns a connection to the pool. If the upstream already has as many idle connections as
+    /// we're willing to keep, the connection is dropped (and closed) instead.
+    pub fn put(&self, address: &str, stream: UpstreamStream) {
+        let mut idle = self.idle.lock();
+        let connections = idle.entry(address.to_string()).or_default();
+        if connections.len() < self.max_idle_per_upstream {
+            connections.push(stream);
+        }
+    }
+
+    /// Number of idle connections to each upstream that has had any
+    pub fn idle_counts(&self) -> HashMap<String, usize> {
+        self.idle
+            .lock()
+            .iter()
+            .map(|(address, connections)| (address.clone(), connections.len()))
+            .collect()
+    }
+}
@@ -0,0 +1,56 @@
+use nix::unistd::{self, Gid, Group, Uid, User};
+
+/// Switches the whole process to the given user and group, for when we were started as root only
+/// to bind to a privileged port. Each may be a name or a numeric ID; without a group, the user's
+/// primary group is used. Supplementary groups are dropped as well, and the switch is checked to be
+/// irreversible, so an error means we may still be privileged and shouldn't go on.
+pub fn drop_to(user: &str, group: Option<&str>) -> Result<(Uid, Gid), String> {
+    let (uid, primary_gid) = resolve_user(user)?;
+    let gid = match group {
+        Some(group) => resolve_group(group)?,
+        None => primary_gid.ok_or_else(|| {
+            format!(
+                "user {:?} has no passwd entry to take a group from; give --run-as-group",
+                user
+            )
+        })?,
+    };
+
+    // The group has to go first: once we've given up root, we're no longer allowed to change it
+    unistd::setgroups(&[gid])
+        .map_err(|err| format!("could not drop supplementary groups: {}", err))?;
+    unistd::setgid(gid).map_err(|err| format!("could not switch to group {}: {}", gid, err))?;
+    unistd::setuid(uid).map_err(|err| format!("could not switch to user {}: {}", uid, err))?;
+    if !uid.is_root() && unistd::setuid(Uid::from_raw(0)).is_ok() {
+        return Err("still able to regain root after switching users".to_string());
+    }
+    Ok((uid, gid))
+}
+
+/// Looks a user up by name or uid, returning its uid along with its primary group if it has a
+/// passwd entry.
+fn resolve_user(user: &str) -> Result<(Uid, Option<Gid>), String> {
+    if let Ok(uid) = user.parse::<u32>() {
+        let uid = Uid::from_raw(uid);
+        let entry = User::from_uid(uid)
+            .map_err(|err| format!("could not look up user {}: {}", uid, err))?;
+        return Ok((uid, entry.map(|entry| entry.gid)));
+    }
+    match User::from_name(user) {
+        Ok(Some(entry)) => Ok((entry.uid, Some(entry.gid))),
+        Ok(None) => Err(format!("no such user {:?}", user)),
+        Err(err) => Err(format!("could not look up user {:?}: {}", user, err)),
+    }
+}
+
+/// Looks a group up by name or gid.
+fn resolve_group(group: &str) -> Result<Gid, String> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
+    match Group::from_name(group) {
+        Ok(Some(entry)) => Ok(entry.gid),
+        Ok(None) => Err(format!("no such group {:?}", group)),
+        Err(err) => Err(format!("could not look up group {:?}: {}", group, err)),
+    }
+}
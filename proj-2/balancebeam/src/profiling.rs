@@ -0,0 +1,24 @@
+use pprof::protos::Message;
+use std::time::Duration;
+
+/// How many times a second the profiler samples every thread's call stack
+const SAMPLE_FREQUENCY: i32 = 99;
+/// Length of a profile when the request doesn't say
+pub const DEFAULT_PROFILE_SECONDS: u64 = 30;
+/// Longest profile that can be asked for, so that a stray request can't leave the profiler running
+/// for hours
+pub const MAX_PROFILE_SECONDS: u64 = 300;
+
+/// Samples the CPU usage of the whole process for the given duration and returns the profile in
+/// the protobuf format pprof reads. Only one profile can be captured at a time; asking for another
+/// while one is running fails with pprof::Error::Running.
+pub async fn capture_cpu_profile(duration: Duration) -> Result<Vec<u8>, pprof::Error> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(SAMPLE_FREQUENCY)
+        // Unwinding through these while a signal handler interrupts them can crash the process
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()?;
+    tokio::time::sleep(duration).await;
+    let profile = guard.report().build()?.pprof()?;
+    Ok(profile.encode_to_vec())
+}
@@ -0,0 +1,407 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Once this many clients have buckets, buckets that have refilled completely are dropped. A full
+/// bucket behaves exactly like a brand new one, so forgetting it changes nothing for the client.
+/// Sliding windows are pruned the same way once they've emptied out.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Where a client stands against a rate limit, to tell it in the X-RateLimit-* response headers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    /// Requests in a full allowance
+    pub limit: usize,
+    /// Requests the client can still make right away
+    pub remaining: usize,
+    /// How long until the client has its full allowance back
+    pub reset: Duration,
+}
+
+impl Quota {
+    /// Returns whichever of two quotas leaves the client fewer requests, which is the one it will
+    /// run into first when more than one limit applies.
+    pub fn tighter(self, other: Quota) -> Quota {
+        if other.remaining < self.remaining {
+            other
+        } else {
+            self
+        }
+    }
+
+    /// Sets the X-RateLimit-Limit, X-RateLimit-Remaining and X-RateLimit-Reset headers, the last
+    /// in whole seconds, rounded up so that a client waiting that long is sure to be let through.
+    pub fn add_headers(&self, headers: &mut http::HeaderMap) {
+        let reset = self.reset.as_secs() + u64::from(self.reset.subsec_nanos() > 0);
+        headers.insert("x-ratelimit-limit", http::HeaderValue::from(self.limit));
+        headers.insert(
+            "x-ratelimit-remaining",
+            http::HeaderValue::from(self.remaining),
+        );
+        headers.insert("x-ratelimit-reset", http::HeaderValue::from(reset));
+    }
+}
+
+/// Holds up to `burst` tokens and refills continuously at `rate` tokens per second. Every request
+/// spends a token, so a client that has been quiet can send a burst of requests at once, but over
+/// the long run can't go faster than the refill rate.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last_refill = now;
+    }
+}
+
+/// A token bucket per client
+pub struct TokenBuckets {
+    /// Tokens added to each bucket per second
+    rate: f64,
+    /// Capacity of each bucket
+    burst: f64,
+    buckets: parking_lot::Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl TokenBuckets {
+    pub fn new(rate: f64, burst: f64) -> TokenBuckets {
+        TokenBuckets {
+            rate,
+            burst,
+            buckets: parking_lot::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Spends one of the client's tokens, returning false (and spending nothing) if the client's
+    /// bucket is empty.
+    pub fn try_take(&self, client: &str, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| {
+                bucket.refill(self.rate, self.burst, now);
+                bucket.tokens < self.burst
+            });
+        }
+        let bucket = buckets
+            .entry(client.to_string())
+            .or_insert_with(|| TokenBucket {
+                tokens: self.burst,
+                last_refill: now,
+            });
+        bucket.refill(self.rate, self.burst, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns true if try_take would succeed for the client right now, without spending anything.
+    pub fn has_token(&self, client: &str, now: Instant) -> bool {
+        match self.buckets.lock().get_mut(client) {
+            Some(bucket) => {
+                bucket.refill(self.rate, self.burst, now);
+                bucket.tokens >= 1.0
+            }
+            None => self.burst >= 1.0,
+        }
+    }
+
+    /// Returns the client's quota right now, without spending anything. The allowance is the
+    /// bucket's capacity, and it's back once the bucket has refilled.
+    pub fn quota(&self, client: &str, now: Instant) -> Quota {
+        let tokens = match self.buckets.lock().get_mut(client) {
+            Some(bucket) => {
+                bucket.refill(self.rate, self.burst, now);
+                bucket.tokens
+            }
+            None => self.burst,
+        };
+        Quota {
+            limit: self.burst as usize,
+            remaining: tokens as usize,
+            reset: Duration::from_secs_f64((self.burst - tokens) / self.rate),
+        }
+    }
+}
+
+/// Allows each client at most `limit` requests in any `window`-long stretch of time, rather than
+/// per fixed interval, so a client can't get twice its allowance through by sending one batch just
+/// before an interval ends and another just after the next one starts.
+pub struct SlidingWindows {
+    limit: usize,
+    window: Duration,
+    /// When each client's requests within the window were allowed, oldest first
+    requests: parking_lot::Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl SlidingWindows {
+    pub fn new(limit: usize, window: Duration) -> SlidingWindows {
+        SlidingWindows {
+            limit,
+            window,
+            requests: parking_lot::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request from the client, returning false (and counting nothing) if the client has
+    /// already had `limit` requests allowed within the window ending now.
+    ///
+    /// Being exact about the window means remembering when each of those requests was made, so a
+    /// busy client costs up to `limit` timestamps rather than the single counter a fixed interval
+    /// needs. Timestamps are dropped as they leave the window, and each check only ever drops the
+    /// ones that have expired since the last, so the time spent evicting stays proportional to the
+    /// requests allowed.
+    pub fn try_record(&self, client: &str, now: Instant) -> bool {
+        let mut requests = self.requests.lock();
+        if requests.len() >= PRUNE_THRESHOLD {
+            requests.retain(|_, times| {
+                evict_expired(times, self.window, now);
+                !times.is_empty()
+            });
+        }
+        let times = requests.entry(client.to_string()).or_default();
+        evict_expired(times, self.window, now);
+        if times.len() >= self.limit {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+
+    /// Returns the client's quota right now, without counting a request. The allowance is back
+    /// once the client's latest request has left the window.
+    pub fn quota(&self, client: &str, now: Instant) -> Quota {
+        let mut requests = self.requests.lock();
+        let (used, reset) = match requests.get_mut(client) {
+            Some(times) => {
+                evict_expired(times, self.window, now);
+                let reset = times.back().map_or(Duration::ZERO, |latest| {
+                    self.window - now.saturating_duration_since(*latest)
+                });
+                (times.len(), reset)
+            }
+            None => (0, Duration::ZERO),
+        };
+        Quota {
+            limit: self.limit,
+            remaining: self.limit.saturating_sub(used),
+            reset,
+        }
+    }
+}
+
+/// Drops the timestamps that are at least a window old.
+fn evict_expired(times: &mut VecDeque<Instant>, window: Duration, now: Instant) {
+    while times
+        .front()
+        .is_some_and(|time| now.saturating_duration_since(*time) >= window)
+    {
+        times.pop_front();
+    }
+}
+
+/// A single token bucket, for pacing something that isn't done on behalf of a particular client.
+/// Unlike TokenBuckets, callers don't get turned away when the bucket is empty; they're told how
+/// long to wait for their turn instead.
+pub struct Throttle {
+    rate: f64,
+    burst: f64,
+    bucket: TokenBucket,
+}
+
+impl Throttle {
+    pub fn new(rate: f64, burst: f64, now: Instant) -> Throttle {
+        Throttle {
+            rate,
+            burst,
+            bucket: TokenBucket {
+                tokens: burst,
+                last_refill: now,
+            },
+        }
+    }
+
+    /// Spends a token and returns how long the caller must wait before going ahead. The bucket may
+    /// go into debt, so that callers queued up behind an empty bucket are spaced out evenly rather
+    /// than all going ahead as soon as the first token comes back.
+    pub fn reserve(&mut self, now: Instant) -> Duration {
+        self.bucket.refill(self.rate, self.burst, now);
+        self.bucket.tokens -= 1.0;
+        if self.bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.bucket.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_allowed_up_to_capacity() {
+        let buckets = TokenBuckets::new(1.0, 5.0);
+        let now = Instant::now();
+        for _ in 0..5 {
+            assert!(buckets.try_take("10.0.0.1", now));
+        }
+        assert!(!buckets.try_take("10.0.0.1", now));
+        // Each client has a bucket of its own
+        assert!(buckets.try_take("10.0.0.2", now));
+    }
+
+    #[test]
+    fn test_steady_state_throttled_to_rate() {
+        let buckets = TokenBuckets::new(10.0, 5.0);
+        let start = Instant::now();
+        // Try a request every 10ms (100 per second) for 10 seconds
+        let allowed = (0..1000)
+            .filter(|i| buckets.try_take("10.0.0.1", start + Duration::from_millis(i * 10)))
+            .count();
+        // The initial burst, plus 10 per second after that
+        assert!(
+            (100..=106).contains(&allowed),
+            "{} requests allowed",
+            allowed
+        );
+    }
+
+    #[test]
+    fn test_has_token_spends_nothing() {
+        let buckets = TokenBuckets::new(1.0, 2.0);
+        let now = Instant::now();
+        assert!(buckets.has_token("10.0.0.1", now));
+        assert!(buckets.try_take("10.0.0.1", now));
+        assert!(buckets.has_token("10.0.0.1", now));
+        assert!(buckets.has_token("10.0.0.1", now));
+        assert!(buckets.try_take("10.0.0.1", now));
+        assert!(!buckets.has_token("10.0.0.1", now));
+        assert!(buckets.has_token("10.0.0.1", now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_bucket_refills_no_higher_than_capacity() {
+        let buckets = TokenBuckets::new(100.0, 3.0);
+        let now = Instant::now();
+        assert!(buckets.try_take("10.0.0.1", now));
+        let later = now + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(buckets.try_take("10.0.0.1", later));
+        }
+        assert!(!buckets.try_take("10.0.0.1", later));
+    }
+
+    #[test]
+    fn test_sliding_window_limit() {
+        let windows = SlidingWindows::new(3, Duration::from_secs(60));
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(windows.try_record("10.0.0.1", now));
+        }
+        assert!(!windows.try_record("10.0.0.1", now));
+        // Each client has a window of its own
+        assert!(windows.try_record("10.0.0.2", now));
+        // Once the first requests leave the window, there's room again
+        assert!(!windows.try_record("10.0.0.1", now + Duration::from_secs(59)));
+        assert!(windows.try_record("10.0.0.1", now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_quota() {
+        let buckets = TokenBuckets::new(2.0, 4.0);
+        let now = Instant::now();
+        let full = Quota {
+            limit: 4,
+            remaining: 4,
+            reset: Duration::ZERO,
+        };
+        assert_eq!(buckets.quota("10.0.0.1", now), full);
+        assert!(buckets.try_take("10.0.0.1", now));
+        assert!(buckets.try_take("10.0.0.1", now));
+        let quota = buckets.quota("10.0.0.1", now);
+        assert_eq!((quota.remaining, quota.reset), (2, Duration::from_secs(1)));
+
+        let windows = SlidingWindows::new(3, Duration::from_secs(60));
+        assert!(windows.try_record("10.0.0.1", now));
+        assert!(windows.try_record("10.0.0.1", now + Duration::from_secs(20)));
+        let quota = windows.quota("10.0.0.1", now + Duration::from_secs(30));
+        assert_eq!((quota.remaining, quota.reset), (1, Duration::from_secs(50)));
+        assert_eq!(quota.tighter(full), quota);
+        assert_eq!(
+            windows
+                .quota("10.0.0.1", now + Duration::from_secs(80))
+                .remaining,
+            3
+        );
+    }
+
+    #[test]
+    fn test_sliding_window_straddling_boundary() {
+        let windows = SlidingWindows::new(10, Duration::from_secs(60));
+        let start = Instant::now();
+        // A full allowance just before where a fixed one-minute interval would reset...
+        let before = start + Duration::from_secs(59);
+        assert_eq!(
+            (0..10)
+                .filter(|_| windows.try_record("10.0.0.1", before))
+                .count(),
+            10
+        );
+        // ...leaves nothing just after it
+        let after = start + Duration::from_secs(61);
+        assert_eq!(
+            (0..10)
+                .filter(|_| windows.try_record("10.0.0.1", after))
+                .count(),
+            0
+        );
+        // Turned-away requests don't count against the client, so a full minute after the first
+        // batch, the whole allowance is back
+        let later = before + Duration::from_secs(60);
+        assert_eq!(
+            (0..10)
+                .filter(|_| windows.try_record("10.0.0.1", later))
+                .count(),
+            10
+        );
+    }
+
+    #[test]
+    fn test_sliding_window_never_stops_recovering() {
+        let windows = SlidingWindows::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        // A client blocked in every minute gets its allowance back in the next one, for as long as
+        // it keeps going, rather than only the first time around
+        for minute in 0..5 {
+            let now = start + Duration::from_secs(60 * minute);
+            assert!(windows.try_record("10.0.0.1", now), "minute {}", minute);
+            assert!(windows.try_record("10.0.0.1", now), "minute {}", minute);
+            assert!(!windows.try_record("10.0.0.1", now), "minute {}", minute);
+        }
+    }
+
+    #[test]
+    fn test_throttle_spaces_out_callers() {
+        let now = Instant::now();
+        let mut throttle = Throttle::new(4.0, 1.0, now);
+        let waits: Vec<Duration> = (0..4).map(|_| throttle.reserve(now)).collect();
+        assert_eq!(
+            waits,
+            [0, 250, 500, 750].map(Duration::from_millis).to_vec()
+        );
+        // Once the queue has been waited out, the next caller goes ahead right away
+        assert_eq!(
+            throttle.reserve(now + Duration::from_secs(1)),
+            Duration::ZERO
+        );
+    }
+}
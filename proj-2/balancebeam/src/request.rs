@@ -1,28 +1,158 @@
+use crate::access_log::BodyHasher;
+use crate::chunked;
 use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
+/// Largest request body we accept unless told otherwise
+pub const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
 #[derive(Debug)]
+#[allow(dead_code, clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request. IncompleteRequest contains the number of
     /// bytes that were successfully read before the client hung up
     IncompleteRequest(usize),
     /// Client sent an invalid HTTP request. httparse::Error contains more details
     MalformedRequest(httparse::Error),
+    /// Client continued a header value onto another line (obs-fold), and ObsFold::Reject is in
+    /// effect
+    ObsFoldedHeader,
+    /// The request target is in absolute form (`GET http://host/ HTTP/1.1`) or authority form
+    /// (`CONNECT host:443 HTTP/1.1`), which are only meant for forward proxies, and
+    /// ReadOptions::allow_absolute_uri is off
+    AbsoluteFormTarget,
+    /// The request has more than one Host header. Which one counts is up to whoever reads it, so
+    /// passing it on would let the client show us one host and the upstream another.
+    DuplicateHost,
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The request body is bigger than ReadOptions::max_body_bytes
     RequestBodyTooLarge,
+    /// The body is chunked, but its chunks are malformed or the client hung up before the last one
+    InvalidChunkedBody,
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
 }
 
+/// A short explanation of the error, fit to show the client that caused it
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IncompleteRequest(bytes_read) => write!(
+                f,
+                "request ended after {} bytes, before its headers were complete",
+                bytes_read
+            ),
+            Error::MalformedRequest(error) => write!(f, "malformed request ({})", error),
+            Error::ObsFoldedHeader => write!(f, "header values may not be folded across lines"),
+            Error::AbsoluteFormTarget => write!(f, "request target must be a path"),
+            Error::DuplicateHost => write!(f, "request has more than one Host header"),
+            Error::InvalidContentLength => write!(f, "Content-Length is not a valid number"),
+            Error::ContentLengthMismatch => {
+                write!(f, "body length does not match Content-Length")
+            }
+            Error::RequestBodyTooLarge => write!(f, "request body is too large"),
+            Error::InvalidChunkedBody => write!(f, "chunked body is malformed"),
+            Error::ConnectionError(error) => write!(f, "connection error ({})", error),
+        }
+    }
+}
+
+impl Error {
+    /// Returns true if the connection can go on to the client's next request after this error.
+    /// Otherwise we can't tell where the failed request ends, and whatever follows it on the
+    /// stream (such as a body we refused to read) would be taken for a request of its own.
+    pub fn allows_next_request(&self) -> bool {
+        matches!(self, Error::AbsoluteFormTarget | Error::DuplicateHost)
+    }
+}
+
+/// What to do with a request whose headers use obsolete line folding (obs-fold), i.e. continue a
+/// header value on a line starting with a space or tab. Proxies and servers disagree about where a
+/// folded header ends, which makes folding a request smuggling risk, so RFC 7230 lets us either
+/// reject it or replace each fold with spaces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ObsFold {
+    /// Respond with 400 Bad Request
+    Reject,
+    /// Join the continuation lines onto the header value with spaces
+    Unfold,
+}
+
+/// How strictly requests from clients are parsed
+#[derive(Clone, Copy, Debug)]
+pub struct ReadOptions {
+    pub obs_fold: ObsFold,
+    /// Whether to accept a request target that names a host (absolute or authority form) rather
+    /// than only a path. Accepting them invites clients to use us as an open forward proxy.
+    pub allow_absolute_uri: bool,
+    /// Largest request body we read, in bytes. A bigger one is refused before any of it is read
+    /// (or, for a chunked body, as soon as its chunks add up to more), so the connection can't be
+    /// used after it.
+    pub max_body_bytes: usize,
+    /// A body with a Content-Length over this many bytes isn't read into the request, but left on
+    /// the stream for the caller to pass on as it arrives (see streamed_body_len).
+    pub stream_body_over: Option<usize>,
+}
+
+/// Stored in the extensions of a request whose body was left on the stream, holding its length
+#[derive(Clone, Copy, Debug)]
+struct StreamedBody(usize);
+
+/// If the request's body was left on the stream rather than read (see
+/// ReadOptions::stream_body_over), returns its length. The body is whatever of read_ahead is left
+/// after the request, followed by as much of the stream as it takes.
+pub fn streamed_body_len(request: &http::Request<Vec<u8>>) -> Option<usize> {
+    request
+        .extensions()
+        .get::<StreamedBody>()
+        .map(|body| body.0)
+}
+
+/// Returns true if the request target is anything other than a path (origin form) or `*`
+/// (asterisk form), i.e. it names the host the request is meant for.
+fn has_absolute_target(request: &http::Request<Vec<u8>>) -> bool {
+    request.uri().scheme().is_some() || request.uri().authority().is_some()
+}
+
+/// Returns the length of the request line and headers, including the blank line that ends them,
+/// if all of them are in the buffer.
+fn find_head_end(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+}
+
+/// Returns true if a line in the head starts with whitespace, i.e. continues the previous line.
+fn has_obs_fold(head: &[u8]) -> bool {
+    head.windows(3)
+        .any(|window| window[..2] == *b"\r\n" && (window[2] == b' ' || window[2] == b'\t'))
+}
+
+/// Replaces every obs-fold (a line break followed by spaces or tabs) in the head with one space.
+fn unfold(head: &[u8]) -> Vec<u8> {
+    let mut unfolded = Vec::with_capacity(head.len());
+    let mut i = 0;
+    while i < head.len() {
+        if head[i..].starts_with(b"\r\n") && matches!(head.get(i + 2), Some(b' ') | Some(b'\t')) {
+            i += 2;
+            while matches!(head.get(i), Some(b' ') | Some(b'\t')) {
+                i += 1;
+            }
+            unfolded.push(b' ');
+        } else {
+            unfolded.push(head[i]);
+            i += 1;
+        }
+    }
+    unfolded
+}
+
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -66,6 +196,158 @@ pub fn extend_header_value(
         .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
 }
 
+/// Returns the host the client asked for: the one named in the request target, for absolute-form
+/// targets, which take precedence over Host, or else the Host header. HTTP/1.0 clients may send
+/// neither.
+pub fn host(request: &http::Request<Vec<u8>>) -> Option<&str> {
+    match request.uri().authority() {
+        Some(authority) => Some(authority.as_str()),
+        None => request.headers().get("host")?.to_str().ok(),
+    }
+}
+
+/// Removes a token from a comma-separated header such as Connection or Upgrade (compared
+/// case-insensitively), removing the header altogether if no other tokens are left.
+fn remove_header_token(request: &mut http::Request<Vec<u8>>, name: &'static str, token: &str) {
+    let remaining: Vec<String> = request
+        .headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|value| !value.is_empty() && !value.eq_ignore_ascii_case(token))
+        .map(str::to_string)
+        .collect();
+    request.headers_mut().remove(name);
+    if !remaining.is_empty() {
+        request.headers_mut().insert(
+            name,
+            http::HeaderValue::from_str(&remaining.join(", ")).unwrap(),
+        );
+    }
+}
+
+/// Returns true if the request body is sent as a series of chunks (Transfer-Encoding: chunked)
+/// rather than with a Content-Length.
+pub fn is_chunked(request: &http::Request<Vec<u8>>) -> bool {
+    chunked::is_chunked(request.headers())
+}
+
+/// Returns the length of the request body, not counting the chunk framing of a chunked one.
+pub fn content_len(request: &http::Request<Vec<u8>>) -> usize {
+    if let Some(len) = streamed_body_len(request) {
+        len
+    } else if is_chunked(request) {
+        chunked::decode(request.body()).map_or(request.body().len(), |body| body.len())
+    } else {
+        request.body().len()
+    }
+}
+
+/// Turns a chunked request into one with a fixed Content-Length, for upstreams that can't read
+/// chunked bodies. The chunks are joined and any trailers are dropped, since a body framed by
+/// Content-Length has nowhere to put them.
+pub fn dechunk(request: &mut http::Request<Vec<u8>>) -> Result<(), Error> {
+    let body = chunked::decode(request.body()).or(Err(Error::InvalidChunkedBody))?;
+    remove_header_token(request, "transfer-encoding", "chunked");
+    request
+        .headers_mut()
+        .insert("content-length", http::HeaderValue::from(body.len()));
+    *request.body_mut() = body;
+    Ok(())
+}
+
+/// Replaces every value of a header with the given one, or removes the header if the value is
+/// empty.
+pub fn set_header_value(
+    request: &mut http::Request<Vec<u8>>,
+    name: &http::HeaderName,
+    value: &http::HeaderValue,
+) {
+    if value.is_empty() {
+        request.headers_mut().remove(name);
+    } else {
+        request.headers_mut().insert(name, value.clone());
+    }
+}
+
+/// Declines an HTTP/2 cleartext upgrade offered with `Upgrade: h2c`. We only speak HTTP/1.1, and a
+/// server is free to ignore an Upgrade header, so the h2c offer (and the HTTP2-Settings header
+/// that goes with it) is stripped and the request is forwarded as a plain HTTP/1.1 request. If the
+/// upstream saw the offer, it might accept it and start speaking HTTP/2 at us.
+pub fn decline_h2c_upgrade(request: &mut http::Request<Vec<u8>>) {
+    request.headers_mut().remove("http2-settings");
+    remove_header_token(request, "connection", "http2-settings");
+    remove_header_token(request, "upgrade", "h2c");
+    if !request.headers().contains_key("upgrade") {
+        remove_header_token(request, "connection", "upgrade");
+    }
+}
+
+/// Replaces the path of the request target, keeping the query (and the scheme and authority, for
+/// absolute-form targets) as they were.
+pub fn set_path(request: &mut http::Request<Vec<u8>>, path: &str) {
+    let mut parts = request.uri().clone().into_parts();
+    let path_and_query = match request.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    // The new path is made of pieces of a path that already parsed, so it parses too
+    parts.path_and_query = Some(path_and_query.parse().unwrap());
+    *request.uri_mut() = http::Uri::from_parts(parts).unwrap();
+}
+
+/// Header in which a client says when it will stop waiting for a response, in milliseconds since
+/// the Unix epoch
+const DEADLINE_HEADER: &str = "x-request-deadline";
+
+/// Returns the deadline the client set with X-Request-Deadline, if any. A value that isn't a
+/// number of milliseconds is ignored, as if there were no deadline.
+pub fn deadline(request: &http::Request<Vec<u8>>) -> Option<std::time::SystemTime> {
+    let millis: u64 = request
+        .headers()
+        .get(DEADLINE_HEADER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    std::time::UNIX_EPOCH.checked_add(std::time::Duration::from_millis(millis))
+}
+
+/// Replaces the request's X-Request-Deadline with the given deadline.
+pub fn set_deadline(request: &mut http::Request<Vec<u8>>, deadline: std::time::SystemTime) {
+    let millis = deadline
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    request
+        .headers_mut()
+        .insert(DEADLINE_HEADER, http::HeaderValue::from(millis as u64));
+}
+
+/// Header counting the balancebeam instances a request has already been forwarded through
+const HOPS_HEADER: &str = "x-balancebeam-hops";
+
+/// Returns how many balancebeam instances have forwarded the request so far. A request without
+/// X-Balancebeam-Hops, or with a value that isn't a number, hasn't been through any.
+pub fn hops(request: &http::Request<Vec<u8>>) -> usize {
+    request
+        .headers()
+        .get(HOPS_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Replaces the request's X-Balancebeam-Hops with the given count.
+pub fn set_hops(request: &mut http::Request<Vec<u8>>, hops: usize) {
+    request
+        .headers_mut()
+        .insert(HOPS_HEADER, http::HeaderValue::from(hops));
+}
+
 /// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the
 /// following:
 ///
@@ -74,18 +356,23 @@ pub fn extend_header_value(
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
 ///
 /// You won't need to touch this function.
+#[allow(clippy::type_complexity)]
 fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(Error::MalformedRequest)?;
 
     if let httparse::Status::Complete(len) = res {
+        // An HTTP/1.0 client's connection is closed after each response unless it asks otherwise, so
+        // its version has to be kept
+        let version = match req.version {
+            Some(0) => http::Version::HTTP_10,
+            _ => http::Version::HTTP_11,
+        };
         let mut request = http::Request::builder()
             .method(req.method.unwrap())
             .uri(req.path.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(version);
         for header in req.headers {
             request = request.header(header.name, header.value);
         }
@@ -96,42 +383,80 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
     }
 }
 
+/// Parses the request line and headers at the start of the buffer, unfolding or rejecting folded
+/// headers according to obs_fold. Returns the request (with an empty body) and the number of bytes
+/// of the buffer its head took up, or None if the buffer doesn't hold a complete head yet.
+#[allow(clippy::type_complexity)]
+fn parse_head(
+    buffer: &[u8],
+    obs_fold: ObsFold,
+) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
+    // Look for folded headers before httparse sees them. Only the head is searched, since the body
+    // may contain anything.
+    let head_end = find_head_end(buffer);
+    if has_obs_fold(&buffer[..head_end.unwrap_or(buffer.len())]) {
+        return match (obs_fold, head_end) {
+            (ObsFold::Reject, _) => Err(Error::ObsFoldedHeader),
+            // We can only unfold once the whole head is here
+            (ObsFold::Unfold, None) => Ok(None),
+            (ObsFold::Unfold, Some(head_end)) => Ok(parse_request(&unfold(&buffer[..head_end]))?
+                .map(|(request, _)| (request, head_end))),
+        };
+    }
+    parse_request(buffer)
+}
+
+/// Returns true if the read-ahead buffer already holds the head of another request, so that it
+/// can be read without waiting on the client.
+pub fn has_buffered_request(read_ahead: &[u8]) -> bool {
+    find_head_end(read_ahead).is_some()
+}
+
 /// Reads an HTTP request from the provided stream, waiting until a complete set of headers is sent.
 /// This function only reads the request line and headers; the read_body function can subsequently
 /// be called in order to read the request body (for a POST request).
 ///
-/// Returns Ok(http::Request) if a valid request is received, or Error if not.
+/// Bytes already read from the stream are taken from read_ahead first. Anything read past the end of
+/// the headers (the start of the body, or further pipelined requests) is left in read_ahead.
+///
+/// Returns Ok(http::Request) if a valid request is received, or Error if not. Folded headers are
+/// rejected or unfolded according to obs_fold.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    read_ahead: &mut Vec<u8>,
+    obs_fold: ObsFold,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut request_buffer = std::mem::take(read_ahead);
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
+        // See if we've read a valid request so far
+        if let Some((request, headers_len)) = parse_head(&request_buffer, obs_fold)? {
+            // We've read a complete set of headers. However, we might have read part of a body or
+            // the next request out of the stream as well. Keep those bytes for later so that we
+            // don't lose them
+            *read_ahead = request_buffer.split_off(headers_len);
+            return Ok(request);
+        }
+
+        // Read bytes from the connection into the buffer, after the bytes we already have
+        let bytes_read = request_buffer.len();
+        if bytes_read >= MAX_HEADERS_SIZE {
+            return Err(Error::IncompleteRequest(bytes_read));
+        }
+        request_buffer.resize(MAX_HEADERS_SIZE, 0);
         let new_bytes = stream
             .read(&mut request_buffer[bytes_read..])
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
+        request_buffer.truncate(bytes_read + new_bytes);
         if new_bytes == 0 {
             // We didn't manage to read a complete request
             return Err(Error::IncompleteRequest(bytes_read));
         }
-        bytes_read += new_bytes;
-
-        // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
-            // We've read a complete set of headers. However, if this was a POST request, a request
-            // body might have been included as well, and we might have read part of the body out of
-            // the stream into header_buffer. We need to add those bytes to the Request body so that
-            // we don't lose them
-            request
-                .body_mut()
-                .extend_from_slice(&request_buffer[headers_len..bytes_read]);
-            return Ok(request);
-        }
     }
 }
 
@@ -140,8 +465,8 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
 /// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
 ) -> Result<(), Error> {
@@ -149,11 +474,11 @@ async fn read_body(
     while request.body().len() < content_length {
         // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
         // space to read that body.)
-        let mut buffer = vec![0_u8; min(512, content_length)];
+        let mut buffer = vec![0_u8; min(512, content_length - request.body().len())];
         let bytes_read = stream
             .read(&mut buffer)
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
 
         // Make sure the client is still sending us bytes
         if bytes_read == 0 {
@@ -181,48 +506,185 @@ async fn read_body(
 }
 
 /// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
+/// closes the connection prematurely or sends an invalid request. read_ahead carries bytes read
+/// from the stream but not yet used from one call to the next, since a client may pipeline several
+/// requests and we may read more than one of them at a time.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    read_ahead: &mut Vec<u8>,
+    options: ReadOptions,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Read headers
-    let mut request = read_headers(stream).await?;
+    let mut request = read_headers(stream, read_ahead, options.obs_fold).await?;
+    if is_chunked(&request) {
+        // The chunks frame the body, and a Content-Length sent alongside them must be ignored
+        // (RFC 7230 section 3.3.3). Passing both on would let an upstream choose the other one.
+        request.headers_mut().remove("content-length");
+        *request.body_mut() = chunked::read_body(stream, read_ahead, options.max_body_bytes)
+            .await
+            .map_err(|err| match err {
+                chunked::Error::Malformed => Error::InvalidChunkedBody,
+                chunked::Error::TooLarge => Error::RequestBodyTooLarge,
+                chunked::Error::Io(err) => Error::ConnectionError(err),
+            })?;
+    }
     // Read body if the client supplied the Content-Length header (which it does for POST requests)
     if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
+        if content_length > options.max_body_bytes {
             return Err(Error::RequestBodyTooLarge);
+        } else if options
+            .stream_body_over
+            .is_some_and(|threshold| content_length > threshold)
+        {
+            request
+                .extensions_mut()
+                .insert(StreamedBody(content_length));
         } else {
+            let buffered = min(content_length, read_ahead.len());
+            request.body_mut().extend(read_ahead.drain(..buffered));
             read_body(stream, &mut request, content_length).await?;
         }
     }
+    // Checked only once the body is read, so that rejecting the request doesn't leave its body in
+    // the stream to be mistaken for the next request
+    let rejection = if !options.allow_absolute_uri && has_absolute_target(&request) {
+        Some(Error::AbsoluteFormTarget)
+    } else if request.headers().get_all("host").iter().count() > 1 {
+        Some(Error::DuplicateHost)
+    } else {
+        None
+    };
+    if let Some(error) = rejection {
+        if let Some(len) = streamed_body_len(&request) {
+            discard_body(stream, read_ahead, len).await?;
+        }
+        return Err(error);
+    }
     Ok(request)
 }
 
+/// Returns true if the client means to close the connection once this request is answered: it sent
+/// Connection: close, or it's an HTTP/1.0 client that didn't ask for keep-alive (RFC 7230 section
+/// 6.3).
+pub fn wants_close(request: &http::Request<Vec<u8>>) -> bool {
+    let has_option = |option: &str| {
+        request
+            .headers()
+            .get_all("connection")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case(option))
+    };
+    has_option("close")
+        || (request.version() == http::Version::HTTP_10 && !has_option("keep-alive"))
+}
+
+/// Returns true if the client is holding the request body back until it's told to send it (RFC
+/// 7231 section 5.1.1). An HTTP/1.0 client can't understand a 100 Continue, so it never is.
+pub fn expects_continue(request: &http::Request<Vec<u8>>) -> bool {
+    request.version() != http::Version::HTTP_10
+        && request
+            .headers()
+            .get("expect")
+            .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+}
+
+/// Reads past a body of len bytes left on the stream (see streamed_body_len) without keeping any of
+/// it, for a request that was answered without being forwarded.
+pub async fn discard_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    read_ahead: &mut Vec<u8>,
+    len: usize,
+) -> Result<(), Error> {
+    let buffered = min(len, read_ahead.len());
+    read_ahead.drain(..buffered);
+    let remaining = (len - buffered) as u64;
+    let discarded = tokio::io::copy(&mut (&mut *stream).take(remaining), &mut tokio::io::sink())
+        .await
+        .map_err(Error::ConnectionError)?;
+    if discarded < remaining {
+        return Err(Error::ContentLengthMismatch);
+    }
+    Ok(())
+}
+
+/// Passes a body of len bytes left on the client stream (see streamed_body_len) on to the upstream
+/// as it arrives, starting with whatever of it is already in read_ahead. Everything sent also goes
+/// through the hasher, if there is one.
+pub async fn copy_body<C: AsyncRead + Unpin, U: AsyncWrite + Unpin>(
+    client: &mut C,
+    read_ahead: &mut Vec<u8>,
+    upstream: &mut U,
+    len: usize,
+    mut hasher: Option<&mut BodyHasher>,
+) -> Result<(), std::io::Error> {
+    let buffered = min(len, read_ahead.len());
+    upstream.write_all(&read_ahead[..buffered]).await?;
+    if let Some(hasher) = hasher.as_deref_mut() {
+        hasher.update(&read_ahead[..buffered]);
+    }
+    read_ahead.drain(..buffered);
+    let mut copied = buffered;
+    let mut buffer = [0_u8; 8192];
+    while copied < len {
+        let wanted = buffer.len().min(len - copied);
+        let bytes_read = client.read(&mut buffer[..wanted]).await?;
+        if bytes_read == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "client hung up {} bytes into a {}-byte request body",
+                    copied, len
+                ),
+            ));
+        }
+        upstream.write_all(&buffer[..bytes_read]).await?;
+        if let Some(hasher) = hasher.as_deref_mut() {
+            hasher.update(&buffer[..bytes_read]);
+        }
+        copied += bytes_read;
+    }
+    upstream.flush().await
+}
+
 /// This function serializes a request to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
     stream
-        .write(&format_request_line(request).into_bytes())
+        .write_all(&format_request_line(request).into_bytes())
         .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(b"\r\n").await?;
     for (header_name, header_value) in request.headers() {
         stream
-            .write(&format!("{}: ", header_name).as_bytes())
+            .write_all(format!("{}: ", header_name).as_bytes())
             .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+    stream.write_all(b"\r\n").await?;
+    if !request.body().is_empty() {
+        stream.write_all(request.body()).await?;
     }
     Ok(())
 }
 
+/// Number of bytes write_to_stream sends for the request.
+pub fn encoded_len(request: &http::Request<Vec<u8>>) -> usize {
+    let head: usize = request
+        .headers()
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum();
+    format_request_line(request).len() + 2 + head + 2 + request.body().len()
+}
+
 pub fn format_request_line(request: &http::Request<Vec<u8>>) -> String {
     format!(
         "{} {} {:?}",
@@ -1,22 +1,29 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use crate::access_log::BodyHasher;
+use crate::chunked;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-const MAX_HEADERS_SIZE: usize = 8000;
+pub const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
 #[derive(Debug)]
+#[allow(dead_code, clippy::enum_variant_names)]
 pub enum Error {
-    /// Client hung up before sending a complete request
-    IncompleteResponse,
+    /// Server hung up before sending a complete response. IncompleteResponse contains the number of
+    /// bytes that were successfully read before the server hung up
+    IncompleteResponse(usize),
     /// Client sent an invalid HTTP request. httparse::Error contains more details
     MalformedResponse(httparse::Error),
+    /// The response line and headers didn't fit in the configured maximum header size
+    ResponseHeadersTooLarge,
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     ResponseBodyTooLarge,
+    /// The body is chunked, but its chunks are malformed or the server hung up before the last one
+    InvalidChunkedBody,
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
 }
@@ -52,12 +59,11 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 ///   Err(Error)
 ///
 /// You won't need to touch this function.
+#[allow(clippy::type_complexity)]
 fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut resp = httparse::Response::new(&mut headers);
-    let res = resp
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+    let res = resp.parse(buffer).map_err(Error::MalformedResponse)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
@@ -74,39 +80,53 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 }
 
 /// Reads an HTTP response from the provided stream, waiting until a complete set of headers is
-/// sent. This function only reads the response line and headers; the read_body function can
-/// subsequently be called in order to read the response body.
+/// sent. This function only reads the response line and headers; read_body_from_stream or
+/// stream_body can subsequently be called in order to read the response body.
 ///
-/// Returns Ok(http::Response) if a valid response is received, or Error if not.
+/// Returns Ok(http::Response) if a valid response is received, or Error if not. At most
+/// max_headers_size bytes are buffered, so a server sending endless headers can't make us grow
+/// without bound.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+pub async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    read_ahead: &mut Vec<u8>,
+    max_headers_size: usize,
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
-    let mut response_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut response_buffer = std::mem::take(read_ahead);
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
+        // See if we've read a valid response so far
+        if let Some((mut response, headers_len)) = parse_response(&response_buffer)? {
+            // We've read a complete set of headers. We may have also read the first part of the
+            // response body, or the next response if this one was informational; leave whatever
+            // is left over in the buffer for the caller.
+            *read_ahead = response_buffer.split_off(headers_len);
+            // The chunks frame the body, and a Content-Length sent alongside them must be ignored
+            // (RFC 7230 section 3.3.3). Passing both on would let the client choose the other one.
+            if is_chunked(&response) {
+                response.headers_mut().remove("content-length");
+            }
+            return Ok(response);
+        }
+
+        let bytes_read = response_buffer.len();
+        if bytes_read >= max_headers_size {
+            // The buffer is full and we still haven't seen the end of the headers
+            return Err(Error::ResponseHeadersTooLarge);
+        }
+        // Read bytes from the connection into the buffer, after the bytes we already have
+        response_buffer.resize(max_headers_size, 0);
         let new_bytes = stream
             .read(&mut response_buffer[bytes_read..])
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
+        response_buffer.truncate(bytes_read + new_bytes);
         if new_bytes == 0 {
             // We didn't manage to read a complete response
-            return Err(Error::IncompleteResponse);
-        }
-        bytes_read += new_bytes;
-
-        // See if we've read a valid response so far
-        if let Some((mut response, headers_len)) = parse_response(&response_buffer[..bytes_read])? {
-            // We've read a complete set of headers. We may have also read the first part of the
-            // response body; take whatever is left over in the response buffer and save that as
-            // the start of the response body.
-            response
-                .body_mut()
-                .extend_from_slice(&response_buffer[headers_len..bytes_read]);
-            return Ok(response);
+            return Err(Error::IncompleteResponse(bytes_read));
         }
     }
 }
@@ -115,8 +135,8 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
 /// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
     response: &mut http::Response<Vec<u8>>,
 ) -> Result<(), Error> {
     // The response may or may not supply a Content-Length header. If it provides the header, then
@@ -129,7 +149,7 @@ async fn read_body(
         let bytes_read = stream
             .read(&mut buffer)
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if bytes_read == 0 {
             // The server has hung up!
             if content_length.is_none() {
@@ -159,52 +179,339 @@ async fn read_body(
     Ok(())
 }
 
+/// Returns true if the response body is sent as a series of chunks (Transfer-Encoding: chunked),
+/// the way servers stream a body whose length they don't know up front. Only chunked bodies can be
+/// followed by trailers.
+pub fn is_chunked(response: &http::Response<Vec<u8>>) -> bool {
+    chunked::is_chunked(response.headers())
+}
+
+/// Reads a chunked response body, starting with whatever of it is in read_ahead. The body is kept
+/// in its chunked form, trailers and all, so that it can be passed on to the client exactly as the
+/// server sent it. Anything read past the end of the body is left in read_ahead.
+async fn read_chunked_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    response: &mut http::Response<Vec<u8>>,
+    read_ahead: &mut Vec<u8>,
+) -> Result<(), Error> {
+    *response.body_mut() = chunked::read_body(stream, read_ahead, MAX_BODY_SIZE)
+        .await
+        .map_err(|err| match err {
+            chunked::Error::Malformed => Error::InvalidChunkedBody,
+            chunked::Error::TooLarge => Error::ResponseBodyTooLarge,
+            chunked::Error::Io(err) => Error::ConnectionError(err),
+        })?;
+    Ok(())
+}
+
 /// This function reads and returns an HTTP response from a stream, returning an Error if the server
-/// closes the connection prematurely or sends an invalid response.
+/// closes the connection prematurely or sends an invalid response. read_ahead holds bytes already
+/// read from the stream that come after the previous response, and is left holding any bytes read
+/// past the end of this one (e.g. the response that follows a 1xx).
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(
-    stream: &mut TcpStream,
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    read_ahead: &mut Vec<u8>,
     request_method: &http::Method,
+    max_headers_size: usize,
 ) -> Result<http::Response<Vec<u8>>, Error> {
-    let mut response = read_headers(stream).await?;
-    // A response may have a body as long as it is not responding to a HEAD request and as long as
-    // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
-        || response.status().as_u16() < 200
-        || response.status() == http::StatusCode::NO_CONTENT
-        || response.status() == http::StatusCode::NOT_MODIFIED)
-    {
-        read_body(stream, &mut response).await?;
-    }
+    let mut response = read_headers(stream, read_ahead, max_headers_size).await?;
+    read_body_from_stream(stream, read_ahead, request_method, &mut response).await?;
     Ok(response)
 }
 
+/// Reads the body of a response whose head came from read_headers into the response.
+pub async fn read_body_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    read_ahead: &mut Vec<u8>,
+    request_method: &http::Method,
+    response: &mut http::Response<Vec<u8>>,
+) -> Result<(), Error> {
+    if has_body(request_method, response.status()) && is_chunked(response) {
+        read_chunked_body(stream, response, read_ahead).await?;
+    } else if has_body(request_method, response.status()) {
+        // Whatever of the body came in along with the headers
+        let buffered = match get_content_length(response)? {
+            Some(content_length) => content_length.min(read_ahead.len()),
+            None => read_ahead.len(),
+        };
+        response.body_mut().extend(read_ahead.drain(..buffered));
+        read_body(stream, response).await?;
+    }
+    Ok(())
+}
+
+/// How a body that's passed on as it arrives is delimited
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreamableBody {
+    /// By a Content-Length of this many bytes; see stream_body
+    Length(usize),
+    /// By its last chunk and trailers; see stream_chunked_body
+    Chunked,
+}
+
+/// Returns how a response's body can be passed on as it arrives, if it can: the body is delimited
+/// by a valid Content-Length or by chunks, and hasn't all arrived yet. A streamed body is never
+/// held whole, so the body size limit doesn't apply to it. Anything else is better read in full
+/// with read_body_from_stream.
+pub fn streamable_body(
+    response: &http::Response<Vec<u8>>,
+    request_method: &http::Method,
+    read_ahead: &[u8],
+) -> Option<StreamableBody> {
+    if !has_body(request_method, response.status()) {
+        return None;
+    }
+    if is_chunked(response) {
+        // Malformed chunks are left for read_body_from_stream to report
+        return match chunked::scan(read_ahead, 0) {
+            Ok(chunked::ChunkScan::Partial(_)) => Some(StreamableBody::Chunked),
+            _ => None,
+        };
+    }
+    match get_content_length(response) {
+        Ok(Some(content_length)) if content_length > read_ahead.len() => {
+            Some(StreamableBody::Length(content_length))
+        }
+        _ => None,
+    }
+}
+
+/// Reasons stream_body can fail to pass a whole body on
+#[derive(Debug)]
+pub enum StreamError {
+    /// The rest of the body couldn't be read from the server
+    Read(Error),
+    /// The client connection stopped taking the body
+    Write(std::io::Error),
+}
+
+/// Copies a response body of content_length bytes from the server to the client as it arrives,
+/// starting with whatever of it is in read_ahead, so that only a small buffer's worth of it is
+/// ever held at once. Nothing past the end of the body is read. Everything sent also goes through
+/// the hasher, if there is one.
+pub async fn stream_body<S: AsyncRead + Unpin, C: AsyncWrite + Unpin>(
+    server: &mut S,
+    read_ahead: &mut Vec<u8>,
+    content_length: usize,
+    client: &mut C,
+    mut hasher: Option<&mut BodyHasher>,
+) -> Result<(), StreamError> {
+    let buffered = content_length.min(read_ahead.len());
+    client
+        .write_all(&read_ahead[..buffered])
+        .await
+        .map_err(StreamError::Write)?;
+    if let Some(hasher) = hasher.as_deref_mut() {
+        hasher.update(&read_ahead[..buffered]);
+    }
+    read_ahead.drain(..buffered);
+    let mut sent = buffered;
+    let mut buffer = [0_u8; 8192];
+    while sent < content_length {
+        let wanted = buffer.len().min(content_length - sent);
+        let bytes_read = server
+            .read(&mut buffer[..wanted])
+            .await
+            .map_err(|error| StreamError::Read(Error::ConnectionError(error)))?;
+        if bytes_read == 0 {
+            return Err(StreamError::Read(Error::ContentLengthMismatch));
+        }
+        client
+            .write_all(&buffer[..bytes_read])
+            .await
+            .map_err(StreamError::Write)?;
+        if let Some(hasher) = hasher.as_deref_mut() {
+            hasher.update(&buffer[..bytes_read]);
+        }
+        sent += bytes_read;
+    }
+    Ok(())
+}
+
+/// Copies a chunked response body from the server to the client as it arrives, starting with
+/// whatever of it is in read_ahead, and checking its framing along the way. The chunks are passed
+/// on exactly as the server sent them, extensions and trailers included, and a chunk of any size
+/// goes through a small buffer's worth at a time. Anything read past the end of the body is left
+/// in read_ahead. Only the chunk data goes through the hasher, if there is one, not the framing.
+/// Returns the number of bytes sent.
+pub async fn stream_chunked_body<S: AsyncRead + Unpin, C: AsyncWrite + Unpin>(
+    server: &mut S,
+    read_ahead: &mut Vec<u8>,
+    client: &mut C,
+    mut hasher: Option<&mut BodyHasher>,
+) -> Result<usize, StreamError> {
+    let malformed = || StreamError::Read(Error::InvalidChunkedBody);
+    let mut sent = 0;
+    loop {
+        let size_line = read_chunk_line(server, read_ahead).await?;
+        let size =
+            chunked::parse_size_line(&size_line[..size_line.len() - 2]).map_err(|_| malformed())?;
+        client
+            .write_all(&size_line)
+            .await
+            .map_err(StreamError::Write)?;
+        sent += size_line.len();
+        if size == 0 {
+            // The last chunk is followed by any number of trailer fields, then an empty line
+            loop {
+                let line = read_chunk_line(server, read_ahead).await?;
+                client.write_all(&line).await.map_err(StreamError::Write)?;
+                sent += line.len();
+                if line == b"\r\n" {
+                    return Ok(sent);
+                }
+            }
+        }
+        match stream_body(server, read_ahead, size, client, hasher.as_deref_mut()).await {
+            Err(StreamError::Read(Error::ContentLengthMismatch)) => return Err(malformed()),
+            result => result?,
+        }
+        sent += size;
+        if read_chunk_line(server, read_ahead).await? != b"\r\n" {
+            return Err(malformed());
+        }
+        client
+            .write_all(b"\r\n")
+            .await
+            .map_err(StreamError::Write)?;
+        sent += 2;
+    }
+}
+
+/// Reads one line of a chunked body's framing, CRLF included, from read_ahead and then the server.
+/// A line longer than MAX_HEADERS_SIZE is taken as malformed rather than buffered indefinitely.
+async fn read_chunk_line<S: AsyncRead + Unpin>(
+    server: &mut S,
+    read_ahead: &mut Vec<u8>,
+) -> Result<Vec<u8>, StreamError> {
+    let mut searched = 0;
+    loop {
+        if let Some(offset) = read_ahead[searched..]
+            .windows(2)
+            .position(|window| window == b"\r\n")
+        {
+            return Ok(read_ahead.drain(..searched + offset + 2).collect());
+        }
+        if read_ahead.len() > MAX_HEADERS_SIZE {
+            return Err(StreamError::Read(Error::InvalidChunkedBody));
+        }
+        // The CR of a CRLF may already be here, waiting on its LF
+        searched = read_ahead.len().saturating_sub(1);
+        let mut buffer = [0_u8; 512];
+        let bytes_read = server
+            .read(&mut buffer)
+            .await
+            .map_err(|error| StreamError::Read(Error::ConnectionError(error)))?;
+        if bytes_read == 0 {
+            return Err(StreamError::Read(Error::InvalidChunkedBody));
+        }
+        read_ahead.extend_from_slice(&buffer[..bytes_read]);
+    }
+}
+
+/// Returns true for an interim response, which the server follows with another response to the
+/// same request. 101 Switching Protocols is the one 1xx status that ends the exchange instead.
+pub fn is_informational(status: http::StatusCode) -> bool {
+    status.is_informational() && status != http::StatusCode::SWITCHING_PROTOCOLS
+}
+
+/// A response may have a body as long as it is not responding to a HEAD request and as long as
+/// the response status code is not 1xx, 204 (no content), or 304 (not modified). A successful
+/// response to CONNECT has no body either; whatever follows it belongs to the tunnel.
+fn has_body(request_method: &http::Method, status: http::StatusCode) -> bool {
+    !(request_method == http::Method::HEAD
+        || (request_method == http::Method::CONNECT && status.is_success())
+        || status.as_u16() < 200
+        || status == http::StatusCode::NO_CONTENT
+        || status == http::StatusCode::NOT_MODIFIED)
+}
+
+/// Returns true if the connection a response was read from can carry another request: the server
+/// didn't ask to close it, and the response's end was marked by its length or its last chunk rather
+/// than by the server hanging up.
+pub fn leaves_connection_reusable(
+    response: &http::Response<Vec<u8>>,
+    request_method: &http::Method,
+) -> bool {
+    let asked_to_close = response
+        .headers()
+        .get_all("connection")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("close"));
+    !asked_to_close
+        && (!has_body(request_method, response.status())
+            || response.headers().contains_key("content-length")
+            || is_chunked(response))
+}
+
 /// This function serializes a response to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
+    response: &http::Response<Vec<u8>>,
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    write_head_to_stream(response, stream).await?;
+    if !response.body().is_empty() {
+        stream.write_all(response.body()).await?;
+    }
+    Ok(())
+}
+
+/// Writes only the response line and headers of a response, for a body that will follow
+/// separately.
+pub async fn write_head_to_stream<S: AsyncWrite + Unpin>(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
     stream
-        .write(&format_response_line(response).into_bytes())
+        .write_all(&format_response_line(response).into_bytes())
         .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(b"\r\n").await?;
     for (header_name, header_value) in response.headers() {
         stream
-            .write(&format!("{}: ", header_name).as_bytes())
+            .write_all(format!("{}: ", header_name).as_bytes())
             .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
     }
+    stream.write_all(b"\r\n").await?;
     Ok(())
 }
 
+/// Appends to a header value, adding the header if the response doesn't have it yet. When the
+/// upstream is itself a proxy that named its own upstream, the header ends up listing the whole
+/// chain, nearest last.
+pub fn extend_header_value(
+    response: &mut http::Response<Vec<u8>>,
+    name: &'static str,
+    extend_value: &str,
+) {
+    let new_value = match response.headers().get(name) {
+        Some(existing_value) => {
+            [existing_value.as_bytes(), b", ", extend_value.as_bytes()].concat()
+        }
+        None => extend_value.as_bytes().to_owned(),
+    };
+    response
+        .headers_mut()
+        .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
+}
+
+/// Number of bytes write_to_stream sends for the response.
+pub fn encoded_len(response: &http::Response<Vec<u8>>) -> usize {
+    let head: usize = response
+        .headers()
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum();
+    format_response_line(response).len() + 2 + head + 2 + response.body().len()
+}
+
 pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
     format!(
         "{:?} {} {}",
@@ -221,11 +528,26 @@ pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
         "HTTP {} {}",
         status.as_u16(),
         status.canonical_reason().unwrap_or("")
-    )
-    .into_bytes();
+    );
+    make_text_response(status, body)
+}
+
+/// Creates an http::Response with a plain text body, for responses that balancebeam generates
+/// itself rather than relaying from an upstream.
+pub fn make_text_response(status: http::StatusCode, body: String) -> http::Response<Vec<u8>> {
+    make_response(status, "text/plain", body.into_bytes())
+}
+
+/// Creates an http::Response with the given body and content type, for responses that balancebeam
+/// generates itself rather than relaying from an upstream.
+pub fn make_response(
+    status: http::StatusCode,
+    content_type: &str,
+    body: Vec<u8>,
+) -> http::Response<Vec<u8>> {
     http::Response::builder()
         .status(status)
-        .header("Content-Type", "text/plain")
+        .header("Content-Type", content_type)
         .header("Content-Length", body.len().to_string())
         .version(http::Version::HTTP_11)
         .body(body)
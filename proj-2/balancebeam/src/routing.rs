@@ -0,0 +1,171 @@
+/// Returns true if the request path falls under the route prefix. Prefixes match whole path
+/// segments, so `/api` covers `/api` and `/api/users` but not `/apiary`. The empty prefix (used for
+/// upstreams configured without one) covers every path.
+fn matches_prefix(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => {
+            prefix.is_empty() || prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/')
+        }
+        None => false,
+    }
+}
+
+/// Picks the route prefix that should serve a request for the given path: the longest prefix that
+/// the path falls under, so that `/api/v2` can be split off from a broader `/api` route.
+///
+/// Returns None if no prefix matches, i.e. there are only prefixed routes and the path is under
+/// none of them.
+pub fn longest_prefix_match<'a, I>(prefixes: I, path: &str) -> Option<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    prefixes
+        .into_iter()
+        .filter(|prefix| matches_prefix(prefix, path))
+        .max_by_key(|prefix| prefix.len())
+}
+
+/// Rewrites a request path into a canonical form, so that paths that mean the same thing route the
+/// same way: runs of slashes are collapsed into one, and `.` and `..` segments are resolved (RFC
+/// 3986 section 5.2.4). A `..` can't climb above the root. Paths that don't start with a slash
+/// (e.g. `*`) are left alone.
+///
+/// Percent-encoding is left as it is unless decode_unreserved is set, in which case encoded
+/// unreserved characters (letters, digits, `-`, `.`, `_` and `~`) are decoded first, which never
+/// changes what a path means. Anything else stays encoded, so `%2F` is never mistaken for a
+/// segment boundary.
+pub fn normalize_path(path: &str, decode_unreserved: bool) -> String {
+    if !path.starts_with('/') {
+        return path.to_string();
+    }
+    let path = if decode_unreserved {
+        decode_unreserved_octets(path)
+    } else {
+        path.to_string()
+    };
+    let mut segments: Vec<&str> = Vec::new();
+    // Whether the path ends in a directory (e.g. `/a/`, `/a/.`), which we keep a trailing slash for
+    let mut ends_in_directory = false;
+    for segment in path.split('/').skip(1) {
+        ends_in_directory = true;
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => {
+                segments.push(segment);
+                ends_in_directory = false;
+            }
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    if ends_in_directory && !segments.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+/// Decodes percent-encoded unreserved characters, and upper-cases the hex digits of the
+/// percent-encodings that are left.
+fn decode_unreserved_octets(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = String::with_capacity(path.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octet = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match octet {
+            Some(octet) if is_unreserved(octet) => decoded.push(octet as char),
+            Some(octet) => decoded.push_str(&format!("%{:02X}", octet)),
+            None => {
+                // Copy the whole character, which may be more than one byte
+                let ch = path[i..].chars().next().unwrap();
+                decoded.push(ch);
+                i += ch.len_utf8();
+                continue;
+            }
+        }
+        i += 3;
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_longest_prefix_match() {
+        let prefixes = ["/api", "/api/v2", "/static/"];
+        assert_eq!(longest_prefix_match(prefixes, "/api"), Some("/api"));
+        assert_eq!(longest_prefix_match(prefixes, "/api/users"), Some("/api"));
+        assert_eq!(
+            longest_prefix_match(prefixes, "/api/v2/users"),
+            Some("/api/v2")
+        );
+        assert_eq!(
+            longest_prefix_match(prefixes, "/static/logo.png"),
+            Some("/static/")
+        );
+        assert_eq!(longest_prefix_match(prefixes, "/apiary"), None);
+        assert_eq!(longest_prefix_match(prefixes, "/"), None);
+    }
+
+    #[test]
+    fn test_default_route_matches_everything_else() {
+        let prefixes = ["", "/api"];
+        assert_eq!(longest_prefix_match(prefixes, "/api/users"), Some("/api"));
+        assert_eq!(longest_prefix_match(prefixes, "/apiary"), Some(""));
+        assert_eq!(longest_prefix_match(prefixes, "/"), Some(""));
+    }
+
+    #[test]
+    fn test_normalize_path_removes_dot_segments() {
+        assert_eq!(normalize_path("/a/../b", false), "/b");
+        assert_eq!(normalize_path("/a/./b/.", false), "/a/b/");
+        assert_eq!(normalize_path("/a/b/..", false), "/a/");
+        assert_eq!(normalize_path("/../../etc/passwd", false), "/etc/passwd");
+        assert_eq!(normalize_path("/a/..", false), "/");
+        assert_eq!(normalize_path("/a/.../b", false), "/a/.../b");
+    }
+
+    #[test]
+    fn test_normalize_path_collapses_slashes() {
+        assert_eq!(normalize_path("//double//slash", false), "/double/slash");
+        assert_eq!(normalize_path("/trailing//", false), "/trailing/");
+        assert_eq!(normalize_path("///", false), "/");
+        assert_eq!(normalize_path("*", false), "*");
+    }
+
+    #[test]
+    fn test_normalize_path_percent_encoding() {
+        // Left alone by default
+        assert_eq!(
+            normalize_path("/percent%2Fencoded/%7euser", false),
+            "/percent%2Fencoded/%7euser"
+        );
+        // Only unreserved characters are decoded; an encoded slash stays part of its segment
+        assert_eq!(
+            normalize_path("/percent%2fencoded/%7euser/%41/b/%2e%2E/x", true),
+            "/percent%2Fencoded/~user/A/x"
+        );
+        assert_eq!(normalize_path("/caf\u{e9}/%zz%4", true), "/caf\u{e9}/%zz%4");
+    }
+
+    #[test]
+    fn test_normalized_paths_route_consistently() {
+        let prefixes = ["", "/admin"];
+        let route = |path: &str| longest_prefix_match(prefixes, &normalize_path(path, false));
+        assert_eq!(route("//admin/users"), Some("/admin"));
+        assert_eq!(route("/public/../admin"), Some("/admin"));
+        assert_eq!(route("/admin/../public"), Some(""));
+    }
+}
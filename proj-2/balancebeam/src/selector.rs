@@ -0,0 +1,597 @@
+use rand::Rng;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Arc;
+
+/// A snapshot of one active upstream, as seen by a selection strategy at the moment a new client
+/// connection needs to be assigned.
+#[derive(Debug, Clone)]
+pub struct Upstream<'a> {
+    /// Address connections to this upstream go to
+    pub address: &'a str,
+    /// Relative capacity of this upstream (always at least 1)
+    pub weight: usize,
+    /// Number of client connections currently being proxied to this upstream
+    pub in_flight: usize,
+}
+
+/// What a selection strategy gets to know about the request an upstream is being picked for. The
+/// built-in strategies ignore it, but a custom one can route on it.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct RequestContext<'a> {
+    pub method: &'a http::Method,
+    pub path: &'a str,
+    pub headers: &'a http::HeaderMap,
+    pub client_ip: &'a str,
+    /// The server name the client asked for in its TLS handshake, if it sent one
+    pub sni: Option<&'a str>,
+}
+
+impl<'a> RequestContext<'a> {
+    pub fn new(
+        request: &'a http::Request<Vec<u8>>,
+        client_ip: &'a str,
+        sni: Option<&'a str>,
+    ) -> RequestContext<'a> {
+        RequestContext {
+            method: request.method(),
+            path: request.uri().path(),
+            headers: request.headers(),
+            client_ip,
+            sni,
+        }
+    }
+}
+
+/// A strategy for picking which upstream serves a client connection's requests. The candidates are
+/// the active upstreams for the request's route that have room for another connection, so a
+/// selector only has to choose among them. Returns the index of the chosen candidate, or None to
+/// turn the request away.
+pub trait UpstreamSelector: Send + Sync {
+    fn select(&self, candidates: &[Upstream], context: &RequestContext) -> Option<usize>;
+
+    /// Returns what about the request the choice of upstream depends on. A client connection keeps
+    /// the upstream selected for one of its requests while later ones have the same key, and has
+    /// one selected again for a request with a different key. A request with no key goes wherever
+    /// the connection's earlier requests went. By default the key is the whole context, so that a
+    /// selector routing on the request is asked again whenever anything it can see changes. The
+    /// built-in strategies that ignore the request pick once per connection, with no key.
+    fn selection_key(&self, context: &RequestContext) -> Option<String> {
+        Some(format!("{:?}", context))
+    }
+}
+
+/// Picks a candidate at random, in proportion to its weight; see weighted_random.
+pub struct Random;
+
+impl UpstreamSelector for Random {
+    fn select(&self, candidates: &[Upstream], _context: &RequestContext) -> Option<usize> {
+        weighted_random(candidates, &mut rand::thread_rng())
+    }
+
+    fn selection_key(&self, _context: &RequestContext) -> Option<String> {
+        None
+    }
+}
+
+/// Picks the candidate with the fewest in-flight connections; see least_connections.
+pub struct LeastConnections;
+
+impl UpstreamSelector for LeastConnections {
+    fn select(&self, candidates: &[Upstream], _context: &RequestContext) -> Option<usize> {
+        least_connections(candidates, &mut rand::thread_rng())
+    }
+
+    fn selection_key(&self, _context: &RequestContext) -> Option<String> {
+        None
+    }
+}
+
+/// Picks the candidate with the fewest in-flight connections relative to its weight; see
+/// weighted_least_connections.
+pub struct WeightedLeastConnections;
+
+impl UpstreamSelector for WeightedLeastConnections {
+    fn select(&self, candidates: &[Upstream], _context: &RequestContext) -> Option<usize> {
+        weighted_least_connections(candidates, &mut rand::thread_rng())
+    }
+
+    fn selection_key(&self, _context: &RequestContext) -> Option<String> {
+        None
+    }
+}
+
+/// Compares the load scores (in_flight / weight) of two candidates without resorting to floating
+/// point: a.in_flight / a.weight < b.in_flight / b.weight iff a.in_flight * b.weight < b.in_flight *
+/// a.weight.
+fn compare_load(a: &Upstream, b: &Upstream) -> Ordering {
+    (a.in_flight * b.weight).cmp(&(b.in_flight * a.weight))
+}
+
+/// Picks the candidate minimizing (in-flight connections / weight), so that a higher-weight
+/// upstream can carry proportionally more concurrent load before being deprioritized. Ties are
+/// broken in favor of the higher weight, and then randomly, so that an idle pool doesn't funnel
+/// every connection to the first upstream in the list.
+///
+/// Returns None if there are no candidates.
+pub fn weighted_least_connections<R: Rng>(candidates: &[Upstream], rng: &mut R) -> Option<usize> {
+    let best = candidates
+        .iter()
+        .min_by(|a, b| compare_load(a, b).then_with(|| b.weight.cmp(&a.weight)))?;
+    let tied: Vec<usize> = candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, candidate)| {
+            compare_load(candidate, best) == Ordering::Equal && candidate.weight == best.weight
+        })
+        .map(|(idx, _)| idx)
+        .collect();
+    Some(tied[rng.gen_range(0..tied.len())])
+}
+
+/// Picks the candidate with the fewest in-flight connections, regardless of weight, so that a
+/// backend that's slow to finish its requests stops being handed new ones. Ties are broken
+/// randomly.
+///
+/// Returns None if there are no candidates.
+pub fn least_connections<R: Rng>(candidates: &[Upstream], rng: &mut R) -> Option<usize> {
+    let fewest = candidates
+        .iter()
+        .map(|candidate| candidate.in_flight)
+        .min()?;
+    let tied: Vec<usize> = candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, candidate)| candidate.in_flight == fewest)
+        .map(|(idx, _)| idx)
+        .collect();
+    Some(tied[rng.gen_range(0..tied.len())])
+}
+
+/// Picks a candidate at random, with each candidate's chance proportional to its weight. Only the
+/// candidates' weights relative to each other matter, so when an upstream drops out, the ones left
+/// keep splitting the load in the same proportions. With every weight at 1, this is a uniform pick.
+///
+/// Returns None if there are no candidates.
+pub fn weighted_random<R: Rng>(candidates: &[Upstream], rng: &mut R) -> Option<usize> {
+    let total: usize = candidates.iter().map(|candidate| candidate.weight).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut ticket = rng.gen_range(0..total);
+    candidates.iter().position(|candidate| {
+        if ticket < candidate.weight {
+            true
+        } else {
+            ticket -= candidate.weight;
+            false
+        }
+    })
+}
+
+/// Takes turns among candidates. The active upstreams can change between picks (a health check
+/// swapping the list, say), so the cursor is never taken as an index into any list: each pick is
+/// worked out against the snapshot of candidates the caller has in hand, and the cursor is kept
+/// below that snapshot's length. A shrinking list can't push the cursor out of range, and a
+/// growing one doesn't make it skip ahead.
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl RoundRobin {
+    pub fn new() -> RoundRobin {
+        RoundRobin {
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the index of the candidate whose turn it is, out of `len` candidates, or None if
+    /// there are no candidates.
+    pub fn pick(&self, len: usize) -> Option<usize> {
+        if len == 0 {
+            return None;
+        }
+        let current = self
+            .next
+            .fetch_update(
+                AtomicOrdering::Relaxed,
+                AtomicOrdering::Relaxed,
+                |current| Some((current % len + 1) % len),
+            )
+            .unwrap();
+        Some(current % len)
+    }
+}
+
+impl UpstreamSelector for RoundRobin {
+    fn select(&self, candidates: &[Upstream], _context: &RequestContext) -> Option<usize> {
+        self.pick(candidates.len())
+    }
+
+    fn selection_key(&self, _context: &RequestContext) -> Option<String> {
+        None
+    }
+}
+
+/// Keeps each session on one upstream, for stateful backends: requests carrying the session cookie
+/// go wherever its value hashes to, and requests without it are left to the fallback strategy.
+/// Every candidate is ranked by a hash of the cookie value and its address, and the top one wins
+/// (rendezvous hashing). An upstream that drops out of rotation only moves its own sessions to
+/// their next-ranked candidate, and they come back once it returns.
+pub struct StickyCookie {
+    cookie: String,
+    fallback: Arc<dyn UpstreamSelector>,
+}
+
+impl StickyCookie {
+    pub fn new(cookie: &str, fallback: Arc<dyn UpstreamSelector>) -> StickyCookie {
+        StickyCookie {
+            cookie: cookie.to_string(),
+            fallback,
+        }
+    }
+}
+
+impl UpstreamSelector for StickyCookie {
+    fn select(&self, candidates: &[Upstream], context: &RequestContext) -> Option<usize> {
+        let session = match cookie_value(context.headers, &self.cookie) {
+            Some(session) => session,
+            None => return self.fallback.select(candidates, context),
+        };
+        candidates
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, candidate)| {
+                let mut hasher = DefaultHasher::new();
+                (session, candidate.address).hash(&mut hasher);
+                hasher.finish()
+            })
+            .map(|(idx, _)| idx)
+    }
+
+    fn selection_key(&self, context: &RequestContext) -> Option<String> {
+        match cookie_value(context.headers, &self.cookie) {
+            Some(session) => Some(session.to_string()),
+            None => self.fallback.selection_key(context),
+        }
+    }
+}
+
+/// Returns the value of the named cookie from the request's Cookie headers, if it was sent.
+pub fn cookie_value<'a>(headers: &'a http::HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all("cookie")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn candidates(specs: &[(usize, usize)]) -> Vec<Upstream<'static>> {
+        specs
+            .iter()
+            .map(|&(weight, in_flight)| Upstream {
+                address: "127.0.0.1:8080",
+                weight,
+                in_flight,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_weighted_least_connections_scoring() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+
+        // Scores: 4/1, 6/3, 3/1. The second upstream has the most connections but the lowest score.
+        let pool = candidates(&[(1, 4), (3, 6), (1, 3)]);
+        assert_eq!(weighted_least_connections(&pool, &mut rng), Some(1));
+
+        // Scores: 1/1, 4/3, 0/1. An idle upstream always wins.
+        let pool = candidates(&[(1, 1), (3, 4), (1, 0)]);
+        assert_eq!(weighted_least_connections(&pool, &mut rng), Some(2));
+
+        // Scores: 2/2, 3/3, 2/1. The first two tie, so the higher weight wins.
+        let pool = candidates(&[(2, 2), (3, 3), (1, 2)]);
+        assert_eq!(weighted_least_connections(&pool, &mut rng), Some(1));
+    }
+
+    #[test]
+    fn test_weighted_least_connections_breaks_full_ties_randomly() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let pool = candidates(&[(2, 0), (2, 0), (1, 0)]);
+        let mut picked = [0_usize; 3];
+        for _ in 0..100 {
+            picked[weighted_least_connections(&pool, &mut rng).unwrap()] += 1;
+        }
+        assert!(picked[0] > 0 && picked[1] > 0);
+        assert_eq!(picked[2], 0);
+    }
+
+    #[test]
+    fn test_weighted_least_connections_empty() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        assert_eq!(weighted_least_connections(&[], &mut rng), None);
+    }
+
+    #[test]
+    fn test_round_robin_takes_turns() {
+        let round_robin = RoundRobin::new();
+        let picks: Vec<usize> = (0..7).map(|_| round_robin.pick(3).unwrap()).collect();
+        assert_eq!(picks, [0, 1, 2, 0, 1, 2, 0]);
+        // The list shrank under us; the next pick must still be in range
+        assert_eq!(round_robin.pick(1), Some(0));
+        assert_eq!(round_robin.pick(0), None);
+    }
+
+    #[test]
+    fn test_round_robin_with_changing_list() {
+        use std::sync::atomic::AtomicBool;
+        use std::sync::{Arc, RwLock};
+        use std::thread;
+
+        let active = Arc::new(RwLock::new(vec!["a", "b", "c"]));
+        let round_robin = Arc::new(RoundRobin::new());
+        let done = Arc::new(AtomicBool::new(false));
+
+        // Keep adding and removing a fourth upstream, the way a flapping health check would
+        let mutator = {
+            let active = active.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                while !done.load(AtomicOrdering::Relaxed) {
+                    active.write().unwrap().push("d");
+                    thread::yield_now();
+                    active.write().unwrap().pop();
+                    thread::yield_now();
+                }
+            })
+        };
+        let pickers: Vec<_> = (0..4)
+            .map(|_| {
+                let active = active.clone();
+                let round_robin = round_robin.clone();
+                thread::spawn(move || {
+                    let mut picked = Vec::new();
+                    for _ in 0..10_000 {
+                        let snapshot = active.read().unwrap().clone();
+                        picked.push(snapshot[round_robin.pick(snapshot.len()).unwrap()]);
+                    }
+                    picked
+                })
+            })
+            .collect();
+        let picked: Vec<&str> = pickers
+            .into_iter()
+            .flat_map(|picker| picker.join().unwrap())
+            .collect();
+        done.store(true, AtomicOrdering::Relaxed);
+        mutator.join().unwrap();
+
+        // The upstreams that never left should have been picked about equally often
+        let counts: Vec<usize> = ["a", "b", "c"]
+            .iter()
+            .map(|name| picked.iter().filter(|pick| *pick == name).count())
+            .collect();
+        let (min, max) = (*counts.iter().min().unwrap(), *counts.iter().max().unwrap());
+        assert!(max * 4 <= min * 5, "unfair picks {:?}", counts);
+    }
+
+    #[test]
+    fn test_least_connections_ignores_weight() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let pool = candidates(&[(10, 4), (1, 2), (1, 3)]);
+        assert_eq!(least_connections(&pool, &mut rng), Some(1));
+        assert_eq!(least_connections(&[], &mut rng), None);
+    }
+
+    #[test]
+    fn test_least_connections_spreads_ties() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let pool = candidates(&[(1, 5), (1, 1), (1, 1)]);
+        let picks: Vec<usize> = (0..100)
+            .map(|_| least_connections(&pool, &mut rng).unwrap())
+            .collect();
+        assert!(picks.iter().all(|idx| *idx != 0));
+        assert!(picks.contains(&1) && picks.contains(&2));
+    }
+
+    /// Share of `picks` weighted random picks landing on each candidate
+    fn weighted_random_shares(specs: &[(usize, usize)], picks: usize) -> Vec<f64> {
+        let pool = candidates(specs);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut counts = vec![0; pool.len()];
+        for _ in 0..picks {
+            counts[weighted_random(&pool, &mut rng).unwrap()] += 1;
+        }
+        counts
+            .into_iter()
+            .map(|count| count as f64 / picks as f64)
+            .collect()
+    }
+
+    #[test]
+    fn test_weighted_random_follows_weights() {
+        let shares = weighted_random_shares(&[(2, 0), (1, 0), (1, 0)], 10_000);
+        assert!((0.48..0.52).contains(&shares[0]), "{:?}", shares);
+        assert!((0.23..0.27).contains(&shares[1]), "{:?}", shares);
+        assert!((0.23..0.27).contains(&shares[2]), "{:?}", shares);
+        assert_eq!(
+            weighted_random(&[], &mut rand::rngs::StdRng::seed_from_u64(0)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_weighted_random_renormalizes_without_dead_upstream() {
+        let all = weighted_random_shares(&[(3, 0), (4, 0), (1, 0)], 10_000);
+        assert!((0.35..0.40).contains(&all[0]), "{:?}", all);
+        assert!((0.10..0.15).contains(&all[2]), "{:?}", all);
+        // With the weight-4 upstream out of rotation, the other two split the load 3:1
+        let survivors = weighted_random_shares(&[(3, 0), (1, 0)], 10_000);
+        assert!((0.73..0.77).contains(&survivors[0]), "{:?}", survivors);
+    }
+
+    #[test]
+    fn test_weighted_random_defaults_to_uniform() {
+        let shares = weighted_random_shares(&[(1, 0), (1, 0), (1, 0), (1, 0)], 10_000);
+        assert!(
+            shares.iter().all(|share| (0.23..0.27).contains(share)),
+            "{:?}",
+            shares
+        );
+    }
+
+    /// Sends requests carrying an `X-Shard: N` header to the Nth candidate, and everything else to
+    /// the first
+    struct ShardSelector;
+
+    impl UpstreamSelector for ShardSelector {
+        fn select(&self, candidates: &[Upstream], context: &RequestContext) -> Option<usize> {
+            let shard = match context.headers.get("x-shard") {
+                Some(value) => value.to_str().ok()?.parse().ok()?,
+                None => 0,
+            };
+            (shard < candidates.len()).then_some(shard)
+        }
+    }
+
+    #[test]
+    fn test_custom_selector_controls_routing() {
+        let selector: Box<dyn UpstreamSelector> = Box::new(ShardSelector);
+        let upstreams = vec![
+            Upstream {
+                address: "10.0.0.1:80",
+                weight: 1,
+                in_flight: 0,
+            },
+            Upstream {
+                address: "10.0.0.2:80",
+                weight: 1,
+                in_flight: 0,
+            },
+        ];
+        let route = |shard: Option<&str>| {
+            let mut request = http::Request::builder().uri("/users/7");
+            if let Some(shard) = shard {
+                request = request.header("X-Shard", shard);
+            }
+            let request = request.body(Vec::new()).unwrap();
+            let context = RequestContext::new(&request, "192.0.2.1", None);
+            selector
+                .select(&upstreams, &context)
+                .map(|idx| upstreams[idx].address)
+        };
+        assert_eq!(route(None), Some("10.0.0.1:80"));
+        assert_eq!(route(Some("1")), Some("10.0.0.2:80"));
+        assert_eq!(route(Some("2")), None);
+        assert_eq!(route(Some("bogus")), None);
+
+        // A request the selector could route differently needs it asked again
+        let key = |shard: &str| {
+            let request = http::Request::builder()
+                .uri("/users/7")
+                .header("X-Shard", shard)
+                .body(Vec::new())
+                .unwrap();
+            selector.selection_key(&RequestContext::new(&request, "192.0.2.1", None))
+        };
+        assert_eq!(key("1"), key("1"));
+        assert_ne!(key("1"), key("2"));
+    }
+
+    #[test]
+    fn test_cookie_value() {
+        let mut headers = http::HeaderMap::new();
+        headers.append("cookie", "theme=dark; session=abc123".parse().unwrap());
+        headers.append("cookie", "cart=7".parse().unwrap());
+        assert_eq!(cookie_value(&headers, "session"), Some("abc123"));
+        assert_eq!(cookie_value(&headers, "cart"), Some("7"));
+        assert_eq!(cookie_value(&headers, "sess"), None);
+    }
+
+    #[test]
+    fn test_sticky_cookie_pins_sessions() {
+        let selector = StickyCookie::new("session", Arc::new(RoundRobin::new()));
+        let addresses = ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80", "10.0.0.4:80"];
+        let pool: Vec<Upstream<'static>> = addresses
+            .iter()
+            .map(|&address| Upstream {
+                address,
+                weight: 1,
+                in_flight: 0,
+            })
+            .collect();
+        let pick = |pool: &[Upstream<'static>], session: Option<&str>| -> &'static str {
+            let mut request = http::Request::builder();
+            if let Some(session) = session {
+                request = request.header("Cookie", format!("session={}", session));
+            }
+            let request = request.body(Vec::new()).unwrap();
+            let context = RequestContext::new(&request, "192.0.2.1", None);
+            pool[selector.select(pool, &context).unwrap()].address
+        };
+
+        let sessions: Vec<String> = (0..40).map(|i| format!("user-{}", i)).collect();
+        let pinned: Vec<&str> = sessions.iter().map(|s| pick(&pool, Some(s))).collect();
+        for (session, upstream) in sessions.iter().zip(&pinned) {
+            assert_eq!(pick(&pool, Some(session)), *upstream);
+        }
+        assert!(addresses.iter().all(|address| pinned.contains(address)));
+
+        // Only the sessions of the upstream that left have to move
+        let survivors = pool[1..].to_vec();
+        for (session, upstream) in sessions.iter().zip(&pinned) {
+            let moved = pick(&survivors, Some(session));
+            if *upstream != addresses[0] {
+                assert_eq!(moved, *upstream);
+            }
+        }
+
+        // Without the cookie, the fallback takes turns as usual
+        let picks: Vec<&str> = (0..3).map(|_| pick(&pool, None)).collect();
+        assert_eq!(picks, addresses[..3]);
+
+        // A request with another session cookie needs its upstream selected again
+        let key = |cookie: Option<&str>| {
+            let mut request = http::Request::builder();
+            if let Some(cookie) = cookie {
+                request = request.header("Cookie", cookie);
+            }
+            let request = request.body(Vec::new()).unwrap();
+            selector.selection_key(&RequestContext::new(&request, "192.0.2.1", None))
+        };
+        assert_eq!(
+            key(Some("theme=dark; session=user-1")),
+            Some("user-1".to_string())
+        );
+        assert_eq!(key(Some("theme=dark")), None);
+        assert_eq!(key(None), None);
+    }
+
+    #[test]
+    fn test_builtin_selectors_through_trait() {
+        let request = http::Request::builder().body(Vec::new()).unwrap();
+        let context = RequestContext::new(&request, "192.0.2.1", None);
+        let pool = candidates(&[(1, 3), (1, 0), (1, 5)]);
+        assert_eq!(WeightedLeastConnections.select(&pool, &context), Some(1));
+        assert!(Random.select(&pool, &context).unwrap() < 3);
+        assert_eq!(Random.select(&[], &context), None);
+        let round_robin = RoundRobin::new();
+        let picks: Vec<Option<usize>> = (0..4)
+            .map(|_| round_robin.select(&pool, &context))
+            .collect();
+        assert_eq!(picks, [Some(0), Some(1), Some(2), Some(0)]);
+        assert_eq!(round_robin.selection_key(&context), None);
+        assert_eq!(Random.selection_key(&context), None);
+    }
+}
@@ -0,0 +1,204 @@
+use std::convert::TryFrom;
+use std::io::BufReader;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{DigitallySignedStruct, SignatureScheme};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+fn open(path: &Path) -> Result<BufReader<std::fs::File>, String> {
+    std::fs::File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| format!("could not open {}: {}", path.display(), err))
+}
+
+/// Builds the acceptor that terminates TLS on client connections, from a PEM file holding the
+/// certificate chain (the server's own certificate first) and a PEM file holding its private key.
+/// Up to session_cache_size sessions are remembered, and tickets handed out, so that reconnecting
+/// clients can resume their session instead of doing a full handshake; 0 turns resumption off.
+pub fn load_acceptor(
+    cert_path: &Path,
+    key_path: &Path,
+    session_cache_size: usize,
+) -> Result<TlsAcceptor, String> {
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("could not read {}: {}", cert_path.display(), err))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", cert_path.display()));
+    }
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)
+        .map_err(|err| format!("could not read {}: {}", key_path.display(), err))?
+        .ok_or_else(|| format!("no private key found in {}", key_path.display()))?;
+    let mut config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| format!("unusable certificate or key: {}", err))?;
+    // We only speak HTTP/1.1, so clients mustn't be allowed to negotiate HTTP/2
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    if session_cache_size > 0 {
+        config.session_storage = rustls::server::ServerSessionMemoryCache::new(session_cache_size);
+        config.ticketer = rustls::crypto::ring::Ticketer::new()
+            .map_err(|err| format!("could not set up session tickets: {}", err))?;
+    } else {
+        config.session_storage = Arc::new(rustls::server::NoServerSessionStorage {});
+        config.send_tls13_tickets = 0;
+    }
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Builds the connector that opens TLS connections to the upstreams. Their certificates are
+/// checked against the usual web PKI roots, unless insecure is set, in which case any certificate
+/// is accepted (for self-signed upstreams in testing; the connection is still encrypted, but not
+/// authenticated).
+pub fn upstream_connector(insecure: bool) -> TlsConnector {
+    let builder = rustls::ClientConfig::builder();
+    let mut config = if insecure {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(
+                rustls::crypto::ring::default_provider(),
+            )))
+            .with_no_client_auth()
+    } else {
+        let roots = rustls::RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    TlsConnector::from(Arc::new(config))
+}
+
+/// Returns the name to give for SNI and check the certificate of an upstream against: its address
+/// without the port.
+pub fn upstream_server_name(address: &str) -> Result<ServerName<'static>, std::io::Error> {
+    let host = match address.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => address,
+    };
+    // IPv6 addresses are written in brackets when followed by a port
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string()).map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} can't be used as a TLS server name: {}", host, err),
+        )
+    })
+}
+
+/// Certificate verifier for --upstream-tls-insecure, which takes the upstream's word for who it
+/// is. Handshake signatures are still checked, so that the connection is at least encrypted to
+/// whoever holds the certificate's key.
+#[derive(Debug)]
+struct AcceptAnyCertificate(rustls::crypto::CryptoProvider);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// A connection to an upstream, which is encrypted with --upstream-tls. Either way it's read and
+/// written the same, so nothing past dial_upstream needs to know which it is.
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<tokio_rustls::client::TlsStream<TcpStream>>),
+}
+
+impl UpstreamStream {
+    /// The TCP connection underneath
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            UpstreamStream::Plain(stream) => stream,
+            UpstreamStream::Tls(stream) => stream.get_ref().0,
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -1,7 +1,10 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, Server};
+use common::{init_logging, read_request_head, BalanceBeam, EchoServer, RawServer, Server};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::sleep;
 
 async fn setup() -> (BalanceBeam, EchoServer) {
     init_logging();
@@ -69,7 +72,7 @@ async fn test_multiple_requests_per_connection() {
                 );
                 let path = format!("/conn-{}/req-{}", task_num, req_num);
                 let response_text = client
-                    .get(&format!("http://{}{}", balancebeam_shared.address, path))
+                    .get(format!("http://{}{}", balancebeam_shared.address, path))
                     .header("x-sent-by", "balancebeam-tests")
                     .send()
                     .await
@@ -99,3 +102,504 @@ async fn test_multiple_requests_per_connection() {
 
     log::info!("All done :)");
 }
+
+/// Open connections much faster than --max-accepts-per-second allows, and make sure they're
+/// accepted at about the configured rate instead of all at once.
+#[tokio::test]
+async fn test_accept_rate_limit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-accepts-per-second", "5"]).await;
+    let balancebeam = Arc::new(balancebeam);
+
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for i in 0..10 {
+        let balancebeam = balancebeam.clone();
+        tasks.push(tokio::task::spawn(async move {
+            // Each get() uses a client of its own, so each request is on a new connection
+            let path = format!("/connection-{}", i);
+            let response_text = balancebeam
+                .get(&path)
+                .await
+                .expect("Error sending request to balancebeam");
+            assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+            start.elapsed()
+        }));
+    }
+    let mut elapsed = Vec::new();
+    for task in tasks {
+        elapsed.push(task.await.expect("Task panicked"));
+    }
+    elapsed.sort();
+
+    // At 5 per second, the 10th connection is accepted 1.8 seconds after the first
+    log::info!("Connections were answered after {:?}", elapsed);
+    assert!(
+        elapsed[9] >= Duration::from_millis(1600),
+        "Connections were accepted too fast: {:?}",
+        elapsed
+    );
+    assert!(
+        elapsed[9] < Duration::from_millis(4000),
+        "Connections were accepted too slowly: {:?}",
+        elapsed
+    );
+    assert!(
+        elapsed[4] >= Duration::from_millis(700),
+        "Connections were accepted in a burst: {:?}",
+        elapsed
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 10);
+    log::info!("All done :)");
+}
+
+/// Sends a request claiming to be from 203.0.113.7 and returns what the upstream saw.
+async fn send_with_real_ip_header(balancebeam: &BalanceBeam) -> String {
+    reqwest::Client::new()
+        .get(format!("http://{}/real-ip", balancebeam.address))
+        .header("CF-Connecting-IP", "203.0.113.7")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response")
+}
+
+/// A trusted proxy's --real-ip-header should be used as the client IP.
+#[tokio::test]
+async fn test_real_ip_header_from_trusted_proxy() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--real-ip-header",
+            "CF-Connecting-IP",
+            "--trusted-proxy",
+            "127.0.0.0/8",
+        ],
+    )
+    .await;
+
+    let response_text = send_with_real_ip_header(&balancebeam).await;
+    assert!(
+        response_text.contains("x-forwarded-for: 203.0.113.7"),
+        "Upstream saw: {}",
+        response_text
+    );
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("203.0.113.7 -> ")));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Anyone not in --trusted-proxy could claim to be anyone, so their header should be ignored.
+#[tokio::test]
+async fn test_real_ip_header_from_untrusted_peer() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--real-ip-header",
+            "CF-Connecting-IP",
+            "--trusted-proxy",
+            "10.0.0.0/8",
+        ],
+    )
+    .await;
+
+    let response_text = send_with_real_ip_header(&balancebeam).await;
+    assert!(
+        response_text.contains("x-forwarded-for: 127.0.0.1"),
+        "Upstream saw: {}",
+        response_text
+    );
+    assert!(!response_text.contains("x-forwarded-for: 203.0.113.7"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --hash-bodies, the access log line should carry the SHA-256 digests of the request body
+/// and of the response body.
+#[tokio::test]
+async fn test_hash_bodies() {
+    init_logging();
+    let upstream = RawServer::new(|mut stream| async move {
+        while read_request_head(&mut stream).await.is_some() {
+            // Every request in this test has a 5-byte body
+            let mut body = [0_u8; 5];
+            if stream.read_exact(&mut body).await.is_err()
+                || stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                    .await
+                    .is_err()
+            {
+                return;
+            }
+        }
+    })
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &["--hash-bodies"]).await;
+
+    let response_text = balancebeam
+        .post("/upload", "hello")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "ok");
+    sleep(Duration::from_millis(100)).await;
+
+    let expected = "request_sha256=2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824 \
+                    response_sha256=2689367b205c16ce32ed4200942b8b8b1e262dfc70d9bc9fbc77c49699a4f1df";
+    assert!(
+        balancebeam
+            .output()
+            .iter()
+            .any(|line| line.contains("<- HTTP/1.1 200 OK") && line.ends_with(expected)),
+        "No access log line with the expected digests"
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Returns the hex SHA-256 of data, as --hash-bodies logs it.
+fn sha256_hex(data: &[u8]) -> String {
+    use sha2::Digest;
+    sha2::Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// --hash-bodies should hash what a body carries, however it's framed and whether it's streamed
+/// or held whole: a chunked response hashes like its joined chunk data, and a body too big to
+/// buffer is hashed as it goes through.
+#[tokio::test]
+async fn test_hash_chunked_and_streamed_bodies() {
+    init_logging();
+    const LARGE: usize = 100_000;
+    let upstream = RawServer::new(|mut stream| async move {
+        while let Some(head) = read_request_head(&mut stream).await {
+            let content_length = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .map_or(0, |len| len.trim().parse().unwrap());
+            let mut body = vec![0_u8; content_length];
+            if stream.read_exact(&mut body).await.is_err() {
+                return;
+            }
+            let result = if head.starts_with("GET /whole-chunks ") {
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n")
+                    .await
+            } else if head.starts_with("GET /split-chunks ") {
+                // The second chunk arrives later, so the body is streamed
+                let first = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1;x=y\r\no\r\n";
+                if stream.write_all(first).await.is_err() {
+                    return;
+                }
+                sleep(Duration::from_millis(200)).await;
+                stream
+                    .write_all(b"1\r\nk\r\n0\r\nx-checksum: 1\r\n\r\n")
+                    .await
+            } else {
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", LARGE);
+                let mut response = head.into_bytes();
+                response.extend(vec![b'z'; LARGE]);
+                stream.write_all(&response).await
+            };
+            if result.is_err() {
+                return;
+            }
+        }
+    })
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--hash-bodies",
+            "--stream-request-body-bytes",
+            "1000",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+    let empty = sha256_hex(b"");
+    let logged = |digests: String| {
+        let output = balancebeam.output();
+        output
+            .iter()
+            .any(|line| line.contains("<- HTTP/1.1 200 OK") && line.ends_with(&digests))
+    };
+
+    for path in ["/whole-chunks", "/split-chunks"] {
+        let response_text = balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "ok");
+        sleep(Duration::from_millis(100)).await;
+        assert!(
+            logged(format!(
+                "request_sha256={} response_sha256={}",
+                empty,
+                sha256_hex(b"ok")
+            )),
+            "No access log line with the digest of {}'s chunk data",
+            path
+        );
+    }
+
+    let upload = "u".repeat(LARGE);
+    let response_text = balancebeam
+        .post("/upload", &upload)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text.len(), LARGE);
+    sleep(Duration::from_millis(100)).await;
+    assert!(
+        logged(format!(
+            "request_sha256={} response_sha256={}",
+            sha256_hex(upload.as_bytes()),
+            sha256_hex(&vec![b'z'; LARGE])
+        )),
+        "No access log line with the digests of the streamed bodies"
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Only a request slower than --slow-request-threshold-ms should get a slow request warning, even
+/// with sampling turned all the way down.
+#[tokio::test]
+async fn test_slow_request_warning() {
+    init_logging();
+    let upstream = RawServer::new(|mut stream| async move {
+        while let Some(head) = read_request_head(&mut stream).await {
+            if head.starts_with("GET /slow ") {
+                sleep(Duration::from_millis(600)).await;
+            }
+            if stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    })
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--slow-request-threshold-ms",
+            "300",
+            "--log-sample-rate",
+            "0",
+        ],
+    )
+    .await;
+
+    for path in ["/fast", "/slow"] {
+        let response_text = balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "ok");
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    let warnings: Vec<String> = balancebeam
+        .output()
+        .into_iter()
+        .filter(|line| line.contains("Slow request"))
+        .collect();
+    assert_eq!(warnings.len(), 1, "Slow request warnings: {:?}", warnings);
+    assert!(warnings[0].contains("WARN"), "{}", warnings[0]);
+    assert!(
+        warnings[0].contains("\"GET /slow HTTP/1.1\""),
+        "{}",
+        warnings[0]
+    );
+    assert!(warnings[0].contains(&upstream.address), "{}", warnings[0]);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// An upstream that answers every request after a delay, which is how long the request stays in
+/// flight in balancebeam.
+async fn slow_upstream(delay: Duration) -> RawServer {
+    RawServer::new(move |mut stream| async move {
+        while read_request_head(&mut stream).await.is_some() {
+            sleep(delay).await;
+            if stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow")
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    })
+    .await
+}
+
+/// On SIGTERM, balancebeam should stop accepting connections, but finish the requests it's in the
+/// middle of before exiting.
+#[tokio::test]
+async fn test_graceful_shutdown_drains_connections() {
+    init_logging();
+    let upstream = slow_upstream(Duration::from_secs(1)).await;
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "600"],
+    )
+    .await;
+
+    let address = balancebeam.address.clone();
+    let in_flight = tokio::spawn(async move {
+        reqwest::get(format!("http://{}/in-flight", address))
+            .await?
+            .text()
+            .await
+    });
+    sleep(Duration::from_millis(300)).await;
+    balancebeam.terminate();
+    sleep(Duration::from_millis(200)).await;
+    assert!(
+        balancebeam.get("/too-late").await.is_err(),
+        "A connection was accepted after SIGTERM"
+    );
+
+    let response_text = in_flight
+        .await
+        .unwrap()
+        .expect("The in-flight request was cut off");
+    assert_eq!(response_text, "slow");
+    assert!(
+        balancebeam.wait_for_exit(Duration::from_secs(2)).await,
+        "balancebeam didn't exit once its connections were done"
+    );
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("Drained 1 connections; forcibly closed 0")));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Connections still busy when --shutdown-grace-seconds runs out are closed, rather than holding
+/// up the exit.
+#[tokio::test]
+async fn test_graceful_shutdown_grace_period() {
+    init_logging();
+    let upstream = slow_upstream(Duration::from_secs(10)).await;
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--shutdown-grace-seconds",
+            "1",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let address = balancebeam.address.clone();
+    let in_flight =
+        tokio::spawn(async move { reqwest::get(format!("http://{}/stuck", address)).await });
+    sleep(Duration::from_millis(300)).await;
+    let start = Instant::now();
+    balancebeam.terminate();
+    assert!(
+        balancebeam.wait_for_exit(Duration::from_secs(3)).await,
+        "balancebeam didn't exit after the grace period"
+    );
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed >= Duration::from_millis(900),
+        "Exited after only {:?}",
+        elapsed
+    );
+    assert!(in_flight.await.unwrap().is_err());
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("Drained 0 connections; forcibly closed 1")));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Returns the effective uid of a running process, from /proc.
+#[cfg(target_os = "linux")]
+fn effective_uid(pid: nix::unistd::Pid) -> u32 {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).unwrap();
+    // Uid: real, effective, saved set, filesystem
+    let uids = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))
+        .expect("No Uid line in /proc status");
+    uids.split_whitespace().nth(1).unwrap().parse().unwrap()
+}
+
+/// With --run-as-user, balancebeam binds its listener as root and then gives root up, but keeps
+/// serving on that listener. This can only be checked when the tests run as root.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_run_as_user_drops_root() {
+    init_logging();
+    if !nix::unistd::geteuid().is_root() {
+        log::info!("Not running as root, so there are no privileges to drop; skipping");
+        return;
+    }
+    let nobody = nix::unistd::User::from_name("nobody")
+        .unwrap()
+        .expect("No nobody user to switch to");
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--run-as-user", "nobody"]).await;
+
+    assert_eq!(effective_uid(balancebeam.pid()), nobody.uid.as_raw());
+    let response_text = balancebeam
+        .get("/after-drop")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /after-drop HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A --run-as-user that can't be switched to should stop balancebeam from starting at all, rather
+/// than leaving it running with more privileges than asked for.
+#[tokio::test]
+async fn test_run_as_unknown_user_fails() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--run-as-user", "no-such-balancebeam-user"],
+    )
+    .await;
+
+    assert!(balancebeam.has_exited(), "balancebeam kept running");
+    assert!(balancebeam.output().iter().any(|line| line
+        .contains("Could not switch to --run-as-user no-such-balancebeam-user: no such user")));
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
@@ -1,8 +1,14 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use common::{
+    init_logging, read_request_head, BalanceBeam, EchoServer, ErrorServer, RawServer,
+    ReservedAddress, Server,
+};
 
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 use tokio::time::sleep;
 
 async fn setup_with_params(
@@ -271,7 +277,7 @@ async fn test_rate_limiting() {
     for i in 0..num_extra_requests {
         let client = reqwest::Client::new();
         let response = client
-            .get(&format!("http://{}/overboard-{}", balancebeam.address, i))
+            .get(format!("http://{}/overboard-{}", balancebeam.address, i))
             .header("x-sent-by", "balancebeam-tests")
             .send()
             .await
@@ -294,3 +300,962 @@ async fn test_rate_limiting() {
 
     log::info!("All done :)");
 }
+
+/// Each client IP gets a quota of its own: one client using up its requests for the minute shouldn't
+/// cost another client any of theirs, even though both are sent to the same upstream. Any 127.x.y.z
+/// address is local on Linux, so the two clients can connect from different ones.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_rate_limiting_per_client_ip() {
+    let rate_limit_threshold = 3;
+    let (balancebeam, mut upstreams) = setup_with_params(1, None, Some(rate_limit_threshold)).await;
+
+    for client_ip in ["127.0.0.2", "127.0.0.3"] {
+        let client = reqwest::Client::builder()
+            .local_address(client_ip.parse::<std::net::IpAddr>().unwrap())
+            .build()
+            .unwrap();
+        let mut statuses = Vec::new();
+        for i in 0..rate_limit_threshold + 2 {
+            let response = client
+                .get(format!("http://{}/request-{}", balancebeam.address, i))
+                .send()
+                .await
+                .expect("Error sending request to balancebeam");
+            statuses.push(response.status().as_u16());
+        }
+        assert_eq!(statuses, [200, 200, 200, 429, 429], "Client {}", client_ip);
+    }
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("Client 127.0.0.2 went over 3 requests per minute")));
+
+    let mut total_request_count = 0;
+    while let Some(upstream) = upstreams.pop() {
+        total_request_count += upstream.stop().await;
+    }
+    assert_eq!(total_request_count, 2 * rate_limit_threshold);
+    log::info!("All done :)");
+}
+
+/// With rate limiting on, every response tells the client its quota: the limit, how many requests
+/// it has left, and how many seconds until the allowance is back. The 429s keep saying so too.
+#[tokio::test]
+async fn test_rate_limit_headers() {
+    let rate_limit_threshold = 3;
+    let (balancebeam, mut upstreams) = setup_with_params(1, None, Some(rate_limit_threshold)).await;
+
+    let client = reqwest::Client::new();
+    for (i, &(status, remaining)) in [(200, 2), (200, 1), (200, 0), (429, 0), (429, 0)]
+        .iter()
+        .enumerate()
+    {
+        let response = client
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        let header = |name: &str| -> u64 {
+            response
+                .headers()
+                .get(name)
+                .unwrap_or_else(|| panic!("Response {} has no {} header", i, name))
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+        assert_eq!(response.status().as_u16(), status, "Response {}", i);
+        assert_eq!(header("x-ratelimit-limit"), 3, "Response {}", i);
+        assert_eq!(header("x-ratelimit-remaining"), remaining, "Response {}", i);
+        // The latest request to be let through leaves the window a minute after it was made
+        let reset = header("x-ratelimit-reset");
+        assert!(
+            (59..=60).contains(&reset),
+            "Response {} resets in {}",
+            i,
+            reset
+        );
+    }
+
+    let mut total_request_count = 0;
+    while let Some(upstream) = upstreams.pop() {
+        total_request_count += upstream.stop().await;
+    }
+    assert_eq!(total_request_count, rate_limit_threshold);
+    log::info!("All done :)");
+}
+
+/// Enable token bucket rate limiting and ensure that a client can send a full burst at once, is
+/// throttled after that, and gets one more request through once a token has been refilled
+#[tokio::test]
+async fn test_token_bucket_rate_limiting() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--rate-limit-rate", "0.5", "--rate-limit-burst", "4"],
+    )
+    .await;
+
+    let status_of = |path: &str| {
+        let url = format!("http://{}{}", balancebeam.address, path);
+        async move {
+            reqwest::get(url)
+                .await
+                .expect("Error sending request to balancebeam")
+                .status()
+                .as_u16()
+        }
+    };
+
+    log::info!("Sending a burst of requests that fits in the bucket");
+    for i in 0..4 {
+        assert_eq!(status_of(&format!("/burst-{}", i)).await, 200);
+    }
+    log::info!("The bucket is empty now, so the next request should be throttled");
+    assert_eq!(status_of("/overboard").await, 429);
+
+    log::info!("Waiting for one token to be refilled");
+    sleep(Duration::from_millis(2200)).await;
+    assert_eq!(status_of("/refilled").await, 200);
+    assert_eq!(status_of("/overboard-again").await, 429);
+
+    assert_eq!(Box::new(upstream).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// With both rate limits on, a request the sliding window turns away shouldn't spend a token from
+/// the client's bucket, or the bucket would run dry on requests that were never let through.
+#[tokio::test]
+async fn test_window_rejections_spend_no_tokens() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--rate-limit-rate",
+            "0.01",
+            "--rate-limit-burst",
+            "2",
+            "--max-requests-per-minute",
+            "1",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for i in 0..5 {
+        let response = client
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), if i == 0 { 200 } else { 429 });
+    }
+
+    let output = balancebeam.output();
+    let over_window = output
+        .iter()
+        .filter(|line| line.contains("went over 1 requests per minute"))
+        .count();
+    assert_eq!(over_window, 4);
+    assert!(!output
+        .iter()
+        .any(|line| line.contains("ran out of rate limit tokens")));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// With --sticky-cookie, every request of a session should reach the same upstream, while requests
+/// without the cookie are still spread around.
+#[tokio::test]
+async fn test_sticky_cookie() {
+    init_logging();
+    let mut upstreams = Vec::new();
+    for _ in 0..3 {
+        upstreams.push(EchoServer::new().await);
+    }
+    let addresses: Vec<&str> = upstreams.iter().map(|u| u.address.as_str()).collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &addresses,
+        &[
+            "--sticky-cookie",
+            "session",
+            "--lb-algorithm",
+            "round-robin",
+        ],
+    )
+    .await;
+
+    // Each request is sent on a new client connection, so each one picks an upstream anew
+    for i in 0..6 {
+        reqwest::Client::new()
+            .get(format!("http://{}/cart-{}", balancebeam.address, i))
+            .header("cookie", "theme=dark; session=shopper-42")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    for i in 0..3 {
+        balancebeam
+            .get(&format!("/anonymous-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let mut counts = Vec::new();
+    for upstream in upstreams {
+        counts.push(Box::new(upstream).stop().await);
+    }
+    counts.sort_unstable();
+    // Round robin gives each upstream one of the anonymous requests, and the session's upstream
+    // gets all six of its requests on top
+    assert_eq!(counts, [1, 1, 7]);
+    log::info!("All done :)");
+}
+
+/// Requests on one keep-alive connection should go wherever their session cookie pins them, as if
+/// each had a connection of its own, even once an earlier request has picked an upstream.
+#[tokio::test]
+async fn test_sticky_cookie_on_keep_alive_connection() {
+    init_logging();
+    let mut upstreams = Vec::new();
+    for _ in 0..3 {
+        upstreams.push(EchoServer::new().await);
+    }
+    let addresses: Vec<&str> = upstreams.iter().map(|u| u.address.as_str()).collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &addresses,
+        &["--sticky-cookie", "session", "--add-upstream-header"],
+    )
+    .await;
+    let served_by = |response: reqwest::Response| {
+        response.headers()["x-upstream-server"]
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+    let sessions: Vec<String> = (0..12).map(|i| format!("shopper-{}", i)).collect();
+
+    // A browser's first request has no cookie yet, and the rest ride the same connection
+    let client = reqwest::Client::new();
+    client
+        .get(format!("http://{}/welcome", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let mut kept_alive = Vec::new();
+    for session in &sessions {
+        let response = client
+            .get(format!("http://{}/cart", balancebeam.address))
+            .header("cookie", format!("session={}", session))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        kept_alive.push(served_by(response));
+    }
+    let connections = balancebeam
+        .output()
+        .iter()
+        .filter(|line| line.contains("Connection received from"))
+        .count();
+    assert_eq!(connections, 1, "The requests didn't share a connection");
+
+    let mut fresh = Vec::new();
+    for session in &sessions {
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/cart", balancebeam.address))
+            .header("cookie", format!("session={}", session))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        fresh.push(served_by(response));
+    }
+    assert_eq!(kept_alive, fresh);
+    assert!(
+        fresh.iter().any(|upstream| *upstream != fresh[0]),
+        "Every session hashed to the same upstream: {:?}",
+        fresh
+    );
+
+    for upstream in upstreams {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Fetches the balancebeam_upstream_up gauge for each of the given upstreams.
+async fn upstreams_up(balancebeam: &BalanceBeam, upstreams: &[&str]) -> Vec<bool> {
+    let metrics = balancebeam
+        .get("/balancebeam/metrics")
+        .await
+        .expect("Error fetching metrics from balancebeam");
+    upstreams
+        .iter()
+        .map(|upstream| {
+            let series = format!("balancebeam_upstream_up{{upstream=\"{}\"}} ", upstream);
+            let line = metrics
+                .lines()
+                .find(|line| line.starts_with(&series))
+                .unwrap_or_else(|| panic!("No upstream_up gauge for {}", upstream));
+            line.ends_with(" 1")
+        })
+        .collect()
+}
+
+/// With --wait-for-healthy-on-startup, upstreams that are down when balancebeam starts should be
+/// out of rotation before the first request arrives.
+#[tokio::test]
+async fn test_wait_for_healthy_on_startup() {
+    init_logging();
+    let healthy = EchoServer::new().await;
+    let dead = [ReservedAddress::new(), ReservedAddress::new()];
+    let upstreams = [healthy.address.as_str(), &dead[0].address, &dead[1].address];
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstreams,
+        &["--wait-for-healthy-on-startup", "--enable-admin-endpoints"],
+    )
+    .await;
+
+    // Connections wait in the listen backlog until the health checks are done, so the very first
+    // request should already see the dead upstreams out of rotation
+    assert_eq!(
+        upstreams_up(&balancebeam, &upstreams).await,
+        vec![true, false, false]
+    );
+    for i in 0..5 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    // The startup health check plus the 5 requests
+    assert_eq!(Box::new(healthy).stop().await, 6);
+    log::info!("All done :)");
+}
+
+/// If the startup health checks take longer than --startup-timeout, balancebeam should start
+/// serving with every upstream in rotation.
+#[tokio::test]
+async fn test_startup_timeout() {
+    init_logging();
+    let healthy = EchoServer::new().await;
+    // Accepts the health check connection but never answers it
+    let stuck = RawServer::new(|stream| async move {
+        let _stream = stream;
+        sleep(Duration::from_secs(60)).await;
+    })
+    .await;
+    let upstreams = [stuck.address.as_str(), healthy.address.as_str()];
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstreams,
+        &[
+            "--wait-for-healthy-on-startup",
+            "--startup-timeout",
+            "1",
+            "--enable-admin-endpoints",
+        ],
+    )
+    .await;
+
+    assert_eq!(
+        upstreams_up(&balancebeam, &upstreams).await,
+        vec![true, true]
+    );
+
+    drop(balancebeam);
+    Box::new(stuck).stop().await;
+    Box::new(healthy).stop().await;
+    log::info!("All done :)");
+}
+
+/// With every upstream down, the 503 should say how many upstreams are healthy and when the next
+/// health check runs, but only with --verbose-errors.
+#[tokio::test]
+async fn test_no_healthy_upstreams_response() {
+    init_logging();
+    let dead = [ReservedAddress::new(), ReservedAddress::new()];
+    let upstreams = [dead[0].address.as_str(), dead[1].address.as_str()];
+    let args = [
+        "--wait-for-healthy-on-startup",
+        "--active-health-check-interval",
+        "30",
+    ];
+
+    let balancebeam =
+        BalanceBeam::new_with_args(&upstreams, &[&args[..], &["--verbose-errors"]].concat()).await;
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(
+        response
+            .headers()
+            .get("x-balancebeam-healthy-upstreams")
+            .map(|value| value.to_str().unwrap()),
+        Some("0")
+    );
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("ERROR balancebeam > No healthy upstreams for route")));
+    let body = response.text().await.unwrap();
+    let seconds: u64 = body
+        .split("Next health check in ")
+        .nth(1)
+        .and_then(|rest| rest.split('s').next())
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or_else(|| panic!("Body doesn't say when the next check is: {:?}", body));
+    assert!((1..=30).contains(&seconds), "Body: {:?}", body);
+    drop(balancebeam);
+
+    let balancebeam = BalanceBeam::new_with_args(&upstreams, &args).await;
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 503);
+    assert!(!response
+        .headers()
+        .contains_key("x-balancebeam-healthy-upstreams"));
+
+    log::info!("All done :)");
+}
+
+/// Drive more traffic than the upstreams are allowed to take and make sure it's spread over them
+/// until each is at its cap, after which the excess is shed with a 503 that says why.
+#[tokio::test]
+async fn test_max_requests_per_upstream_per_second() {
+    init_logging();
+    let upstreams = [EchoServer::new().await, EchoServer::new().await];
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address, &upstreams[1].address],
+        &[
+            "--max-requests-per-upstream-per-second",
+            "5",
+            "--verbose-errors",
+        ],
+    )
+    .await;
+
+    // Send the whole burst at once, so that as little time as possible passes for the caps to
+    // refill in
+    let start = std::time::Instant::now();
+    let requests: Vec<_> = (0..40)
+        .map(|i| {
+            let url = format!("http://{}/request-{}", balancebeam.address, i);
+            tokio::spawn(async move {
+                let response = reqwest::get(url)
+                    .await
+                    .expect("Error sending request to balancebeam");
+                let status = response.status().as_u16();
+                (status, response.text().await.unwrap())
+            })
+        })
+        .collect();
+    let mut statuses = Vec::new();
+    for request in requests {
+        let (status, body) = request.await.expect("Request task panicked");
+        if status == 503 {
+            assert_eq!(body, "All upstreams are at their request rate cap.\n");
+        }
+        statuses.push(status);
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    assert!(
+        statuses
+            .iter()
+            .all(|status| *status == 200 || *status == 503),
+        "Unexpected statuses: {:?}",
+        statuses
+    );
+    // A request is only shed once every upstream is out of tokens, so both bursts of 5 get used
+    let successes = statuses.iter().filter(|status| **status == 200).count();
+    assert!(successes >= 10, "Only {} requests were served", successes);
+    let allowed = 5 + (5.0 * elapsed).ceil() as usize;
+    if 2 * allowed < statuses.len() {
+        assert!(successes < statuses.len(), "No requests were shed");
+        let output = balancebeam.output();
+        assert!(output
+            .iter()
+            .any(|line| line.contains("is at its request rate cap")));
+    }
+    assert!(!balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("No healthy upstreams")));
+
+    let mut served = 0;
+    for upstream in upstreams {
+        let requests = Box::new(upstream).stop().await;
+        assert!(
+            requests <= allowed,
+            "Upstream got {} requests in {:.2}s",
+            requests,
+            elapsed
+        );
+        served += requests;
+    }
+    assert_eq!(served, successes);
+    log::info!("All done :)");
+}
+
+/// The rate limit is checked before an upstream is picked, so a client that's over its limit
+/// shouldn't get balancebeam to open upstream connections it would never send anything on.
+#[tokio::test]
+async fn test_rate_limited_requests_open_no_upstream_connections() {
+    init_logging();
+    let (upstream, requests) = counting_upstream(false).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "2",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    for i in 0..6 {
+        // A new client connection every time, so every request would need an upstream of its own
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), if i < 2 { 200 } else { 429 });
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+    assert_eq!(upstream.peer_addresses().len(), 2);
+
+    drop(balancebeam);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Requests turned away with a 429 or 413 never reach an upstream, so they shouldn't use up its
+/// request rate cap and get other clients' requests shed.
+#[tokio::test]
+async fn test_rejected_requests_leave_upstream_rate_cap() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&format!("{}#max_body=10", upstream.address)],
+        &[
+            "--max-requests-per-upstream-per-second",
+            "2",
+            "--rate-limit-rate",
+            "0.01",
+            "--rate-limit-burst",
+            "1",
+            "--real-ip-header",
+            "CF-Connecting-IP",
+            "--trusted-proxy",
+            "127.0.0.0/8",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let status_of = |client_ip: String, body: &'static str| {
+        let request = client
+            .post(format!("http://{}/", balancebeam.address))
+            .header("CF-Connecting-IP", client_ip)
+            .body(body);
+        async move {
+            request
+                .send()
+                .await
+                .expect("Error sending request to balancebeam")
+                .status()
+                .as_u16()
+        }
+    };
+
+    assert_eq!(status_of("203.0.113.1".to_string(), "").await, 200);
+    for _ in 0..5 {
+        assert_eq!(status_of("203.0.113.1".to_string(), "").await, 429);
+    }
+    for i in 0..5 {
+        let client_ip = format!("203.0.113.{}", 10 + i);
+        assert_eq!(status_of(client_ip, "far more than ten bytes").await, 413);
+    }
+    // The upstream has only taken one of its two requests this second
+    assert_eq!(status_of("203.0.113.2".to_string(), "").await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// With round-robin selection, new connections should take turns among the upstreams.
+#[tokio::test]
+async fn test_round_robin_selection() {
+    init_logging();
+    let upstreams = [
+        EchoServer::new().await,
+        EchoServer::new().await,
+        EchoServer::new().await,
+    ];
+    let addresses: Vec<&str> = upstreams
+        .iter()
+        .map(|upstream| upstream.address.as_str())
+        .collect();
+    let balancebeam =
+        BalanceBeam::new_with_args(&addresses, &["--lb-algorithm", "round-robin"]).await;
+
+    for i in 0..9 {
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    for upstream in upstreams {
+        assert_eq!(Box::new(upstream).stop().await, 3);
+    }
+    log::info!("All done :)");
+}
+
+/// An upstream that counts the requests it answers, and hangs up after each one unless
+/// keep_alive is set.
+async fn counting_upstream(keep_alive: bool) -> (RawServer, Arc<AtomicUsize>) {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let handler_requests = requests.clone();
+    let upstream = RawServer::new(move |mut stream| {
+        let requests = handler_requests.clone();
+        async move {
+            while read_request_head(&mut stream).await.is_some() {
+                requests.fetch_add(1, Ordering::SeqCst);
+                if stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                    .await
+                    .is_err()
+                    || !keep_alive
+                {
+                    return;
+                }
+            }
+        }
+    })
+    .await;
+    (upstream, requests)
+}
+
+/// Health checks to an upstream that keeps connections alive should all go over one connection,
+/// instead of a new one every round.
+#[tokio::test]
+async fn test_health_checks_reuse_probe_connection() {
+    init_logging();
+    let (upstream, requests) = counting_upstream(true).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "1"],
+    )
+    .await;
+
+    sleep(Duration::from_millis(3500)).await;
+    assert!(
+        requests.load(Ordering::SeqCst) >= 3,
+        "Only {} health checks ran",
+        requests.load(Ordering::SeqCst)
+    );
+    assert_eq!(upstream.peer_addresses().len(), 1);
+
+    drop(balancebeam);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// If the upstream closes the probe connection between rounds, the next check should dial a new
+/// one rather than counting the upstream as down.
+#[tokio::test]
+async fn test_health_checks_redial_dead_probe_connection() {
+    init_logging();
+    let (upstream, requests) = counting_upstream(false).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "1"],
+    )
+    .await;
+
+    sleep(Duration::from_millis(3500)).await;
+    let checks = requests.load(Ordering::SeqCst);
+    assert!(checks >= 3, "Only {} health checks ran", checks);
+    assert_eq!(upstream.peer_addresses().len(), checks);
+    let response_text = balancebeam
+        .get("/")
+        .await
+        .expect("Upstream should still be in rotation");
+    assert_eq!(response_text, "ok");
+
+    drop(balancebeam);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With weights of 3 and 1, the default selection should send about three quarters of new
+/// connections to the first upstream.
+#[tokio::test]
+async fn test_weighted_random_selection() {
+    init_logging();
+    let heavy = EchoServer::new().await;
+    let light = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[
+            &format!("{}=3", heavy.address),
+            &format!("{}=1", light.address),
+        ],
+        &["--active-health-check-interval", "600"],
+    )
+    .await;
+
+    for i in 0..80 {
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    drop(balancebeam);
+    let heavy_requests = Box::new(heavy).stop().await;
+    let light_requests = Box::new(light).stop().await;
+    assert_eq!(heavy_requests + light_requests, 80);
+    assert!(
+        (48..=72).contains(&heavy_requests),
+        "Weight 3 upstream got {} of 80 requests",
+        heavy_requests
+    );
+    log::info!("All done :)");
+}
+
+/// An upstream that answers with its name, except that it hangs up halfway through its response to
+/// /die.
+async fn named_upstream(name: &'static str) -> RawServer {
+    RawServer::new(move |mut stream| async move {
+        while let Some(head) = read_request_head(&mut stream).await {
+            if head.starts_with("GET /die ") {
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\npartial")
+                    .await;
+                return;
+            }
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n{}", name);
+            if stream.write_all(response.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    })
+    .await
+}
+
+/// Sends a request over a kept-alive client connection and returns the response body, which for
+/// named_upstream is the name of the upstream that answered.
+async fn get_over(conn: &mut tokio::net::TcpStream, path: &str) -> String {
+    use tokio::io::AsyncReadExt;
+    let request = format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", path);
+    conn.write_all(request.as_bytes()).await.unwrap();
+    read_request_head(conn)
+        .await
+        .expect("balancebeam hung up without responding");
+    let mut body = [0_u8; 1];
+    conn.read_exact(&mut body).await.unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+/// With --lb-algorithm least-connections, each new client connection should go to the upstream
+/// with the fewest connections in flight, and a connection whose upstream died mid-request should
+/// stop counting against it.
+#[tokio::test]
+async fn test_least_connections_selection() {
+    init_logging();
+    let upstreams = [named_upstream("a").await, named_upstream("b").await];
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address, &upstreams[1].address],
+        &[
+            "--lb-algorithm",
+            "least-connections",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+    let connect = || tokio::net::TcpStream::connect(&balancebeam.address);
+
+    // Two connections held open at once have to be on different upstreams
+    let mut first = connect().await.unwrap();
+    let first_upstream = get_over(&mut first, "/").await;
+    let mut second = connect().await.unwrap();
+    let second_upstream = get_over(&mut second, "/").await;
+    assert_ne!(first_upstream, second_upstream);
+
+    // Kill the first connection's exchange mid-response. Its upstream is then the less loaded one,
+    // so that's where the next connection should go, every time.
+    first
+        .write_all(b"GET /die HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let mut rest = Vec::new();
+    tokio::io::AsyncReadExt::read_to_end(&mut first, &mut rest)
+        .await
+        .unwrap();
+    for _ in 0..3 {
+        let mut next = connect().await.unwrap();
+        assert_eq!(get_over(&mut next, "/").await, first_upstream);
+    }
+
+    drop(second);
+    drop(balancebeam);
+    for upstream in upstreams {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// A failed connection to an upstream should only send that request elsewhere. Taking the upstream
+/// out of rotation is the health checker's call, so once it's reachable again it gets traffic
+/// again, without waiting for a health check.
+#[tokio::test]
+async fn test_failed_connect_keeps_upstream_in_rotation() {
+    init_logging();
+    let flaky_address = ReservedAddress::new();
+    let healthy = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&flaky_address.address, &healthy.address],
+        &[
+            "--lb-algorithm",
+            "round-robin",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    for i in 0..4 {
+        balancebeam
+            .get(&format!("/down-{}", i))
+            .await
+            .expect("Request should have been sent to the healthy upstream");
+    }
+    let flaky = EchoServer::new_at_reserved(flaky_address);
+    for i in 0..4 {
+        balancebeam
+            .get(&format!("/up-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    drop(balancebeam);
+    assert_eq!(Box::new(flaky).stop().await, 2);
+    assert_eq!(Box::new(healthy).stop().await, 6);
+    log::info!("All done :)");
+}
+
+/// With --passive-failure-threshold, an upstream that answers that many requests in a row with a
+/// 5xx should stop getting traffic right away, without waiting for a health check.
+#[tokio::test]
+async fn test_passive_failure_threshold() {
+    init_logging();
+    let failing = ErrorServer::new().await;
+    let healthy = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&failing.address, &healthy.address],
+        &[
+            "--lb-algorithm",
+            "round-robin",
+            "--passive-failure-threshold",
+            "3",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let mut errors = 0;
+    for i in 0..12 {
+        let status = reqwest::get(format!("http://{}/request-{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam")
+            .status()
+            .as_u16();
+        match status {
+            200 => {}
+            500 => errors += 1,
+            status => panic!("Unexpected status {}", status),
+        }
+    }
+    assert_eq!(errors, 3);
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("failed 3 requests in a row")));
+
+    drop(balancebeam);
+    assert_eq!(Box::new(failing).stop().await, 3);
+    assert_eq!(Box::new(healthy).stop().await, 9);
+    log::info!("All done :)");
+}
+
+/// With --ejected-recheck-interval, an ejected upstream that recovers should be back in rotation
+/// after about that interval, long before the next full round of health checks.
+#[tokio::test]
+async fn test_ejected_upstream_rechecked_sooner() {
+    init_logging();
+    let failing = ErrorServer::new().await;
+    let failing_address = failing.address.clone();
+    let healthy = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&failing.address, &healthy.address],
+        &[
+            "--lb-algorithm",
+            "round-robin",
+            "--passive-failure-threshold",
+            "1",
+            "--active-health-check-interval",
+            "600",
+            "--ejected-recheck-interval",
+            "1",
+        ],
+    )
+    .await;
+
+    log::info!("Getting the failing upstream ejected");
+    for i in 0..2 {
+        reqwest::get(format!("http://{}/request-{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("taking it out of rotation")));
+
+    log::info!("Bringing the upstream back healthy");
+    Box::new(failing).stop().await;
+    let restored = EchoServer::new_at_address(failing_address).await;
+    sleep(Duration::from_millis(2500)).await;
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("passed a recheck")));
+
+    for i in 0..4 {
+        let status = reqwest::get(format!("http://{}/after-{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam")
+            .status();
+        assert_eq!(status.as_u16(), 200);
+    }
+
+    drop(balancebeam);
+    // The recheck that brought it back, and its share of the requests after that
+    assert_eq!(Box::new(restored).stop().await, 3);
+    Box::new(healthy).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,700 @@
+mod common;
+
+use common::{init_logging, read_request_head, BalanceBeam, EchoServer, ErrorServer, Server};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+async fn setup() -> (BalanceBeam, EchoServer) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--enable-admin-endpoints"]).await;
+    (balancebeam, upstream)
+}
+
+/// Finds the value of a metric line such as `balancebeam_rate_limited_total 3` in a metrics scrape.
+fn metric_value<T: std::str::FromStr>(metrics: &str, series: &str) -> Option<T> {
+    metrics.lines().find_map(|line| {
+        let (name, value) = line.rsplit_once(' ')?;
+        if name == series {
+            value.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Client connections, and the upstream connections they hold on to, should show up in the active
+/// connection gauges while they're open, and in the peaks for good.
+#[tokio::test]
+async fn test_connection_gauges() {
+    let (balancebeam, upstream) = setup().await;
+
+    // Each connection keeps its upstream connection for its next request until it's closed
+    let mut held = Vec::new();
+    for i in 0..3 {
+        let mut conn = TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Could not connect to balancebeam");
+        let request = format!("GET /held-{} HTTP/1.1\r\nHost: localhost\r\n\r\n", i);
+        conn.write_all(request.as_bytes()).await.unwrap();
+        let head = read_request_head(&mut conn)
+            .await
+            .expect("balancebeam hung up without responding");
+        assert!(
+            head.starts_with("HTTP/1.1 200"),
+            "Unexpected response: {}",
+            head
+        );
+        held.push(conn);
+    }
+
+    // The connection the metrics are fetched over counts as a client connection too
+    let metrics = balancebeam
+        .get("/balancebeam/metrics")
+        .await
+        .expect("Error fetching metrics from balancebeam");
+    for (series, value) in [
+        ("balancebeam_client_connections_active", 4),
+        ("balancebeam_client_connections_peak", 4),
+        ("balancebeam_upstream_connections_active", 3),
+        ("balancebeam_upstream_connections_peak", 3),
+    ] {
+        assert_eq!(metric_value(&metrics, series), Some(value), "{}", series);
+    }
+
+    drop(held);
+    sleep(Duration::from_millis(200)).await;
+    let metrics = balancebeam
+        .get("/balancebeam/metrics")
+        .await
+        .expect("Error fetching metrics from balancebeam");
+    for (series, value) in [
+        ("balancebeam_client_connections_active", 1),
+        ("balancebeam_client_connections_peak", 4),
+        ("balancebeam_upstream_connections_active", 0),
+        ("balancebeam_upstream_connections_peak", 3),
+    ] {
+        assert_eq!(metric_value(&metrics, series), Some(value), "{}", series);
+    }
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Send some traffic, make sure it shows up in the metrics, then reset the metrics and make sure
+/// every counter goes back to zero.
+#[tokio::test]
+async fn test_metrics_reset() {
+    let (balancebeam, upstream) = setup().await;
+    let upstream_series = format!(
+        "balancebeam_upstream_requests_total{{upstream=\"{}\"}}",
+        upstream.address
+    );
+    let requests_series = format!(
+        "balancebeam_requests_total{{upstream=\"{}\",status=\"200\"}}",
+        upstream.address
+    );
+
+    for i in 0..3 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    log::info!("Checking that the requests were counted");
+    let metrics = balancebeam
+        .get("/balancebeam/metrics")
+        .await
+        .expect("Error fetching metrics from balancebeam");
+    assert_eq!(metric_value(&metrics, &requests_series), Some(3));
+    assert_eq!(metric_value(&metrics, &upstream_series), Some(3));
+    assert_eq!(
+        metric_value(&metrics, "balancebeam_responses_total{status=\"200\"}"),
+        Some(3)
+    );
+
+    log::info!("Resetting metrics");
+    balancebeam
+        .post("/balancebeam/metrics/reset", "")
+        .await
+        .expect("Error resetting metrics");
+
+    let metrics = balancebeam
+        .get("/balancebeam/metrics")
+        .await
+        .expect("Error fetching metrics from balancebeam");
+    assert_eq!(metric_value::<u64>(&metrics, &requests_series), None);
+    assert_eq!(
+        metric_value(&metrics, "balancebeam_request_duration_seconds_count"),
+        Some(0)
+    );
+    assert_eq!(
+        metric_value(&metrics, "balancebeam_rate_limited_total"),
+        Some(0)
+    );
+    assert_eq!(metric_value::<u64>(&metrics, &upstream_series), None);
+    assert_eq!(
+        metric_value::<u64>(&metrics, "balancebeam_responses_total{status=\"200\"}"),
+        None
+    );
+
+    log::info!("Checking that the admin requests never reached the upstream");
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// Send requests from several short-lived clients through a pool that keeps one idle connection,
+/// and make sure the pool metrics show the first client dialing and the rest reusing.
+#[tokio::test]
+async fn test_connection_pool_metrics() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--enable-admin-endpoints", "--max-idle-per-upstream", "1"],
+    )
+    .await;
+    let series = |name: &str| format!("{}{{upstream=\"{}\"}}", name, upstream.address);
+
+    for i in 0..4 {
+        // Each get() uses a new client connection, so the upstream connection goes back to the
+        // pool once the client hangs up
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let metrics = balancebeam
+        .get("/balancebeam/metrics")
+        .await
+        .expect("Error fetching metrics from balancebeam");
+    assert_eq!(
+        metric_value(&metrics, &series("balancebeam_upstream_dials_total")),
+        Some(1)
+    );
+    assert_eq!(
+        metric_value(
+            &metrics,
+            &series("balancebeam_upstream_connection_reuses_total")
+        ),
+        Some(3)
+    );
+    assert_eq!(
+        metric_value(
+            &metrics,
+            &series("balancebeam_upstream_connection_reuse_ratio")
+        ),
+        Some(0.75)
+    );
+    assert_eq!(
+        metric_value(&metrics, &series("balancebeam_upstream_connections_idle")),
+        Some(1)
+    );
+    assert_eq!(
+        metric_value(&metrics, &series("balancebeam_upstream_connections_in_use")),
+        Some(0)
+    );
+
+    // The echo server waits for open connections to close before it stops, so shut down
+    // balancebeam (and its pooled connection) first
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// With --prewarm-connections-per-upstream, each upstream's connections should be waiting in the
+/// pool by the time balancebeam starts serving, so that the first request doesn't have to dial.
+#[tokio::test]
+async fn test_prewarmed_connections() {
+    init_logging();
+    let upstreams = [EchoServer::new().await, EchoServer::new().await];
+    let upstream_addresses: Vec<&str> = upstreams
+        .iter()
+        .map(|upstream| upstream.address.as_str())
+        .collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_addresses,
+        &[
+            "--enable-admin-endpoints",
+            "--wait-for-healthy-on-startup",
+            "--max-idle-per-upstream",
+            "2",
+            "--prewarm-connections-per-upstream",
+            "2",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+    let series = |name: &str, upstream: &str| format!("{}{{upstream=\"{}\"}}", name, upstream);
+
+    let metrics = balancebeam
+        .get("/balancebeam/metrics")
+        .await
+        .expect("Error fetching metrics from balancebeam");
+    for upstream in &upstream_addresses {
+        assert_eq!(
+            metric_value(
+                &metrics,
+                &series("balancebeam_upstream_connections_idle", upstream)
+            ),
+            Some(2),
+            "Pooled connections to {}",
+            upstream
+        );
+        assert_eq!(
+            metric_value(
+                &metrics,
+                &series("balancebeam_upstream_dials_total", upstream)
+            ),
+            Some(2)
+        );
+    }
+
+    balancebeam
+        .get("/warm")
+        .await
+        .expect("Error sending request to balancebeam");
+    let metrics = balancebeam
+        .get("/balancebeam/metrics")
+        .await
+        .expect("Error fetching metrics from balancebeam");
+    let total = |name: &str| -> u64 {
+        upstream_addresses
+            .iter()
+            .filter_map(|upstream| metric_value::<u64>(&metrics, &series(name, upstream)))
+            .sum()
+    };
+    assert_eq!(total("balancebeam_upstream_connection_reuses_total"), 1);
+    assert_eq!(total("balancebeam_upstream_dials_total"), 4);
+
+    // The echo servers wait for open connections to close before they stop
+    drop(balancebeam);
+    for upstream in upstreams {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Requests should be counted by whether their upstream connection was freshly dialed or had
+/// carried a request before, whether that was for the same client or one before it in the pool.
+#[tokio::test]
+async fn test_new_and_reused_connection_request_metrics() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--enable-admin-endpoints",
+            "--max-idle-per-upstream",
+            "1",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+    let series = |name: &str| format!("{}{{upstream=\"{}\"}}", name, upstream.address);
+    // The body has to be read for the client to send another request over the same connection
+    let send = |client: &reqwest::Client, path: &str| {
+        let request = client
+            .get(format!("http://{}{}", balancebeam.address, path))
+            .send();
+        async {
+            request
+                .await
+                .expect("Error sending request to balancebeam")
+                .text()
+                .await
+                .unwrap();
+        }
+    };
+
+    // Two requests over one client connection: the first dials, the second reuses
+    let client = reqwest::Client::new();
+    for path in ["/first", "/second"] {
+        send(&client, path).await;
+    }
+    drop(client);
+    sleep(Duration::from_millis(100)).await;
+
+    // The next client gets the pooled connection, and one that comes along while it still holds
+    // it has to dial another
+    let holding = reqwest::Client::new();
+    send(&holding, "/pooled").await;
+    let another = reqwest::Client::new();
+    send(&another, "/dialed").await;
+
+    let metrics = balancebeam
+        .get("/balancebeam/metrics")
+        .await
+        .expect("Error fetching metrics from balancebeam");
+    assert_eq!(
+        metric_value(
+            &metrics,
+            &series("balancebeam_upstream_new_connection_requests_total")
+        ),
+        Some(2)
+    );
+    assert_eq!(
+        metric_value(
+            &metrics,
+            &series("balancebeam_upstream_reused_connection_requests_total")
+        ),
+        Some(2)
+    );
+    assert_eq!(
+        metric_value(&metrics, &series("balancebeam_upstream_dials_total")),
+        Some(2)
+    );
+    assert_eq!(
+        metric_value(
+            &metrics,
+            &series("balancebeam_upstream_connection_reuses_total")
+        ),
+        Some(1)
+    );
+
+    drop(holding);
+    drop(another);
+    drop(balancebeam);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Checks that every line of a scrape is a `# TYPE` comment or a sample in the Prometheus text
+/// exposition format, and that every sample belongs to the family declared before it. Returns the
+/// samples as (series, value) pairs.
+fn parse_exposition(metrics: &str) -> Vec<(String, f64)> {
+    let type_line =
+        regex::Regex::new(r"^# TYPE ([a-zA-Z_:][a-zA-Z0-9_:]*) (counter|gauge|histogram)$")
+            .unwrap();
+    let sample_line = regex::Regex::new(
+        r#"^([a-zA-Z_:][a-zA-Z0-9_:]*)(\{[a-zA-Z_][a-zA-Z0-9_]*="(?:[^"\\\n]|\\[\\"n])*"(?:,[a-zA-Z_][a-zA-Z0-9_]*="(?:[^"\\\n]|\\[\\"n])*")*\})? (\S+)$"#,
+    )
+    .unwrap();
+    let mut family: Option<(String, String)> = None;
+    let mut samples = Vec::new();
+    for line in metrics.lines() {
+        if let Some(captures) = type_line.captures(line) {
+            family = Some((captures[1].to_string(), captures[2].to_string()));
+            continue;
+        }
+        let captures = sample_line
+            .captures(line)
+            .unwrap_or_else(|| panic!("Invalid exposition line: {:?}", line));
+        let name = &captures[1];
+        let (family_name, family_type) = family.as_ref().expect("Sample before any # TYPE");
+        let suffixes: &[&str] = if family_type == "histogram" {
+            &["_bucket", "_sum", "_count"]
+        } else {
+            &[""]
+        };
+        assert!(
+            suffixes
+                .iter()
+                .any(|suffix| name == format!("{}{}", family_name, suffix)),
+            "{} doesn't belong to the {} family",
+            name,
+            family_name
+        );
+        let value: f64 = captures[3]
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid sample value: {:?}", line));
+        samples.push((
+            format!("{}{}", name, captures.get(2).map_or("", |m| m.as_str())),
+            value,
+        ));
+    }
+    samples
+}
+
+/// Scrape the metrics after sending traffic to a healthy and a failing upstream, and make sure the
+/// scrape is valid exposition text with the expected label sets.
+#[tokio::test]
+async fn test_labeled_metrics_exposition() {
+    init_logging();
+    let healthy = EchoServer::new().await;
+    let failing = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&healthy.address, &failing.address],
+        &["--enable-admin-endpoints"],
+    )
+    .await;
+
+    // Enough requests that both upstreams are all but certain to be picked at least once
+    for _ in 0..20 {
+        balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let metrics = balancebeam
+        .get("/balancebeam/metrics")
+        .await
+        .expect("Error fetching metrics from balancebeam");
+    let samples = parse_exposition(&metrics);
+    let value = |series: String| {
+        samples
+            .iter()
+            .find(|(name, _)| *name == series)
+            .map(|(_, value)| *value)
+    };
+
+    let healthy_ok = value(format!(
+        "balancebeam_requests_total{{upstream=\"{}\",status=\"200\"}}",
+        healthy.address
+    ))
+    .unwrap_or(0.0);
+    let failing_errors: f64 = samples
+        .iter()
+        .filter(|(name, _)| {
+            name.starts_with(&format!(
+                "balancebeam_requests_total{{upstream=\"{}\",status=",
+                failing.address
+            ))
+        })
+        .map(|(_, value)| value)
+        .sum();
+    assert_eq!(healthy_ok + failing_errors, 20.0);
+    assert!(healthy_ok > 0.0 && failing_errors > 0.0);
+
+    for upstream in [&healthy.address, &failing.address] {
+        assert_eq!(
+            value(format!(
+                "balancebeam_upstream_up{{upstream=\"{}\"}}",
+                upstream
+            )),
+            Some(1.0)
+        );
+    }
+
+    assert_eq!(
+        value("balancebeam_request_duration_seconds_count".to_string()),
+        Some(20.0)
+    );
+    assert_eq!(
+        value("balancebeam_request_duration_seconds_bucket{le=\"+Inf\"}".to_string()),
+        Some(20.0)
+    );
+    let buckets: Vec<f64> = samples
+        .iter()
+        .filter(|(name, _)| name.starts_with("balancebeam_request_duration_seconds_bucket"))
+        .map(|(_, value)| *value)
+        .collect();
+    assert!(
+        buckets.windows(2).all(|pair| pair[0] <= pair[1]),
+        "Buckets aren't cumulative: {:?}",
+        buckets
+    );
+
+    Box::new(healthy).stop().await;
+    Box::new(failing).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --log-events, a request that's forwarded and one that's rate limited should show up as
+/// the expected sequence of lifecycle events.
+#[tokio::test]
+async fn test_lifecycle_events() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--log-events",
+            "--rate-limit-rate",
+            "0.01",
+            "--rate-limit-burst",
+            "1",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    // Each request comes in over a connection of its own. The first one spends the only token.
+    for expected_status in [200, 429] {
+        let response = reqwest::get(format!("http://{}/", balancebeam.address))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), expected_status);
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    let events: Vec<String> = balancebeam
+        .output()
+        .iter()
+        .filter_map(|line| {
+            line.split_once("Event: ")
+                .map(|(_, event)| event.to_string())
+        })
+        .collect();
+    assert_eq!(
+        events,
+        [
+            "ConnectionAccepted { client: \"127.0.0.1\" }".to_string(),
+            format!(
+                "RequestForwarded {{ upstream: \"{}\", status: 200 }}",
+                upstream.address
+            ),
+            "ConnectionAccepted { client: \"127.0.0.1\" }".to_string(),
+            "RateLimited { client: \"127.0.0.1\" }".to_string(),
+        ]
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --enable-profiling, the pprof endpoint should capture a CPU profile of the proxy while it
+/// handles traffic, and answer with one that decodes as a pprof protobuf.
+#[tokio::test]
+async fn test_cpu_profile() {
+    use pprof::protos::Message;
+
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--enable-admin-endpoints", "--enable-profiling"],
+    )
+    .await;
+
+    let profile = {
+        let capture = reqwest::get(format!(
+            "http://{}/balancebeam/debug/pprof/profile?seconds=1",
+            balancebeam.address
+        ));
+        // Give the profiler something to sample
+        let traffic = async {
+            for i in 0..50 {
+                balancebeam
+                    .get(&format!("/request-{}", i))
+                    .await
+                    .expect("Error sending request to balancebeam");
+            }
+        };
+        let (response, ()) = tokio::join!(capture, traffic);
+        let response = response.expect("Error fetching profile from balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        response.bytes().await.unwrap()
+    };
+    assert!(!profile.is_empty());
+    let profile = pprof::protos::Profile::decode(profile.as_ref())
+        .expect("Profile should be a pprof protobuf");
+    assert!(!profile.sample_type.is_empty());
+    assert!(!profile.string_table.is_empty());
+
+    let response = reqwest::get(format!(
+        "http://{}/balancebeam/debug/pprof/profile?seconds=forever",
+        balancebeam.address
+    ))
+    .await
+    .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 400);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Profiling is opt-in, even with the other admin endpoints enabled.
+#[tokio::test]
+async fn test_cpu_profile_disabled_by_default() {
+    let (balancebeam, upstream) = setup().await;
+    let response = reqwest::get(format!(
+        "http://{}/balancebeam/debug/pprof/profile?seconds=1",
+        balancebeam.address
+    ))
+    .await
+    .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 404);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --metrics-bind, the metrics should be served at /metrics on their own listener, without
+/// the admin endpoints being enabled on the proxy's.
+#[tokio::test]
+async fn test_metrics_listener() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--metrics-bind",
+            "127.0.0.1:0",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+    let metrics_address = balancebeam
+        .logged_address("Serving metrics on ")
+        .await
+        .expect("balancebeam didn't log its metrics address");
+
+    for i in 0..3 {
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let response = reqwest::get(format!("http://{}/metrics", metrics_address))
+        .await
+        .expect("Error fetching metrics from the metrics listener");
+    assert_eq!(response.status().as_u16(), 200);
+    let metrics = response.text().await.unwrap();
+    assert_eq!(
+        metric_value(&metrics, "balancebeam_requests_served_total"),
+        Some(3)
+    );
+    assert_eq!(
+        metric_value(
+            &metrics,
+            &format!(
+                "balancebeam_upstream_requests_total{{upstream=\"{}\"}}",
+                upstream.address
+            )
+        ),
+        Some(3)
+    );
+    assert_eq!(
+        metric_value(&metrics, "balancebeam_responses_total{status=\"200\"}"),
+        Some(3)
+    );
+    assert_eq!(
+        metric_value(&metrics, "balancebeam_rate_limited_total"),
+        Some(0)
+    );
+    assert_eq!(
+        metric_value(&metrics, "balancebeam_active_upstreams"),
+        Some(1)
+    );
+
+    let response = reqwest::get(format!("http://{}/other", metrics_address))
+        .await
+        .expect("Error sending request to the metrics listener");
+    assert_eq!(response.status().as_u16(), 404);
+
+    // The proxy's own listener still forwards everything, admin paths included
+    let forwarded = balancebeam
+        .get("/balancebeam/metrics")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(forwarded.contains("GET /balancebeam/metrics HTTP/1.1"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,48 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::io::Read;
+
+/// Ask for a response with several weighted encodings, one of which (br) balancebeam doesn't
+/// support, and make sure we get the best supported one back.
+#[tokio::test]
+async fn test_compression_negotiation() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--compress-responses"]).await;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!("http://{}/compressed", balancebeam.address))
+        .header("accept-encoding", "br;q=1.0, gzip;q=0.5, deflate;q=0.8")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(
+        response
+            .headers()
+            .get("content-encoding")
+            .map(|value| value.to_str().unwrap()),
+        Some("deflate")
+    );
+    let body = response.bytes().await.expect("Error reading response body");
+    let mut response_text = String::new();
+    flate2::read::DeflateDecoder::new(&body[..])
+        .read_to_string(&mut response_text)
+        .expect("Response body is not valid deflate data");
+    assert!(response_text.contains("GET /compressed HTTP/1.1"));
+
+    log::info!("Making sure a client that doesn't ask for compression gets plain text");
+    let response = client
+        .get(format!("http://{}/uncompressed", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response.headers().get("content-encoding").is_none());
+    let response_text = response.text().await.expect("Error reading response body");
+    assert!(response_text.contains("GET /uncompressed HTTP/1.1"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
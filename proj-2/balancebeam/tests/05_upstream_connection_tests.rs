@@ -0,0 +1,864 @@
+mod common;
+
+use common::{init_logging, read_request_head, BalanceBeam, EchoServer, RawServer, Server};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+/// Answers every request on the connection with an empty 200 OK.
+async fn respond_ok(mut stream: tokio::net::TcpStream) {
+    while read_request_head(&mut stream).await.is_some() {
+        if stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Make sure upstream connections originate from the configured source address. Any 127.x.y.z
+/// address is local on Linux, so we can pick one that differs from the default 127.0.0.1.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_upstream_source_ip() {
+    init_logging();
+    let upstream = RawServer::new(respond_ok).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--upstream-source-ip", "127.0.0.2"])
+            .await;
+
+    balancebeam
+        .get("/")
+        .await
+        .expect("Error sending request to balancebeam");
+
+    let peers = upstream.peer_addresses();
+    assert!(!peers.is_empty(), "Upstream never received a connection");
+    for peer in peers {
+        assert_eq!(peer.ip().to_string(), "127.0.0.2");
+    }
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// A source address that doesn't belong to this host should be rejected at startup.
+#[tokio::test]
+async fn test_invalid_upstream_source_ip() {
+    init_logging();
+    let upstream = RawServer::new(respond_ok).await;
+    // 192.0.2.0/24 is reserved for documentation, so it won't be assigned to any interface
+    let mut balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--upstream-source-ip", "192.0.2.1"])
+            .await;
+    assert!(
+        balancebeam.has_exited(),
+        "balancebeam should refuse to start with a non-local source address"
+    );
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// With --upstream-connect-timeout-ms, an upstream whose connections never get set up should cost
+/// a request that long at most before it's sent to another upstream instead.
+#[tokio::test]
+async fn test_upstream_connect_timeout() {
+    init_logging();
+    // A listener that never accepts, with its backlog already full, leaves new connections to it
+    // hanging rather than refused
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let stuck = socket.listen(0).unwrap();
+    let stuck_address = stuck.local_addr().unwrap().to_string();
+    let _backlog_filler = TcpStream::connect(&stuck_address).await.unwrap();
+    let healthy = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&stuck_address, &healthy.address],
+        &[
+            "--lb-algorithm",
+            "round-robin",
+            "--upstream-connect-timeout-ms",
+            "300",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let mut slowest = Duration::ZERO;
+    for i in 0..4 {
+        let start = Instant::now();
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        slowest = slowest.max(start.elapsed());
+    }
+    // Every other request waited out the timeout on the stuck upstream first
+    assert!(
+        (Duration::from_millis(300)..Duration::from_secs(2)).contains(&slowest),
+        "Slowest request took {:?}",
+        slowest
+    );
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("connection timed out after 300ms")));
+
+    drop(balancebeam);
+    assert_eq!(Box::new(healthy).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// Starts balancebeam with a single upstream that accepts only one client connection at a time,
+/// and occupies that slot with a client connection that sends one request and then sits idle.
+async fn setup_saturated(
+    queue_timeout_ms: &str,
+    extra_args: &[&str],
+) -> (BalanceBeam, EchoServer, TcpStream) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            &[
+                "--max-connections-per-upstream",
+                "1",
+                "--queue-timeout-ms",
+                queue_timeout_ms,
+            ],
+            extra_args,
+        ]
+        .concat(),
+    )
+    .await;
+    let mut hog = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    // The upstream slot is claimed once balancebeam has a request to route
+    hog.write_all(b"GET /hog HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .expect("Could not send request to balancebeam");
+    // Give balancebeam a moment to forward the request and claim the upstream slot
+    sleep(Duration::from_millis(200)).await;
+    (balancebeam, upstream, hog)
+}
+
+/// Saturate the only upstream, then send another request. Freeing the slot before the queue
+/// timeout elapses should let the queued request through rather than failing it with a 503.
+#[tokio::test]
+async fn test_queued_request_proceeds_when_slot_frees() {
+    let (balancebeam, upstream, hog) = setup_saturated("3000", &[]).await;
+
+    tokio::spawn(async move {
+        sleep(Duration::from_millis(500)).await;
+        log::info!("Closing the connection that holds the upstream slot");
+        drop(hog);
+    });
+
+    let start = Instant::now();
+    let response_text = balancebeam
+        .get("/queued")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /queued HTTP/1.1"));
+    assert!(
+        start.elapsed() >= Duration::from_millis(400),
+        "Request should have waited for the upstream slot to free up"
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// If the slot never frees up, the queued request should get a 503 once the queue timeout elapses,
+/// blamed on the upstreams being busy rather than down.
+#[tokio::test]
+async fn test_queued_request_times_out() {
+    let (balancebeam, upstream, hog) = setup_saturated("300", &["--verbose-errors"]).await;
+
+    let response = reqwest::get(format!("http://{}/queued", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(
+        response.text().await.unwrap(),
+        "All upstreams are at capacity.\n"
+    );
+    let output = balancebeam.output();
+    assert!(output
+        .iter()
+        .any(|line| line.contains("stayed at its connection limit")));
+    assert!(!output
+        .iter()
+        .any(|line| line.contains("No healthy upstreams")));
+
+    // The echo server waits for open connections to close before it stops
+    drop(hog);
+    // Only the hog's request should have reached the upstream
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// With --max-total-upstream-connections, once that many client connections are being proxied,
+/// another one should be turned away even though there's an upstream with nothing to do, and let
+/// through again once a connection frees up.
+#[tokio::test]
+async fn test_max_total_upstream_connections() {
+    init_logging();
+    let upstreams = [EchoServer::new().await, EchoServer::new().await];
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address, &upstreams[1].address],
+        &[
+            "--max-total-upstream-connections",
+            "1",
+            "--queue-timeout-ms",
+            "300",
+            "--verbose-errors",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+    let mut hog = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    hog.write_all(b"GET /hog HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .expect("Could not send request to balancebeam");
+    sleep(Duration::from_millis(200)).await;
+
+    let start = Instant::now();
+    let response = reqwest::get(format!("http://{}/throttled", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 503);
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    assert_eq!(
+        response.text().await.unwrap(),
+        "Too many upstream connections are in use.\n"
+    );
+
+    drop(hog);
+    sleep(Duration::from_millis(100)).await;
+    let response_text = balancebeam
+        .get("/after")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /after HTTP/1.1"));
+
+    drop(balancebeam);
+    let mut served = 0;
+    for upstream in upstreams {
+        served += Box::new(upstream).stop().await;
+    }
+    assert_eq!(served, 2);
+    log::info!("All done :)");
+}
+
+/// Answers a single request and then closes the connection without saying so in the response, the
+/// way an upstream does when its keep-alive timeout expires between requests.
+async fn respond_ok_once(mut stream: tokio::net::TcpStream) {
+    if read_request_head(&mut stream).await.is_some() {
+        let _ = stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .await;
+    }
+}
+
+/// Sends a bodiless request over an existing client connection and returns the response status.
+/// Responses from respond_ok_once carry a 2-byte body, which is read and discarded.
+async fn send_on_connection(conn: &mut TcpStream, method: &str) -> u16 {
+    let request = format!(
+        "{} / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n",
+        method
+    );
+    conn.write_all(request.as_bytes()).await.unwrap();
+    let head = read_request_head(conn)
+        .await
+        .expect("balancebeam hung up without responding");
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    let content_length: usize = head
+        .lines()
+        .find_map(|line| {
+            line.to_lowercase()
+                .strip_prefix("content-length: ")
+                .map(str::to_string)
+        })
+        .map(|value| value.parse().unwrap())
+        .unwrap_or(0);
+    let mut body = vec![0_u8; content_length];
+    conn.read_exact(&mut body).await.unwrap();
+    status
+}
+
+/// If the upstream closes a connection between requests, the next idempotent request from the same
+/// client should be retried over a fresh connection instead of failing.
+#[tokio::test]
+async fn test_stale_upstream_connection_retried() {
+    init_logging();
+    let upstream = RawServer::new(respond_ok_once).await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    assert_eq!(send_on_connection(&mut conn, "GET").await, 200);
+    // Give the upstream's close time to reach balancebeam
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(send_on_connection(&mut conn, "GET").await, 200);
+
+    drop(conn);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// A non-idempotent request might have been acted on, so it must not be retried on a fresh
+/// connection.
+#[tokio::test]
+async fn test_stale_upstream_connection_not_retried_for_post() {
+    init_logging();
+    let upstream = RawServer::new(respond_ok_once).await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    assert_eq!(send_on_connection(&mut conn, "POST").await, 200);
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(send_on_connection(&mut conn, "POST").await, 502);
+
+    drop(conn);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A pooled connection the upstream has since closed should be noticed and skipped before it's
+/// handed to a later client. Otherwise a POST, which can't be retried, would get a 502.
+#[tokio::test]
+async fn test_closed_pooled_connection_not_reused() {
+    init_logging();
+    let upstream = RawServer::new(respond_ok_once).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-idle-per-upstream",
+            "1",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    assert_eq!(send_on_connection(&mut conn, "GET").await, 200);
+    // The upstream connection goes back to the pool once the client hangs up, and the upstream's
+    // close reaches balancebeam while it sits there
+    drop(conn);
+    sleep(Duration::from_millis(200)).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    assert_eq!(send_on_connection(&mut conn, "POST").await, 200);
+    assert!(!balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("closed idle connection")));
+
+    drop(conn);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// A minimal forward proxy that supports CONNECT only. Every CONNECT target is recorded in
+/// `targets` before the tunnel is opened.
+async fn connect_proxy(targets: Arc<Mutex<Vec<String>>>) -> RawServer {
+    RawServer::new(move |mut stream| {
+        let targets = targets.clone();
+        async move {
+            let head = match read_request_head(&mut stream).await {
+                Some(head) => head,
+                None => return,
+            };
+            let target = match head.strip_prefix("CONNECT ") {
+                Some(rest) => rest.split(' ').next().unwrap().to_string(),
+                None => {
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n")
+                        .await;
+                    return;
+                }
+            };
+            targets.lock().unwrap().push(target.clone());
+            let mut upstream = match TcpStream::connect(&target).await {
+                Ok(upstream) => upstream,
+                Err(_) => {
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n")
+                        .await;
+                    return;
+                }
+            };
+            if stream
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await
+                .is_ok()
+            {
+                let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+            }
+        }
+    })
+    .await
+}
+
+/// With a forward proxy configured, upstream connections should be tunneled through it.
+#[tokio::test]
+async fn test_upstream_http_proxy() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let targets = Arc::new(Mutex::new(Vec::new()));
+    let proxy = connect_proxy(targets.clone()).await;
+    let proxy_url = format!("http://{}", proxy.address);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--upstream-http-proxy", &proxy_url])
+            .await;
+
+    let response_text = balancebeam
+        .get("/through-the-proxy")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /through-the-proxy HTTP/1.1"));
+    assert_eq!(*targets.lock().unwrap(), vec![upstream.address.clone()]);
+
+    // Closing balancebeam tears down the tunnel, which the echo server waits for before stopping
+    drop(balancebeam);
+    assert_eq!(Box::new(proxy).stop().await, 1);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Answers every request on the connection, asking to close the connection after each response if
+/// `close` is set. The connection is left open either way, so that a proxy ignoring the request to
+/// close could go on using it.
+async fn respond_ok_keep_open(mut stream: TcpStream, close: bool) {
+    let connection = if close { "close" } else { "keep-alive" };
+    while read_request_head(&mut stream).await.is_some() {
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nConnection: {}\r\nContent-Length: 2\r\n\r\nok",
+            connection
+        );
+        if stream.write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// An upstream that answers with Connection: close should get a fresh connection for the client's
+/// next request.
+#[tokio::test]
+async fn test_upstream_connection_close_honored() {
+    init_logging();
+    let upstream = RawServer::new(|stream| respond_ok_keep_open(stream, true)).await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    assert_eq!(send_on_connection(&mut conn, "GET").await, 200);
+    assert_eq!(send_on_connection(&mut conn, "GET").await, 200);
+
+    drop(conn);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// With keep-alive, the client's requests should share one upstream connection.
+#[tokio::test]
+async fn test_upstream_keep_alive_reused() {
+    init_logging();
+    let upstream = RawServer::new(|stream| respond_ok_keep_open(stream, false)).await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    assert_eq!(send_on_connection(&mut conn, "GET").await, 200);
+    assert_eq!(send_on_connection(&mut conn, "GET").await, 200);
+
+    drop(conn);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Reads a request, then hangs up a moment later without answering it.
+async fn hang_up_after_request(mut stream: TcpStream) {
+    read_request_head(&mut stream).await;
+    sleep(Duration::from_millis(300)).await;
+}
+
+/// If the client hangs up while the upstream is closing the connection too, that's an ordinary
+/// teardown and shouldn't be logged as an error.
+#[tokio::test]
+async fn test_simultaneous_close_not_an_error() {
+    init_logging();
+    let upstream = RawServer::new(hang_up_after_request).await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    drop(conn);
+    sleep(Duration::from_millis(600)).await;
+
+    let output = balancebeam.output();
+    assert!(
+        output.iter().any(|line| line.contains("both closed")),
+        "Simultaneous close wasn't noticed"
+    );
+    let errors: Vec<&String> = output
+        .iter()
+        .filter(|line| line.contains("ERROR") || line.contains("WARN"))
+        .collect();
+    assert!(errors.is_empty(), "Unexpected errors logged: {:?}", errors);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Finds the value of a metric line such as `balancebeam_upstream_requests_total{...} 3` in a
+/// metrics scrape.
+fn metric_value(metrics: &str, series: &str) -> Option<u64> {
+    metrics.lines().find_map(|line| {
+        let (name, value) = line.rsplit_once(' ')?;
+        if name == series {
+            value.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// When a stale upstream connection is retried over a fresh one to a different upstream, the
+/// request should be counted against the upstream that actually served it, not the one the client
+/// connection started out on.
+#[tokio::test]
+async fn test_reconnect_attributed_to_serving_upstream() {
+    init_logging();
+    let upstreams = vec![
+        RawServer::new(respond_ok_once).await,
+        RawServer::new(respond_ok_once).await,
+    ];
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address, &upstreams[1].address],
+        &["--enable-admin-endpoints"],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    assert_eq!(send_on_connection(&mut conn, "GET").await, 200);
+    let mut upstreams = upstreams.into_iter();
+    let (first, second) = (upstreams.next().unwrap(), upstreams.next().unwrap());
+    let (first, second) = if first.peer_addresses().is_empty() {
+        (second, first)
+    } else {
+        (first, second)
+    };
+    let (first_address, second_address) = (first.address.clone(), second.address.clone());
+    // Take down the upstream that served the first request, so that the retry of the second
+    // request has to go to the other one
+    assert_eq!(Box::new(first).stop().await, 1);
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(send_on_connection(&mut conn, "GET").await, 200);
+    drop(conn);
+
+    let metrics = balancebeam
+        .get("/balancebeam/metrics")
+        .await
+        .expect("Error fetching metrics from balancebeam");
+    for (address, count) in [(&first_address, 1), (&second_address, 1)] {
+        assert_eq!(
+            metric_value(
+                &metrics,
+                &format!(
+                    "balancebeam_upstream_requests_total{{upstream=\"{}\"}}",
+                    address
+                )
+            ),
+            Some(count),
+            "Wrong request count for {}",
+            address
+        );
+        assert_eq!(
+            metric_value(
+                &metrics,
+                &format!(
+                    "balancebeam_requests_total{{upstream=\"{}\",status=\"200\"}}",
+                    address
+                )
+            ),
+            Some(count),
+            "Wrong response count for {}",
+            address
+        );
+    }
+
+    assert_eq!(Box::new(second).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Once a client connection has transferred --max-connection-bytes, it should be closed after the
+/// response that took it over the limit.
+#[tokio::test]
+async fn test_max_connection_bytes() {
+    init_logging();
+    let upstream = RawServer::new(respond_ok).await;
+    // Each exchange below is a 54-byte request and a 38-byte response, so the fourth one takes the
+    // connection past 300 bytes
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-connection-bytes", "300"]).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for _ in 0..4 {
+        assert_eq!(send_on_connection(&mut conn, "GET").await, 200);
+    }
+    // balancebeam should hang up rather than answer another request
+    let _ = conn
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n")
+        .await;
+    let mut buf = [0_u8; 1];
+    assert!(matches!(conn.read(&mut buf).await, Ok(0) | Err(_)));
+
+    // A new connection starts with a fresh allowance
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    assert_eq!(send_on_connection(&mut conn, "GET").await, 200);
+
+    drop(conn);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// A connection stuck waiting on an upstream that never answers (with no read timeout to catch it)
+/// should be torn down by the reaper once it's been open for --connection-hard-limit.
+#[tokio::test]
+async fn test_connection_hard_limit() {
+    init_logging();
+    let stuck = RawServer::new(|mut stream| async move {
+        read_request_head(&mut stream).await;
+        // Never answers, but holds the connection open until balancebeam lets go of it
+        let mut buf = [0_u8; 1];
+        let _ = stream.read(&mut buf).await;
+    })
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&stuck.address],
+        &[
+            "--connection-hard-limit",
+            "1",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let started = Instant::now();
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"GET /wedged HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buf = [0_u8; 1];
+    let read = tokio::time::timeout(Duration::from_secs(5), conn.read(&mut buf))
+        .await
+        .expect("The wedged connection wasn't aborted");
+    assert!(matches!(read, Ok(0) | Err(_)), "Unexpected response");
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("over --connection-hard-limit")));
+
+    drop(balancebeam);
+    Box::new(stuck).stop().await;
+    log::info!("All done :)");
+}
+
+/// Reads a request and hangs up without sending back any of a response.
+async fn hang_up_unanswered(mut stream: tokio::net::TcpStream) {
+    read_request_head(&mut stream).await;
+}
+
+/// Reads a request with the 5-byte body status_of sends, and hangs up partway through the response.
+/// Reading the whole request first makes sure the hang-up is a clean close rather than a reset,
+/// which could discard the partial response before balancebeam reads it.
+async fn hang_up_mid_response(mut stream: tokio::net::TcpStream) {
+    if read_request_head(&mut stream).await.is_some() {
+        let mut body = [0_u8; 5];
+        if stream.read_exact(&mut body).await.is_ok() {
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Le").await;
+        }
+    }
+}
+
+/// Sends a request on a fresh client connection, so that round robin moves on to the next upstream
+/// each time, and returns the response status.
+async fn status_of(balancebeam: &BalanceBeam, method: reqwest::Method, path: &str) -> u16 {
+    reqwest::Client::new()
+        .request(method, format!("http://{}{}", balancebeam.address, path))
+        .body("hello")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// With --max-retries, a request whose upstream hangs up without answering should be sent on to
+/// another upstream, POST or not, while without it the client gets a 502.
+#[tokio::test]
+async fn test_failed_request_retried_on_other_upstream() {
+    init_logging();
+    let failing = RawServer::new(hang_up_unanswered).await;
+    let healthy = EchoServer::new().await;
+    let upstreams = [failing.address.as_str(), healthy.address.as_str()];
+    let args = [
+        "--lb-algorithm",
+        "round-robin",
+        "--active-health-check-interval",
+        "600",
+    ];
+
+    let balancebeam =
+        BalanceBeam::new_with_args(&upstreams, &[&args[..], &["--max-retries", "1"]].concat())
+            .await;
+    for i in 0..4 {
+        assert_eq!(
+            status_of(&balancebeam, reqwest::Method::GET, &format!("/get-{}", i)).await,
+            200
+        );
+        assert_eq!(
+            status_of(&balancebeam, reqwest::Method::POST, &format!("/post-{}", i)).await,
+            200
+        );
+    }
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("retrying request against") && line.contains(&healthy.address)));
+    drop(balancebeam);
+
+    let balancebeam = BalanceBeam::new_with_args(&upstreams, &args).await;
+    let mut statuses = Vec::new();
+    for i in 0..4 {
+        statuses.push(status_of(&balancebeam, reqwest::Method::GET, &format!("/{}", i)).await);
+    }
+    assert!(statuses.contains(&502), "Statuses: {:?}", statuses);
+    assert!(statuses.contains(&200), "Statuses: {:?}", statuses);
+    drop(balancebeam);
+
+    // Every request went to the healthy upstream in the end when retrying, and half of them did
+    // without
+    assert_eq!(Box::new(healthy).stop().await, 8 + 2);
+    Box::new(failing).stop().await;
+    log::info!("All done :)");
+}
+
+/// Once an upstream has started sending a response, the request may well have been acted on, so it
+/// mustn't be retried elsewhere.
+#[tokio::test]
+async fn test_partly_answered_request_not_retried() {
+    init_logging();
+    let failing = RawServer::new(hang_up_mid_response).await;
+    let healthy = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&failing.address, &healthy.address],
+        &[
+            "--lb-algorithm",
+            "round-robin",
+            "--max-retries",
+            "3",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+    let statuses = [
+        status_of(&balancebeam, reqwest::Method::POST, "/first").await,
+        status_of(&balancebeam, reqwest::Method::POST, "/second").await,
+    ];
+    assert!(statuses.contains(&502), "Statuses: {:?}", statuses);
+
+    drop(balancebeam);
+    assert_eq!(Box::new(healthy).stop().await, 1);
+    Box::new(failing).stop().await;
+    log::info!("All done :)");
+}
+
+/// Answers each request on the connection with a body of as many bytes as its path says (e.g.
+/// /2000).
+async fn respond_sized(mut stream: tokio::net::TcpStream) {
+    while let Some(head) = read_request_head(&mut stream).await {
+        let size: usize = head
+            .split(' ')
+            .nth(1)
+            .and_then(|path| path[1..].parse().ok())
+            .unwrap_or(0);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            size,
+            "x".repeat(size)
+        );
+        if stream.write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// With --reuse-max-response-bytes, a connection that served a larger response should be closed
+/// rather than pooled, while one that served a small response is pooled as usual.
+#[tokio::test]
+async fn test_reuse_max_response_bytes() {
+    init_logging();
+    let upstream = RawServer::new(respond_sized).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--enable-admin-endpoints",
+            "--max-idle-per-upstream",
+            "1",
+            "--reuse-max-response-bytes",
+            "1000",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    // Each get() uses a new client connection, so the upstream connection goes back to the pool (if
+    // it's fit to) once the client hangs up
+    for path in ["/10", "/10", "/5000", "/10"] {
+        balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    // The second and third requests reused the first one's connection, which was closed after the
+    // third request's large response
+    let metrics = balancebeam
+        .get("/balancebeam/metrics")
+        .await
+        .expect("Error fetching metrics from balancebeam");
+    let series = |name: &str| format!("{}{{upstream=\"{}\"}}", name, upstream.address);
+    assert_eq!(
+        metric_value(&metrics, &series("balancebeam_upstream_dials_total")),
+        Some(2)
+    );
+    assert_eq!(
+        metric_value(
+            &metrics,
+            &series("balancebeam_upstream_connection_reuses_total")
+        ),
+        Some(2)
+    );
+
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
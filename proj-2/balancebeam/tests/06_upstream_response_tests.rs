@@ -0,0 +1,810 @@
+mod common;
+
+use common::{init_logging, read_request_head, BalanceBeam, EchoServer, RawServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+/// An upstream that answers with a status line and then an endless stream of headers should get a
+/// 502 once the header limit is exceeded, rather than being buffered forever.
+#[tokio::test]
+async fn test_oversized_response_headers() {
+    init_logging();
+    let upstream = RawServer::new(|mut stream| async move {
+        if read_request_head(&mut stream).await.is_none() {
+            return;
+        }
+        if stream.write_all(b"HTTP/1.1 200 OK\r\n").await.is_err() {
+            return;
+        }
+        // Stop eventually so a broken limit fails the test instead of hanging it
+        for i in 0..100_000 {
+            let header = format!("x-junk-{}: {}\r\n", i, "a".repeat(100));
+            if stream.write_all(header.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    })
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--max-response-header-bytes", "4096"],
+    )
+    .await;
+
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Headers within the limit should be passed through as usual.
+#[tokio::test]
+async fn test_response_headers_within_limit() {
+    init_logging();
+    let upstream = RawServer::new(|mut stream| async move {
+        while read_request_head(&mut stream).await.is_some() {
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nx-padding: {}\r\nContent-Length: 2\r\n\r\nok",
+                "a".repeat(2000)
+            );
+            if stream.write_all(response.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    })
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--max-response-header-bytes", "4096"],
+    )
+    .await;
+
+    let response_text = balancebeam
+        .get("/")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "ok");
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Informational responses should be relayed to the client ahead of the final response, even when
+/// the upstream sends both in a single write.
+#[tokio::test]
+async fn test_early_hints_relayed() {
+    init_logging();
+    let upstream = RawServer::new(|mut stream| async move {
+        while read_request_head(&mut stream).await.is_some() {
+            let response = "HTTP/1.1 100 Continue\r\n\r\n\
+                            HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n\
+                            HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+            if stream.write_all(response.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    })
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(b"GET /hints HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let hints = read_request_head(&mut conn)
+        .await
+        .expect("balancebeam hung up without responding");
+    assert!(
+        hints.starts_with("HTTP/1.1 103"),
+        "Unexpected response: {}",
+        hints
+    );
+    assert!(
+        hints
+            .to_lowercase()
+            .contains("link: </style.css>; rel=preload"),
+        "Unexpected response: {}",
+        hints
+    );
+    let head = read_request_head(&mut conn)
+        .await
+        .expect("balancebeam hung up after the 103");
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    let mut body = [0_u8; 2];
+    conn.read_exact(&mut body).await.unwrap();
+    assert_eq!(&body, b"ok");
+
+    drop(conn);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// gRPC-style upstreams report the call's status in trailers after a chunked body. The chunks and
+/// trailers should reach the client intact, and the upstream connection should stay usable since
+/// the last chunk marks the end of the response.
+#[tokio::test]
+async fn test_grpc_trailers_forwarded() {
+    init_logging();
+    const CHUNKED_BODY: &str = "5\r\nhello\r\n6;note=x\r\n world\r\n0\r\n\
+                                grpc-status: 0\r\ngrpc-message: OK\r\n\r\n";
+    let upstream = RawServer::new(|mut stream| async move {
+        while read_request_head(&mut stream).await.is_some() {
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/grpc-web\r\n\
+                 Transfer-Encoding: chunked\r\nTrailer: grpc-status, grpc-message\r\n\r\n{}",
+                CHUNKED_BODY
+            );
+            if stream.write_all(response.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    })
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    for _ in 0..2 {
+        conn.write_all(
+            b"POST /echo.Echo/Say HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await
+        .unwrap();
+        let head = read_request_head(&mut conn)
+            .await
+            .expect("balancebeam hung up without responding");
+        assert!(
+            head.starts_with("HTTP/1.1 200"),
+            "Unexpected response: {}",
+            head
+        );
+        assert!(
+            head.to_lowercase().contains("transfer-encoding: chunked"),
+            "Unexpected response: {}",
+            head
+        );
+        let mut body = vec![0_u8; CHUNKED_BODY.len()];
+        conn.read_exact(&mut body).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&body), CHUNKED_BODY);
+    }
+
+    drop(conn);
+    // Both responses should have come over the same upstream connection
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A client that aborts a download is ordinary client behavior, so the failed write should only be
+/// logged at debug level, not as a warning.
+#[tokio::test]
+async fn test_aborted_download_not_a_warning() {
+    init_logging();
+    let upstream = RawServer::new(|mut stream| async move {
+        while read_request_head(&mut stream).await.is_some() {
+            let body = vec![b'a'; 8_000_000];
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            if stream.write_all(head.as_bytes()).await.is_err()
+                || stream.write_all(&body).await.is_err()
+            {
+                return;
+            }
+        }
+    })
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(b"GET /big HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut start = [0_u8; 16];
+    conn.read_exact(&mut start).await.unwrap();
+    // Hanging up with unread data makes the kernel reset the connection
+    drop(conn);
+    sleep(Duration::from_millis(500)).await;
+
+    let output = balancebeam.output();
+    assert!(
+        output
+            .iter()
+            .any(|line| line.contains("DEBUG") && line.contains("went away")),
+        "Aborted download wasn't noticed"
+    );
+    let warnings: Vec<&String> = output
+        .iter()
+        .filter(|line| line.contains("ERROR") || line.contains("WARN"))
+        .collect();
+    assert!(
+        warnings.is_empty(),
+        "Unexpected warnings logged: {:?}",
+        warnings
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Headers named with --strip-response-header should be removed from upstream responses, however
+/// either side capitalizes them, while every other header gets through.
+#[tokio::test]
+async fn test_strip_response_headers() {
+    init_logging();
+    let upstream = RawServer::new(|mut stream| async move {
+        while read_request_head(&mut stream).await.is_some() {
+            let response = "HTTP/1.1 200 OK\r\nX-Powered-By: PHP/5.4.0\r\n\
+                            x-aspnet-version: 4.0.30319\r\nX-Internal-Trace-Id: 8f2c\r\n\
+                            X-Request-Id: abc123\r\nContent-Length: 2\r\n\r\nok";
+            if stream.write_all(response.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    })
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--strip-response-header",
+            "x-powered-by",
+            "--strip-response-header",
+            "X-AspNet-Version",
+            "--strip-response-header",
+            "X-INTERNAL-TRACE-ID",
+        ],
+    )
+    .await;
+
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    let headers = response.headers();
+    for stripped in ["x-powered-by", "x-aspnet-version", "x-internal-trace-id"] {
+        assert!(
+            !headers.contains_key(stripped),
+            "{} should have been stripped",
+            stripped
+        );
+    }
+    assert_eq!(headers["x-request-id"], "abc123");
+    assert_eq!(response.text().await.unwrap(), "ok");
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --add-upstream-header, each response should name the upstream that served it. Without
+/// it, the upstreams stay anonymous.
+#[tokio::test]
+async fn test_add_upstream_header() {
+    init_logging();
+    let upstreams = [EchoServer::new().await, EchoServer::new().await];
+    let upstream_addresses: Vec<&str> = upstreams
+        .iter()
+        .map(|upstream| upstream.address.as_str())
+        .collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_addresses,
+        &[
+            "--add-upstream-header",
+            "--lb-algorithm",
+            "round-robin",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let mut served_by = std::collections::HashSet::new();
+    for i in 0..4 {
+        let response = reqwest::get(format!("http://{}/which-{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam");
+        served_by.insert(
+            response.headers()["x-upstream-server"]
+                .to_str()
+                .unwrap()
+                .to_string(),
+        );
+        response.text().await.unwrap();
+    }
+    let expected: std::collections::HashSet<String> = upstream_addresses
+        .iter()
+        .map(|address| address.to_string())
+        .collect();
+    assert_eq!(served_by, expected);
+
+    let anonymous = BalanceBeam::new(&upstream_addresses, None, None).await;
+    let response = reqwest::get(format!("http://{}/anonymous", anonymous.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(!response.headers().contains_key("x-upstream-server"));
+    response.text().await.unwrap();
+
+    // The echo servers wait for open connections to close before they stop
+    drop(balancebeam);
+    drop(anonymous);
+    for upstream in upstreams {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// --response-read-timeout bounds the time to read the whole response, so an upstream that sends
+/// its headers right away but then trickles out the body still gets cut off with a 504.
+#[tokio::test]
+async fn test_response_read_timeout() {
+    init_logging();
+    let upstream = RawServer::new(|mut stream| async move {
+        while let Some(head) = read_request_head(&mut stream).await {
+            if head.starts_with("GET /fast ") {
+                if stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                continue;
+            }
+            // Each byte comes well within any per-read timeout, but all ten take 4 seconds
+            if stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n")
+                .await
+                .is_err()
+            {
+                return;
+            }
+            for _ in 0..10 {
+                sleep(Duration::from_millis(400)).await;
+                if stream.write_all(b"a").await.is_err() {
+                    return;
+                }
+            }
+        }
+    })
+    .await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--response-read-timeout", "1"]).await;
+
+    let start = std::time::Instant::now();
+    let response = reqwest::get(format!("http://{}/slow", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(3),
+        "Timed out after {:?}",
+        elapsed
+    );
+
+    let response_text = balancebeam
+        .get("/fast")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "ok");
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// --upstream-read-timeout-ms applies to reading the body as a whole, not to each read on its own,
+/// so a body that trickles in steadily still gets a 504. The stalled upstream connection is torn
+/// down rather than reused.
+#[tokio::test]
+async fn test_upstream_read_timeout_on_trickled_body() {
+    init_logging();
+    let upstream = RawServer::new(|mut stream| async move {
+        while let Some(head) = read_request_head(&mut stream).await {
+            if head.starts_with("GET /fast ") {
+                if stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                continue;
+            }
+            if stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n")
+                .await
+                .is_err()
+            {
+                return;
+            }
+            for _ in 0..10 {
+                sleep(Duration::from_millis(200)).await;
+                if stream.write_all(b"a").await.is_err() {
+                    return;
+                }
+            }
+        }
+    })
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--upstream-read-timeout-ms",
+            "700",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let start = std::time::Instant::now();
+    let response = reqwest::get(format!("http://{}/slow", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed >= Duration::from_millis(700) && elapsed < Duration::from_millis(1800),
+        "Timed out after {:?}",
+        elapsed
+    );
+
+    let response_text = balancebeam
+        .get("/fast")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "ok");
+
+    // One connection for the stalled request, and a fresh one for the request after it
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// A large body should be passed on to the client as it arrives from the upstream, rather than
+/// only once it has all been read. The upstream holds back the second half of the body until the
+/// client has seen the first bytes of it, which can only happen if balancebeam is streaming.
+#[tokio::test]
+async fn test_large_response_streamed() {
+    init_logging();
+    const HALF: usize = 1_000_000;
+    let first_bytes_seen = std::sync::Arc::new(tokio::sync::Notify::new());
+    let upstream = {
+        let first_bytes_seen = first_bytes_seen.clone();
+        RawServer::new(move |mut stream| {
+            let first_bytes_seen = first_bytes_seen.clone();
+            async move {
+                if read_request_head(&mut stream).await.is_none() {
+                    return;
+                }
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", HALF * 2);
+                if stream.write_all(head.as_bytes()).await.is_err()
+                    || stream.write_all(&vec![b'a'; HALF]).await.is_err()
+                {
+                    return;
+                }
+                first_bytes_seen.notified().await;
+                let _ = stream.write_all(&vec![b'b'; HALF]).await;
+            }
+        })
+        .await
+    };
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        // Keep health check requests from getting the held-back response
+        &["--active-health-check-interval", "600"],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(b"GET /download HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let mut first = [0_u8; 1];
+    let head = tokio::time::timeout(Duration::from_secs(5), async {
+        let head = read_request_head(&mut conn)
+            .await
+            .expect("balancebeam hung up without responding");
+        conn.read_exact(&mut first).await.unwrap();
+        head
+    })
+    .await
+    .expect("Response didn't start arriving until the upstream finished sending it");
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "Unexpected head: {}",
+        head
+    );
+    first_bytes_seen.notify_one();
+
+    let mut body = first.to_vec();
+    body.resize(HALF * 2, 0);
+    conn.read_exact(&mut body[1..]).await.unwrap();
+    assert!(body[..HALF].iter().all(|byte| *byte == b'a'));
+    assert!(body[HALF..].iter().all(|byte| *byte == b'b'));
+
+    drop(conn);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A chunked body should be streamed too, with its framing passed on exactly as the upstream sent
+/// it: a chunk far bigger than balancebeam's buffers, chunk extensions, and trailers after the last
+/// chunk. The upstream stalls partway into the big chunk until the client has seen the start of it.
+#[tokio::test]
+async fn test_chunked_response_streamed() {
+    init_logging();
+    const HALF: usize = 1_000_000;
+    let first_bytes_seen = std::sync::Arc::new(tokio::sync::Notify::new());
+    let upstream = {
+        let first_bytes_seen = first_bytes_seen.clone();
+        RawServer::new(move |mut stream| {
+            let first_bytes_seen = first_bytes_seen.clone();
+            async move {
+                if read_request_head(&mut stream).await.is_none() {
+                    return;
+                }
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n",
+                    HALF * 2
+                );
+                if stream.write_all(head.as_bytes()).await.is_err()
+                    || stream.write_all(&vec![b'a'; HALF]).await.is_err()
+                {
+                    return;
+                }
+                first_bytes_seen.notified().await;
+                let _ = stream.write_all(&vec![b'b'; HALF]).await;
+                let _ = stream
+                    .write_all(b"\r\n5;name=value\r\nhello\r\n0\r\nx-checksum: 1\r\n\r\n")
+                    .await;
+            }
+        })
+        .await
+    };
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        // Keep health check requests from getting the held-back response
+        &["--active-health-check-interval", "600"],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(b"GET /download HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let size_line = format!("{:x}\r\n", HALF * 2);
+    let mut body = vec![0_u8; size_line.len() + 1];
+    let head = tokio::time::timeout(Duration::from_secs(5), async {
+        let head = read_request_head(&mut conn)
+            .await
+            .expect("balancebeam hung up without responding");
+        conn.read_exact(&mut body).await.unwrap();
+        head
+    })
+    .await
+    .expect("Response didn't start arriving until the upstream finished sending it");
+    assert!(
+        head.to_lowercase().contains("transfer-encoding: chunked"),
+        "Unexpected head: {}",
+        head
+    );
+    first_bytes_seen.notify_one();
+
+    let rest = b"\r\n5;name=value\r\nhello\r\n0\r\nx-checksum: 1\r\n\r\n";
+    let start = body.len();
+    body.resize(size_line.len() + HALF * 2 + rest.len(), 0);
+    conn.read_exact(&mut body[start..]).await.unwrap();
+    let (framing, data) = body.split_at(size_line.len());
+    assert_eq!(framing, size_line.as_bytes());
+    assert!(data[..HALF].iter().all(|byte| *byte == b'a'));
+    assert!(data[HALF..HALF * 2].iter().all(|byte| *byte == b'b'));
+    assert_eq!(&data[HALF * 2..], rest);
+
+    drop(conn);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A chunked response should reach the client intact whether it's streamed or read in full first
+/// (as it is with a response read timeout): chunk extensions, a chunk bigger than any buffer, and
+/// trailers after the last chunk. A Content-Length sent alongside the chunks must not be passed on,
+/// since the client could take it over the chunks and lose track of the connection.
+#[tokio::test]
+async fn test_chunked_response_framing() {
+    init_logging();
+    const BIG_CHUNK: usize = 300_000;
+    let upstream = RawServer::new(|mut stream| async move {
+        while read_request_head(&mut stream).await.is_some() {
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 e;line=1\r\n{{\"id\": \"one\"}}\n\r\n{:X}\r\n",
+                BIG_CHUNK
+            );
+            if stream.write_all(head.as_bytes()).await.is_err()
+                || stream.write_all(&vec![b'x'; BIG_CHUNK]).await.is_err()
+                || stream
+                    .write_all(b"\r\n0\r\nx-line-count: 2\r\n\r\n")
+                    .await
+                    .is_err()
+            {
+                return;
+            }
+        }
+    })
+    .await;
+
+    for args in [&[][..], &["--response-read-timeout", "10"][..]] {
+        let upstream_address = upstream.address.clone();
+        let balancebeam = BalanceBeam::new_with_args(&[&upstream_address], args).await;
+        let mut conn = TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Could not connect to balancebeam");
+        for _ in 0..2 {
+            conn.write_all(b"GET /lines HTTP/1.1\r\n\r\n")
+                .await
+                .unwrap();
+            let head = read_request_head(&mut conn)
+                .await
+                .expect("balancebeam hung up without responding");
+            assert!(
+                head.starts_with("HTTP/1.1 200"),
+                "Unexpected head: {}",
+                head
+            );
+            assert!(
+                !head.to_lowercase().contains("content-length"),
+                "Head with {:?}: {}",
+                args,
+                head
+            );
+            let expected = format!(
+                "e;line=1\r\n{{\"id\": \"one\"}}\n\r\n{:X}\r\n{}\r\n0\r\nx-line-count: 2\r\n\r\n",
+                BIG_CHUNK,
+                "x".repeat(BIG_CHUNK)
+            );
+            let mut body = vec![0_u8; expected.len()];
+            conn.read_exact(&mut body).await.unwrap();
+            assert!(body == expected.as_bytes(), "Body mangled with {:?}", args);
+        }
+    }
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+fn epoch_millis(time: std::time::SystemTime) -> u64 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// A client's X-Request-Deadline bounds how long balancebeam waits for the upstream, and is passed
+/// on to the upstream, cut down to the response read timeout if that's sooner.
+#[tokio::test]
+async fn test_request_deadline_honored() {
+    init_logging();
+    let upstream = RawServer::new(|mut stream| async move {
+        while let Some(head) = read_request_head(&mut stream).await {
+            if head.starts_with("GET /slow ") {
+                sleep(Duration::from_secs(10)).await;
+                return;
+            }
+            let deadline = head
+                .lines()
+                .find_map(|line| line.strip_prefix("x-request-deadline: "))
+                .unwrap_or("none")
+                .to_string();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                deadline.len(),
+                deadline
+            );
+            if stream.write_all(response.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    })
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--response-read-timeout",
+            "5",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    let now = std::time::SystemTime::now();
+    let forwarded_deadline: u64 = client
+        .get(format!("http://{}/fast", balancebeam.address))
+        .header(
+            "x-request-deadline",
+            epoch_millis(now + Duration::from_secs(60)),
+        )
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap()
+        .parse()
+        .expect("The deadline wasn't forwarded to the upstream");
+    assert!(
+        forwarded_deadline > epoch_millis(now)
+            && forwarded_deadline <= epoch_millis(std::time::SystemTime::now()) + 5000,
+        "Forwarded a deadline {}ms from the request",
+        forwarded_deadline as i64 - epoch_millis(now) as i64
+    );
+
+    let start = std::time::Instant::now();
+    let response = client
+        .get(format!("http://{}/slow", balancebeam.address))
+        .header(
+            "x-request-deadline",
+            epoch_millis(std::time::SystemTime::now() + Duration::from_millis(800)),
+        )
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed >= Duration::from_millis(700) && elapsed < Duration::from_millis(2000),
+        "Timed out after {:?}",
+        elapsed
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// A request that's already past its deadline gets a 504 straight away, without being forwarded.
+#[tokio::test]
+async fn test_expired_request_deadline() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("http://{}/late", balancebeam.address))
+        .header(
+            "x-request-deadline",
+            epoch_millis(std::time::SystemTime::now() - Duration::from_secs(1)),
+        )
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+
+    // A deadline that can't be read is no deadline at all
+    let response_text = client
+        .get(format!("http://{}/on-time", balancebeam.address))
+        .header("x-request-deadline", "soon")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("GET /on-time HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
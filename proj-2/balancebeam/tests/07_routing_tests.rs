@@ -0,0 +1,220 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Requests under a route's prefix go to that route's upstreams, and requests under no prefix get
+/// a 404 when there's no default group to fall back on.
+#[tokio::test]
+async fn test_no_route_default_status() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let route = format!("/api={}", upstream.address);
+    let balancebeam = BalanceBeam::new(&[&route], None, None).await;
+
+    let response_text = balancebeam
+        .get("/api/users")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /api/users HTTP/1.1"));
+
+    let response = reqwest::get(format!("http://{}/apiary", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 404);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Each request goes to the group with the longest prefix covering its path, so a more specific
+/// route takes its requests away from a broader one, and other groups see none of them.
+#[tokio::test]
+async fn test_longest_prefix_route_wins() {
+    init_logging();
+    let api_upstream = EchoServer::new().await;
+    let api_v2_upstream = EchoServer::new().await;
+    let static_upstream = EchoServer::new().await;
+    let routes = [
+        format!("/api={}", api_upstream.address),
+        format!("/api/v2={}", api_v2_upstream.address),
+        format!("/static={}", static_upstream.address),
+    ];
+    let routes: Vec<&str> = routes.iter().map(String::as_str).collect();
+    let balancebeam = BalanceBeam::new(&routes, None, None).await;
+
+    for path in [
+        "/api/users",
+        "/api/v2/users",
+        "/api/v2",
+        "/static/app.js",
+        "/api/v1",
+    ] {
+        let response_text = balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    assert_eq!(Box::new(api_upstream).stop().await, 2);
+    assert_eq!(Box::new(api_v2_upstream).stop().await, 2);
+    assert_eq!(Box::new(static_upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// The status and body sent for unroutable requests can be configured.
+#[tokio::test]
+async fn test_no_route_configured_status() {
+    init_logging();
+    let api_upstream = EchoServer::new().await;
+    let static_upstream = EchoServer::new().await;
+    let api_route = format!("/api={}", api_upstream.address);
+    let static_route = format!("/static={}", static_upstream.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&api_route, &static_route],
+        &[
+            "--no-route-status",
+            "421",
+            "--no-route-body",
+            "no service here",
+        ],
+    )
+    .await;
+
+    let response = reqwest::get(format!("http://{}/admin/login", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 421);
+    assert_eq!(response.text().await.unwrap(), "no service here");
+
+    assert_eq!(Box::new(api_upstream).stop().await, 0);
+    assert_eq!(Box::new(static_upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// A request with a method its route doesn't allow should get a 405 whose Allow header lists
+/// exactly the methods --route-methods gave the route, without reaching the upstream. Other routes
+/// allow any method.
+#[tokio::test]
+async fn test_route_methods() {
+    init_logging();
+    let api_upstream = EchoServer::new().await;
+    let static_upstream = EchoServer::new().await;
+    let api_route = format!("/api={}", api_upstream.address);
+    let static_route = format!("/static={}", static_upstream.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&api_route, &static_route],
+        &["--route-methods", "/static=GET,HEAD"],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let url = |path: &str| format!("http://{}{}", balancebeam.address, path);
+    let response = client
+        .delete(url("/static/app.js"))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 405);
+    assert_eq!(response.headers()["allow"], "GET, HEAD");
+    for request in [
+        client.get(url("/static/app.js")),
+        client.delete(url("/api/users")),
+    ] {
+        let response = request
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    assert_eq!(Box::new(api_upstream).stop().await, 1);
+    assert_eq!(Box::new(static_upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Sends a GET for the path exactly as given (HTTP clients tend to resolve dot segments
+/// themselves), hangs up our side, and returns everything balancebeam sent back.
+async fn get_raw_path(balancebeam: &BalanceBeam, path: &str) -> String {
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let request = format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", path);
+    conn.write_all(request.as_bytes()).await.unwrap();
+    conn.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    conn.read_to_end(&mut response).await.unwrap();
+    String::from_utf8(response).unwrap()
+}
+
+/// With --normalize-path, dot segments and repeated slashes are resolved before the request is
+/// routed, and the upstream sees the normalized path. Percent-encoding is kept unless asked
+/// otherwise.
+#[tokio::test]
+async fn test_normalize_path() {
+    init_logging();
+    let api_upstream = EchoServer::new().await;
+    let default_upstream = EchoServer::new().await;
+    let api_route = format!("/api={}", api_upstream.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&api_route, &default_upstream.address],
+        &["--normalize-path"],
+    )
+    .await;
+
+    let response = get_raw_path(&balancebeam, "/static/../api/./users?page=2").await;
+    assert!(
+        response.contains("GET /api/users?page=2 HTTP/1.1"),
+        "Unexpected response: {}",
+        response
+    );
+    let response = get_raw_path(&balancebeam, "//api//users").await;
+    assert!(
+        response.contains("GET /api/users HTTP/1.1"),
+        "Unexpected response: {}",
+        response
+    );
+    // Escaping the API route with .. lands in the default route, not the API's
+    let response = get_raw_path(&balancebeam, "/api/../files/%7Ea%2Fb").await;
+    assert!(
+        response.contains("GET /files/%7Ea%2Fb HTTP/1.1"),
+        "Unexpected response: {}",
+        response
+    );
+
+    assert_eq!(Box::new(api_upstream).stop().await, 2);
+    assert_eq!(Box::new(default_upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Without --normalize-path, paths are routed and forwarded exactly as the client sent them, and
+/// --decode-unreserved-percent decodes only what can't change the path's meaning.
+#[tokio::test]
+async fn test_path_normalization_options() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let plain = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let response = get_raw_path(&plain, "/a/..//b").await;
+    assert!(
+        response.contains("GET /a/..//b HTTP/1.1"),
+        "Unexpected response: {}",
+        response
+    );
+
+    let decoding = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--normalize-path", "--decode-unreserved-percent"],
+    )
+    .await;
+    let response = get_raw_path(&decoding, "/%7Euser/%2e%2e/a%2fb").await;
+    assert!(
+        response.contains("GET /a%2Fb HTTP/1.1"),
+        "Unexpected response: {}",
+        response
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
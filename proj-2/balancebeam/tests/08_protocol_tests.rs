@@ -0,0 +1,857 @@
+mod common;
+
+use common::{init_logging, read_request_head, BalanceBeam, EchoServer, RawServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Replies to every request with its own request line and headers as the body, so tests can see
+/// exactly what balancebeam forwarded.
+async fn echo_head(mut stream: TcpStream) {
+    while let Some(head) = read_request_head(&mut stream).await {
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            head.len(),
+            head
+        );
+        if stream.write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Sends raw request bytes over a new connection to balancebeam and returns the response head and
+/// body.
+async fn send_raw(balancebeam: &BalanceBeam, request: &str) -> (String, String) {
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(request.as_bytes()).await.unwrap();
+    read_response(&mut conn).await
+}
+
+/// Reads a response head and its Content-Length body from a connection to balancebeam.
+async fn read_response(conn: &mut TcpStream) -> (String, String) {
+    let head = read_request_head(conn)
+        .await
+        .expect("balancebeam hung up without responding");
+    let content_length: usize = head
+        .lines()
+        .find_map(|line| {
+            line.to_lowercase()
+                .strip_prefix("content-length: ")
+                .map(str::to_string)
+        })
+        .map(|value| value.parse().unwrap())
+        .unwrap_or(0);
+    let mut body = vec![0_u8; content_length];
+    conn.read_exact(&mut body).await.unwrap();
+    (head, String::from_utf8(body).unwrap())
+}
+
+/// An h2c upgrade offer should be ignored: the upstream must not see it, and the client should get
+/// an ordinary HTTP/1.1 response.
+#[tokio::test]
+async fn test_h2c_upgrade_declined() {
+    init_logging();
+    let upstream = RawServer::new(echo_head).await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let (head, forwarded) = send_raw(
+        &balancebeam,
+        "GET /h2c HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings, keep-alive\r\n\
+         Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\n\r\n",
+    )
+    .await;
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    assert!(forwarded.starts_with("GET /h2c HTTP/1.1"));
+    let forwarded = forwarded.to_lowercase();
+    assert!(!forwarded.contains("upgrade"), "Forwarded: {}", forwarded);
+    assert!(
+        !forwarded.contains("http2-settings"),
+        "Forwarded: {}",
+        forwarded
+    );
+    assert!(
+        forwarded.contains("connection: keep-alive"),
+        "Forwarded: {}",
+        forwarded
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+const FOLDED_REQUEST: &str =
+    "GET /folded HTTP/1.1\r\nHost: localhost\r\nX-Folded: first\r\n \t second\r\nX-After: yes\r\n\r\n";
+
+/// By default, a request with a folded header should be rejected without reaching the upstream.
+#[tokio::test]
+async fn test_obs_fold_rejected() {
+    init_logging();
+    let upstream = RawServer::new(echo_head).await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let (head, _) = send_raw(&balancebeam, FOLDED_REQUEST).await;
+    assert!(
+        head.starts_with("HTTP/1.1 400"),
+        "Unexpected response: {}",
+        head
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// With --obs-fold unfold, the continuation should be joined onto the header value.
+#[tokio::test]
+async fn test_obs_fold_unfolded() {
+    init_logging();
+    let upstream = RawServer::new(echo_head).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--obs-fold", "unfold"]).await;
+
+    let (head, forwarded) = send_raw(&balancebeam, FOLDED_REQUEST).await;
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    assert!(
+        forwarded.contains("x-folded: first second\r\n"),
+        "Forwarded: {}",
+        forwarded
+    );
+    assert!(
+        forwarded.contains("x-after: yes"),
+        "Forwarded: {}",
+        forwarded
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+const ABSOLUTE_FORM_REQUEST: &str =
+    "GET http://example.com/absolute HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+/// Absolute-form targets are for forward proxies, so they should be refused by default.
+#[tokio::test]
+async fn test_absolute_form_rejected() {
+    init_logging();
+    let upstream = RawServer::new(echo_head).await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let (head, _) = send_raw(&balancebeam, ABSOLUTE_FORM_REQUEST).await;
+    assert!(
+        head.starts_with("HTTP/1.1 400"),
+        "Unexpected response: {}",
+        head
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// A request with two Host headers could be routed by one and served by the other, so it should be
+/// refused rather than forwarded.
+#[tokio::test]
+async fn test_duplicate_host_rejected() {
+    init_logging();
+    let upstream = RawServer::new(echo_head).await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let (head, _) = send_raw(
+        &balancebeam,
+        "GET /two-hosts HTTP/1.1\r\nHost: example.com\r\nHost: internal.example.com\r\n\r\n",
+    )
+    .await;
+    assert!(
+        head.starts_with("HTTP/1.1 400"),
+        "Unexpected response: {}",
+        head
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// With --allow-absolute-uri, absolute-form targets should be forwarded.
+#[tokio::test]
+async fn test_absolute_form_allowed() {
+    init_logging();
+    let upstream = RawServer::new(echo_head).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--allow-absolute-uri"]).await;
+
+    let (head, forwarded) = send_raw(&balancebeam, ABSOLUTE_FORM_REQUEST).await;
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    assert!(
+        forwarded.starts_with("GET http://example.com/absolute HTTP/1.1"),
+        "Forwarded: {}",
+        forwarded
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// --set-header should replace whatever the client sent for a header, with the last value given
+/// for a header winning, and an empty value removing the header.
+#[tokio::test]
+async fn test_set_header() {
+    init_logging();
+    let upstream = RawServer::new(echo_head).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--set-header",
+            "Host:internal.example.com",
+            "--set-header",
+            "X-Env: staging",
+            "--set-header",
+            "X-Env: prod",
+            "--set-header",
+            "X-Debug-Token:",
+        ],
+    )
+    .await;
+
+    let (head, forwarded) = send_raw(
+        &balancebeam,
+        "GET /rewritten HTTP/1.1\r\nHost: example.com\r\nX-Env: dev\r\n\
+         X-Debug-Token: secret\r\nX-Kept: yes\r\n\r\n",
+    )
+    .await;
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    let forwarded_headers: Vec<&str> = forwarded.lines().skip(1).collect();
+    let values = |name: &str| -> Vec<String> {
+        forwarded_headers
+            .iter()
+            .filter_map(|line| line.strip_prefix(&format!("{}: ", name)))
+            .map(str::to_string)
+            .collect()
+    };
+    assert_eq!(values("host"), ["internal.example.com"]);
+    assert_eq!(values("x-env"), ["prod"]);
+    assert!(
+        values("x-debug-token").is_empty(),
+        "Forwarded: {}",
+        forwarded
+    );
+    assert_eq!(values("x-kept"), ["yes"]);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// --add-forwarded-headers should tell the upstream which Host the client asked for and that it
+/// came in over plain http, replacing whatever the client claimed in those headers itself.
+#[tokio::test]
+async fn test_add_forwarded_headers() {
+    init_logging();
+    let upstream = RawServer::new(echo_head).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--add-forwarded-headers",
+            "--set-header",
+            "Host:internal.example.com",
+        ],
+    )
+    .await;
+
+    let (head, forwarded) = send_raw(
+        &balancebeam,
+        "GET /forwarded HTTP/1.1\r\nHost: shop.example.com\r\nX-Forwarded-Host: evil.example\r\n\
+         X-Forwarded-Proto: https\r\n\r\n",
+    )
+    .await;
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    let forwarded_headers: Vec<&str> = forwarded.lines().skip(1).collect();
+    assert!(
+        forwarded_headers.contains(&"x-forwarded-host: shop.example.com"),
+        "Forwarded: {}",
+        forwarded
+    );
+    assert!(
+        forwarded_headers.contains(&"x-forwarded-proto: http"),
+        "Forwarded: {}",
+        forwarded
+    );
+    assert_eq!(forwarded.matches("x-forwarded-").count(), 3);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --max-hops, each forwarded request should carry its hop count up by one, and one that has
+/// been through too many balancebeam instances already should get a 508 instead of being forwarded.
+#[tokio::test]
+async fn test_max_hops() {
+    init_logging();
+    let upstream = RawServer::new(echo_head).await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &["--max-hops", "3"]).await;
+
+    let (head, forwarded) = send_raw(
+        &balancebeam,
+        "GET /first HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    )
+    .await;
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    assert!(
+        forwarded
+            .lines()
+            .any(|line| line == "x-balancebeam-hops: 1"),
+        "Forwarded: {}",
+        forwarded
+    );
+
+    let (head, forwarded) = send_raw(
+        &balancebeam,
+        "GET /last-hop HTTP/1.1\r\nHost: example.com\r\nX-Balancebeam-Hops: 3\r\n\r\n",
+    )
+    .await;
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    assert!(
+        forwarded
+            .lines()
+            .any(|line| line == "x-balancebeam-hops: 4"),
+        "Forwarded: {}",
+        forwarded
+    );
+
+    let (head, _) = send_raw(
+        &balancebeam,
+        "GET /looping HTTP/1.1\r\nHost: example.com\r\nX-Balancebeam-Hops: 12\r\n\r\n",
+    )
+    .await;
+    assert!(
+        head.starts_with("HTTP/1.1 508"),
+        "Unexpected response: {}",
+        head
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Pipelined requests should all be answered, in order, with balancebeam reading no more than
+/// --max-pipeline-depth of them at a time.
+#[tokio::test]
+async fn test_pipelined_requests_read_in_bounded_batches() {
+    init_logging();
+    let upstream = RawServer::new(echo_head).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-pipeline-depth", "3"]).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let requests: String = (0..10)
+        .map(|i| format!("GET /pipelined-{} HTTP/1.1\r\nHost: localhost\r\n\r\n", i))
+        .collect();
+    conn.write_all(requests.as_bytes()).await.unwrap();
+
+    for i in 0..10 {
+        let head = read_request_head(&mut conn)
+            .await
+            .expect("balancebeam hung up without responding");
+        assert!(
+            head.starts_with("HTTP/1.1 200"),
+            "Unexpected response: {}",
+            head
+        );
+        let content_length: usize = head
+            .to_lowercase()
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: ")?.parse().ok())
+            .unwrap();
+        let mut body = vec![0_u8; content_length];
+        conn.read_exact(&mut body).await.unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert!(
+            body.starts_with(&format!("GET /pipelined-{} HTTP/1.1", i)),
+            "Response {} was for: {}",
+            i,
+            body
+        );
+    }
+
+    let batches: Vec<usize> = balancebeam
+        .output()
+        .iter()
+        .filter_map(|line| {
+            line.split("Read a batch of ")
+                .nth(1)?
+                .split(' ')
+                .next()?
+                .parse()
+                .ok()
+        })
+        .collect();
+    assert!(!batches.is_empty(), "No pipelined batches were read");
+    assert!(
+        batches.iter().all(|&batch| batch <= 3),
+        "Batches were too big: {:?}",
+        batches
+    );
+
+    drop(conn);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Once the upstream answers an Upgrade request with 101 Switching Protocols, whatever the protocol,
+/// balancebeam should get out of the way and pass bytes through in both directions.
+#[tokio::test]
+async fn test_arbitrary_upgrade_tunneled() {
+    init_logging();
+    let upstream = RawServer::new(|mut stream| async move {
+        if read_request_head(&mut stream).await.is_none() {
+            return;
+        }
+        // Start talking the new protocol in the same write as the 101
+        if stream
+            .write_all(
+                b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\n\
+                  Upgrade: x-custom-protocol/1\r\n\r\nHELLO",
+            )
+            .await
+            .is_err()
+        {
+            return;
+        }
+        // Then echo back everything the client sends, upper-cased
+        let mut buf = [0_u8; 1024];
+        loop {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(len) => {
+                    if stream
+                        .write_all(&buf[..len].to_ascii_uppercase())
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            }
+        }
+    })
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(
+        b"GET /chat HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\n\
+          Upgrade: x-custom-protocol/1\r\n\r\n",
+    )
+    .await
+    .unwrap();
+    let head = read_request_head(&mut conn)
+        .await
+        .expect("balancebeam hung up without responding");
+    assert!(
+        head.starts_with("HTTP/1.1 101"),
+        "Unexpected response: {}",
+        head
+    );
+    let mut greeting = [0_u8; 5];
+    conn.read_exact(&mut greeting).await.unwrap();
+    assert_eq!(&greeting, b"HELLO");
+
+    for message in ["ping", "not http at all\r\n\r\n"] {
+        conn.write_all(message.as_bytes()).await.unwrap();
+        let mut reply = vec![0_u8; message.len()];
+        conn.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, message.to_ascii_uppercase().as_bytes());
+    }
+
+    drop(conn);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Like echo_head, but also reads a Content-Length body and echoes it after the head. It doesn't
+/// understand chunked bodies, like the upstreams #no-chunked is meant for.
+async fn echo_head_and_body(mut stream: TcpStream) {
+    while let Some(head) = read_request_head(&mut stream).await {
+        let content_length: usize = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .map_or(0, |value| value.parse().unwrap());
+        let mut body = vec![0_u8; content_length];
+        if stream.read_exact(&mut body).await.is_err() {
+            return;
+        }
+        let echoed = format!("{}\r\n\r\n{}", head, String::from_utf8_lossy(&body));
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            echoed.len(),
+            echoed
+        );
+        if stream.write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// A chunked request to a #no-chunked upstream should reach it with the chunks joined into a body
+/// framed by Content-Length.
+#[tokio::test]
+async fn test_chunked_request_dechunked_for_no_chunked_upstream() {
+    init_logging();
+    let upstream = RawServer::new(echo_head_and_body).await;
+    let balancebeam =
+        BalanceBeam::new(&[&format!("{}#no-chunked", upstream.address)], None, None).await;
+
+    let (head, forwarded) = send_raw(
+        &balancebeam,
+        "POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+         5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nX-Checksum: 1\r\n\r\n",
+    )
+    .await;
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    let (forwarded_head, forwarded_body) = forwarded.split_once("\r\n\r\n").unwrap();
+    assert!(
+        forwarded_head.contains("content-length: 12"),
+        "Forwarded: {}",
+        forwarded_head
+    );
+    assert!(
+        !forwarded_head.contains("transfer-encoding"),
+        "Forwarded: {}",
+        forwarded_head
+    );
+    assert_eq!(forwarded_body, "hello, world");
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Bodies over --max-request-body-bytes should get a 413 without the upstream hearing of them,
+/// whether the client says how big the body is up front or sends it in chunks.
+#[tokio::test]
+async fn test_max_request_body_bytes() {
+    init_logging();
+    let upstream = RawServer::new(echo_head_and_body).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-request-body-bytes", "10"]).await;
+
+    let (head, forwarded) = send_raw(
+        &balancebeam,
+        "POST /fits HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\n0123456789",
+    )
+    .await;
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    assert!(
+        forwarded.ends_with("0123456789"),
+        "Forwarded: {}",
+        forwarded
+    );
+
+    // The body doesn't have to be sent for the 413: the Content-Length says it's too big
+    let (head, _) = send_raw(
+        &balancebeam,
+        "POST /too-big HTTP/1.1\r\nHost: localhost\r\nContent-Length: 11\r\n\r\n",
+    )
+    .await;
+    assert!(
+        head.starts_with("HTTP/1.1 413"),
+        "Unexpected response: {}",
+        head
+    );
+
+    let (head, _) = send_raw(
+        &balancebeam,
+        "POST /too-big-chunked HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+         6\r\nhello,\r\n6\r\n world\r\n0\r\n\r\n",
+    )
+    .await;
+    assert!(
+        head.starts_with("HTTP/1.1 413"),
+        "Unexpected response: {}",
+        head
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// The body of a request that got a 413 for --max-request-body-bytes is never read, so the
+/// connection must be closed rather than have the body read as the client's next request. The same
+/// goes for the chunks a chunked body still had to come when it went over.
+#[tokio::test]
+async fn test_oversized_body_not_read_as_request() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-request-body-bytes", "10"]).await;
+
+    for (request, rest) in [
+        (
+            "POST /too-big HTTP/1.1\r\nHost: localhost\r\nContent-Length: 36\r\n\r\n\
+             GET /smuggled HTTP/1.1\r\nHost: x\r\n\r\n",
+            "",
+        ),
+        (
+            "POST /too-big-chunked HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+             b\r\nhello world\r\n",
+            "0\r\n\r\nGET /smuggled HTTP/1.1\r\nHost: x\r\n\r\n",
+        ),
+    ] {
+        let mut conn = TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Could not connect to balancebeam");
+        conn.write_all(request.as_bytes()).await.unwrap();
+        let (head, _) = read_response(&mut conn).await;
+        assert!(
+            head.starts_with("HTTP/1.1 413"),
+            "Unexpected response: {}",
+            head
+        );
+        assert!(
+            head.to_lowercase().contains("connection: close"),
+            "Unexpected response: {}",
+            head
+        );
+        // balancebeam may well have hung up already
+        let _ = conn.write_all(rest.as_bytes()).await;
+        let mut buf = [0_u8; 1];
+        let closed = tokio::time::timeout(std::time::Duration::from_secs(2), conn.read(&mut buf))
+            .await
+            .is_ok_and(|read| matches!(read, Ok(0) | Err(_)));
+        assert!(closed, "Connection left open after {:?}", request);
+    }
+
+    assert_eq!(
+        Box::new(upstream).stop().await,
+        0,
+        "A request made it out of an oversized body"
+    );
+    log::info!("All done :)");
+}
+
+/// A request with a bigger body than its upstream's #max_body should get a 413 from balancebeam,
+/// without the upstream ever seeing it.
+#[tokio::test]
+async fn test_upstream_max_body() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new(&[&format!("{}#max_body=10", upstream.address)], None, None).await;
+
+    let client = reqwest::Client::new();
+    for (body, status) in [("0123456789", 200), ("0123456789A", 413)] {
+        let response = client
+            .post(format!("http://{}/upload", balancebeam.address))
+            .body(body)
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), status, "Body {:?}", body);
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A body over --stream-request-body-bytes should start reaching the upstream before the client
+/// has finished sending it, and a client waiting on 100 Continue should be told to go ahead.
+#[tokio::test]
+async fn test_streamed_request_body() {
+    init_logging();
+    let (halfway_tx, mut halfway_rx) = tokio::sync::mpsc::unbounded_channel();
+    let upstream = RawServer::new(move |mut stream: TcpStream| {
+        let halfway_tx = halfway_tx.clone();
+        async move {
+            let head = read_request_head(&mut stream).await.unwrap();
+            let mut body = vec![0_u8; 40];
+            stream.read_exact(&mut body[..20]).await.unwrap();
+            halfway_tx.send(()).unwrap();
+            stream.read_exact(&mut body[20..]).await.unwrap();
+            let echoed = format!("{}\r\n\r\n{}", head, String::from_utf8_lossy(&body));
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                echoed.len(),
+                echoed
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    })
+    .await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--stream-request-body-bytes", "16"])
+            .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(
+        b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 40\r\n\
+          Expect: 100-continue\r\n\r\n",
+    )
+    .await
+    .unwrap();
+    let head = read_request_head(&mut conn).await.unwrap();
+    assert!(
+        head.starts_with("HTTP/1.1 100"),
+        "Unexpected response: {}",
+        head
+    );
+    conn.write_all(b"first half of the body").await.unwrap();
+    tokio::time::timeout(std::time::Duration::from_secs(5), halfway_rx.recv())
+        .await
+        .expect("Upstream didn't get the start of the body before the rest was sent");
+    conn.write_all(b", then the rest").await.unwrap();
+    conn.write_all(b"...").await.unwrap();
+
+    let (head, forwarded) = read_response(&mut conn).await;
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    assert!(
+        forwarded.ends_with("first half of the body, then the rest..."),
+        "Forwarded: {}",
+        forwarded
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// A request whose body is to be streamed but which is answered without being forwarded leaves its
+/// body on the client connection, and it must not be mistaken for the next request.
+#[tokio::test]
+async fn test_unforwarded_streamed_body_discarded() {
+    init_logging();
+    let upstream = RawServer::new(echo_head_and_body).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&format!("{}#max_body=30", upstream.address)],
+        &["--stream-request-body-bytes", "16"],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(
+        b"POST /too-big HTTP/1.1\r\nHost: localhost\r\nContent-Length: 40\r\n\r\n\
+          GET /smuggled HTTP/1.1\r\nHost: x.test\r\n\r\n",
+    )
+    .await
+    .unwrap();
+    let (head, _) = read_response(&mut conn).await;
+    assert!(
+        head.starts_with("HTTP/1.1 413"),
+        "Unexpected response: {}",
+        head
+    );
+    conn.write_all(b"GET /next HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let (head, forwarded) = read_response(&mut conn).await;
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    assert!(
+        forwarded.starts_with("GET /next HTTP/1.1"),
+        "Forwarded: {}",
+        forwarded
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Sends each request on the same connection, reading back its response, and returns the response
+/// heads along with whether balancebeam hung up after the last one.
+async fn exchange_on_connection(
+    balancebeam: &BalanceBeam,
+    requests: &[&str],
+) -> (Vec<String>, bool) {
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let mut heads = Vec::new();
+    for request in requests {
+        conn.write_all(request.as_bytes()).await.unwrap();
+        heads.push(read_response(&mut conn).await.0);
+    }
+    let mut buf = [0_u8; 1];
+    let closed = tokio::time::timeout(std::time::Duration::from_secs(2), conn.read(&mut buf))
+        .await
+        .is_ok_and(|read| matches!(read, Ok(0) | Err(_)));
+    (heads, closed)
+}
+
+/// A client that sends Connection: close, or speaks HTTP/1.0 without asking for keep-alive, should
+/// have its connection closed once its request is answered. An HTTP/1.0 client that does ask for
+/// keep-alive can send another request on the same connection.
+#[tokio::test]
+async fn test_client_connection_close() {
+    init_logging();
+    let upstream = RawServer::new(echo_head).await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    for request in [
+        "GET /close HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        "GET /http10 HTTP/1.0\r\n\r\n",
+    ] {
+        let (heads, closed) = exchange_on_connection(&balancebeam, &[request]).await;
+        assert!(
+            heads[0].contains(" 200 "),
+            "Unexpected response: {}",
+            heads[0]
+        );
+        assert!(closed, "Connection left open after {:?}", request);
+    }
+
+    let keep_alive = "GET /http10 HTTP/1.0\r\nConnection: keep-alive\r\n\r\n";
+    let (heads, closed) = exchange_on_connection(&balancebeam, &[keep_alive, keep_alive]).await;
+    assert!(
+        heads.iter().all(|head| head.contains(" 200 ")),
+        "Responses: {:?}",
+        heads
+    );
+    assert!(!closed, "Keep-alive connection was closed");
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
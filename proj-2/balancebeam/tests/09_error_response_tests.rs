@@ -0,0 +1,222 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Writes an error body file for balancebeam to load, and returns its path.
+fn write_body_file(name: &str, contents: &str) -> String {
+    let path = std::env::temp_dir().join(format!("balancebeam-{}-{}", std::process::id(), name));
+    std::fs::write(&path, contents).expect("Could not write error body file");
+    path.to_str().unwrap().to_string()
+}
+
+/// Statuses with an --error-body get that body, and every other status keeps the default one.
+#[tokio::test]
+async fn test_configured_error_bodies() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let not_found = write_body_file("404.html", "<h1>Nothing here</h1>\n");
+    let too_many = write_body_file("429.txt", "Slow down, please.\n");
+    let route = format!("/api={}", upstream.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&route],
+        &[
+            "--error-body",
+            &format!("404={}", not_found),
+            "--error-body",
+            &format!("429={}", too_many),
+            "--rate-limit-rate",
+            "0.01",
+            "--rate-limit-burst",
+            "1",
+            "--enable-admin-endpoints",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("http://{}/missing", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 404);
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/html; charset=utf-8"
+    );
+    assert_eq!(response.text().await.unwrap(), "<h1>Nothing here</h1>\n");
+
+    // The first request spends the only token, so the second one is throttled
+    for expected_status in [200, 429] {
+        let response = client
+            .get(format!("http://{}/api/limited", balancebeam.address))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), expected_status);
+        if expected_status == 429 {
+            assert_eq!(response.text().await.unwrap(), "Slow down, please.\n");
+        }
+    }
+
+    let response = client
+        .post(format!(
+            "http://{}/balancebeam/metrics",
+            balancebeam.address
+        ))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 405);
+    assert_eq!(
+        response.text().await.unwrap(),
+        "HTTP 405 Method Not Allowed"
+    );
+
+    std::fs::remove_file(not_found).unwrap();
+    std::fs::remove_file(too_many).unwrap();
+    drop(client);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// An --error-body file that can't be read should stop balancebeam from starting.
+#[tokio::test]
+async fn test_missing_error_body_file() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--error-body", "404=/nonexistent/balancebeam-404.html"],
+    )
+    .await;
+    assert!(balancebeam.has_exited());
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Sends raw bytes to balancebeam, hangs up our side, and returns everything it sent back.
+async fn send_and_hang_up(balancebeam: &BalanceBeam, request: &str) -> String {
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(request.as_bytes()).await.unwrap();
+    conn.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    conn.read_to_end(&mut response).await.unwrap();
+    String::from_utf8(response).unwrap()
+}
+
+/// Sends a request balancebeam can't parse, with verbose errors on, and checks that the 400 it
+/// gets back gives the expected reason.
+async fn assert_bad_request_reason(request: &str, reason: &str) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &["--verbose-errors"]).await;
+    let response = send_and_hang_up(&balancebeam, request).await;
+    assert!(
+        response.starts_with("HTTP/1.1 400"),
+        "Unexpected response: {:?}",
+        response
+    );
+    assert!(
+        response.ends_with(&format!("\r\n\r\nBad request: {}\n", reason)),
+        "Unexpected response: {:?}",
+        response
+    );
+    assert_eq!(Box::new(upstream).stop().await, 0);
+}
+
+#[tokio::test]
+async fn test_verbose_incomplete_request() {
+    assert_bad_request_reason(
+        "GET / HTTP/1.1\r\nHost: example.com\r\n",
+        "request ended after 35 bytes, before its headers were complete",
+    )
+    .await;
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_verbose_malformed_request() {
+    assert_bad_request_reason(
+        "GET / HTTP/1.1\r\nBad Header\r\n\r\n",
+        "malformed request (invalid header name)",
+    )
+    .await;
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_verbose_obs_folded_header() {
+    assert_bad_request_reason(
+        "GET / HTTP/1.1\r\nX-Folded: one\r\n two\r\n\r\n",
+        "header values may not be folded across lines",
+    )
+    .await;
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_verbose_absolute_form_target() {
+    assert_bad_request_reason(
+        "GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        "request target must be a path",
+    )
+    .await;
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_verbose_duplicate_host() {
+    assert_bad_request_reason(
+        "GET / HTTP/1.1\r\nHost: a.example.com\r\nHost: b.example.com\r\n\r\n",
+        "request has more than one Host header",
+    )
+    .await;
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_verbose_invalid_content_length() {
+    assert_bad_request_reason(
+        "POST / HTTP/1.1\r\nContent-Length: lots\r\n\r\n",
+        "Content-Length is not a valid number",
+    )
+    .await;
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_verbose_content_length_mismatch() {
+    assert_bad_request_reason(
+        "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort",
+        "body length does not match Content-Length",
+    )
+    .await;
+    log::info!("All done :)");
+}
+
+/// Without --verbose-errors, a request we can't parse gets the plain 400.
+#[tokio::test]
+async fn test_unparsable_request_reason_needs_verbose_errors() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let response = send_and_hang_up(
+        &balancebeam,
+        "POST / HTTP/1.1\r\nContent-Length: lots\r\n\r\n",
+    )
+    .await;
+    assert!(
+        response.starts_with("HTTP/1.1 400"),
+        "Unexpected response: {:?}",
+        response
+    );
+    assert!(!response.contains("Content-Length is not"));
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::{Duration, Instant};
+
+/// About the configured fraction of responses should be replaced with a 503, and the rest passed
+/// through untouched.
+#[tokio::test]
+async fn test_injected_errors() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--fault-inject-error-percent",
+            "30",
+            "--fault-inject-seed",
+            "7",
+            // Keep health check requests out of the upstream's count
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let mut errors = 0;
+    for i in 0..100 {
+        let status = client
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .status()
+            .as_u16();
+        match status {
+            200 => {}
+            503 => errors += 1,
+            status => panic!("Unexpected status {}", status),
+        }
+    }
+    assert!(
+        (15..=45).contains(&errors),
+        "{} of 100 requests failed",
+        errors
+    );
+
+    // The upstream still gets every request; only its response is replaced
+    drop(client);
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 100);
+    log::info!("All done :)");
+}
+
+/// Delayed responses should still arrive, just late, and dropped ones should never arrive.
+#[tokio::test]
+async fn test_injected_delays_and_drops() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let delaying = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--fault-inject-delay-percent",
+            "100",
+            "--fault-inject-delay-ms",
+            "500",
+        ],
+    )
+    .await;
+    let start = Instant::now();
+    delaying
+        .get("/delayed")
+        .await
+        .expect("Delayed request should still succeed");
+    assert!(start.elapsed() >= Duration::from_millis(500));
+
+    let dropping = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--fault-inject-drop-percent", "100"],
+    )
+    .await;
+    assert!(
+        dropping.get("/dropped").await.is_err(),
+        "Dropped request shouldn't get a response"
+    );
+
+    drop(delaying);
+    drop(dropping);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Fault injection is opt-in: with no rates configured, nothing is injected.
+#[tokio::test]
+async fn test_no_faults_by_default() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    for i in 0..20 {
+        let status = reqwest::get(format!("http://{}/request-{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam")
+            .status();
+        assert_eq!(status.as_u16(), 200);
+    }
+    assert!(!balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("fault")));
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
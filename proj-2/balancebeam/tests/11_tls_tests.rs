@@ -0,0 +1,593 @@
+mod common;
+
+use common::{init_logging, read_request_head, BalanceBeam, EchoServer, RawServer, Server};
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509Builder, X509NameBuilder};
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+use tokio_rustls::rustls;
+
+/// Writes a freshly generated self-signed certificate for localhost and its private key to PEM
+/// files in the temp directory, returning their paths. `name` keeps tests from sharing files.
+fn write_self_signed_cert(name: &str) -> (PathBuf, PathBuf) {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+    let mut subject = X509NameBuilder::new().unwrap();
+    subject.append_entry_by_text("CN", "localhost").unwrap();
+    let subject = subject.build();
+    let mut cert = X509Builder::new().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+        .unwrap();
+    cert.set_subject_name(&subject).unwrap();
+    cert.set_issuer_name(&subject).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    // Certificates are checked against the subject alternative names, not the CN
+    let alt_name = SubjectAlternativeName::new()
+        .dns("localhost")
+        .build(&cert.x509v3_context(None, None))
+        .unwrap();
+    cert.append_extension(alt_name).unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+    let dir = std::env::temp_dir();
+    let cert_path = dir.join(format!("balancebeam-{}-{}.crt", name, std::process::id()));
+    let key_path = dir.join(format!("balancebeam-{}-{}.key", name, std::process::id()));
+    std::fs::write(&cert_path, cert.build().to_pem().unwrap()).unwrap();
+    std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    (cert_path, key_path)
+}
+
+/// With --tls-cert and --tls-key, clients should connect over https://, and requests should reach
+/// the upstream as usual.
+#[tokio::test]
+async fn test_tls_termination() {
+    init_logging();
+    let (cert_path, key_path) = write_self_signed_cert("termination");
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            cert_path.to_str().unwrap(),
+            "--tls-key",
+            key_path.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    // The certificate is self-signed, so there's nothing to verify it against
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    for i in 0..3 {
+        let path = format!("/secure-{}", i);
+        let response_text = client
+            .get(format!("https://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam over TLS")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    }
+
+    assert!(
+        balancebeam.get("/plaintext").await.is_err(),
+        "Plaintext HTTP shouldn't be answered once TLS is on"
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    let _ = std::fs::remove_file(cert_path);
+    let _ = std::fs::remove_file(key_path);
+    log::info!("All done :)");
+}
+
+/// With TLS terminated, --add-forwarded-headers should tell the upstream the client used https.
+#[tokio::test]
+async fn test_tls_forwarded_proto() {
+    init_logging();
+    let (cert_path, key_path) = write_self_signed_cert("forwarded-proto");
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            cert_path.to_str().unwrap(),
+            "--tls-key",
+            key_path.to_str().unwrap(),
+            "--add-forwarded-headers",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let response_text = client
+        .get(format!("https://{}/secure", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam over TLS")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("x-forwarded-proto: https"));
+    assert!(response_text.contains(&format!("x-forwarded-host: {}", balancebeam.address)));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    let _ = std::fs::remove_file(cert_path);
+    let _ = std::fs::remove_file(key_path);
+    log::info!("All done :)");
+}
+
+/// The server name a client asks for in its TLS handshake should be logged with its connection,
+/// and a client that doesn't send one (as when connecting by IP address) logged with an empty one.
+#[tokio::test]
+async fn test_tls_sni_logged() {
+    init_logging();
+    let (cert_path, key_path) = write_self_signed_cert("sni");
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            cert_path.to_str().unwrap(),
+            "--tls-key",
+            key_path.to_str().unwrap(),
+        ],
+    )
+    .await;
+    let port = balancebeam.address.rsplit_once(':').unwrap().1;
+
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .resolve("tenant.example", balancebeam.address.parse().unwrap())
+        .build()
+        .unwrap();
+    for url in [
+        format!("https://tenant.example:{}/named", port),
+        format!("https://{}/unnamed", balancebeam.address),
+    ] {
+        client
+            .get(&url)
+            .send()
+            .await
+            .expect("Error sending request to balancebeam over TLS");
+    }
+    let output = balancebeam.output();
+    assert!(
+        output
+            .iter()
+            .any(|line| line.contains("(SNI \"tenant.example\")")),
+        "Log: {:#?}",
+        output
+    );
+    assert!(
+        output.iter().any(|line| line.contains("(SNI \"\")")),
+        "Log: {:#?}",
+        output
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    let _ = std::fs::remove_file(cert_path);
+    let _ = std::fs::remove_file(key_path);
+    log::info!("All done :)");
+}
+
+/// Returns a TLS client that only trusts the certificates in the given PEM files.
+fn client_trusting(cert_paths: &[&Path]) -> tokio_rustls::TlsConnector {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert_path in cert_paths {
+        roots.add(read_cert(cert_path)).unwrap();
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    tokio_rustls::TlsConnector::from(Arc::new(config))
+}
+
+/// Reads the first certificate in a PEM file.
+fn read_cert(cert_path: &Path) -> rustls::pki_types::CertificateDer<'static> {
+    rustls_pemfile::certs(&mut std::io::BufReader::new(
+        std::fs::File::open(cert_path).unwrap(),
+    ))
+    .next()
+    .expect("No certificate in the file")
+    .unwrap()
+}
+
+/// Connects to balancebeam over TLS.
+async fn tls_connect(
+    connector: &tokio_rustls::TlsConnector,
+    address: &str,
+) -> tokio_rustls::client::TlsStream<TcpStream> {
+    let stream = TcpStream::connect(address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let server_name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+    connector
+        .connect(server_name, stream)
+        .await
+        .expect("TLS handshake with balancebeam failed")
+}
+
+/// Sends a request over a TLS connection to balancebeam and reads all of its response, asserting
+/// that it's a 200.
+async fn tls_request(stream: &mut tokio_rustls::client::TlsStream<TcpStream>, path: &str) {
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    // Session tickets come in ahead of the response, so they've been taken in by the time it has
+    let head = read_request_head(stream)
+        .await
+        .expect("balancebeam hung up without responding");
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    let content_length = head
+        .lines()
+        .find_map(|line| {
+            line.to_lowercase()
+                .strip_prefix("content-length: ")
+                .map(str::to_string)
+        })
+        .expect("Response has no Content-Length");
+    let mut body = vec![0_u8; content_length.trim().parse().unwrap()];
+    stream.read_exact(&mut body).await.unwrap();
+}
+
+/// A client reconnecting with the session of its previous connection should resume it, skipping
+/// the full handshake.
+#[tokio::test]
+async fn test_tls_session_resumption() {
+    init_logging();
+    let (cert_path, key_path) = write_self_signed_cert("resumption");
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            cert_path.to_str().unwrap(),
+            "--tls-key",
+            key_path.to_str().unwrap(),
+            "--tls-session-cache-size",
+            "16",
+        ],
+    )
+    .await;
+
+    let connector = client_trusting(&[&cert_path]);
+    let mut first = tls_connect(&connector, &balancebeam.address).await;
+    tls_request(&mut first, "/first").await;
+    assert_eq!(
+        first.get_ref().1.handshake_kind(),
+        Some(rustls::HandshakeKind::Full)
+    );
+    drop(first);
+    let mut second = tls_connect(&connector, &balancebeam.address).await;
+    tls_request(&mut second, "/second").await;
+    assert_eq!(
+        second.get_ref().1.handshake_kind(),
+        Some(rustls::HandshakeKind::Resumed)
+    );
+    drop(second);
+
+    Box::new(upstream).stop().await;
+    let _ = std::fs::remove_file(cert_path);
+    let _ = std::fs::remove_file(key_path);
+    log::info!("All done :)");
+}
+
+/// With --tls-session-cache-size 0, every connection should get a full handshake.
+#[tokio::test]
+async fn test_tls_session_resumption_disabled() {
+    init_logging();
+    let (cert_path, key_path) = write_self_signed_cert("no-resumption");
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            cert_path.to_str().unwrap(),
+            "--tls-key",
+            key_path.to_str().unwrap(),
+            "--tls-session-cache-size",
+            "0",
+        ],
+    )
+    .await;
+
+    let connector = client_trusting(&[&cert_path]);
+    for path in ["/first", "/second"] {
+        let mut stream = tls_connect(&connector, &balancebeam.address).await;
+        tls_request(&mut stream, path).await;
+        assert_eq!(
+            stream.get_ref().1.handshake_kind(),
+            Some(rustls::HandshakeKind::Full)
+        );
+    }
+
+    Box::new(upstream).stop().await;
+    let _ = std::fs::remove_file(cert_path);
+    let _ = std::fs::remove_file(key_path);
+    log::info!("All done :)");
+}
+
+/// After the certificate files are replaced, a SIGHUP should get new connections the new
+/// certificate, while connections already open carry on. Files that can't be loaded should leave
+/// the certificate as it was.
+#[tokio::test]
+async fn test_tls_reload_on_sighup() {
+    init_logging();
+    let (cert_path, key_path) = write_self_signed_cert("reload");
+    let (renewed_cert_path, renewed_key_path) = write_self_signed_cert("reload-renewed");
+    let original_cert = read_cert(&cert_path);
+    let renewed_cert = read_cert(&renewed_cert_path);
+    let upstream = EchoServer::new().await;
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            cert_path.to_str().unwrap(),
+            "--tls-key",
+            key_path.to_str().unwrap(),
+        ],
+    )
+    .await;
+    let connector = client_trusting(&[&cert_path, &renewed_cert_path]);
+    let presented = |stream: &tokio_rustls::client::TlsStream<TcpStream>| {
+        stream.get_ref().1.peer_certificates().unwrap()[0].clone()
+    };
+
+    let mut before = tls_connect(&connector, &balancebeam.address).await;
+    tls_request(&mut before, "/before").await;
+    assert_eq!(presented(&before), original_cert);
+
+    std::fs::copy(&renewed_cert_path, &cert_path).unwrap();
+    std::fs::copy(&renewed_key_path, &key_path).unwrap();
+    balancebeam.hang_up();
+    sleep(Duration::from_millis(200)).await;
+    let mut after = tls_connect(&connector, &balancebeam.address).await;
+    tls_request(&mut after, "/after").await;
+    assert_eq!(presented(&after), renewed_cert);
+    tls_request(&mut before, "/before-again").await;
+
+    std::fs::write(&cert_path, "not a certificate").unwrap();
+    balancebeam.hang_up();
+    sleep(Duration::from_millis(200)).await;
+    assert!(!balancebeam.has_exited());
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("Could not reload --tls-cert or --tls-key")));
+    let mut broken = tls_connect(&connector, &balancebeam.address).await;
+    tls_request(&mut broken, "/broken").await;
+    assert_eq!(presented(&broken), renewed_cert);
+
+    Box::new(upstream).stop().await;
+    for path in [cert_path, key_path, renewed_cert_path, renewed_key_path] {
+        let _ = std::fs::remove_file(path);
+    }
+    log::info!("All done :)");
+}
+
+/// A certificate that can't be loaded should stop balancebeam from starting, rather than leave it
+/// serving plaintext.
+#[tokio::test]
+async fn test_missing_tls_cert_rejected() {
+    init_logging();
+    let (cert_path, key_path) = write_self_signed_cert("missing");
+    std::fs::remove_file(&cert_path).unwrap();
+    let upstream = EchoServer::new().await;
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            cert_path.to_str().unwrap(),
+            "--tls-key",
+            key_path.to_str().unwrap(),
+        ],
+    )
+    .await;
+    assert!(
+        balancebeam.has_exited(),
+        "balancebeam should refuse to start without its certificate"
+    );
+    Box::new(upstream).stop().await;
+    let _ = std::fs::remove_file(key_path);
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that only speaks HTTPS, with the given certificate. Each response body says
+/// which of the upstream's connections (numbered from 0 in the order they were accepted) carried
+/// the request.
+async fn tls_upstream(cert_path: &Path, key_path: &Path) -> RawServer {
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(
+        std::fs::File::open(cert_path).unwrap(),
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+    let key = rustls_pemfile::private_key(&mut std::io::BufReader::new(
+        std::fs::File::open(key_path).unwrap(),
+    ))
+    .unwrap()
+    .unwrap();
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+    let connections = Arc::new(AtomicUsize::new(0));
+    RawServer::new(move |stream| {
+        let acceptor = acceptor.clone();
+        let connection = connections.fetch_add(1, Ordering::SeqCst);
+        async move {
+            let mut stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+            while let Some(head) = read_request_head(&mut stream).await {
+                let body = format!(
+                    "{} on connection {}",
+                    head.lines().next().unwrap(),
+                    connection
+                );
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                if stream.write_all(response.as_bytes()).await.is_err() {
+                    return;
+                }
+            }
+        }
+    })
+    .await
+}
+
+/// With --upstream-tls, requests reach an HTTPS upstream, and its connections are pooled between
+/// clients like plaintext ones. --upstream-tls-insecure lets a self-signed upstream through.
+#[tokio::test]
+async fn test_upstream_tls() {
+    init_logging();
+    let (cert_path, key_path) = write_self_signed_cert("upstream");
+    let upstream = tls_upstream(&cert_path, &key_path).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--upstream-tls",
+            "--upstream-tls-insecure",
+            "--max-idle-per-upstream",
+            "1",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let mut connections = Vec::new();
+    for i in 0..3 {
+        // A new client each time, so that the upstream connection goes back to the pool in between
+        let path = format!("/upstream-tls-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        let (request_line, connection) = response_text
+            .split_once(" on connection ")
+            .unwrap_or_else(|| panic!("Unexpected response: {}", response_text));
+        assert_eq!(request_line, format!("GET {} HTTP/1.1", path));
+        connections.push(connection.to_string());
+    }
+    assert!(
+        connections
+            .iter()
+            .all(|connection| *connection == connections[0]),
+        "The pooled TLS connection wasn't reused: {:?}",
+        connections
+    );
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("not verified for any upstream")));
+
+    Box::new(upstream).stop().await;
+    let _ = std::fs::remove_file(cert_path);
+    let _ = std::fs::remove_file(key_path);
+    log::info!("All done :)");
+}
+
+/// Without --upstream-tls-insecure, an upstream whose certificate can't be verified isn't sent
+/// anything.
+#[tokio::test]
+async fn test_upstream_tls_unverified_certificate() {
+    init_logging();
+    let (cert_path, key_path) = write_self_signed_cert("unverified");
+    let upstream = tls_upstream(&cert_path, &key_path).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--upstream-tls", "--active-health-check-interval", "600"],
+    )
+    .await;
+
+    let response = reqwest::get(format!("http://{}/unverified", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    assert!(!response.text().await.unwrap().contains("GET /unverified"));
+
+    Box::new(upstream).stop().await;
+    let _ = std::fs::remove_file(cert_path);
+    let _ = std::fs::remove_file(key_path);
+    log::info!("All done :)");
+}
+
+/// An upstream flagged #tls-insecure should get its self-signed certificate accepted, while the
+/// others' certificates are still checked.
+#[tokio::test]
+async fn test_upstream_tls_insecure_flag() {
+    init_logging();
+    let (cert_path, key_path) = write_self_signed_cert("insecure-flag");
+    let insecure = tls_upstream(&cert_path, &key_path).await;
+    let checked = tls_upstream(&cert_path, &key_path).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[
+            &format!("/insecure={}#tls-insecure", insecure.address),
+            &format!("/checked={}", checked.address),
+        ],
+        &["--upstream-tls", "--active-health-check-interval", "600"],
+    )
+    .await;
+
+    let response_text = balancebeam
+        .get("/insecure/a")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(
+        response_text.starts_with("GET /insecure/a HTTP/1.1"),
+        "Unexpected response: {}",
+        response_text
+    );
+    let response = reqwest::get(format!("http://{}/checked/a", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    let output = balancebeam.output();
+    assert!(output.iter().any(|line| line.contains(&format!(
+        "Upstream {} is flagged #tls-insecure",
+        insecure.address
+    ))));
+    assert!(!output.iter().any(|line| line.contains(&format!(
+        "Upstream {} is flagged #tls-insecure",
+        checked.address
+    ))));
+
+    Box::new(insecure).stop().await;
+    Box::new(checked).stop().await;
+    let _ = std::fs::remove_file(cert_path);
+    let _ = std::fs::remove_file(key_path);
+    log::info!("All done :)");
+}
@@ -1,13 +1,13 @@
-use rand::Rng;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
 use tokio::time::sleep;
 
 pub struct BalanceBeam {
-    #[allow(dead_code)]
     child: Child, // process is killed when dropped (Command::kill_on_drop)
     pub address: String,
+    output: Arc<Mutex<Vec<String>>>, // every line the child has printed so far
 }
 
 impl BalanceBeam {
@@ -24,28 +24,38 @@ impl BalanceBeam {
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
     ) -> BalanceBeam {
-        let mut rng = rand::thread_rng();
-        let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
-        let mut cmd = Command::new(BalanceBeam::target_bin_path());
-        cmd.arg("--bind").arg(&address);
-        for upstream in upstreams {
-            cmd.arg("--upstream").arg(upstream);
-        }
+        let mut args = Vec::new();
         if let Some(active_health_check_interval) = active_health_check_interval {
-            cmd.arg("--active-health-check-interval")
-                .arg(active_health_check_interval.to_string());
+            args.push("--active-health-check-interval".to_string());
+            args.push(active_health_check_interval.to_string());
         }
         if let Some(max_requests_per_minute) = max_requests_per_minute {
-            cmd.arg("--max-requests-per-minute")
-                .arg(max_requests_per_minute.to_string());
+            args.push("--max-requests-per-minute".to_string());
+            args.push(max_requests_per_minute.to_string());
+        }
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        BalanceBeam::new_with_args(upstreams, &args).await
+    }
+
+    /// Starts balancebeam with the given upstreams, passing any additional command-line arguments
+    /// through verbatim.
+    pub async fn new_with_args(upstreams: &[&str], extra_args: &[&str]) -> BalanceBeam {
+        let mut cmd = Command::new(BalanceBeam::target_bin_path());
+        // Let the OS pick the port; balancebeam logs the one it got
+        cmd.arg("--bind").arg("127.0.0.1:0");
+        for upstream in upstreams {
+            cmd.arg("--upstream").arg(upstream);
         }
+        cmd.args(extra_args);
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
-        let mut child = cmd.spawn().expect(&format!(
-            "Could not execute balancebeam binary {}",
-            BalanceBeam::target_bin_path().to_str().unwrap()
-        ));
+        let mut child = cmd.spawn().unwrap_or_else(|_| {
+            panic!(
+                "Could not execute balancebeam binary {}",
+                BalanceBeam::target_bin_path().to_str().unwrap()
+            )
+        });
 
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
@@ -54,6 +64,8 @@ impl BalanceBeam {
             .stdout
             .take()
             .expect("Child process somehow missing stdout pipe!");
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let stdout_output = output.clone();
         tokio::spawn(async move {
             let mut stdout_reader = BufReader::new(stdout).lines();
             while let Some(line) = stdout_reader
@@ -62,12 +74,14 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stdout")
             {
                 println!("Balancebeam output: {}", line);
+                stdout_output.lock().unwrap().push(line);
             }
         });
         let stderr = child
             .stderr
             .take()
             .expect("Child process somehow missing stderr pipe!");
+        let stderr_output = output.clone();
         tokio::spawn(async move {
             let mut stderr_reader = BufReader::new(stderr).lines();
             while let Some(line) = stderr_reader
@@ -76,19 +90,92 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stderr")
             {
                 println!("Balancebeam output: {}", line);
+                stderr_output.lock().unwrap().push(line);
             }
         });
 
         // Hack: wait for executable to start running
         sleep(Duration::from_secs(1)).await;
-        BalanceBeam { child, address }
+        let mut balancebeam = BalanceBeam {
+            child,
+            address: String::new(),
+            output,
+        };
+        if let Some(address) = balancebeam
+            .logged_address("Listening for requests on ")
+            .await
+        {
+            balancebeam.address = address;
+        }
+        balancebeam
+    }
+
+    /// Returns the address balancebeam logged right after the given message, waiting a little for
+    /// the line to show up. Returns None if balancebeam exits first (e.g. because it rejected its
+    /// configuration).
+    pub async fn logged_address(&mut self, message: &str) -> Option<String> {
+        for _ in 0..50 {
+            let address = self.output().iter().find_map(|line| {
+                line.split_once(message)
+                    .map(|(_, address)| address.trim().to_string())
+            });
+            if address.is_some() {
+                return address;
+            }
+            if self.has_exited() {
+                return None;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        None
+    }
+
+    /// Returns the lines balancebeam has printed (i.e. its log) so far.
+    pub fn output(&self) -> Vec<String> {
+        self.output.lock().unwrap().clone()
+    }
+
+    /// Returns true if the balancebeam process has already exited (e.g. because it rejected its
+    /// configuration).
+    pub fn has_exited(&mut self) -> bool {
+        self.child
+            .try_wait()
+            .expect("Error checking balancebeam process status")
+            .is_some()
+    }
+
+    /// Returns the process ID of balancebeam, which must still be running.
+    pub fn pid(&self) -> nix::unistd::Pid {
+        nix::unistd::Pid::from_raw(self.child.id().expect("balancebeam already exited") as i32)
+    }
+
+    /// Sends balancebeam SIGTERM, asking it to shut down.
+    pub fn terminate(&self) {
+        nix::sys::signal::kill(self.pid(), nix::sys::signal::Signal::SIGTERM)
+            .expect("Could not send SIGTERM to balancebeam");
+    }
+
+    /// Sends balancebeam SIGHUP, asking it to reload its TLS certificate.
+    pub fn hang_up(&self) {
+        nix::sys::signal::kill(self.pid(), nix::sys::signal::Signal::SIGHUP)
+            .expect("Could not send SIGHUP to balancebeam");
+    }
+
+    /// Waits up to timeout for the balancebeam process to exit, returning whether it did.
+    pub async fn wait_for_exit(&mut self, timeout: Duration) -> bool {
+        let exited = tokio::time::timeout(timeout, self.child.wait())
+            .await
+            .is_ok();
+        // Give the output readers a moment to catch up with the last lines it printed
+        sleep(Duration::from_millis(100)).await;
+        exited
     }
 
     #[allow(dead_code)]
     pub async fn get(&self, path: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
         client
-            .get(&format!("http://{}{}", self.address, path))
+            .get(format!("http://{}{}", self.address, path))
             .header("x-sent-by", "balancebeam-tests")
             .send()
             .await?
@@ -100,7 +187,7 @@ impl BalanceBeam {
     pub async fn post(&self, path: &str, body: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
         client
-            .post(&format!("http://{}{}", self.address, path))
+            .post(format!("http://{}{}", self.address, path))
             .header("x-sent-by", "balancebeam-tests")
             .body(body.to_string())
             .send()
@@ -1,8 +1,8 @@
 use crate::common::server::Server;
+use crate::common::ReservedAddress;
 use async_trait::async_trait;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response};
-use rand::Rng;
 use std::sync::{atomic, Arc};
 use tokio::sync::oneshot;
 
@@ -41,12 +41,26 @@ pub struct EchoServer {
 
 impl EchoServer {
     pub async fn new() -> EchoServer {
-        let mut rng = rand::thread_rng();
-        EchoServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024..65535))).await
+        EchoServer::new_at_address("127.0.0.1:0".to_string()).await
     }
 
     pub async fn new_at_address(bind_addr_string: String) -> EchoServer {
-        let bind_addr = bind_addr_string.parse().unwrap();
+        let listener = tokio::net::TcpListener::bind(&bind_addr_string)
+            .await
+            .and_then(|listener| listener.into_std())
+            .unwrap_or_else(|err| {
+                panic!("EchoServer could not bind to {}: {}", bind_addr_string, err)
+            });
+        EchoServer::new_with_listener(listener)
+    }
+
+    /// Starts serving on a port reserved earlier, e.g. one given to balancebeam as a dead upstream.
+    pub fn new_at_reserved(reserved: ReservedAddress) -> EchoServer {
+        EchoServer::new_with_listener(reserved.listen())
+    }
+
+    fn new_with_listener(listener: std::net::TcpListener) -> EchoServer {
+        let address = listener.local_addr().unwrap().to_string();
         // Create a one-shot channel that can be used to tell the server to shut down
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
 
@@ -65,7 +79,8 @@ impl EchoServer {
                     }))
                 }
             });
-            let server = hyper::Server::bind(&bind_addr)
+            let server = hyper::Server::from_tcp(listener)
+                .unwrap()
                 .serve(service)
                 .with_graceful_shutdown(async {
                     shutdown_rx.await.ok();
@@ -80,7 +95,7 @@ impl EchoServer {
             shutdown_signal_sender: shutdown_tx,
             server_task,
             state: server_state,
-            address: bind_addr_string,
+            address,
         }
     }
 }
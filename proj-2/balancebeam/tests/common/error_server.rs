@@ -2,7 +2,6 @@ use crate::common::server::Server;
 use async_trait::async_trait;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Response};
-use rand::Rng;
 use std::sync::{atomic, Arc};
 use tokio::sync::oneshot;
 
@@ -29,13 +28,25 @@ pub struct ErrorServer {
 impl ErrorServer {
     #[allow(dead_code)]
     pub async fn new() -> ErrorServer {
-        let mut rng = rand::thread_rng();
-        ErrorServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024..65535))).await
+        ErrorServer::new_at_address("127.0.0.1:0".to_string()).await
     }
 
     #[allow(dead_code)]
     pub async fn new_at_address(bind_addr_string: String) -> ErrorServer {
-        let bind_addr = bind_addr_string.parse().unwrap();
+        let listener = tokio::net::TcpListener::bind(&bind_addr_string)
+            .await
+            .and_then(|listener| listener.into_std())
+            .unwrap_or_else(|err| {
+                panic!(
+                    "ErrorServer could not bind to {}: {}",
+                    bind_addr_string, err
+                )
+            });
+        ErrorServer::new_with_listener(listener)
+    }
+
+    fn new_with_listener(listener: std::net::TcpListener) -> ErrorServer {
+        let address = listener.local_addr().unwrap().to_string();
         // Create a one-shot channel that can be used to tell the server to shut down
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
 
@@ -56,7 +67,8 @@ impl ErrorServer {
                     }))
                 }
             });
-            let server = hyper::Server::bind(&bind_addr)
+            let server = hyper::Server::from_tcp(listener)
+                .unwrap()
                 .serve(service)
                 .with_graceful_shutdown(async {
                     shutdown_rx.await.ok();
@@ -71,7 +83,7 @@ impl ErrorServer {
             shutdown_signal_sender: shutdown_tx,
             server_task,
             state: server_state,
-            address: bind_addr_string,
+            address,
         }
     }
 }
@@ -1,6 +1,10 @@
+// Each integration test binary only uses some of these helpers
+#![allow(dead_code, unused_imports)]
+
 mod balancebeam;
 mod echo_server;
 mod error_server;
+mod raw_server;
 mod server;
 
 use std::sync;
@@ -8,10 +12,37 @@ use std::sync;
 pub use balancebeam::BalanceBeam;
 pub use echo_server::EchoServer;
 pub use error_server::ErrorServer;
+pub use raw_server::{read_request_head, RawServer};
 pub use server::Server;
 
 static INIT_TESTS: sync::Once = sync::Once::new();
 
+/// A local port held by a socket that is bound but not listening, so connections to it are refused
+/// and nothing else in the test run can take it until it's handed to a server.
+pub struct ReservedAddress {
+    socket: tokio::net::TcpSocket,
+    pub address: String,
+}
+
+impl ReservedAddress {
+    pub fn new() -> ReservedAddress {
+        let socket = tokio::net::TcpSocket::new_v4().expect("Could not create a socket");
+        socket
+            .bind("127.0.0.1:0".parse().unwrap())
+            .expect("Could not reserve a local port");
+        let address = socket.local_addr().unwrap().to_string();
+        ReservedAddress { socket, address }
+    }
+
+    /// Starts listening on the reserved port.
+    pub fn listen(self) -> std::net::TcpListener {
+        self.socket
+            .listen(1024)
+            .and_then(|listener| listener.into_std())
+            .expect("Could not listen on a reserved port")
+    }
+}
+
 pub fn init_logging() {
     INIT_TESTS.call_once(|| {
         pretty_env_logger::formatted_builder()
@@ -0,0 +1,85 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Debug, Default)]
+struct ServerState {
+    pub peer_addresses: Mutex<Vec<SocketAddr>>,
+}
+
+/// An upstream server that hands each accepted connection to a test-supplied handler, for tests
+/// that need to control the exact bytes on the wire (misbehaving upstreams, unusual framing, etc.)
+/// rather than going through hyper.
+pub struct RawServer {
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl RawServer {
+    pub async fn new<F, Fut>(handler: F) -> RawServer
+    where
+        F: Fn(TcpStream) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("RawServer could not bind");
+        let address = listener.local_addr().unwrap().to_string();
+        let server_state = Arc::new(ServerState::default());
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            while let Ok((stream, peer_address)) = listener.accept().await {
+                server_task_state
+                    .peer_addresses
+                    .lock()
+                    .unwrap()
+                    .push(peer_address);
+                tokio::spawn(handler(stream));
+            }
+        });
+        RawServer {
+            server_task,
+            address,
+            state: server_state,
+        }
+    }
+
+    /// Addresses of every client (i.e. balancebeam connection) that has connected so far
+    pub fn peer_addresses(&self) -> Vec<SocketAddr> {
+        self.state.peer_addresses.lock().unwrap().clone()
+    }
+}
+
+/// Reads the request line and headers of one request from the stream, returning them as a string
+/// (without the terminating blank line). Returns None if the peer hangs up first.
+pub async fn read_request_head<S: AsyncRead + Unpin>(stream: &mut S) -> Option<String> {
+    let mut head = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).await.ok()? == 0 {
+            return None;
+        }
+        head.push(byte[0]);
+    }
+    head.truncate(head.len() - 4);
+    Some(String::from_utf8_lossy(&head).into_owned())
+}
+
+#[async_trait]
+impl Server for RawServer {
+    /// Stops accepting connections and returns the number of connections that were accepted.
+    async fn stop(self: Box<Self>) -> usize {
+        self.server_task.abort();
+        let _ = self.server_task.await;
+        self.state.peer_addresses.lock().unwrap().len()
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
@@ -0,0 +1,357 @@
+// State of a single game of hangman, kept separate from the terminal I/O in main.rs so that it can
+// be saved to disk, resumed, and tested.
+use serde::{Deserialize, Serialize};
+use serde_json;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+
+pub const NUM_INCORRECT_GUESSES: u32 = 5;
+const HIDDEN_CHAR: char = '-';
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Game {
+    secret_word: String,
+    // Letters of the secret word revealed so far, with HIDDEN_CHAR in positions not yet guessed
+    revealed: Vec<char>,
+    // Every letter the player has guessed, in the order they were guessed
+    guessed: Vec<char>,
+    guesses_left: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GuessOutcome {
+    // The letter appears in the secret word
+    Hit,
+    // The letter doesn't appear in the secret word, and the player lost a guess
+    Miss,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GuessError {
+    // The player didn't type anything but whitespace
+    Empty,
+    // The secret word is plain ASCII, so a letter outside it can't be in the word, and is more
+    // likely a typo (or a keyboard layout surprise) than a real guess
+    NotAscii(char),
+}
+
+impl fmt::Display for GuessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GuessError::Empty => write!(f, "please type a letter"),
+            GuessError::NotAscii(letter) => {
+                write!(
+                    f,
+                    "{:?} isn't an ASCII letter; please guess one that is",
+                    letter
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    // The save file couldn't be read
+    Io(io::Error),
+    // The save file isn't a valid saved game
+    Corrupt(serde_json::Error),
+    // The save file parsed, but describes a game that can't exist
+    Incompatible(&'static str),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "could not read save file: {}", err),
+            LoadError::Corrupt(err) => write!(f, "save file is corrupt: {}", err),
+            LoadError::Incompatible(reason) => write!(f, "save file is incompatible: {}", reason),
+        }
+    }
+}
+
+impl Game {
+    pub fn new(secret_word: &str) -> Game {
+        Game {
+            secret_word: secret_word.to_string(),
+            revealed: vec![HIDDEN_CHAR; secret_word.chars().count()],
+            guessed: Vec::new(),
+            guesses_left: NUM_INCORRECT_GUESSES,
+        }
+    }
+
+    pub fn secret_word(&self) -> &str {
+        &self.secret_word
+    }
+
+    pub fn word_so_far(&self) -> String {
+        self.revealed.iter().collect()
+    }
+
+    pub fn guessed_letters(&self) -> String {
+        self.guessed.iter().collect()
+    }
+
+    pub fn guesses_left(&self) -> u32 {
+        self.guesses_left
+    }
+
+    pub fn is_won(&self) -> bool {
+        !self.revealed.contains(&HIDDEN_CHAR)
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.guesses_left == 0
+    }
+
+    // Picks the letter to guess out of a line the player typed. Only the first letter counts. A
+    // secret word made entirely of ASCII only takes ASCII guesses; one with other letters in it
+    // takes any letter.
+    pub fn parse_guess(&self, input: &str) -> Result<char, GuessError> {
+        let letter = input.trim().chars().next().ok_or(GuessError::Empty)?;
+        if self.secret_word.is_ascii() && !letter.is_ascii() {
+            return Err(GuessError::NotAscii(letter));
+        }
+        Ok(letter)
+    }
+
+    // Reveals every occurrence of the letter in the secret word. A letter that doesn't appear costs
+    // the player a guess, even if they've guessed it before.
+    pub fn guess(&mut self, letter: char) -> GuessOutcome {
+        self.guessed.push(letter);
+        let mut found = false;
+        for (index, ch) in self.secret_word.chars().enumerate() {
+            if ch == letter {
+                self.revealed[index] = ch;
+                found = true;
+            }
+        }
+        if found {
+            GuessOutcome::Hit
+        } else {
+            self.guesses_left = self.guesses_left.saturating_sub(1);
+            GuessOutcome::Miss
+        }
+    }
+
+    // Describes the letters still hidden without giving any of them away, e.g. "3 letters remain,
+    // including 2 vowels". Each hidden position counts, so a letter appearing twice counts twice.
+    pub fn hint_summary(&self) -> String {
+        let hidden: Vec<char> = self
+            .secret_word
+            .chars()
+            .zip(self.revealed.iter())
+            .filter(|(_, revealed)| **revealed == HIDDEN_CHAR)
+            .map(|(ch, _)| ch)
+            .collect();
+        let vowels = hidden.iter().filter(|ch| is_vowel(**ch)).count();
+        format!(
+            "{} {}, including {} {}",
+            hidden.len(),
+            if hidden.len() == 1 {
+                "letter remains"
+            } else {
+                "letters remain"
+            },
+            vowels,
+            if vowels == 1 { "vowel" } else { "vowels" }
+        )
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(path, json)
+    }
+
+    pub fn load(path: &str) -> Result<Game, LoadError> {
+        let json = fs::read_to_string(path).map_err(LoadError::Io)?;
+        let game: Game = serde_json::from_str(&json).map_err(LoadError::Corrupt)?;
+        game.validate()?;
+        Ok(game)
+    }
+
+    // Makes sure a deserialized game is internally consistent, so that a hand-edited or outdated
+    // save file can't put the game into a state that would panic later.
+    fn validate(&self) -> Result<(), LoadError> {
+        if self.secret_word.is_empty() {
+            return Err(LoadError::Incompatible("secret word is empty"));
+        }
+        if self.revealed.len() != self.secret_word.chars().count() {
+            return Err(LoadError::Incompatible(
+                "revealed letters don't match the secret word's length",
+            ));
+        }
+        let guessed: HashSet<char> = self.guessed.iter().cloned().collect();
+        for (revealed, actual) in self.revealed.iter().zip(self.secret_word.chars()) {
+            if *revealed != HIDDEN_CHAR && (*revealed != actual || !guessed.contains(revealed)) {
+                return Err(LoadError::Incompatible(
+                    "revealed letters don't match the secret word",
+                ));
+            }
+        }
+        if self.guesses_left > NUM_INCORRECT_GUESSES {
+            return Err(LoadError::Incompatible("too many guesses left"));
+        }
+        // There's nothing left to play in a finished game
+        if self.is_lost() {
+            return Err(LoadError::Incompatible("game is already lost"));
+        }
+        if self.is_won() {
+            return Err(LoadError::Incompatible("game is already won"));
+        }
+        Ok(())
+    }
+}
+
+fn is_vowel(ch: char) -> bool {
+    "aeiou".contains(ch.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn temp_path(name: &str) -> String {
+        let mut path = env::temp_dir();
+        path.push(format!("hangman-test-{}-{}.json", process::id(), name));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_save_and_resume_round_trip() {
+        let mut game = Game::new("lobster");
+        assert_eq!(game.guess('o'), GuessOutcome::Hit);
+        assert_eq!(game.guess('z'), GuessOutcome::Miss);
+        let path = temp_path("round-trip");
+        game.save(&path).unwrap();
+
+        let mut resumed = Game::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(resumed, game);
+        assert_eq!(resumed.word_so_far(), "-o-----");
+        assert_eq!(resumed.guessed_letters(), "oz");
+        assert_eq!(resumed.guesses_left(), NUM_INCORRECT_GUESSES - 1);
+
+        // The resumed game should carry on exactly where the saved one left off
+        for letter in "lbster".chars() {
+            assert_eq!(resumed.guess(letter), GuessOutcome::Hit);
+        }
+        assert!(resumed.is_won());
+        assert_eq!(resumed.guesses_left(), NUM_INCORRECT_GUESSES - 1);
+    }
+
+    #[test]
+    fn test_hint_summary_counts_hidden_positions() {
+        let mut game = Game::new("banana");
+        assert_eq!(game.hint_summary(), "6 letters remain, including 3 vowels");
+        game.guess('a');
+        assert_eq!(game.hint_summary(), "3 letters remain, including 0 vowels");
+        game.guess('z');
+        assert_eq!(game.hint_summary(), "3 letters remain, including 0 vowels");
+
+        let mut game = Game::new("lobster");
+        game.guess('l');
+        game.guess('b');
+        game.guess('s');
+        game.guess('t');
+        game.guess('r');
+        assert_eq!(game.hint_summary(), "2 letters remain, including 2 vowels");
+        game.guess('o');
+        assert_eq!(game.hint_summary(), "1 letter remains, including 1 vowel");
+    }
+
+    #[test]
+    fn test_non_ascii_guess_rejected_for_ascii_word() {
+        let game = Game::new("lobster");
+        assert_eq!(game.parse_guess("o\n"), Ok('o'));
+        assert_eq!(game.parse_guess("é\n"), Err(GuessError::NotAscii('é')));
+        assert_eq!(game.parse_guess("ö\n"), Err(GuessError::NotAscii('ö')));
+        assert_eq!(game.parse_guess("  \n"), Err(GuessError::Empty));
+        // Rejected guesses don't cost anything
+        assert_eq!(game.guesses_left(), NUM_INCORRECT_GUESSES);
+        assert_eq!(game.guessed_letters(), "");
+    }
+
+    #[test]
+    fn test_non_ascii_guess_allowed_for_non_ascii_word() {
+        let mut game = Game::new("café");
+        let letter = game.parse_guess("é\n").unwrap();
+        assert_eq!(game.guess(letter), GuessOutcome::Hit);
+        assert_eq!(game.word_so_far(), "---é");
+    }
+
+    #[test]
+    fn test_load_corrupt_save() {
+        let path = temp_path("corrupt");
+        fs::write(&path, "{ this is not json").unwrap();
+        let result = Game::load(&path);
+        fs::remove_file(&path).unwrap();
+        match result {
+            Err(LoadError::Corrupt(_)) => {}
+            other => panic!("expected a corrupt save error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_load_incompatible_save() {
+        let path = temp_path("incompatible");
+        fs::write(
+            &path,
+            r#"{"secret_word":"lobster","revealed":["x"],"guessed":[],"guesses_left":5}"#,
+        )
+        .unwrap();
+        let result = Game::load(&path);
+        fs::remove_file(&path).unwrap();
+        match result {
+            Err(LoadError::Incompatible(_)) => {}
+            other => panic!("expected an incompatible save error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_load_finished_save() {
+        for (name, json) in [
+            (
+                "lost",
+                r#"{"secret_word":"lobster","revealed":["-","o","-","-","-","-","-"],"guessed":["o"],"guesses_left":0}"#,
+            ),
+            (
+                "won",
+                r#"{"secret_word":"ox","revealed":["o","x"],"guessed":["o","x"],"guesses_left":5}"#,
+            ),
+        ]
+        .iter()
+        {
+            let path = temp_path(name);
+            fs::write(&path, json).unwrap();
+            let result = Game::load(&path);
+            fs::remove_file(&path).unwrap();
+            match result {
+                Err(LoadError::Incompatible(_)) => {}
+                other => panic!("expected a {} game to be refused, got {:?}", name, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_miss_after_last_guess_does_not_underflow() {
+        let mut game = Game::new("ox");
+        for _ in 0..NUM_INCORRECT_GUESSES + 1 {
+            assert_eq!(game.guess('z'), GuessOutcome::Miss);
+        }
+        assert!(game.is_lost());
+        assert_eq!(game.guesses_left(), 0);
+    }
+
+    #[test]
+    fn test_load_missing_save() {
+        match Game::load(&temp_path("missing")) {
+            Err(LoadError::Io(_)) => {}
+            other => panic!("expected an I/O error, got {:?}", other),
+        }
+    }
+}
@@ -13,43 +13,98 @@
 // We've tried to limit/hide Rust's quirks since we'll discuss those details
 // more in depth in the coming lectures.
 extern crate rand;
+extern crate serde;
+extern crate serde_json;
+
+mod game;
+mod words;
+
+use game::{Game, GuessOutcome};
 use rand::Rng;
-use std::collections::HashSet;
+use std::env;
 use std::fs;
 use std::io;
 use std::io::Write;
+use std::process;
 
-const NUM_INCORRECT_GUESSES: u32 = 5;
 const WORDS_PATH: &str = "words.txt";
+// Where the game is saved if the player types "save" without having resumed from a file
+const DEFAULT_SAVE_PATH: &str = "hangman-save.json";
+const SAVE_COMMAND: &str = "save";
+// Describes the hidden letters without revealing any, and doesn't cost a guess
+const HINT_COMMAND: &str = "hint";
+
+// Picks a word from words.txt, only out of those in the given category if there is one. Words
+// without a category are in words::DEFAULT_CATEGORY.
+fn pick_a_random_word(category: Option<&str>) -> Result<String, String> {
+    let file_string = fs::read_to_string(WORDS_PATH)
+        .map_err(|err| format!("unable to read {}: {}", WORDS_PATH, err))?;
+    let word_list = words::parse_word_list(&file_string);
+    let words = words::playable_words(&word_list, category)
+        .map_err(|err| format!("{} has {}", WORDS_PATH, err))?;
+    if words.len() < words::FEW_WORDS {
+        let noun = if words.len() == 1 { "word" } else { "words" };
+        println!(
+            "Note: there are only {} {} to pick from, so expect to see the same ones again.",
+            words.len(),
+            noun
+        );
+    }
+    Ok(String::from(
+        words[rand::thread_rng().gen_range(0, words.len())],
+    ))
+}
 
-fn pick_a_random_word() -> String {
-    let file_string = fs::read_to_string(WORDS_PATH).expect("Unable to read file.");
-    let words: Vec<&str> = file_string.split('\n').collect();
-    String::from(words[rand::thread_rng().gen_range(0, words.len())].trim())
+// Returns the value following the given flag on the command line, if any
+fn arg_value(flag: &str) -> Option<String> {
+    let args: Vec<String> = env::args().collect();
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|index| args.get(index + 1).cloned())
 }
 
 fn main() {
-    let secret_word = pick_a_random_word();
-    // Note: given what you know about Rust so far, it's easier to pull characters out of a
-    // vector than it is to pull them out of a string. You can get the ith character of
-    // secret_word by doing secret_word_chars[i].
-    let secret_word_chars: Vec<char> = secret_word.chars().collect();
+    let resume_path = arg_value("--resume");
+    let category = arg_value("--category");
+    let new_game = || match pick_a_random_word(category.as_deref()) {
+        Ok(word) => Game::new(&word),
+        Err(err) => {
+            println!("Unable to pick a word: {}", err);
+            process::exit(1);
+        }
+    };
+    let mut game = match &resume_path {
+        Some(path) => match Game::load(path) {
+            Ok(game) => {
+                println!("Resuming saved game from {}", path);
+                game
+            }
+            Err(err) => {
+                println!(
+                    "Unable to resume game from {} ({}); starting a new game.",
+                    path, err
+                );
+                new_game()
+            }
+        },
+        None => new_game(),
+    };
+    let save_path = resume_path.unwrap_or_else(|| DEFAULT_SAVE_PATH.to_string());
     // Uncomment for debugging:
-    println!("random word: {}", secret_word);
+    println!("random word: {}", game.secret_word());
 
-    // Your code here! :)
     println!("Welcome to CS110L Hangman!");
-    let word_len = secret_word_chars.len();
-    let mut remain_len = word_len;
-    let mut found_word = vec!['-'; word_len];
-    let mut guesses = String::new();
-    let mut num_guess = NUM_INCORRECT_GUESSES;
-    let mut found_char: HashSet<char> = HashSet::new();
+    println!(
+        "(Type \"{}\" at any time to save your game and quit, or \"{}\" for a hint.)",
+        SAVE_COMMAND, HINT_COMMAND
+    );
     loop {
-        let word_str: String = found_word.clone().into_iter().collect();
-        println!("The word so far is {:?}", word_str);
-        println!("You have guessed the following letters: {:?}", guesses);
-        println!("You have {:?} guess left", num_guess);
+        println!("The word so far is {:?}", game.word_so_far());
+        println!(
+            "You have guessed the following letters: {:?}",
+            game.guessed_letters()
+        );
+        println!("You have {:?} guess left", game.guesses_left());
 
         print!("Please guess a letter: ");
         io::stdout().flush().expect("Error flushing stdout.");
@@ -58,37 +113,47 @@ fn main() {
             .read_line(&mut guess)
             .expect("Error reading line.");
 
-        let guess_char = guess.chars().next().unwrap();
-
-        let find_index: Vec<usize> = secret_word_chars
-            .iter()
-            .enumerate()
-            .filter_map(|(index, &ch)| if ch == guess_char { Some(index) } else { None })
-            .collect();
+        if guess.trim() == SAVE_COMMAND {
+            match game.save(&save_path) {
+                Ok(()) => {
+                    println!(
+                        "Game saved to {}. Resume it with --resume {}",
+                        save_path, save_path
+                    );
+                    break;
+                }
+                Err(err) => {
+                    println!("Unable to save game to {}: {}", save_path, err);
+                    continue;
+                }
+            }
+        }
 
-        let find_num = find_index.len();
+        if guess.trim() == HINT_COMMAND {
+            println!("Hint: {}", game.hint_summary());
+            continue;
+        }
 
-        if find_num == 0 {
-            num_guess -= 1;
-            println!("Sorry, the letter is not in the word");
-        } else if found_char.insert(guess_char) {
-            for index in find_index {
-                found_word[index] = guess_char;
+        let guess_char = match game.parse_guess(&guess) {
+            Ok(letter) => letter,
+            Err(err) => {
+                println!("Invalid guess: {}", err);
+                continue;
             }
-            remain_len -= find_num;
+        };
+        if game.guess(guess_char) == GuessOutcome::Miss {
+            println!("Sorry, the letter is not in the word");
         }
 
-        guesses.push(guess_char);
-
-        if num_guess == 0 {
+        if game.is_lost() {
             println!("Sorry, you ran out of guesses!");
             break;
         }
 
-        if remain_len == 0 {
+        if game.is_won() {
             println!(
                 "Congratulations you guessed the secret word: {:?}!",
-                secret_word
+                game.secret_word()
             );
             break;
         }
@@ -0,0 +1,125 @@
+// Parsing of the word list. Each line holds a word, optionally tagged with a category after a
+// colon (e.g. "xylophone:hard"), so that a game can be restricted to words of one category.
+
+// The category of words listed without one
+pub const DEFAULT_CATEGORY: &str = "general";
+
+#[derive(Debug, PartialEq)]
+pub struct Word {
+    pub word: String,
+    pub category: String,
+}
+
+// Parses one line of the word list, returning None for a blank line. Surrounding whitespace and
+// the case of the category are ignored, so "Apple : Easy" is the word "Apple" in category "easy".
+pub fn parse_line(line: &str) -> Option<Word> {
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+    let (word, category) = match line.rfind(':') {
+        Some(index) => (line[..index].trim(), line[index + 1..].trim()),
+        None => (line, ""),
+    };
+    if word.is_empty() {
+        return None;
+    }
+    let category = if category.is_empty() {
+        DEFAULT_CATEGORY.to_string()
+    } else {
+        category.to_lowercase()
+    };
+    Some(Word {
+        word: word.to_string(),
+        category,
+    })
+}
+
+pub fn parse_word_list(contents: &str) -> Vec<Word> {
+    contents.lines().filter_map(parse_line).collect()
+}
+
+// Returns the words a game may pick from: those in the given category, or every word if no
+// category was asked for.
+pub fn in_category<'a>(words: &'a [Word], category: Option<&str>) -> Vec<&'a str> {
+    words
+        .iter()
+        .filter(|word| category.is_none_or(|category| word.category.eq_ignore_ascii_case(category)))
+        .map(|word| word.word.as_str())
+        .collect()
+}
+
+// Word lists shorter than this make for games that keep repeating, which the player is warned of
+pub const FEW_WORDS: usize = 3;
+
+// Returns the words a game may pick from, like in_category, or an error saying there are none (so
+// that there's never an empty list to pick from).
+pub fn playable_words<'a>(
+    words: &'a [Word],
+    category: Option<&str>,
+) -> Result<Vec<&'a str>, String> {
+    let playable = in_category(words, category);
+    if playable.is_empty() {
+        return Err(match category {
+            Some(category) => format!("no words in category {:?}", category),
+            None => String::from("no words"),
+        });
+    }
+    Ok(playable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_category_filtering() {
+        let words = parse_word_list("apple:easy\nxylophone:hard\n\nbanana:Easy\nquartz : hard\n");
+        assert_eq!(in_category(&words, Some("easy")), vec!["apple", "banana"]);
+        assert_eq!(
+            in_category(&words, Some("HARD")),
+            vec!["xylophone", "quartz"]
+        );
+        assert!(in_category(&words, Some("medium")).is_empty());
+        assert_eq!(in_category(&words, None).len(), 4);
+    }
+
+    #[test]
+    fn test_no_playable_words() {
+        let words = parse_word_list("\n   \n:hard\n\n");
+        assert!(words.is_empty());
+        assert_eq!(playable_words(&words, None), Err(String::from("no words")));
+        let words = parse_word_list("apple:easy\n");
+        assert_eq!(
+            playable_words(&words, Some("hard")),
+            Err(String::from("no words in category \"hard\""))
+        );
+    }
+
+    #[test]
+    fn test_single_word() {
+        let words = parse_word_list("rustacean\n");
+        assert_eq!(playable_words(&words, None), Ok(vec!["rustacean"]));
+        assert_eq!(
+            playable_words(&words, Some(DEFAULT_CATEGORY)),
+            Ok(vec!["rustacean"])
+        );
+    }
+
+    #[test]
+    fn test_plain_lines_get_default_category() {
+        let words = parse_word_list("immutable\nborrowed:\nlobster:sea\n");
+        assert_eq!(
+            words[0],
+            Word {
+                word: "immutable".to_string(),
+                category: DEFAULT_CATEGORY.to_string(),
+            }
+        );
+        assert_eq!(words[1].category, DEFAULT_CATEGORY);
+        assert_eq!(
+            in_category(&words, Some(DEFAULT_CATEGORY)),
+            vec!["immutable", "borrowed"]
+        );
+    }
+}
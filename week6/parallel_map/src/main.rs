@@ -1,7 +1,64 @@
-use crossbeam_channel;
+use futures_util::{Stream, StreamExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::{thread, time};
 
-fn parallel_map<T, U, F>(mut input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
+/// Options for the worker threads spawned by parallel_map_with_config.
+#[derive(Clone, Debug)]
+struct WorkerConfig {
+    /// Workers are named "{name_prefix}-{i}", which shows up in panic messages and profilers
+    name_prefix: String,
+    /// Stack size for each worker, in bytes. None uses the std default (currently 2 MiB), which
+    /// may be too small for closures that recurse deeply.
+    stack_size: Option<usize>,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        WorkerConfig {
+            name_prefix: String::from("parallel-map-worker"),
+            stack_size: None,
+        }
+    }
+}
+
+fn parallel_map<T, U, F>(input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static + Default,
+{
+    parallel_map_with_config(input_vec, num_threads, &WorkerConfig::default(), f)
+}
+
+/// Like parallel_map, but with one worker per core the machine makes available to us, and never
+/// more workers than there are inputs.
+fn parallel_map_auto<T, U, F>(input_vec: Vec<T>, f: F) -> Vec<U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static + Default,
+{
+    let num_threads = auto_thread_count(input_vec.len());
+    parallel_map(input_vec, num_threads, f)
+}
+
+/// Number of workers parallel_map_auto uses for an input of the given length. If the available
+/// parallelism can't be determined, we fall back to a single worker.
+fn auto_thread_count(input_len: usize) -> usize {
+    thread::available_parallelism()
+        .map_or(1, |parallelism| parallelism.get())
+        .min(input_len)
+}
+
+fn parallel_map_with_config<T, U, F>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    config: &WorkerConfig,
+    f: F,
+) -> Vec<U>
 where
     F: FnOnce(T) -> U + Send + Copy + 'static,
     T: Send + 'static,
@@ -10,22 +67,95 @@ where
     let mut output_vec: Vec<U> = Vec::with_capacity(input_vec.len());
     output_vec.resize_with(input_vec.len(), Default::default);
 
+    let (output_receiver, threads) = spawn_workers(input_vec, num_threads, config, f);
+    while let Ok(elem_pair) = output_receiver.recv() {
+        let (idx, data) = elem_pair;
+        output_vec[idx] = data;
+    }
+
+    // join the threads until all work finishes
+    for thread in threads {
+        thread.join().expect("Panic occurred in thread!");
+    }
+    output_vec
+}
+
+/// Like parallel_map, but hands back each result, paired with the index of its input, as soon as it
+/// is done, for async code to consume with `.next().await` instead of blocking until every input
+/// has been mapped. Results arrive in the order they finish, not the order of the inputs.
+fn parallel_map_into_stream<T, U, F>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    f: F,
+) -> impl Stream<Item = (usize, U)>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let (output_receiver, threads) =
+        spawn_workers(input_vec, num_threads, &WorkerConfig::default(), f);
+    // Receiving from the crossbeam channel blocks, so it's done on a thread of its own, which
+    // passes results on to a channel that can be awaited. The stream ends once the workers are
+    // done and this thread has joined them.
+    let (stream_sender, stream_receiver) = futures_channel::mpsc::unbounded();
+    thread::Builder::new()
+        .name(format!("{}-stream", WorkerConfig::default().name_prefix))
+        .spawn(move || {
+            while let Ok(elem_pair) = output_receiver.recv() {
+                // If the consumer dropped the stream, there's nobody left to send results to, but
+                // the workers still get to finish
+                let _ = stream_sender.unbounded_send(elem_pair);
+            }
+            for thread in threads {
+                thread.join().expect("Panic occurred in thread!");
+            }
+        })
+        .expect("failed to spawn stream thread!");
+    stream_receiver
+}
+
+/// Spawns the workers and sends them every input, paired with its index. Returns the channel the
+/// workers send each (index, output) pair back on, which disconnects once all the work is done,
+/// along with the workers' join handles.
+fn spawn_workers<T, U, F>(
+    mut input_vec: Vec<T>,
+    num_threads: usize,
+    config: &WorkerConfig,
+    f: F,
+) -> (
+    crossbeam_channel::Receiver<(usize, U)>,
+    Vec<thread::JoinHandle<()>>,
+)
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
     let (input_sender, input_receiver) = crossbeam_channel::unbounded();
     let (output_sender, output_receiver) = crossbeam_channel::unbounded();
 
     let mut threads = Vec::new();
-    for _ in 0..num_threads {
+    for i in 0..num_threads {
         let input_receiver = input_receiver.clone();
         let output_sender = output_sender.clone();
-        threads.push(thread::spawn(move || {
-            while let Ok(elem_pair) = input_receiver.recv() {
-                // receive the tuple and send back the processed pair
-                let (idx, data) = elem_pair;
-                output_sender
-                    .send((idx, f(data)))
-                    .expect("no receivers found in output channel!");
-            }
-        }));
+        let mut builder = thread::Builder::new().name(format!("{}-{}", config.name_prefix, i));
+        if let Some(stack_size) = config.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        threads.push(
+            builder
+                .spawn(move || {
+                    while let Ok(elem_pair) = input_receiver.recv() {
+                        // receive the tuple and send back the processed pair
+                        let (idx, data) = elem_pair;
+                        output_sender
+                            .send((idx, f(data)))
+                            .expect("no receivers found in output channel!");
+                    }
+                })
+                .expect("failed to spawn worker thread!"),
+        );
     }
 
     for i in (0..input_vec.len()).rev() {
@@ -37,6 +167,58 @@ where
     drop(input_sender);
     drop(output_sender);
 
+    (output_receiver, threads)
+}
+
+/// Like parallel_map, but for inputs whose costs are known (or can be estimated) up front. Each
+/// input comes paired with its cost, and the inputs are divided among the workers ahead of time so
+/// that every worker ends up with about the same total cost, instead of one worker being left to
+/// finish a few expensive inputs long after the others are done.
+fn parallel_map_weighted<T, U, F>(
+    input_with_costs: Vec<(T, u64)>,
+    num_threads: usize,
+    f: F,
+) -> Vec<U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static + Default,
+{
+    let mut output_vec: Vec<U> = Vec::with_capacity(input_with_costs.len());
+    output_vec.resize_with(input_with_costs.len(), Default::default);
+
+    let costs: Vec<u64> = input_with_costs.iter().map(|(_, cost)| *cost).collect();
+    let assignments = lpt_assignment(&costs, num_threads);
+    let mut inputs: Vec<Option<T>> = input_with_costs
+        .into_iter()
+        .map(|(data, _)| Some(data))
+        .collect();
+
+    let (output_sender, output_receiver) = crossbeam_channel::unbounded();
+
+    let mut threads = Vec::new();
+    for (i, indices) in assignments.into_iter().enumerate() {
+        let work: Vec<(usize, T)> = indices
+            .into_iter()
+            .map(|idx| (idx, inputs[idx].take().unwrap()))
+            .collect();
+        let output_sender = output_sender.clone();
+        threads.push(
+            thread::Builder::new()
+                .name(format!("{}-{}", WorkerConfig::default().name_prefix, i))
+                .spawn(move || {
+                    for (idx, data) in work {
+                        output_sender
+                            .send((idx, f(data)))
+                            .expect("no receivers found in output channel!");
+                    }
+                })
+                .expect("failed to spawn worker thread!"),
+        );
+    }
+
+    drop(output_sender);
+
     while let Ok(elem_pair) = output_receiver.recv() {
         let (idx, data) = elem_pair;
         output_vec[idx] = data;
@@ -49,12 +231,221 @@ where
     output_vec
 }
 
+/// Divides inputs among workers using the greedy longest-processing-time-first rule: going from
+/// the most expensive input to the cheapest, each input goes to the worker with the least total
+/// cost so far. Returns the input indices assigned to each worker, most expensive first.
+fn lpt_assignment(costs: &[u64], num_threads: usize) -> Vec<Vec<usize>> {
+    assert!(num_threads > 0, "parallel_map needs at least one thread");
+    let mut order: Vec<usize> = (0..costs.len()).collect();
+    order.sort_by_key(|&idx| Reverse(costs[idx]));
+
+    let mut assignments = vec![Vec::new(); num_threads];
+    // Min-heap of (total cost so far, worker)
+    let mut loads: BinaryHeap<Reverse<(u64, usize)>> = (0..num_threads)
+        .map(|worker| Reverse((0, worker)))
+        .collect();
+    for idx in order {
+        let Reverse((load, worker)) = loads.pop().unwrap();
+        assignments[worker].push(idx);
+        loads.push(Reverse((load + costs[idx], worker)));
+    }
+    assignments
+}
+
+/// A pseudorandom delay of up to max_ms for processing the given input, for making workers finish
+/// out of order in demos and tests. The delay for each input depends only on the seed and the
+/// input, not on which worker happens to pick the input up, so the same seed scrambles the
+/// completion order the same way every run.
+fn jitter(seed: u64, input: u64, max_ms: u64) -> time::Duration {
+    let mut rng = StdRng::seed_from_u64(seed ^ input.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    time::Duration::from_millis(rng.gen_range(0..=max_ms))
+}
+
 fn main() {
+    let seed = 110;
     let v = vec![6, 7, 8, 9, 10, 1, 2, 3, 4, 5, 12, 18, 11, 5, 20];
-    let squares = parallel_map(v, 10, |num| {
+    let squares = parallel_map(v, 10, move |num| {
+        thread::sleep(jitter(seed, num, 500));
         println!("{} squared is {}", num, num * num);
-        thread::sleep(time::Duration::from_millis(500));
         num * num
     });
-    println!("squares: {:?}", squares);
+    println!("squares (seed {}): {:?}", seed, squares);
+
+    let cubes = parallel_map_auto((1..=20).collect(), |num: u64| num * num * num);
+    println!("cubes: {:?}", cubes);
+
+    // Sleeping for num * 100ms costs about num units of work
+    let sleeps: Vec<(u64, u64)> = vec![9, 1, 1, 8, 2, 2, 7, 3, 3]
+        .into_iter()
+        .map(|num| (num, num))
+        .collect();
+    let slept = parallel_map_weighted(sleeps, 3, |num| {
+        thread::sleep(time::Duration::from_millis(num * 100));
+        num
+    });
+    println!("slept: {:?}", slept);
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("failed to start async runtime!");
+    runtime.block_on(async {
+        let mut doubled = parallel_map_into_stream((1..=10).collect(), 4, |num: u64| {
+            thread::sleep(time::Duration::from_millis((10 - num) * 50));
+            num * 2
+        });
+        while let Some((idx, num)) = doubled.next().await {
+            println!("input {} doubled is {}", idx, num);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::time::{Duration, Instant};
+
+    /// Squares the inputs in parallel, holding each one up by its jitter for the seed. Returns each
+    /// square along with how long after the start it was done.
+    fn jittered_squares(input: Vec<u64>, num_threads: usize, seed: u64) -> Vec<(u64, Duration)> {
+        let start = Instant::now();
+        parallel_map(input, num_threads, move |n| {
+            thread::sleep(jitter(seed, n, 20));
+            (n * n, start.elapsed())
+        })
+    }
+
+    #[test]
+    fn test_jitter_is_reproducible() {
+        let delays = |seed| (0..50).map(|n| jitter(seed, n, 20)).collect::<Vec<_>>();
+        assert_eq!(delays(7), delays(7));
+        assert_ne!(delays(7), delays(8));
+        assert!(delays(7)
+            .iter()
+            .all(|delay| *delay <= Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn test_order_preserved_under_scrambled_completion() {
+        let input: Vec<u64> = (0..24).collect();
+        let expected: Vec<u64> = input.iter().map(|n| n * n).collect();
+        for seed in 0..5 {
+            let output = jittered_squares(input.clone(), 4, seed);
+            let squares: Vec<u64> = output.iter().map(|(square, _)| *square).collect();
+            assert_eq!(squares, expected, "seed {}", seed);
+            // Make sure the workers really did finish out of order, or the check above proves
+            // nothing
+            assert!(
+                output.windows(2).any(|pair| pair[0].1 > pair[1].1),
+                "seed {} didn't scramble completion",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn test_workers_are_named() {
+        let config = WorkerConfig {
+            name_prefix: String::from("test-worker"),
+            stack_size: Some(4 * 1024 * 1024),
+        };
+        let names = parallel_map_with_config((0..20).collect(), 4, &config, |_: i32| {
+            // Give every worker a chance to pick up some of the input
+            thread::sleep(time::Duration::from_millis(10));
+            thread::current().name().unwrap_or("").to_string()
+        });
+        let names: HashSet<String> = names.into_iter().collect();
+        let expected: HashSet<String> = (0..4).map(|i| format!("test-worker-{}", i)).collect();
+        assert!(!names.is_empty());
+        assert!(names.is_subset(&expected), "unexpected names {:?}", names);
+    }
+
+    #[test]
+    fn test_parallel_map_auto_small_input() {
+        let names = parallel_map_auto(vec![1, 2], |_: i32| {
+            thread::sleep(time::Duration::from_millis(10));
+            thread::current().name().unwrap_or("").to_string()
+        });
+        assert!(auto_thread_count(2) <= 2);
+        // Only workers 0 and 1 may exist; none beyond the number of items
+        let names: HashSet<String> = names.into_iter().collect();
+        let allowed: HashSet<String> = (0..2)
+            .map(|i| format!("{}-{}", WorkerConfig::default().name_prefix, i))
+            .collect();
+        assert!(names.is_subset(&allowed), "unexpected names {:?}", names);
+        assert_eq!(auto_thread_count(0), 0);
+        assert!(parallel_map_auto(Vec::new(), |n: i32| n).is_empty());
+    }
+
+    #[test]
+    fn test_parallel_map_auto_large_input() {
+        let available = thread::available_parallelism().unwrap().get();
+        assert_eq!(auto_thread_count(10_000), available);
+        let output = parallel_map_auto((0..10_000).collect(), |n: u64| n * 2);
+        assert_eq!(output, (0..10_000).map(|n| n * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_lpt_assignment_balances_costs() {
+        let costs = [90, 10, 50, 40, 30, 30, 20, 20, 10, 100, 60, 40];
+        let assignments = lpt_assignment(&costs, 3);
+
+        let mut assigned: Vec<usize> = assignments.iter().flatten().copied().collect();
+        assigned.sort_unstable();
+        assert_eq!(assigned, (0..costs.len()).collect::<Vec<_>>());
+
+        let totals: Vec<u64> = assignments
+            .iter()
+            .map(|indices| indices.iter().map(|&idx| costs[idx]).sum())
+            .collect();
+        // 500 total across 3 workers; LPT is guaranteed to be within 4/3 of the best split, but on
+        // this input it lands within one small item of perfectly even
+        let (min, max) = (totals.iter().min().unwrap(), totals.iter().max().unwrap());
+        assert!(max - min <= 10, "unbalanced totals {:?}", totals);
+    }
+
+    #[test]
+    fn test_parallel_map_weighted() {
+        let input: Vec<(u64, u64)> = (0..20).map(|n| (n, n % 7 + 1)).collect();
+        let output = parallel_map_weighted(input, 4, |n| {
+            thread::sleep(time::Duration::from_millis(n % 7));
+            n * n
+        });
+        assert_eq!(output, (0..20).map(|n| n * n).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_parallel_map_into_stream() {
+        let input: Vec<u64> = (0..50).collect();
+        let stream = parallel_map_into_stream(input, 4, |n| {
+            thread::sleep(time::Duration::from_millis(n % 5));
+            n * n
+        });
+        let mut results: Vec<(usize, u64)> = stream.collect().await;
+        assert_eq!(results.len(), 50);
+        results.sort_unstable();
+        assert_eq!(
+            results,
+            (0..50).map(|n| (n as usize, n * n)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_large_stack_size() {
+        fn depth(n: u64) -> u64 {
+            // Keep a sizable frame alive on every level so the recursion needs a big stack
+            let frame = [n; 64];
+            if n == 0 {
+                0
+            } else {
+                1 + depth(frame[63] - 1)
+            }
+        }
+        let config = WorkerConfig {
+            stack_size: Some(64 * 1024 * 1024),
+            ..WorkerConfig::default()
+        };
+        let depths = parallel_map_with_config(vec![20_000_u64; 4], 2, &config, depth);
+        assert_eq!(depths, vec![20_000; 4]);
+    }
 }